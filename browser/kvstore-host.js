@@ -0,0 +1,90 @@
+// Host imports for the wasm32 build (`kvstore_host` module). Store files are
+// kept in memory so the engine's synchronous storage calls can be served, and
+// every change is mirrored to IndexedDB in order. Commits return before the
+// IndexedDB write lands; await `flush()` when durability matters.
+export async function createHost(dbName = "kvstore") {
+  const request = (req) =>
+    new Promise((resolve, reject) => {
+      req.onsuccess = () => resolve(req.result);
+      req.onerror = () => reject(req.error);
+    });
+  const open = indexedDB.open(dbName, 1);
+  open.onupgradeneeded = () => open.result.createObjectStore("files");
+  const db = await request(open);
+
+  const files = new Map();
+  const tx = db.transaction("files", "readonly").objectStore("files");
+  const [keys, values] = await Promise.all([request(tx.getAllKeys()), request(tx.getAll())]);
+  keys.forEach((key, i) => files.set(key, new Uint8Array(values[i])));
+
+  let memory = null;
+  let pending = Promise.resolve();
+  const encoder = new TextEncoder();
+  const decoder = new TextDecoder();
+  const view = (ptr, len) => new Uint8Array(memory.buffer, ptr, len);
+  const name = (ptr, len) => decoder.decode(view(ptr, len));
+  const persist = (file) => {
+    const data = files.get(file);
+    pending = pending.then(() => {
+      const store = db.transaction("files", "readwrite").objectStore("files");
+      return request(data === undefined ? store.delete(file) : store.put(data.slice(), file));
+    });
+    return 0;
+  };
+
+  const kvstore_host = {
+    host_now_millis: () => Date.now(),
+    host_random: (ptr, len) => {
+      for (let at = 0; at < len; at += 65536) crypto.getRandomValues(view(ptr + at, Math.min(65536, len - at)));
+      return 0;
+    },
+    host_list: (ptr, cap) => {
+      const out = encoder.encode([...files.keys()].join("\n"));
+      if (out.length <= cap) view(ptr, out.length).set(out);
+      return out.length;
+    },
+    host_size: (n, nl) => {
+      const data = files.get(name(n, nl));
+      return data === undefined ? -1 : data.length;
+    },
+    host_read: (n, nl, ptr, cap) => {
+      const data = files.get(name(n, nl)) ?? new Uint8Array(0);
+      view(ptr, Math.min(cap, data.length)).set(data.subarray(0, cap));
+      return data.length;
+    },
+    host_write: (n, nl, ptr, len) => {
+      const file = name(n, nl);
+      files.set(file, view(ptr, len).slice());
+      return persist(file);
+    },
+    host_append: (n, nl, ptr, len) => {
+      const file = name(n, nl);
+      const old = files.get(file) ?? new Uint8Array(0);
+      const data = new Uint8Array(old.length + len);
+      data.set(old);
+      data.set(view(ptr, len), old.length);
+      files.set(file, data);
+      return persist(file);
+    },
+    host_truncate: (n, nl, len) => {
+      const file = name(n, nl);
+      const data = files.get(file);
+      if (data === undefined) return -1;
+      files.set(file, data.slice(0, len));
+      return persist(file);
+    },
+    host_remove: (n, nl) => {
+      const file = name(n, nl);
+      files.delete(file);
+      return persist(file);
+    },
+  };
+
+  return {
+    imports: { kvstore_host },
+    bind(instance) {
+      memory = instance.exports.memory;
+    },
+    flush: () => pending,
+  };
+}
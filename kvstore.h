@@ -0,0 +1,48 @@
+#ifndef KVSTORE_H
+#define KVSTORE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KV_OK 0
+#define KV_NOT_FOUND 1
+#define KV_ERR_ABORTED -1
+#define KV_ERR_IO -2
+#define KV_ERR_CORRUPTION -3
+#define KV_ERR_INVALID -4
+#define KV_ERR_NO_MEMORY -5
+/* A Rust panic was caught at the call boundary; kv_last_error() has the message. Functions returning a pointer
+ * return NULL instead, and void functions just record the message. The store or transaction involved may be left
+ * unusable, but the host process keeps running. */
+#define KV_ERR_PANIC -6
+
+typedef struct KvStoreHandle KvStoreHandle;
+typedef struct KvTxnHandle KvTxnHandle;
+
+/* Message for the last failed call on this thread, or NULL. Valid until the next failure. */
+const char *kv_last_error(void);
+
+KvStoreHandle *kv_open_memory(void);
+int kv_open(const char *path, KvStoreHandle **out);
+void kv_close(KvStoreHandle *store);
+
+KvTxnHandle *kv_begin(const KvStoreHandle *store);
+/* Both functions free the transaction handle. */
+int kv_commit(KvTxnHandle *txn);
+void kv_rollback(KvTxnHandle *txn);
+
+/* Keys and values must be UTF-8. On KV_OK, *value must be released with kv_free(*value, *value_len). */
+int kv_get(const KvTxnHandle *txn, const uint8_t *key, size_t key_len, uint8_t **value, size_t *value_len);
+void kv_free(uint8_t *buf, size_t len);
+int kv_put(KvTxnHandle *txn, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+int kv_delete(KvTxnHandle *txn, const uint8_t *key, size_t key_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,1130 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Bound, Deref, RangeBounds};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+pub type Version = u64;
+pub type Writes = BTreeMap<String, Option<String>>;
+type KeyFilter = Box<dyn Fn(&str) -> bool + Send + Sync>;
+pub const SLAB_PAGE: usize = 1 << 20;
+static SLAB_PAGES: AtomicUsize = AtomicUsize::new(0);
+static SLAB_RESERVED: AtomicUsize = AtomicUsize::new(0);
+static SLAB_LIVE: AtomicUsize = AtomicUsize::new(0);
+// Threads carve small values from their own stripe's open page rather than contending on a single one.
+const SLAB_STRIPES: usize = 8;
+static SLABS: [SpinLock<Cursor>; SLAB_STRIPES] = [const { SpinLock::new_const(Cursor(None)) }; SLAB_STRIPES];
+struct Cursor(Option<(Arc<Page>, usize)>);
+impl Cursor {
+    fn reserve(&mut self, len: usize) -> (Arc<Page>, usize) {
+        if self.0.as_ref().is_none_or(|(page, used)| used + len > page.capacity) {
+            if let Some((page, _)) = self.0.take() {
+                page.sealed.store(true, Ordering::Relaxed);
+            }
+            self.0 = Some((Page::new(SLAB_PAGE), 0));
+        }
+        let (page, used) = self.0.as_mut().unwrap();
+        let start = *used;
+        *used += len;
+        (Arc::clone(page), start)
+    }
+}
+#[cfg(feature = "std")]
+fn home_stripe() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::thread_local! {
+        static STRIPE: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SLAB_STRIPES;
+    }
+    STRIPE.try_with(|stripe| *stripe).unwrap_or(0)
+}
+#[cfg(not(feature = "std"))]
+fn home_stripe() -> usize {
+    0
+}
+fn reserve(len: usize) -> (Arc<Page>, usize) {
+    let home = home_stripe();
+    (0..SLAB_STRIPES)
+        .find_map(|i| SLABS[(home + i) % SLAB_STRIPES].try_with(|cursor| cursor.reserve(len)))
+        .unwrap_or_else(|| SLABS[home].with(|cursor| cursor.reserve(len)))
+}
+struct Page {
+    bytes: NonNull<u8>,
+    capacity: usize,
+    live: AtomicUsize,
+    sealed: AtomicBool,
+}
+unsafe impl Send for Page {}
+unsafe impl Sync for Page {}
+impl Page {
+    fn new(capacity: usize) -> Arc<Page> {
+        let mut buffer = core::mem::ManuallyDrop::new(Vec::<u8>::with_capacity(capacity.max(1)));
+        SLAB_PAGES.fetch_add(1, Ordering::Relaxed);
+        SLAB_RESERVED.fetch_add(buffer.capacity(), Ordering::Relaxed);
+        let bytes = NonNull::new(buffer.as_mut_ptr()).unwrap();
+        Arc::new(Page { bytes, capacity: buffer.capacity(), live: AtomicUsize::new(0), sealed: AtomicBool::new(false) })
+    }
+}
+impl Drop for Page {
+    fn drop(&mut self) {
+        SLAB_PAGES.fetch_sub(1, Ordering::Relaxed);
+        SLAB_RESERVED.fetch_sub(self.capacity, Ordering::Relaxed);
+        drop(unsafe { Vec::from_raw_parts(self.bytes.as_ptr(), 0, self.capacity) });
+    }
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlabUsage {
+    pub pages: usize,
+    pub reserved: usize,
+    pub live: usize,
+}
+pub fn slab_usage() -> SlabUsage {
+    SlabUsage {
+        pages: SLAB_PAGES.load(Ordering::Relaxed),
+        reserved: SLAB_RESERVED.load(Ordering::Relaxed),
+        live: SLAB_LIVE.load(Ordering::Relaxed),
+    }
+}
+pub struct Value {
+    page: Arc<Page>,
+    start: usize,
+    len: usize,
+}
+impl Value {
+    pub fn new(text: &str) -> Value {
+        let len = text.len();
+        let (page, start) = match len > SLAB_PAGE / 4 {
+            true => (Page::new(len), 0),
+            false => reserve(len),
+        };
+        unsafe { core::ptr::copy_nonoverlapping(text.as_ptr(), page.bytes.as_ptr().add(start), len) };
+        page.live.fetch_add(len, Ordering::Relaxed);
+        SLAB_LIVE.fetch_add(len, Ordering::Relaxed);
+        Value { page, start, len }
+    }
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(self.page.bytes.as_ptr().add(self.start), self.len)) }
+    }
+    pub fn fragmented(&self) -> bool {
+        self.page.sealed.load(Ordering::Relaxed) && self.page.live.load(Ordering::Relaxed) * 2 < self.page.capacity
+    }
+    pub fn relocate(&mut self) {
+        *self = Value::new(self.as_str());
+    }
+    pub fn scrub(self) {
+        for i in 0..self.len {
+            unsafe { core::ptr::write_volatile(self.page.bytes.as_ptr().add(self.start + i), 0) };
+        }
+        core::sync::atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+impl Drop for Value {
+    fn drop(&mut self) {
+        self.page.live.fetch_sub(self.len, Ordering::Relaxed);
+        SLAB_LIVE.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+impl Deref for Value {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+impl AsRef<str> for Value {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        Value::new(self.as_str())
+    }
+}
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+impl Eq for Value {}
+impl PartialEq<str> for Value {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+impl core::fmt::Debug for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::new(text)
+    }
+}
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::new(&text)
+    }
+}
+/// A key's bytes, allocated once when the key first enters a [`VersionMap`]. Clones share that allocation, so the
+/// engine's side tables (point indexes, recency, the read cache) refer to a key without copying it.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(Arc<str>);
+impl Key {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    pub fn ptr_eq(&self, other: &Key) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+    pub fn strong_count(key: &Key) -> usize {
+        Arc::strong_count(&key.0)
+    }
+}
+impl Deref for Key {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+impl AsRef<str> for Key {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+impl core::borrow::Borrow<str> for Key {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+impl PartialEq<str> for Key {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+impl PartialEq<&str> for Key {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+impl PartialEq<String> for Key {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+impl core::fmt::Debug for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&*self.0, f)
+    }
+}
+impl core::fmt::Display for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl From<&str> for Key {
+    fn from(text: &str) -> Self {
+        Key(Arc::from(text))
+    }
+}
+impl From<String> for Key {
+    fn from(text: String) -> Self {
+        Key(Arc::from(text))
+    }
+}
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        String::from(&*key.0)
+    }
+}
+type Node = (Version, Option<Value>);
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Handle {
+    start: u32,
+    len: u32,
+    cap: u32,
+}
+impl Handle {
+    fn slots(&self) -> core::ops::Range<usize> {
+        self.start as usize..self.start as usize + self.len as usize
+    }
+}
+fn slot(index: usize) -> u32 {
+    u32::try_from(index).expect("version arena exceeds u32 handles")
+}
+#[derive(Clone, Default)]
+pub struct VersionMap {
+    handles: BTreeMap<Key, Handle>,
+    nodes: Vec<Node>,
+    garbage: usize,
+    moving: Option<Moving>,
+}
+/// An arena compaction in progress: chains of keys up to `through` have been copied into the map's new arena, the
+/// rest still live in `old`.
+#[derive(Clone)]
+struct Moving {
+    old: Vec<Node>,
+    through: Option<Key>,
+    moved: usize,
+    garbage: usize,
+}
+impl Moving {
+    fn holds(&self, key: &str) -> bool {
+        self.through.as_deref().is_none_or(|through| key > through)
+    }
+}
+#[derive(Clone, Copy)]
+struct Arenas<'a> {
+    nodes: &'a [Node],
+    moving: Option<&'a Moving>,
+}
+impl<'a> Arenas<'a> {
+    fn chain(&self, key: &str, handle: &Handle) -> &'a Chain {
+        match self.moving {
+            Some(moving) if moving.holds(key) => Chain::new(&moving.old[handle.slots()]),
+            _ => Chain::new(&self.nodes[handle.slots()]),
+        }
+    }
+}
+fn chain_mut<'a>(
+    key: &'a Key,
+    handle: &'a mut Handle,
+    nodes: &'a mut Vec<Node>,
+    garbage: &'a mut usize,
+    moving: &'a mut Option<Moving>,
+) -> ChainMut<'a> {
+    match moving {
+        Some(moving) if moving.holds(key) => ChainMut { key, handle, nodes: &mut moving.old, garbage: &mut moving.garbage },
+        _ => ChainMut { key, handle, nodes, garbage },
+    }
+}
+impl VersionMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn arenas(&self) -> Arenas<'_> {
+        Arenas { nodes: &self.nodes, moving: self.moving.as_ref() }
+    }
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.handles.contains_key(key)
+    }
+    pub fn get(&self, key: &str) -> Option<&Chain> {
+        self.handles.get(key).map(|handle| self.arenas().chain(key, handle))
+    }
+    pub fn get_key_value(&self, key: &str) -> Option<(&Key, &Chain)> {
+        self.handles.get_key_value(key).map(|(key, handle)| (key, self.arenas().chain(key, handle)))
+    }
+    pub fn key(&self, key: &str) -> Option<&Key> {
+        self.handles.get_key_value(key).map(|(key, _)| key)
+    }
+    pub fn get_mut(&mut self, key: &str) -> Option<ChainMut<'_>> {
+        let (key, handle) = self.handles.range_mut::<str, _>((Bound::Included(key), Bound::Included(key))).next()?;
+        Some(chain_mut(key, handle, &mut self.nodes, &mut self.garbage, &mut self.moving))
+    }
+    pub fn entry(&mut self, key: String) -> ChainMut<'_> {
+        if !self.handles.contains_key(key.as_str()) {
+            self.handles.insert(Key::from(key.as_str()), Handle::default());
+        }
+        self.get_mut(&key).unwrap()
+    }
+    pub fn insert<V: Into<Value>>(&mut self, key: String, version: Version, value: Option<V>) -> Option<Option<Value>> {
+        self.entry(key).insert(version, value)
+    }
+    pub fn remove(&mut self, key: &str) -> Option<ChainBuf> {
+        let (key, handle) = self.handles.remove_entry(key)?;
+        let (nodes, garbage) = match &mut self.moving {
+            Some(moving) if moving.holds(&key) => (&mut moving.old, &mut moving.garbage),
+            _ => (&mut self.nodes, &mut self.garbage),
+        };
+        *garbage += handle.cap as usize;
+        Some(ChainBuf(nodes[handle.slots()].iter_mut().map(core::mem::take).collect()))
+    }
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &Key> + ExactSizeIterator {
+        self.handles.keys()
+    }
+    pub fn iter(&self) -> Chains<'_, alloc::collections::btree_map::Iter<'_, Key, Handle>> {
+        Chains { handles: self.handles.iter(), arenas: self.arenas() }
+    }
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Chains<'_, alloc::collections::btree_map::Range<'_, Key, Handle>> {
+        let bounds = (range.start_bound().map(String::as_str), range.end_bound().map(String::as_str));
+        Chains { handles: self.handles.range::<str, _>(bounds), arenas: self.arenas() }
+    }
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Value> {
+        let old = self.moving.as_mut().map(|moving| moving.old.iter_mut());
+        self.nodes.iter_mut().chain(old.into_iter().flatten()).filter_map(|(_, value)| value.as_mut())
+    }
+    pub fn for_each_mut(&mut self, f: impl FnMut(&Key, ChainMut<'_>)) {
+        self.for_each_mut_in::<core::ops::RangeFull>(.., f)
+    }
+    pub fn for_each_mut_in<R: RangeBounds<String>>(&mut self, range: R, mut f: impl FnMut(&Key, ChainMut<'_>)) {
+        let bounds = (range.start_bound().map(String::as_str), range.end_bound().map(String::as_str));
+        for (key, handle) in self.handles.range_mut::<str, _>(bounds) {
+            f(key, chain_mut(key, handle, &mut self.nodes, &mut self.garbage, &mut self.moving));
+        }
+    }
+    pub fn garbage(&self) -> usize {
+        self.garbage + self.moving.as_ref().map_or(0, |moving| moving.garbage)
+    }
+    pub fn compact(&mut self) -> usize {
+        loop {
+            if let Some(reclaimed) = self.compact_step(usize::MAX) {
+                return reclaimed;
+            }
+        }
+    }
+    /// Copies the chains of the next `batch` keys into a fresh arena, so a caller can spread one compaction over
+    /// several lock holds. Returns the slots reclaimed once every key has been moved and the old arena is dropped.
+    pub fn compact_step(&mut self, batch: usize) -> Option<usize> {
+        let (nodes, garbage) = (&mut self.nodes, &mut self.garbage);
+        let moving = self.moving.get_or_insert_with(|| {
+            let old = core::mem::take(nodes);
+            *nodes = Vec::with_capacity(old.len().saturating_sub(*garbage));
+            Moving { old, through: None, moved: 0, garbage: core::mem::take(garbage) }
+        });
+        let start = match &moving.through {
+            Some(through) => Bound::Excluded(through.as_str()),
+            None => Bound::Unbounded,
+        };
+        let mut last = None;
+        let mut count = 0;
+        for (key, handle) in self.handles.range_mut::<str, _>((start, Bound::Unbounded)).take(batch) {
+            let start = slot(nodes.len());
+            nodes.extend(moving.old[handle.slots()].iter_mut().map(core::mem::take));
+            moving.moved += handle.len as usize;
+            *handle = Handle { start, len: handle.len, cap: handle.len };
+            last = Some(key);
+            count += 1;
+        }
+        if count == batch {
+            moving.through = last.cloned();
+            return None;
+        }
+        let reclaimed = moving.old.len() - moving.moved;
+        self.moving = None;
+        Some(reclaimed)
+    }
+}
+impl PartialEq for VersionMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+impl Eq for VersionMap {}
+impl core::fmt::Debug for VersionMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+impl<'a> IntoIterator for &'a VersionMap {
+    type Item = (&'a Key, &'a Chain);
+    type IntoIter = Chains<'a, alloc::collections::btree_map::Iter<'a, Key, Handle>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+pub struct Chains<'a, I> {
+    handles: I,
+    arenas: Arenas<'a>,
+}
+impl<'a, I: Iterator<Item = (&'a Key, &'a Handle)>> Iterator for Chains<'a, I> {
+    type Item = (&'a Key, &'a Chain);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, handle) = self.handles.next()?;
+        Some((key, self.arenas.chain(key, handle)))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.handles.size_hint()
+    }
+}
+impl<'a, I: DoubleEndedIterator<Item = (&'a Key, &'a Handle)>> DoubleEndedIterator for Chains<'a, I> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, handle) = self.handles.next_back()?;
+        Some((key, self.arenas.chain(key, handle)))
+    }
+}
+impl<'a, I: ExactSizeIterator<Item = (&'a Key, &'a Handle)>> ExactSizeIterator for Chains<'a, I> {}
+#[derive(Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Chain([Node]);
+impl Chain {
+    fn new(nodes: &[Node]) -> &Chain {
+        // Sound because `Chain` is a transparent wrapper around the slice.
+        unsafe { &*(nodes as *const [Node] as *const Chain) }
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn get(&self, version: &Version) -> Option<&Option<Value>> {
+        self.0.binary_search_by_key(version, |&(v, _)| v).ok().map(|i| &self.0[i].1)
+    }
+    pub fn latest(&self) -> Option<(&Version, &Option<Value>)> {
+        self.0.last().map(|(v, value)| (v, value))
+    }
+    pub fn at(&self, version: Version) -> Option<(&Version, &Option<Value>)> {
+        match self.0.last() {
+            Some((last, value)) if *last <= version => Some((last, value)),
+            _ => {
+                let i = self.0.partition_point(|&(v, _)| v <= version).checked_sub(1)?;
+                let (v, value) = &self.0[i];
+                Some((v, value))
+            }
+        }
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Version, &Option<Value>)> + ExactSizeIterator {
+        self.0.iter().map(|(v, value)| (v, value))
+    }
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &Version> + ExactSizeIterator {
+        self.0.iter().map(|(v, _)| v)
+    }
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &Option<Value>> + ExactSizeIterator {
+        self.0.iter().map(|(_, value)| value)
+    }
+    pub fn range<R: RangeBounds<Version>>(&self, range: R) -> impl DoubleEndedIterator<Item = (&Version, &Option<Value>)> {
+        let start = match range.start_bound() {
+            Bound::Included(v) => self.0.partition_point(|(x, _)| x < v),
+            Bound::Excluded(v) => self.0.partition_point(|(x, _)| x <= v),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(v) => self.0.partition_point(|(x, _)| x <= v),
+            Bound::Excluded(v) => self.0.partition_point(|(x, _)| x < v),
+            Bound::Unbounded => self.0.len(),
+        };
+        self.0[start..end.max(start)].iter().map(|(v, value)| (v, value))
+    }
+}
+impl<'a> IntoIterator for &'a Chain {
+    type Item = (&'a Version, &'a Option<Value>);
+    type IntoIter = core::iter::Map<core::slice::Iter<'a, Node>, fn(&'a Node) -> (&'a Version, &'a Option<Value>)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter().map(|(v, value)| (v, value))
+    }
+}
+pub struct ChainMut<'a> {
+    key: &'a Key,
+    handle: &'a mut Handle,
+    nodes: &'a mut Vec<Node>,
+    garbage: &'a mut usize,
+}
+impl ChainMut<'_> {
+    pub fn key(&self) -> &Key {
+        self.key
+    }
+    pub fn insert<V: Into<Value>>(&mut self, version: Version, value: Option<V>) -> Option<Option<Value>> {
+        let value = value.map(Into::into);
+        let run = &mut self.nodes[self.handle.slots()];
+        let at = match run.last() {
+            Some(&(last, _)) if last < version => run.len(),
+            _ => match run.binary_search_by_key(&version, |&(v, _)| v) {
+                Ok(i) => return Some(core::mem::replace(&mut run[i].1, value)),
+                Err(i) => i,
+            },
+        };
+        self.reserve_one();
+        let len = self.handle.len as usize;
+        let run = &mut self.nodes[self.handle.start as usize..][..len + 1];
+        run[len] = (version, value);
+        run[at..].rotate_right(1);
+        self.handle.len += 1;
+        None
+    }
+    fn reserve_one(&mut self) {
+        let Handle { start, len, cap } = *self.handle;
+        if len < cap {
+            return;
+        }
+        let (start, cap) = (start as usize, cap as usize);
+        if start + cap == self.nodes.len() {
+            self.nodes.push(Node::default());
+            self.handle.cap += 1;
+            return;
+        }
+        let moved = self.nodes.len();
+        for i in start..start + cap {
+            let node = core::mem::take(&mut self.nodes[i]);
+            self.nodes.push(node);
+        }
+        self.nodes.resize_with(moved + (cap * 2).max(1), Node::default);
+        *self.garbage += cap;
+        *self.handle = Handle { start: slot(moved), len, cap: slot(self.nodes.len() - moved) };
+    }
+    pub fn drain_through(&mut self, version: Version) -> ChainBuf {
+        let run = &mut self.nodes[self.handle.slots()];
+        let count = run.partition_point(|&(v, _)| v <= version);
+        let drained = run[..count].iter_mut().map(core::mem::take).collect();
+        run.rotate_left(count);
+        self.handle.len -= slot(count);
+        ChainBuf(drained)
+    }
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Value> {
+        self.nodes[self.handle.slots()].iter_mut().filter_map(|(_, value)| value.as_mut())
+    }
+}
+impl Deref for ChainMut<'_> {
+    type Target = Chain;
+    fn deref(&self) -> &Chain {
+        Chain::new(&self.nodes[self.handle.slots()])
+    }
+}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainBuf(Vec<Node>);
+impl ChainBuf {
+    pub fn pop_last(&mut self) -> Option<(Version, Option<Value>)> {
+        self.0.pop()
+    }
+}
+impl Deref for ChainBuf {
+    type Target = Chain;
+    fn deref(&self) -> &Chain {
+        Chain::new(&self.0)
+    }
+}
+impl IntoIterator for ChainBuf {
+    type Item = (Version, Option<Value>);
+    type IntoIter = alloc::vec::IntoIter<(Version, Option<Value>)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+pub trait RawMutex<T> {
+    fn new(value: T) -> Self;
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+struct Unlock<'a>(&'a AtomicBool);
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+impl<T> SpinLock<T> {
+    pub const fn new_const(value: T) -> Self {
+        Self { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+    fn try_with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        let _unlock = Unlock(&self.locked);
+        Some(f(unsafe { &mut *self.value.get() }))
+    }
+}
+impl<T> RawMutex<T> for SpinLock<T> {
+    fn new(value: T) -> Self {
+        Self::new_const(value)
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        let _unlock = Unlock(&self.locked);
+        f(unsafe { &mut *self.value.get() })
+    }
+}
+#[cfg(feature = "std")]
+impl<T> RawMutex<T> for std::sync::Mutex<T> {
+    fn new(value: T) -> Self {
+        std::sync::Mutex::new(value)
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock().unwrap())
+    }
+}
+pub trait VersionSource: Send + Sync {
+    fn next(&self, last: Version) -> Version;
+}
+pub struct Counter;
+impl VersionSource for Counter {
+    fn next(&self, last: Version) -> Version {
+        last + 1
+    }
+}
+pub struct State {
+    pub map: VersionMap,
+    pub version: Version,
+    pub allocated: Version,
+    pub next: Arc<dyn VersionSource>,
+}
+/// Work a commit's log step hands back to run once its writes are in the map, still under the engine lock. It is
+/// given the interned key of every write, so side tables can hold the map's own key rather than a copy.
+pub trait Published {
+    fn published(self, keys: &[Key], state: &State);
+}
+impl Published for () {
+    fn published(self, _: &[Key], _: &State) {}
+}
+impl<F: FnOnce(&[Key], &State)> Published for F {
+    fn published(self, keys: &[Key], state: &State) {
+        self(keys, state)
+    }
+}
+#[derive(Debug)]
+pub enum CommitError<E> {
+    Stale { version: Version, current: Version },
+    Log(E),
+}
+pub struct Engine<M> {
+    state: M,
+}
+impl<M: RawMutex<State>> Engine<M> {
+    pub fn new(map: VersionMap, version: Version) -> Self {
+        Self { state: M::new(State { map, version, allocated: version, next: Arc::new(Counter) }) }
+    }
+    pub fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.with(f)
+    }
+    pub fn current_version(&self) -> Version {
+        self.with(|state| state.version)
+    }
+    pub fn begin(&self) -> TxnBuffer {
+        TxnBuffer::new(self.current_version())
+    }
+    pub fn read_at(&self, key: &str, version: Version) -> Option<String> {
+        self.lookup_at(key, version).flatten()
+    }
+    pub fn lookup_at(&self, key: &str, version: Version) -> Option<Option<String>> {
+        self.with(|state| {
+            let (_, value) = state.map.get(key)?.at(version)?;
+            Some(value.as_deref().map(str::to_owned))
+        })
+    }
+    pub fn scan_at<R: RangeBounds<String>>(&self, range: R, version: Version) -> Vec<(String, String)> {
+        self.scan_raw_at(range, version)
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|v| (key, v)))
+            .collect()
+    }
+    pub fn scan_raw_at<R: RangeBounds<String>>(&self, range: R, version: Version) -> Vec<(String, Option<String>)> {
+        self.with(|state| {
+            state
+                .map
+                .range(range)
+                .filter_map(|(key, versions)| {
+                    let (_, value) = versions.at(version)?;
+                    Some((key.as_str().to_owned(), value.as_deref().map(str::to_owned)))
+                })
+                .collect()
+        })
+    }
+    pub fn commit<P: Published, E>(
+        &self,
+        writes: Writes,
+        at: Option<Version>,
+        log: impl FnOnce(Version, &mut Writes, &State) -> Result<P, E>,
+    ) -> Result<Version, CommitError<E>> {
+        self.stage(writes, at, true, log)
+    }
+    pub fn stage<P: Published, E>(
+        &self,
+        mut writes: Writes,
+        at: Option<Version>,
+        publish: bool,
+        log: impl FnOnce(Version, &mut Writes, &State) -> Result<P, E>,
+    ) -> Result<Version, CommitError<E>> {
+        self.with(|state| {
+            let version = at.unwrap_or_else(|| state.next.next(state.allocated));
+            if version <= state.allocated {
+                return Err(CommitError::Stale { version, current: state.allocated });
+            }
+            let published = log(version, &mut writes, state).map_err(CommitError::Log)?;
+            let mut keys = Vec::with_capacity(writes.len());
+            for (key, value) in writes {
+                let mut chain = state.map.entry(key);
+                chain.insert(version, value);
+                keys.push(chain.key().clone());
+            }
+            state.allocated = version;
+            if publish {
+                state.version = version;
+            }
+            published.published(&keys, state);
+            Ok(version)
+        })
+    }
+}
+#[derive(Debug)]
+pub struct Aborted;
+pub fn scrub(value: String) {
+    let mut bytes = value.into_bytes();
+    bytes.resize(bytes.capacity(), 0);
+    for byte in bytes.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    core::sync::atomic::compiler_fence(Ordering::SeqCst);
+}
+pub struct TxnBuffer {
+    snapshot_version: Version,
+    writes: Writes,
+    aborted: bool,
+    sensitive: Option<KeyFilter>,
+}
+impl Drop for TxnBuffer {
+    fn drop(&mut self) {
+        self.scrub_writes();
+    }
+}
+impl TxnBuffer {
+    pub fn new(snapshot_version: Version) -> Self {
+        Self { snapshot_version, writes: BTreeMap::new(), aborted: false, sensitive: None }
+    }
+    pub fn scrub_with(&mut self, sensitive: impl Fn(&str) -> bool + Send + Sync + 'static) {
+        self.sensitive = Some(Box::new(sensitive));
+    }
+    fn is_sensitive(&self, key: &str) -> bool {
+        self.sensitive.as_ref().is_some_and(|sensitive| sensitive(key))
+    }
+    fn scrub_writes(&mut self) {
+        for (key, value) in core::mem::take(&mut self.writes) {
+            if let Some(value) = value.filter(|_| self.is_sensitive(&key)) {
+                scrub(value);
+            }
+        }
+    }
+    fn replace(&mut self, key: String, value: Option<String>) {
+        let sensitive = self.is_sensitive(&key);
+        if let Some(Some(old)) = self.writes.insert(key, value).filter(|_| sensitive) {
+            scrub(old);
+        }
+    }
+    pub fn snapshot_version(&self) -> Version {
+        self.snapshot_version
+    }
+    fn check(&self) -> Result<(), Aborted> {
+        if self.aborted {
+            Err(Aborted)
+        } else {
+            Ok(())
+        }
+    }
+    pub fn get<M: RawMutex<State>>(&self, engine: &Engine<M>, key: &str) -> Result<Option<String>, Aborted> {
+        self.get_with(key, |key, version| engine.read_at(key, version))
+    }
+    pub fn get_with(
+        &self,
+        key: &str,
+        read: impl FnOnce(&str, Version) -> Option<String>,
+    ) -> Result<Option<String>, Aborted> {
+        self.check()?;
+        if let Some(val) = self.writes.get(key) {
+            return Ok(val.clone());
+        }
+        Ok(read(key, self.snapshot_version))
+    }
+    pub fn scan<M: RawMutex<State>, R: RangeBounds<String> + Clone>(
+        &self,
+        engine: &Engine<M>,
+        range: R,
+    ) -> Result<Vec<(String, String)>, Aborted> {
+        self.scan_with(range, |range, version| engine.scan_at(range, version))
+    }
+    pub fn scan_with<R: RangeBounds<String> + Clone>(
+        &self,
+        range: R,
+        read: impl FnOnce(R, Version) -> Vec<(String, String)>,
+    ) -> Result<Vec<(String, String)>, Aborted> {
+        self.check()?;
+        let mut merged: BTreeMap<String, String> = read(range.clone(), self.snapshot_version).into_iter().collect();
+        for (key, value) in self.writes.range(range) {
+            match value {
+                Some(v) => merged.insert(key.clone(), v.clone()),
+                None => merged.remove(key),
+            };
+        }
+        Ok(merged.into_iter().collect())
+    }
+    pub fn put(&mut self, key: String, value: String) -> Result<(), Aborted> {
+        self.check()?;
+        self.replace(key, Some(value));
+        Ok(())
+    }
+    pub fn delete(&mut self, key: String) -> Result<(), Aborted> {
+        self.check()?;
+        self.replace(key, None);
+        Ok(())
+    }
+    pub fn rollback(&mut self) {
+        self.scrub_writes();
+        self.aborted = true;
+    }
+    pub fn has_writes(&self) -> bool {
+        !self.aborted && !self.writes.is_empty()
+    }
+    pub fn into_writes(mut self) -> Option<Writes> {
+        (!self.aborted && !self.writes.is_empty()).then(|| core::mem::take(&mut self.writes))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn versions(chain: &Chain) -> Vec<(Version, Option<&str>)> {
+        chain.iter().map(|(v, value)| (*v, value.as_deref())).collect()
+    }
+    fn commit(engine: &Engine<SpinLock<State>>, buffer: TxnBuffer) -> Version {
+        engine.commit(buffer.into_writes().unwrap(), None, |_, _, _| Ok::<_, ()>(())).unwrap()
+    }
+    #[test]
+    fn transactions_read_their_snapshot_and_their_own_writes() {
+        let engine = Engine::<SpinLock<State>>::new(VersionMap::new(), 0);
+        let mut setup = engine.begin();
+        setup.put("a".to_owned(), "1".to_owned()).unwrap();
+        setup.put("b".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(commit(&engine, setup), 1);
+        let mut reader = engine.begin();
+        let mut writer = engine.begin();
+        writer.put("a".to_owned(), "2".to_owned()).unwrap();
+        writer.delete("b".to_owned()).unwrap();
+        writer.put("c".to_owned(), "2".to_owned()).unwrap();
+        assert_eq!(writer.get(&engine, "b").unwrap(), None);
+        let own = writer.scan(&engine, ..).unwrap();
+        assert_eq!(own, [("a".to_owned(), "2".to_owned()), ("c".to_owned(), "2".to_owned())]);
+        assert_eq!(commit(&engine, writer), 2);
+        assert_eq!(reader.get(&engine, "a").unwrap().as_deref(), Some("1"));
+        assert_eq!(reader.scan(&engine, ..).unwrap().len(), 2);
+        assert_eq!(engine.begin().get(&engine, "a").unwrap().as_deref(), Some("2"));
+        assert_eq!(engine.lookup_at("b", 2), Some(None));
+        assert_eq!(engine.lookup_at("c", 1), None);
+        reader.put("a".to_owned(), "3".to_owned()).unwrap();
+        reader.rollback();
+        assert!(reader.get(&engine, "a").is_err());
+        assert!(reader.put("a".to_owned(), "4".to_owned()).is_err());
+        assert!(!reader.has_writes() && reader.into_writes().is_none());
+    }
+    #[test]
+    fn failed_and_stale_commits_leave_the_map_untouched() {
+        let engine = Engine::<SpinLock<State>>::new(VersionMap::new(), 5);
+        let writes = || Writes::from([("k".to_owned(), Some("v".to_owned()))]);
+        assert!(matches!(engine.commit(writes(), None, |_, _, _| Err::<(), _>("disk full")), Err(CommitError::Log("disk full"))));
+        assert!(matches!(engine.commit(writes(), Some(5), |_, _, _| Ok::<_, ()>(())), Err(CommitError::Stale { version: 5, current: 5 })));
+        assert!(engine.with(|state| state.map.is_empty()));
+        assert_eq!(engine.stage(writes(), None, false, |_, _, _| Ok::<_, ()>(())).unwrap(), 6);
+        assert_eq!(engine.current_version(), 5);
+        assert_eq!(engine.begin().get(&engine, "k").unwrap(), None);
+        assert_eq!(engine.commit(writes(), Some(9), |_, _, _| Ok::<_, ()>(())).unwrap(), 9);
+        assert_eq!(engine.begin().get(&engine, "k").unwrap().as_deref(), Some("v"));
+    }
+    #[test]
+    fn commits_intern_each_key_once_and_publish_the_shared_handle() {
+        let engine = Engine::<SpinLock<State>>::new(VersionMap::new(), 0);
+        let writes = || Writes::from([("k".to_owned(), Some("v".to_owned()))]);
+        let mut published = Vec::new();
+        for _ in 0..2 {
+            engine.commit(writes(), None, |_, _, _| Ok::<_, ()>(|keys: &[Key], _: &State| published.extend_from_slice(keys))).unwrap();
+        }
+        engine.with(|state| {
+            let key = state.map.key("k").unwrap();
+            assert!(published.iter().all(|shared| shared.ptr_eq(key)));
+            assert_eq!(Key::strong_count(key), 3);
+            assert_eq!(state.map.get_mut("k").unwrap().key(), "k");
+        });
+    }
+    #[test]
+    fn values_are_carved_from_pages_and_freed_with_them() {
+        let small = Value::new("small");
+        let empty = Value::new("");
+        let text = "é".repeat(SLAB_PAGE / 4);
+        let large = Value::new(&text);
+        assert_eq!((small.as_str(), empty.as_str(), large.as_str()), ("small", "", text.as_str()));
+        assert_eq!(small.page.capacity, SLAB_PAGE);
+        assert!(small.page.live.load(Ordering::Relaxed) >= 5);
+        assert_eq!((large.start, large.page.capacity, large.page.live.load(Ordering::Relaxed)), (0, text.len(), text.len()));
+        assert!(slab_usage().live >= text.len() + 5);
+        let copy = large.clone();
+        assert!(!Arc::ptr_eq(&copy.page, &large.page));
+        assert_eq!(copy, large);
+        let page = Arc::downgrade(&large.page);
+        drop(large);
+        assert!(page.upgrade().is_none());
+        assert_eq!(copy.as_str(), text);
+    }
+    #[test]
+    fn relocation_leaves_fragmented_pages() {
+        let mut value = Value::new("moved");
+        let old = Arc::clone(&value.page);
+        let filler = "x".repeat(SLAB_PAGE / 4);
+        while !old.sealed.load(Ordering::Relaxed) {
+            assert!(!value.fragmented());
+            drop(Value::new(&filler));
+        }
+        assert!(value.fragmented());
+        let live = old.live.load(Ordering::Relaxed);
+        value.relocate();
+        assert!(!Arc::ptr_eq(&value.page, &old));
+        assert_eq!(value.as_str(), "moved");
+        assert!(old.live.load(Ordering::Relaxed) <= live - 5);
+    }
+    #[test]
+    fn scrub_zeroes_the_value_in_its_page() {
+        let value = Value::new("secret");
+        let (page, start) = (Arc::clone(&value.page), value.start);
+        value.scrub();
+        let bytes = unsafe { core::slice::from_raw_parts(page.bytes.as_ptr().add(start), 6) };
+        assert_eq!(bytes, [0; 6]);
+    }
+    #[test]
+    fn threads_allocate_from_their_own_stripes() {
+        let per_thread = if cfg!(miri) { 50 } else { 5000 };
+        let threads: Vec<_> = (0..SLAB_STRIPES * 2)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let values: Vec<_> = (0..per_thread).map(|i| (i, Value::new(&alloc::format!("{}-{}", t, i)))).collect();
+                    values.iter().all(|(i, value)| value.as_str() == alloc::format!("{}-{}", t, i))
+                })
+            })
+            .collect();
+        assert!(threads.into_iter().all(|thread| thread.join().unwrap()));
+    }
+    #[test]
+    fn busy_stripes_are_skipped() {
+        let lock = SpinLock::new_const(1);
+        assert_eq!(lock.try_with(|v| *v), Some(1));
+        lock.with(|_| assert_eq!(lock.try_with(|v| *v), None));
+        assert_eq!(lock.try_with(|v| *v), Some(1));
+    }
+    #[test]
+    fn chains_answer_point_and_range_queries_by_version() {
+        let mut map = VersionMap::new();
+        for v in [30, 10, 20] {
+            map.insert("k".to_owned(), v, Some(format!("v{}", v)));
+        }
+        let chain = map.get("k").unwrap();
+        assert_eq!(chain.keys().copied().collect::<Vec<_>>(), [10, 20, 30]);
+        assert_eq!(chain.get(&20).and_then(|value| value.as_deref()), Some("v20"));
+        assert_eq!(chain.get(&25), None);
+        assert_eq!(chain.latest().map(|(v, _)| *v), Some(30));
+        let range = |r: (Bound<Version>, Bound<Version>)| chain.range(r).map(|(v, _)| *v).collect::<Vec<_>>();
+        assert_eq!(range((Bound::Excluded(10), Bound::Unbounded)), [20, 30]);
+        assert_eq!(range((Bound::Included(10), Bound::Excluded(30))), [10, 20]);
+        assert_eq!(range((Bound::Included(31), Bound::Included(5))), Vec::<Version>::new());
+    }
+    #[test]
+    fn reads_past_the_newest_version_see_the_chain_tail() {
+        let mut map = VersionMap::new();
+        map.insert("live".to_owned(), 1, Some("old"));
+        map.insert("live".to_owned(), 3, Some("new"));
+        map.insert("gone".to_owned(), 1, Some("x"));
+        map.insert("gone".to_owned(), 2, None::<&str>);
+        let engine = Engine::<SpinLock<State>>::new(map, 3);
+        assert_eq!(engine.read_at("live", Version::MAX).as_deref(), Some("new"));
+        assert_eq!(engine.read_at("live", 2).as_deref(), Some("old"));
+        assert_eq!(engine.lookup_at("gone", Version::MAX), Some(None));
+        assert_eq!(engine.lookup_at("gone", 1), Some(Some("x".to_owned())));
+        assert_eq!(engine.lookup_at("missing", Version::MAX), None);
+        assert_eq!(engine.scan_at(.., Version::MAX), [("live".to_owned(), "new".to_owned())]);
+    }
+    #[test]
+    fn chains_stay_sorted_across_arena_relocations() {
+        let mut map = VersionMap::new();
+        for v in [2, 4, 6, 8] {
+            map.insert("a".to_owned(), v, Some("a"));
+            map.insert("b".to_owned(), v + 1, Some("b"));
+        }
+        map.insert("a".to_owned(), 5, None::<&str>);
+        assert_eq!(map.insert("b".to_owned(), 3, Some("b3")), Some(Some(Value::new("b"))));
+        assert!(map.garbage() > 0);
+        let a = map.get("a").unwrap();
+        assert_eq!(a.keys().copied().collect::<Vec<_>>(), [2, 4, 5, 6, 8]);
+        assert_eq!(a.at(5).map(|(v, value)| (*v, value.is_none())), Some((5, true)));
+        assert_eq!(a.at(7).map(|(v, _)| *v), Some(6));
+        assert_eq!(a.at(1), None);
+        assert_eq!(versions(map.get("b").unwrap())[0], (3, Some("b3")));
+        let before = map.clone();
+        assert!(map.compact() >= before.garbage());
+        assert_eq!(map.garbage(), 0);
+        assert_eq!(map, before);
+    }
+    #[test]
+    fn stepped_compaction_keeps_serving_and_taking_writes_between_batches() {
+        let mut map = VersionMap::new();
+        for v in 1..=3 {
+            for key in ["a", "c", "e", "g"] {
+                map.insert(key.to_owned(), v, Some(key));
+            }
+        }
+        map.remove("g");
+        let garbage = map.garbage();
+        assert_eq!(map.compact_step(1), None);
+        map.insert("a".to_owned(), 4, Some("a4"));
+        map.insert("b".to_owned(), 4, Some("b"));
+        map.insert("f".to_owned(), 4, Some("f"));
+        map.get_mut("e").unwrap().drain_through(1);
+        assert_eq!(map.compact_step(2), None);
+        map.insert("c".to_owned(), 5, None::<&str>);
+        assert_eq!(versions(map.get("a").unwrap()).last(), Some(&(4, Some("a4"))));
+        assert_eq!(map.iter().map(|(key, versions)| (key.as_str(), versions.len())).collect::<Vec<_>>(), [("a", 4), ("b", 1), ("c", 4), ("e", 2), ("f", 1)]);
+        let before = map.clone();
+        let reclaimed = core::iter::from_fn(|| Some(map.compact_step(2))).flatten().next().unwrap();
+        assert!(reclaimed >= garbage);
+        assert_eq!(map.garbage(), 0);
+        assert_eq!(map, before);
+        assert_eq!(map.values_mut().count(), 11);
+    }
+    #[test]
+    fn drain_through_keeps_newer_versions_in_place() {
+        let mut map = VersionMap::new();
+        for v in 1..=5 {
+            map.insert("k".to_owned(), v, Some(v.to_string()));
+        }
+        let mut chain = map.get_mut("k").unwrap();
+        let mut drained = chain.drain_through(3);
+        assert_eq!(versions(&drained), [(1, Some("1")), (2, Some("2")), (3, Some("3"))]);
+        let visible = drained.pop_last().unwrap();
+        chain.insert(visible.0, visible.1);
+        assert_eq!(versions(&chain), [(3, Some("3")), (4, Some("4")), (5, Some("5"))]);
+        assert_eq!(map.garbage(), 0);
+        let removed = map.remove("k").unwrap();
+        assert_eq!(removed.len(), 3);
+        assert!(map.is_empty());
+        assert!(map.garbage() > 0);
+        assert!(map.compact() > 0);
+    }
+    #[test]
+    fn scrub_zeroes_values_that_own_their_page() {
+        let secret = Value::new(&"s".repeat(SLAB_PAGE));
+        let (page, len) = (Arc::clone(&secret.page), secret.len);
+        assert_eq!(page.capacity, SLAB_PAGE);
+        secret.scrub();
+        assert!(unsafe { core::slice::from_raw_parts(page.bytes.as_ptr(), len) }.iter().all(|&byte| byte == 0));
+    }
+    #[test]
+    fn buffers_drop_replaced_and_rolled_back_writes() {
+        fn secret(key: &str) -> bool {
+            key.starts_with("vault:")
+        }
+        let engine = Engine::<SpinLock<State>>::new(VersionMap::new(), 0);
+        let mut buffer = engine.begin();
+        buffer.scrub_with(secret);
+        buffer.put("vault:pw".to_owned(), "first".to_owned()).unwrap();
+        buffer.put("vault:pw".to_owned(), "second".to_owned()).unwrap();
+        buffer.put("plain:k".to_owned(), "v".to_owned()).unwrap();
+        assert_eq!(buffer.get(&engine, "vault:pw").unwrap().as_deref(), Some("second"));
+        buffer.rollback();
+        assert!(!buffer.has_writes());
+        assert!(buffer.get(&engine, "vault:pw").is_err());
+        let mut buffer = engine.begin();
+        buffer.scrub_with(secret);
+        buffer.put("vault:pw".to_owned(), "kept".to_owned()).unwrap();
+        commit(&engine, buffer);
+        assert_eq!(engine.begin().get(&engine, "vault:pw").unwrap().as_deref(), Some("kept"));
+    }
+}
@@ -0,0 +1,120 @@
+use super::{KVStore, Result, KEYSPACE_SEPARATOR};
+use std::path::Path;
+use std::sync::Arc;
+pub trait KvTree {
+    fn insert(&self, key: &str, value: &str) -> Result<Option<String>>;
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn remove(&self, key: &str) -> Result<Option<String>>;
+    fn iter(&self) -> Result<std::vec::IntoIter<(String, String)>>;
+    fn flush(&self) -> Result<()>;
+}
+pub trait KvDatabase: KvTree + Sized {
+    type Tree: KvTree;
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self>;
+    fn open_tree(&self, name: &str) -> Result<Self::Tree>;
+}
+#[derive(Clone)]
+pub struct Tree {
+    store: Arc<KVStore>,
+    prefix: String,
+}
+impl Tree {
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+impl KvTree for Tree {
+    fn insert(&self, key: &str, value: &str) -> Result<Option<String>> {
+        let mut tx = self.store.begin();
+        let previous = tx.get(&self.key(key))?;
+        tx.put(self.key(key), value.to_owned())?;
+        tx.commit()?;
+        Ok(previous)
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.begin().get(&self.key(key))
+    }
+    fn remove(&self, key: &str) -> Result<Option<String>> {
+        let mut tx = self.store.begin();
+        let previous = tx.get(&self.key(key))?;
+        if previous.is_some() {
+            tx.delete(self.key(key))?;
+            tx.commit()?;
+        }
+        Ok(previous)
+    }
+    fn iter(&self) -> Result<std::vec::IntoIter<(String, String)>> {
+        let rows = self.store.begin().scan(self.prefix.clone()..)?;
+        let prefix_len = self.prefix.len();
+        let rows: Vec<_> = rows
+            .into_iter()
+            .take_while(|(key, _)| key.starts_with(&self.prefix))
+            .filter(|(key, _)| !self.prefix.is_empty() || !key.contains(KEYSPACE_SEPARATOR))
+            .map(|(key, value)| (key[prefix_len..].to_owned(), value))
+            .collect();
+        Ok(rows.into_iter())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+#[derive(Clone)]
+pub struct Db {
+    default: Tree,
+}
+impl Db {
+    pub fn from_store(store: Arc<KVStore>) -> Self {
+        Self { default: Tree { store, prefix: String::new() } }
+    }
+}
+impl KvTree for Db {
+    fn insert(&self, key: &str, value: &str) -> Result<Option<String>> {
+        self.default.insert(key, value)
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.default.get(key)
+    }
+    fn remove(&self, key: &str) -> Result<Option<String>> {
+        self.default.remove(key)
+    }
+    fn iter(&self) -> Result<std::vec::IntoIter<(String, String)>> {
+        self.default.iter()
+    }
+    fn flush(&self) -> Result<()> {
+        self.default.flush()
+    }
+}
+impl KvDatabase for Db {
+    type Tree = Tree;
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_store(KVStore::open(path)?))
+    }
+    fn open_tree(&self, name: &str) -> Result<Tree> {
+        Ok(Tree { store: Arc::clone(&self.default.store), prefix: format!("{}{}", name, KEYSPACE_SEPARATOR) })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    #[test]
+    fn trees_keep_their_keys_apart_and_persist() {
+        let dir = TempDir::new("adapter");
+        let db = Db::open(dir.path()).unwrap();
+        let users = db.open_tree("users").unwrap();
+        assert_eq!(db.insert("k", "default").unwrap(), None);
+        assert_eq!(users.insert("k", "1").unwrap(), None);
+        assert_eq!(users.insert("k", "2").unwrap(), Some("1".to_owned()));
+        users.insert("l", "3").unwrap();
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("default"));
+        assert_eq!(db.iter().unwrap().collect::<Vec<_>>(), [("k".to_owned(), "default".to_owned())]);
+        assert_eq!(users.iter().unwrap().map(|(key, _)| key).collect::<Vec<_>>(), ["k", "l"]);
+        assert_eq!(users.remove("l").unwrap().as_deref(), Some("3"));
+        assert_eq!(users.remove("l").unwrap(), None);
+        db.flush().unwrap();
+        drop((db, users));
+        let db = Db::open(dir.path()).unwrap();
+        assert_eq!(db.open_tree("users").unwrap().get("k").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.open_tree("users").unwrap().get("l").unwrap(), None);
+    }
+}
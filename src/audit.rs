@@ -0,0 +1,109 @@
+use crate::*;
+pub(crate) const AUDIT_KEYSPACE: &str = "__audit";
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub version: Version,
+    pub key: String,
+    pub when: u64,
+    pub actor: Option<String>,
+    pub txn: Option<u64>,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    pub hash: String,
+}
+impl AuditEntry {
+    pub(crate) fn chain(&self, previous: &str) -> String {
+        let mut text = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            previous,
+            self.version,
+            self.key,
+            self.when,
+            self.old_hash.as_deref().unwrap_or("-"),
+            self.new_hash.as_deref().unwrap_or("-"),
+            self.actor.as_deref().unwrap_or(""),
+        );
+        if let Some(txn) = self.txn {
+            text.push_str(&format!("\n{}", txn));
+        }
+        hex(&sha256(text.as_bytes()))
+    }
+    pub(crate) fn encode(&self) -> String {
+        let when = match self.txn {
+            Some(txn) => format!("{}:{}", self.when, txn),
+            None => self.when.to_string(),
+        };
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.hash,
+            when,
+            self.old_hash.as_deref().unwrap_or("-"),
+            self.new_hash.as_deref().unwrap_or("-"),
+            self.actor.as_deref().unwrap_or(""),
+        )
+    }
+    pub(crate) fn parse(name: &str, value: &str) -> Result<Self> {
+        let corrupt = || KVError::Corruption(format!("bad audit entry {:?}", name));
+        let (version, key) = name.split_once(KEYSPACE_SEPARATOR).ok_or_else(corrupt)?;
+        let fields: Vec<&str> = value.splitn(5, '\t').collect();
+        let [hash, when, old, new, actor] = fields.as_slice() else {
+            return Err(corrupt());
+        };
+        let digest = |field: &str| (field != "-").then(|| field.to_owned());
+        let (when, txn) = match when.split_once(':') {
+            Some((when, txn)) => (when, Some(txn.parse().map_err(|_| corrupt())?)),
+            None => (*when, None),
+        };
+        Ok(Self {
+            version: version.parse().map_err(|_| corrupt())?,
+            key: key.to_owned(),
+            when: when.parse().map_err(|_| corrupt())?,
+            actor: (!actor.is_empty()).then(|| actor.to_string()),
+            txn,
+            old_hash: digest(old),
+            new_hash: digest(new),
+            hash: hash.to_string(),
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn audit_flag_and_actor_survive_a_reopen() {
+        let dir = TempDir::new("audit");
+        let id = {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.enable_audit().unwrap();
+            let mut tx = store.begin();
+            tx.set_actor("alice");
+            tx.put("k".to_owned(), "1".to_owned()).unwrap();
+            let id = tx.id();
+            tx.commit().unwrap();
+            id
+        };
+        let store = KVStore::open(dir.path()).unwrap();
+        let mut tx = store.begin();
+        tx.put("k".to_owned(), "2".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let entries = store.audit_since(0).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].actor.as_deref(), entries[0].txn), (Some("alice"), Some(id)));
+        assert_eq!(entries[1].actor, None);
+        assert!(entries.iter().all(|entry| entry.key == "k"));
+        assert_eq!(store.verify_audit().unwrap(), 2);
+    }
+    #[test]
+    fn audit_keyspace_is_never_writable() {
+        let store = KVStore::new();
+        let forged = format!("{}{}{:020}{}k", AUDIT_KEYSPACE, KEYSPACE_SEPARATOR, 1, KEYSPACE_SEPARATOR);
+        let mut tx = store.begin();
+        assert!(matches!(tx.put(forged.clone(), "x".to_owned()), Err(KVError::InvalidInput(_))));
+        tx.put_internal(forged.clone(), "x".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::InvalidInput(_))));
+        store.enable_audit().unwrap();
+        let mut tx = store.begin();
+        tx.put_internal(forged, "x".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,73 @@
+use super::{ffi, KVError, KVStore, Result, Storage};
+use std::ffi::c_int;
+use std::io;
+use std::sync::Arc;
+#[link(wasm_import_module = "kvstore_host")]
+extern "C" {
+    pub(super) fn host_now_millis() -> f64;
+    fn host_list(buf: *mut u8, cap: usize) -> usize;
+    fn host_size(name: *const u8, name_len: usize) -> f64;
+    fn host_read(name: *const u8, name_len: usize, buf: *mut u8, cap: usize) -> usize;
+    fn host_write(name: *const u8, name_len: usize, data: *const u8, len: usize) -> i32;
+    fn host_append(name: *const u8, name_len: usize, data: *const u8, len: usize) -> i32;
+    fn host_truncate(name: *const u8, name_len: usize, len: f64) -> i32;
+    fn host_remove(name: *const u8, name_len: usize) -> i32;
+    pub(super) fn host_random(buf: *mut u8, len: usize) -> i32;
+}
+fn check(rc: i32, op: &str, name: &str) -> Result<()> {
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(KVError::Io(io::Error::other(format!("browser storage: {} {} failed ({})", op, name, rc))))
+    }
+}
+pub struct HostStorage;
+impl Storage for HostStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        let len = unsafe { host_list(std::ptr::null_mut(), 0) };
+        let mut buf = vec![0u8; len];
+        unsafe { host_list(buf.as_mut_ptr(), buf.len()) };
+        let names = String::from_utf8(buf).map_err(|_| KVError::Corruption("browser storage: bad file list".to_owned()))?;
+        Ok(names.split('\n').filter(|n| !n.is_empty()).map(str::to_owned).collect())
+    }
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let size = unsafe { host_size(name.as_ptr(), name.len()) };
+        if size < 0.0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, name.to_owned()).into());
+        }
+        let mut buf = vec![0u8; size as usize];
+        unsafe { host_read(name.as_ptr(), name.len(), buf.as_mut_ptr(), buf.len()) };
+        Ok(buf)
+    }
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        check(unsafe { host_write(name.as_ptr(), name.len(), bytes.as_ptr(), bytes.len()) }, "write", name)
+    }
+    fn append(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        check(unsafe { host_append(name.as_ptr(), name.len(), bytes.as_ptr(), bytes.len()) }, "append", name)
+    }
+    fn truncate(&self, name: &str, len: u64) -> Result<()> {
+        check(unsafe { host_truncate(name.as_ptr(), name.len(), len as f64) }, "truncate", name)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        check(unsafe { host_remove(name.as_ptr(), name.len()) }, "remove", name)
+    }
+}
+#[no_mangle]
+pub extern "C" fn kv_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+/// # Safety
+/// `out` must be a valid pointer to write the handle to.
+#[no_mangle]
+pub unsafe extern "C" fn kv_open_browser(out: *mut *mut ffi::KvStoreHandle) -> c_int {
+    if out.is_null() {
+        return ffi::KV_ERR_INVALID;
+    }
+    match KVStore::open_storage(Arc::new(HostStorage)) {
+        Ok(store) => {
+            *out = Box::into_raw(Box::new(ffi::KvStoreHandle(store)));
+            ffi::KV_OK
+        }
+        Err(e) => ffi::report(e),
+    }
+}
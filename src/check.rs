@@ -0,0 +1,285 @@
+use crate::*;
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    pub version: Version,
+    pub keys: usize,
+    pub records: usize,
+    pub skipped: Vec<String>,
+}
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub version: Version,
+    pub keys: usize,
+    pub files: Vec<FileCheck>,
+    pub indexes: Vec<IndexCheck>,
+    pub problems: Vec<String>,
+    pub warnings: Vec<String>,
+}
+#[derive(Debug)]
+pub struct FileCheck {
+    pub name: String,
+    pub kind: &'static str,
+    pub bytes: u64,
+    pub records: usize,
+    pub problem: Option<String>,
+}
+#[derive(Debug)]
+pub struct IndexCheck {
+    pub name: String,
+    pub entries: usize,
+    pub missing: usize,
+    pub stale: usize,
+}
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+    pub fn to_json(&self) -> Json {
+        let strings = |items: &[String]| Json::Array(items.iter().cloned().map(Json::Str).collect());
+        let files = self.files.iter().map(|file| {
+            Json::Object(vec![
+                ("name".to_owned(), Json::Str(file.name.clone())),
+                ("kind".to_owned(), Json::Str(file.kind.to_owned())),
+                ("bytes".to_owned(), Json::Num(file.bytes as f64)),
+                ("records".to_owned(), Json::Num(file.records as f64)),
+                ("problem".to_owned(), file.problem.clone().map_or(Json::Null, Json::Str)),
+            ])
+        });
+        let indexes = self.indexes.iter().map(|index| {
+            Json::Object(vec![
+                ("name".to_owned(), Json::Str(index.name.clone())),
+                ("entries".to_owned(), Json::Num(index.entries as f64)),
+                ("missing".to_owned(), Json::Num(index.missing as f64)),
+                ("stale".to_owned(), Json::Num(index.stale as f64)),
+            ])
+        });
+        Json::Object(vec![
+            ("ok".to_owned(), Json::Bool(self.is_ok())),
+            ("version".to_owned(), Json::Num(self.version as f64)),
+            ("keys".to_owned(), Json::Num(self.keys as f64)),
+            ("files".to_owned(), Json::Array(files.collect())),
+            ("indexes".to_owned(), Json::Array(indexes.collect())),
+            ("problems".to_owned(), strings(&self.problems)),
+            ("warnings".to_owned(), strings(&self.warnings)),
+        ])
+    }
+}
+pub fn check_store(storage: &dyn Storage) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let names = storage.list()?;
+    let manifest = match StoreManifest::load(storage) {
+        Ok(Some(manifest)) => {
+            let bytes = storage.read(MANIFEST)?.len() as u64;
+            let records = manifest.snapshots.len() + manifest.segments.len();
+            report.files.push(FileCheck { name: MANIFEST.to_owned(), kind: "manifest", bytes, records, problem: None });
+            manifest
+        }
+        Ok(None) => {
+            report.warnings.push("no manifest; files were found by scanning the directory".to_owned());
+            StoreManifest::scan(storage)?
+        }
+        Err(e) => {
+            report.problems.push(format!("{}: {}", MANIFEST, e));
+            StoreManifest::scan(storage)?
+        }
+    };
+    let scanned = StoreManifest::scan(storage)?;
+    for ext in ["snap", "wal"] {
+        for (_, name) in scanned.files(ext).into_iter().filter(|file| !manifest.files(ext).contains(file)) {
+            report.warnings.push(format!("{} is not listed in the manifest", name));
+        }
+    }
+    let mut base = (0, mvcc::VersionMap::new(), Vec::new());
+    for (version, name) in manifest.files("snap") {
+        if !names.contains(&name) {
+            report.problems.push(format!("manifest lists missing file {}", name));
+            continue;
+        }
+        let bytes = storage.read(&name)?;
+        let mut file = FileCheck { name: name.clone(), kind: "snapshot", bytes: bytes.len() as u64, records: 0, problem: None };
+        match decode_snapshot(&bytes) {
+            Ok((found, data, timeline)) => {
+                file.records = data.len();
+                file.problem = if found != version {
+                    Some(format!("header says version {}", found))
+                } else if let Some((key, _)) = data.iter().find(|(_, versions)| versions.latest().is_some_and(|(v, _)| *v > found)) {
+                    Some(format!("key {:?} has a version newer than the snapshot", key))
+                } else if timeline.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    Some("commit timeline is out of order".to_owned())
+                } else {
+                    base = (found, data, timeline);
+                    None
+                };
+            }
+            Err(e) => file.problem = Some(e.to_string()),
+        }
+        report.problems.extend(file.problem.iter().map(|problem| format!("{}: {}", name, problem)));
+        report.files.push(file);
+    }
+    let (snapshot_version, mut data, _) = base;
+    let segments = manifest.files("wal");
+    let mut last_version = 0;
+    for (i, (base, name)) in segments.iter().enumerate() {
+        if !names.contains(name) {
+            report.problems.push(format!("manifest lists missing file {}", name));
+            continue;
+        }
+        let bytes = storage.read(name)?;
+        let mut file = FileCheck { name: name.clone(), kind: "wal", bytes: bytes.len() as u64, records: 0, problem: None };
+        match parse_segment(&bytes, name) {
+            Ok(segment) => {
+                file.records = segment.records.len();
+                let hidden = salvage_frames(&bytes).0.len() - segment.records.len() - segment.markers.len();
+                if segment.torn && hidden > 0 {
+                    file.problem = Some(format!(
+                        "damaged record at offset {} hides {} readable records, run kvctl salvage",
+                        segment.valid_len, hidden
+                    ));
+                } else if segment.torn && i + 1 == segments.len() {
+                    report.warnings.push(format!("{}: torn tail at offset {} is dropped on open", name, segment.valid_len));
+                } else if segment.torn {
+                    file.problem = Some(format!("damaged record at offset {}", segment.valid_len));
+                }
+                for record in segment.records {
+                    if record.version <= last_version || record.version <= *base {
+                        file.problem.get_or_insert(format!("version {} does not follow {}", record.version, last_version));
+                    }
+                    last_version = last_version.max(record.version);
+                    if record.version > snapshot_version {
+                        for (key, value) in record.writes {
+                            data.insert(key, record.version, value);
+                        }
+                    }
+                }
+            }
+            Err(e) => file.problem = Some(e.to_string()),
+        }
+        report.problems.extend(file.problem.iter().map(|problem| format!("{}: {}", name, problem)));
+        report.files.push(file);
+    }
+    if segments.is_empty() && manifest.snapshots.is_empty() {
+        report.warnings.push("no snapshots or wal segments found".to_owned());
+    }
+    report.version = last_version.max(snapshot_version);
+    report.keys = data
+        .iter()
+        .filter(|(key, versions)| keyspace_of(key) != INDEX_KEYSPACE && matches!(versions.latest(), Some((_, Some(_)))))
+        .count();
+    let latest = |key: &str| data.get(key).and_then(|versions| versions.latest().and_then(|(_, value)| value.as_deref().map(str::to_owned)));
+    for (name, def) in load_indexes(&data) {
+        if latest(&index_build_key(&name)).is_some() {
+            report.warnings.push(format!("index {} is still being built", name));
+            continue;
+        }
+        let expected: BTreeMap<String, String> = data
+            .range(prefix_range(&def.prefix))
+            .filter(|(key, _)| keyspace_of(key) != INDEX_KEYSPACE)
+            .flat_map(|(key, _)| def.entries(&name, key, &latest(key)))
+            .collect();
+        let entries = format!("{}{}{}{}", INDEX_KEYSPACE, KEYSPACE_SEPARATOR, name, KEYSPACE_SEPARATOR);
+        let actual: BTreeMap<String, String> =
+            data.range(prefix_range(&entries)).filter_map(|(key, _)| Some((key.to_string(), latest(key)?))).collect();
+        let missing = expected.keys().filter(|entry| !actual.contains_key(*entry)).count();
+        let stale = actual.iter().filter(|(entry, covered)| expected.get(*entry) != Some(covered)).count();
+        if missing + stale > 0 {
+            report.problems.push(format!("index {}: {} missing and {} stale entries", name, missing, stale));
+        }
+        report.indexes.push(IndexCheck { name, entries: actual.len(), missing, stale });
+    }
+    Ok(report)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn commit_each(dir: &Path, keys: &[&str]) {
+        let store = KVStore::open(dir).unwrap();
+        for key in keys {
+            let mut tx = store.begin();
+            tx.put(key.to_string(), format!("{}-value", key)).unwrap();
+            tx.commit().unwrap();
+        }
+    }
+    // Flips the last byte of the `index`th record in the first wal segment.
+    fn damage_record(dir: &Path, index: usize) {
+        let path = dir.join(segment_name(0));
+        let mut bytes = fs::read(&path).unwrap();
+        let mut pos = WAL_MAGIC.len();
+        for _ in 0..index {
+            pos += 8 + u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        }
+        pos += 8 + u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        bytes[pos - 1] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+    }
+    #[test]
+    fn the_checker_separates_torn_tails_from_damage_that_hides_records() {
+        let dir = TempDir::new("fsck");
+        commit_each(dir.path(), &["a", "b", "c"]);
+        let report = check_store(&FsStorage::new(dir.path())).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!((report.version, report.keys), (3, 3));
+        assert_eq!(report.files.iter().map(|file| file.kind).collect::<Vec<_>>(), ["manifest", "wal"]);
+        assert_eq!(report.files[1].records, 3);
+        assert_eq!(report.to_json().get("ok"), Some(&Json::Bool(true)));
+        damage_record(dir.path(), 2);
+        let report = check_store(&FsStorage::new(dir.path())).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(report.warnings.iter().any(|warning| warning.contains("torn tail")), "{:?}", report.warnings);
+        let dir = TempDir::new("fsck-hidden");
+        commit_each(dir.path(), &["a", "b", "c"]);
+        damage_record(dir.path(), 1);
+        fs::copy(dir.path().join(segment_name(0)), dir.path().join(segment_name(9))).unwrap();
+        let report = check_store(&FsStorage::new(dir.path())).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].contains("hides 1 readable records"), "{}", report.problems[0]);
+        assert!(report.warnings.iter().any(|warning| warning.contains(&segment_name(9))), "{:?}", report.warnings);
+        assert_eq!(report.to_json().get("ok"), Some(&Json::Bool(false)));
+    }
+    #[test]
+    fn salvage_keeps_every_readable_record_around_the_damage() {
+        let dir = TempDir::new("salvage");
+        commit_each(dir.path(), &["a", "b", "c"]);
+        damage_record(dir.path(), 1);
+        let (store, report) = KVStore::salvage_from(&FsStorage::new(dir.path())).unwrap();
+        assert_eq!((report.version, report.records, report.keys), (3, 2, 2));
+        assert!(report.skipped.iter().any(|skipped| skipped.contains("unreadable bytes")), "{:?}", report.skipped);
+        assert!(report.skipped.contains(&"versions 2..=2 are not recoverable".to_owned()), "{:?}", report.skipped);
+        let tx = store.begin();
+        assert_eq!(tx.get("a").unwrap().as_deref(), Some("a-value"));
+        assert_eq!(tx.get("b").unwrap(), None);
+        assert_eq!(tx.get("c").unwrap().as_deref(), Some("c-value"));
+        let copy = TempDir::new("salvaged");
+        assert_eq!(store.save_to(copy.path()).unwrap(), 3);
+        assert!(check_store(&FsStorage::new(copy.path())).unwrap().is_ok());
+        assert_eq!(KVStore::open(copy.path()).unwrap().begin().get("c").unwrap().as_deref(), Some("c-value"));
+    }
+    #[test]
+    fn damaged_records_quarantine_their_keys_until_rewritten() {
+        let dir = TempDir::new("quarantine");
+        commit_each(dir.path(), &["a", "b", "c"]);
+        damage_record(dir.path(), 1);
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            assert_eq!(store.quarantined(), vec![("b".to_owned(), 2)]);
+            assert!(FsStorage::new(dir.path()).list().unwrap().iter().any(|name| name.ends_with(".bad")));
+            let tx = store.begin();
+            assert_eq!(tx.get("a").unwrap().as_deref(), Some("a-value"));
+            assert_eq!(tx.get("c").unwrap().as_deref(), Some("c-value"));
+            match tx.get("b") {
+                Err(KVError::Corruption(reason)) => assert!(reason.contains("quarantined"), "{}", reason),
+                other => panic!("expected a quarantined read, got {:?}", other),
+            }
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.quarantined().len(), 1);
+        let mut tx = store.begin();
+        tx.put("b".to_owned(), "rewritten".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.begin().get("b").unwrap().as_deref(), Some("rewritten"));
+        drop(store);
+        let store = KVStore::open(dir.path()).unwrap();
+        assert!(store.quarantined().is_empty());
+        assert_eq!(store.begin().get("b").unwrap().as_deref(), Some("rewritten"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::*;
+pub(crate) const COORDINATOR_LOG: &str = "coordinator.log";
+pub(crate) struct CoordinatorState {
+    pub(crate) last_id: u64,
+    pub(crate) committed: BTreeSet<u64>,
+    pub(crate) active: BTreeSet<u64>,
+}
+pub struct Coordinator {
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) state: Mutex<CoordinatorState>,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub committed: usize,
+    pub aborted: usize,
+}
+impl Coordinator {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        fs::create_dir_all(path.as_ref())?;
+        Self::open_storage(Arc::new(FsStorage::new(path)))
+    }
+    pub fn open_storage(storage: Arc<dyn Storage>) -> Result<Self> {
+        let mut committed = BTreeSet::new();
+        if storage.list()?.iter().any(|name| name == COORDINATOR_LOG) {
+            let segment = parse_segment(&storage.read(COORDINATOR_LOG)?, COORDINATOR_LOG)?;
+            if segment.torn {
+                storage.truncate(COORDINATOR_LOG, segment.valid_len)?;
+            }
+            committed.extend(segment.records.iter().map(|r| r.version));
+        } else {
+            storage.append(COORDINATOR_LOG, WAL_MAGIC)?;
+        }
+        let last_id = committed.last().copied().unwrap_or(0);
+        Ok(Self { storage, state: Mutex::new(CoordinatorState { last_id, committed, active: BTreeSet::new() }) })
+    }
+    pub fn begin(&self, stores: &[&Arc<KVStore>]) -> DistributedTransaction<'_> {
+        DistributedTransaction { coordinator: self, parts: stores.iter().map(|store| store.begin()).collect() }
+    }
+    pub fn recover(&self, stores: &[&Arc<KVStore>]) -> Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+        for store in stores {
+            for id in store.in_doubt() {
+                let (active, committed) = {
+                    let state = self.state.lock().unwrap();
+                    (state.active.contains(&id), state.committed.contains(&id))
+                };
+                if active {
+                    continue;
+                }
+                if committed {
+                    if store.commit_prepared(id)?.is_some() {
+                        report.committed += 1;
+                    }
+                } else if store.abort_prepared(id)? {
+                    report.aborted += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+    pub(crate) fn commit_parts(&self, parts: Vec<Transaction>) -> Result<Vec<Version>> {
+        let mut flat = Vec::with_capacity(parts.len());
+        let mut roots = Vec::with_capacity(parts.len());
+        for part in parts {
+            roots.push(flat.len());
+            part.flatten_into(&mut flat);
+        }
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.last_id = hybrid_next(state.last_id);
+            let id = state.last_id;
+            state.active.insert(id);
+            id
+        };
+        let result = self.run(id, flat);
+        self.state.lock().unwrap().active.remove(&id);
+        let versions = result?;
+        Ok(roots.into_iter().map(|i| versions[i]).collect())
+    }
+    pub(crate) fn run(&self, id: u64, parts: Vec<Transaction>) -> Result<Vec<Version>> {
+        let mut results = Vec::with_capacity(parts.len());
+        let mut prepared: Vec<Arc<KVStore>> = Vec::new();
+        for part in parts {
+            let snapshot = part.buffer.snapshot_version();
+            results.push((snapshot, None));
+            let store = Arc::clone(&part.store);
+            let txn = part.id();
+            let Some((writes, meta)) = part.into_writes() else {
+                continue;
+            };
+            let admitted = match txn >= store.draining.load(Ordering::Acquire) {
+                true => Err(KVError::Closed),
+                false => Ok(()),
+            };
+            if let Err(e) = admitted.and_then(|()| store.prepare_with(id, writes, meta)) {
+                for store in &prepared {
+                    let _ = store.abort_prepared(id);
+                }
+                return Err(e);
+            }
+            results.last_mut().unwrap().1 = Some(prepared.len());
+            prepared.push(store);
+        }
+        if !prepared.is_empty() {
+            if let Err(e) = self.storage.append(COORDINATOR_LOG, &WalRecord::encode(id, now_millis(), &BTreeMap::new())) {
+                for store in &prepared {
+                    let _ = store.abort_prepared(id);
+                }
+                return Err(e);
+            }
+            self.state.lock().unwrap().committed.insert(id);
+        }
+        let mut versions = Vec::with_capacity(prepared.len());
+        for store in &prepared {
+            versions.push(store.commit_prepared(id)?.unwrap_or_else(|| store.current_version()));
+        }
+        Ok(results.into_iter().map(|(snapshot, i)| i.map_or(snapshot, |i| versions[i])).collect())
+    }
+}
+pub struct DistributedTransaction<'a> {
+    pub(crate) coordinator: &'a Coordinator,
+    pub(crate) parts: Vec<Transaction>,
+}
+impl DistributedTransaction<'_> {
+    pub fn part(&mut self, index: usize) -> &mut Transaction {
+        &mut self.parts[index]
+    }
+    pub fn commit(self) -> Result<Vec<Version>> {
+        self.coordinator.commit_parts(self.parts)
+    }
+    pub fn rollback(&mut self) -> Result<()> {
+        self.parts.iter_mut().try_for_each(|part| part.rollback())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn writes(key: &str, value: &str) -> Writes {
+        Writes::from([(key.to_owned(), Some(value.to_owned()))])
+    }
+    #[test]
+    fn commits_land_in_every_store_or_none() {
+        let dir = TempDir::new("2pc");
+        let coordinator = Coordinator::open(dir.path()).unwrap();
+        let (a, b) = (KVStore::new(), KVStore::new());
+        let mut txn = coordinator.begin(&[&a, &b]);
+        txn.part(0).put("ks:k".into(), "a".into()).unwrap();
+        txn.part(1).put("ks:k".into(), "b".into()).unwrap();
+        let versions = txn.commit().unwrap();
+        assert_eq!(versions, vec![a.current_version(), b.current_version()]);
+        assert_eq!(a.begin().get("ks:k").unwrap().as_deref(), Some("a"));
+        assert_eq!(b.begin().get("ks:k").unwrap().as_deref(), Some("b"));
+        b.close().unwrap();
+        let mut txn = coordinator.begin(&[&a, &b]);
+        txn.part(0).put("ks:k".into(), "a2".into()).unwrap();
+        txn.part(1).put("ks:k".into(), "b2".into()).unwrap();
+        assert!(txn.commit().is_err());
+        assert_eq!(a.begin().get("ks:k").unwrap().as_deref(), Some("a"));
+        assert!(a.in_doubt().is_empty());
+    }
+    #[test]
+    fn recovery_resolves_prepared_transactions_after_a_reopen() {
+        let dir = TempDir::new("2pc-recover");
+        let (log, a_dir, b_dir) = (dir.path().join("log"), dir.path().join("a"), dir.path().join("b"));
+        {
+            let coordinator = Coordinator::open(&log).unwrap();
+            let (a, b) = (KVStore::open(&a_dir).unwrap(), KVStore::open(&b_dir).unwrap());
+            for (id, value) in [(7, "decided"), (8, "undecided")] {
+                a.prepare(id, writes("ks:k", value)).unwrap();
+                b.prepare(id, writes("ks:k", value)).unwrap();
+            }
+            coordinator.storage.append(COORDINATOR_LOG, &WalRecord::encode(7, now_millis(), &BTreeMap::new())).unwrap();
+        }
+        let coordinator = Coordinator::open(&log).unwrap();
+        let (a, b) = (KVStore::open(&a_dir).unwrap(), KVStore::open(&b_dir).unwrap());
+        assert_eq!(a.in_doubt(), vec![7, 8]);
+        assert_eq!(a.begin().get("ks:k").unwrap(), None);
+        assert_eq!(coordinator.recover(&[&a, &b]).unwrap(), RecoveryReport { committed: 2, aborted: 2 });
+        for store in [&a, &b] {
+            assert!(store.in_doubt().is_empty());
+            assert_eq!(store.begin().get("ks:k").unwrap().as_deref(), Some("decided"));
+        }
+        drop((a, b));
+        let a = KVStore::open(&a_dir).unwrap();
+        assert!(a.in_doubt().is_empty());
+        assert_eq!(a.begin().get("ks:k").unwrap().as_deref(), Some("decided"));
+    }
+}
@@ -0,0 +1,778 @@
+use crate::*;
+pub(crate) const KEYRING: &str = "KEYRING";
+pub(crate) const KEYRING_MAGIC: &[u8; 8] = b"KVKEYS02";
+pub(crate) const ENCRYPTED_MAGIC: &[u8; 8] = b"KVENC003";
+pub(crate) const ENCRYPTED_HEADER: usize = ENCRYPTED_MAGIC.len() + 4 + 1;
+pub(crate) const SEALED_FILE: u8 = 1;
+pub(crate) const LOG_FILE: u8 = 2;
+pub(crate) const CHUNK_HEADER: usize = 4 + 12;
+pub(crate) const FINAL_CHUNK: u32 = 1 << 31;
+pub(crate) const CHUNK_TAG: usize = 32;
+pub trait KeyProvider: Send + Sync {
+    fn current_key(&self) -> Result<String>;
+    fn wrap(&self, key_id: &str, plain: &[u8]) -> Result<Vec<u8>>;
+    fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>>;
+}
+pub(crate) fn seal_local(master: &[u8; 32], key_id: &str, plain: &[u8]) -> Result<Vec<u8>> {
+    let nonce: [u8; 12] = random_bytes()?;
+    let mut sealed = plain.to_vec();
+    chacha20(&hmac_sha256(master, b"kvstore keyring encryption"), &nonce, &mut sealed);
+    let mut sealed = [&nonce[..], &sealed].concat();
+    let tag = hmac_sha256(&hmac_sha256(master, b"kvstore keyring authentication"), &[key_id.as_bytes(), &sealed].concat());
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+pub(crate) fn open_local(master: &[u8; 32], key_id: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || KVError::Corruption("wrapped keyring is damaged".to_owned());
+    let (body, tag) = sealed.split_at_checked(sealed.len().saturating_sub(32)).ok_or_else(corrupt)?;
+    if body.len() < 12 {
+        return Err(corrupt());
+    }
+    if !ct_eq(&hmac_sha256(&hmac_sha256(master, b"kvstore keyring authentication"), &[key_id.as_bytes(), body].concat()), tag) {
+        return Err(KVError::InvalidConfig { option: "master_key", reason: "the key does not unlock this store".to_owned() });
+    }
+    let mut plain = body[12..].to_vec();
+    chacha20(&hmac_sha256(master, b"kvstore keyring encryption"), body[..12].try_into().unwrap(), &mut plain);
+    Ok(plain)
+}
+pub(crate) struct StaticKey(pub(crate) [u8; 32]);
+impl KeyProvider for StaticKey {
+    fn current_key(&self) -> Result<String> {
+        Ok("static".to_owned())
+    }
+    fn wrap(&self, key_id: &str, plain: &[u8]) -> Result<Vec<u8>> {
+        seal_local(&self.0, key_id, plain)
+    }
+    fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        open_local(&self.0, key_id, wrapped)
+    }
+}
+pub struct LocalKeyFile {
+    pub(crate) dir: PathBuf,
+}
+impl LocalKeyFile {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self { dir: dir.as_ref().to_path_buf() }
+    }
+    pub fn generate(&self, key_id: &str) -> Result<()> {
+        let path = self.path(key_id)?;
+        let key: [u8; 32] = random_bytes()?;
+        fs::create_dir_all(&self.dir)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(hex(&key).as_bytes())?;
+        file.sync_all()?;
+        sync_dir(&self.dir)
+    }
+    pub(crate) fn path(&self, key_id: &str) -> Result<PathBuf> {
+        if key_id.is_empty() || !key_id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)) || key_id.starts_with('.') {
+            return Err(KVError::InvalidInput(format!("{:?} is not a valid key id", key_id)));
+        }
+        Ok(self.dir.join(format!("{}.key", key_id)))
+    }
+    pub(crate) fn key(&self, key_id: &str) -> Result<[u8; 32]> {
+        let text = match fs::read_to_string(self.path(key_id)?) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(KVError::InvalidConfig { option: "key_provider", reason: format!("no key file for key id {}", key_id) });
+            }
+            text => text?,
+        };
+        unhex(text.trim()).and_then(|key| key.try_into().ok()).ok_or_else(|| KVError::InvalidConfig {
+            option: "key_provider",
+            reason: format!("key file {}.key does not hold 32 hex-encoded bytes", key_id),
+        })
+    }
+}
+impl KeyProvider for LocalKeyFile {
+    fn current_key(&self) -> Result<String> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_suffix(".key").filter(|id| self.path(id).is_ok()) {
+                ids.push(id.to_owned());
+            }
+        }
+        ids.into_iter().max().ok_or_else(|| KVError::InvalidConfig {
+            option: "key_provider",
+            reason: format!("{} holds no key files", self.dir.display()),
+        })
+    }
+    fn wrap(&self, key_id: &str, plain: &[u8]) -> Result<Vec<u8>> {
+        seal_local(&self.key(key_id)?, key_id, plain)
+    }
+    fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        open_local(&self.key(key_id)?, key_id, wrapped)
+    }
+}
+#[derive(Default)]
+pub(crate) struct Keyring {
+    pub(crate) current: u32,
+    pub(crate) keys: BTreeMap<u32, [u8; 32]>,
+}
+impl Keyring {
+    pub(crate) fn seal(&self, provider: &dyn KeyProvider) -> Result<(String, Vec<u8>)> {
+        let mut plain = Vec::new();
+        put_u32(&mut plain, self.current);
+        put_u32(&mut plain, self.keys.len() as u32);
+        for (id, key) in &self.keys {
+            put_u32(&mut plain, *id);
+            plain.extend_from_slice(key);
+        }
+        let key_id = provider.current_key()?;
+        let mut sealed = KEYRING_MAGIC.to_vec();
+        put_str(&mut sealed, &key_id);
+        sealed.extend_from_slice(&provider.wrap(&key_id, &plain)?);
+        Ok((key_id, sealed))
+    }
+    pub(crate) fn master_key_id(sealed: &[u8]) -> Result<(String, &[u8])> {
+        if !sealed.starts_with(KEYRING_MAGIC) {
+            return Err(KVError::Corruption("keyring is damaged".to_owned()));
+        }
+        let mut d = Decoder::new(&sealed[KEYRING_MAGIC.len()..]);
+        let key_id = d.string()?;
+        Ok((key_id, &d.buf[d.pos..]))
+    }
+    pub(crate) fn open(sealed: &[u8], provider: &dyn KeyProvider) -> Result<Self> {
+        let (key_id, wrapped) = Self::master_key_id(sealed)?;
+        let plain = provider.unwrap(&key_id, wrapped)?;
+        let mut d = Decoder::new(&plain);
+        let mut keyring = Keyring { current: d.u32()?, keys: BTreeMap::new() };
+        for _ in 0..d.u32()? {
+            let id = d.u32()?;
+            keyring.keys.insert(id, d.take(32)?.try_into().unwrap());
+        }
+        Ok(keyring)
+    }
+}
+pub(crate) struct EncryptedStorage {
+    pub(crate) inner: Arc<dyn Storage>,
+    pub(crate) provider: Mutex<Arc<dyn KeyProvider>>,
+    pub(crate) keyring: RwLock<Keyring>,
+    pub(crate) appends: Mutex<HashMap<String, (u32, u64)>>,
+}
+impl EncryptedStorage {
+    /// Opens `inner` under `provider`, creating a keyring for an empty store. A store that already holds plaintext
+    /// files is refused rather than left half encrypted; export it and import it into a fresh encrypted store.
+    pub(crate) fn open(inner: Arc<dyn Storage>, provider: Arc<dyn KeyProvider>, writable: bool) -> Result<Self> {
+        let names = inner.list()?;
+        let keyring = match names.iter().any(|name| name == KEYRING) {
+            true => Keyring::open(&inner.read(KEYRING)?, provider.as_ref())?,
+            false if names.iter().any(|name| name != LOCK_FILE && !name.ends_with(".tmp")) => {
+                return Err(KVError::InvalidInput("store is not encrypted; export it into an encrypted store".to_owned()));
+            }
+            false if !writable => Keyring::default(),
+            false => {
+                let keyring = Keyring { current: 1, keys: BTreeMap::from([(1, random_bytes()?)]) };
+                inner.write(KEYRING, &keyring.seal(provider.as_ref())?.1)?;
+                keyring
+            }
+        };
+        Ok(EncryptedStorage { inner, provider: Mutex::new(provider), keyring: RwLock::new(keyring), appends: Mutex::new(HashMap::new()) })
+    }
+    pub(crate) fn rewrap(&self, provider: Option<Arc<dyn KeyProvider>>) -> Result<String> {
+        let mut current = self.provider.lock().unwrap();
+        let provider = provider.unwrap_or_else(|| Arc::clone(&current));
+        let (key_id, sealed) = self.keyring.read().unwrap().seal(provider.as_ref())?;
+        self.inner.write(KEYRING, &sealed)?;
+        *current = provider;
+        Ok(key_id)
+    }
+    pub(crate) fn master_key_id(&self) -> Result<Option<String>> {
+        match self.inner.list()?.iter().any(|name| name == KEYRING) {
+            true => Ok(Some(Keyring::master_key_id(&self.inner.read(KEYRING)?)?.0)),
+            false => Ok(None),
+        }
+    }
+    pub(crate) fn rotate_data_key(&self) -> Result<u32> {
+        let provider = self.provider.lock().unwrap();
+        let mut keyring = self.keyring.write().unwrap();
+        let id = keyring.keys.keys().next_back().map_or(1, |id| id + 1);
+        keyring.keys.insert(id, random_bytes()?);
+        keyring.current = id;
+        self.inner.write(KEYRING, &keyring.seal(provider.as_ref())?.1)?;
+        Ok(id)
+    }
+    pub(crate) fn retire_keys(&self, live: &BTreeSet<u32>) -> Result<usize> {
+        let provider = self.provider.lock().unwrap();
+        let mut keyring = self.keyring.write().unwrap();
+        let current = keyring.current;
+        let before = keyring.keys.len();
+        keyring.keys.retain(|id, _| *id == current || live.contains(id));
+        let retired = before - keyring.keys.len();
+        if retired > 0 {
+            self.inner.write(KEYRING, &keyring.seal(provider.as_ref())?.1)?;
+        }
+        Ok(retired)
+    }
+    pub(crate) fn current_key(&self) -> u32 {
+        self.keyring.read().unwrap().current
+    }
+    pub(crate) fn data_key(&self, id: u32) -> Result<[u8; 32]> {
+        let keyring = self.keyring.read().unwrap();
+        keyring.keys.get(&id).copied().ok_or_else(|| KVError::Corruption(format!("data key {} is missing from the keyring", id)))
+    }
+    pub(crate) fn key_of(&self, name: &str) -> Result<Option<u32>> {
+        let (len, mut stream) = self.inner.read_stream(name)?;
+        if len < ENCRYPTED_HEADER as u64 {
+            return Ok(None);
+        }
+        let mut header = [0; ENCRYPTED_HEADER];
+        stream.read_exact(&mut header)?;
+        Ok(Self::header_of(name, &header).ok().map(|(id, _)| id))
+    }
+    /// Reads the data key id and file mode from an encrypted file's header. Anything else is corruption: once a
+    /// store has a keyring, a plaintext file in it was not written by this store.
+    pub(crate) fn header_of(name: &str, bytes: &[u8]) -> Result<(u32, u8)> {
+        match bytes.starts_with(ENCRYPTED_MAGIC) && bytes.len() >= ENCRYPTED_HEADER {
+            true => Ok((u32::from_le_bytes(bytes[ENCRYPTED_MAGIC.len()..ENCRYPTED_HEADER - 1].try_into().unwrap()), bytes[ENCRYPTED_HEADER - 1])),
+            false => Err(KVError::Corruption(format!("{} is not encrypted", name))),
+        }
+    }
+    /// Encrypts `plain` as the chunk that starts at `offset` in `name`. The tag covers the file name, header and
+    /// offset as well as the ciphertext and final flag, so chunks cannot be altered, reordered, moved between files
+    /// or have their final flag stripped undetected.
+    pub(crate) fn chunk(key: &[u8; 32], name: &str, header: &[u8], offset: u64, plain: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce: [u8; 12] = random_bytes()?;
+        let mut chunk = Vec::with_capacity(CHUNK_HEADER + plain.len() + CHUNK_TAG);
+        put_u32(&mut chunk, plain.len() as u32 | if last { FINAL_CHUNK } else { 0 });
+        chunk.extend_from_slice(&nonce);
+        chunk.extend_from_slice(plain);
+        chacha20(key, &nonce, &mut chunk[CHUNK_HEADER..]);
+        let tag = Self::chunk_tag(key, name, header, offset, &chunk);
+        chunk.extend_from_slice(&tag);
+        Ok(chunk)
+    }
+    pub(crate) fn chunk_tag(key: &[u8; 32], name: &str, header: &[u8], offset: u64, sealed: &[u8]) -> [u8; 32] {
+        let mut message = Vec::with_capacity(name.len() + header.len() + 12 + sealed.len());
+        put_str(&mut message, name);
+        message.extend_from_slice(header);
+        message.extend_from_slice(&offset.to_le_bytes());
+        message.extend_from_slice(sealed);
+        hmac_sha256(&hmac_sha256(key, b"kvstore chunk authentication"), &message)
+    }
+    /// Splits an encrypted file into its chunks and returns them with the offset where the last whole chunk ends.
+    /// A sealed file must hold exactly one final chunk, so cutting it short is caught. A log never has a final
+    /// chunk, because appends cannot rewrite earlier bytes; a torn partial chunk at its end is what an interrupted
+    /// append leaves behind and is ignored, and a log cut at a chunk boundary reads like one whose later appends
+    /// never happened.
+    pub(crate) fn chunks(name: &str, bytes: &[u8]) -> Result<(Vec<Range<usize>>, usize)> {
+        let (_, mode) = Self::header_of(name, bytes)?;
+        let mut pos = ENCRYPTED_HEADER;
+        let mut chunks = Vec::new();
+        let mut last = false;
+        while bytes.len().saturating_sub(pos) >= CHUNK_HEADER + CHUNK_TAG && !last {
+            let raw = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+            let len = (raw & !FINAL_CHUNK) as usize;
+            if bytes.len() - pos - CHUNK_HEADER - CHUNK_TAG < len {
+                break;
+            }
+            last = raw & FINAL_CHUNK != 0;
+            chunks.push(pos..pos + CHUNK_HEADER + len + CHUNK_TAG);
+            pos += CHUNK_HEADER + len + CHUNK_TAG;
+        }
+        match mode {
+            SEALED_FILE if last && pos == bytes.len() => Ok((chunks, pos)),
+            SEALED_FILE => Err(KVError::Corruption(format!("{} is truncated or has trailing bytes", name))),
+            LOG_FILE if !last => Ok((chunks, pos)),
+            LOG_FILE => Err(KVError::Corruption(format!("{} has a final chunk but is a log", name))),
+            mode => Err(KVError::Corruption(format!("{} has unknown encrypted file mode {}", name, mode))),
+        }
+    }
+    pub(crate) fn open_chunk(key: &[u8; 32], name: &str, bytes: &[u8], chunk: Range<usize>) -> Result<Vec<u8>> {
+        let (sealed, tag) = bytes[chunk.clone()].split_at(chunk.len() - CHUNK_TAG);
+        if !ct_eq(&Self::chunk_tag(key, name, &bytes[..ENCRYPTED_HEADER], chunk.start as u64, sealed), tag) {
+            return Err(KVError::Corruption(format!("{} fails authentication at offset {}", name, chunk.start)));
+        }
+        let mut plain = sealed[CHUNK_HEADER..].to_vec();
+        chacha20(key, sealed[4..CHUNK_HEADER].try_into().unwrap(), &mut plain);
+        Ok(plain)
+    }
+    pub(crate) fn decrypt(&self, name: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+        let (id, _) = Self::header_of(name, bytes)?;
+        let key = self.data_key(id)?;
+        let mut plain = Vec::with_capacity(bytes.len());
+        for chunk in Self::chunks(name, bytes)?.0 {
+            plain.extend_from_slice(&Self::open_chunk(&key, name, bytes, chunk)?);
+        }
+        Ok(plain)
+    }
+    pub(crate) fn header(id: u32, mode: u8) -> Vec<u8> {
+        [&ENCRYPTED_MAGIC[..], &id.to_le_bytes(), &[mode]].concat()
+    }
+    /// Encrypts `bytes` under the current data key as a sealed file.
+    pub(crate) fn seal(&self, name: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+        let id = self.current_key();
+        let header = Self::header(id, SEALED_FILE);
+        let chunk = Self::chunk(&self.data_key(id)?, name, &header, ENCRYPTED_HEADER as u64, bytes, true)?;
+        Ok([header, chunk].concat())
+    }
+    /// Starts a log under the current data key holding `plain`, and returns its key id and length.
+    pub(crate) fn start_log(&self, name: &str, plain: &[u8]) -> Result<(u32, u64)> {
+        let id = self.current_key();
+        let header = Self::header(id, LOG_FILE);
+        let mut file = header.clone();
+        if !plain.is_empty() {
+            file.extend_from_slice(&Self::chunk(&self.data_key(id)?, name, &header, ENCRYPTED_HEADER as u64, plain, false)?);
+        }
+        self.inner.write(name, &file)?;
+        Ok((id, file.len() as u64))
+    }
+}
+impl Storage for EncryptedStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.inner.list()?.into_iter().filter(|name| name != KEYRING).collect())
+    }
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        self.decrypt(name, &self.inner.read(name)?)
+    }
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let mut appends = self.appends.lock().unwrap();
+        appends.remove(name);
+        self.inner.write(name, &self.seal(name, bytes)?)
+    }
+    fn append(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let mut appends = self.appends.lock().unwrap();
+        let (id, end) = match appends.get(name) {
+            Some(tail) => *tail,
+            None => {
+                let exists = self.inner.list()?.iter().any(|listed| listed == name);
+                let existing = if exists { self.inner.read(name)? } else { Vec::new() };
+                match existing.is_empty() {
+                    true => self.start_log(name, &[])?,
+                    false => match Self::header_of(name, &existing)? {
+                        (_, SEALED_FILE) => self.start_log(name, &self.decrypt(name, &existing)?)?,
+                        (id, _) => {
+                            let end = Self::chunks(name, &existing)?.1;
+                            if end < existing.len() {
+                                self.inner.truncate(name, end as u64)?;
+                            }
+                            (id, end as u64)
+                        }
+                    },
+                }
+            }
+        };
+        let chunk = Self::chunk(&self.data_key(id)?, name, &Self::header(id, LOG_FILE), end, bytes, false)?;
+        self.inner.append(name, &chunk)?;
+        appends.insert(name.to_owned(), (id, end + chunk.len() as u64));
+        Ok(())
+    }
+    fn truncate(&self, name: &str, len: u64) -> Result<()> {
+        let mut appends = self.appends.lock().unwrap();
+        let bytes = self.inner.read(name)?;
+        let (id, mode) = Self::header_of(name, &bytes)?;
+        let key = self.data_key(id)?;
+        if mode == SEALED_FILE {
+            let plain = self.decrypt(name, &bytes)?;
+            if (plain.len() as u64) > len {
+                appends.remove(name);
+                self.inner.write(name, &self.seal(name, &plain[..len as usize])?)?;
+            }
+            return Ok(());
+        }
+        let mut logical = 0;
+        for chunk in Self::chunks(name, &bytes)?.0 {
+            let size = (chunk.len() - CHUNK_HEADER - CHUNK_TAG) as u64;
+            if logical + size > len {
+                let start = chunk.start as u64;
+                let kept = match len > logical {
+                    true => Some(Self::open_chunk(&key, name, &bytes, chunk)?),
+                    false => None,
+                };
+                self.inner.truncate(name, start)?;
+                appends.remove(name);
+                if let Some(plain) = kept {
+                    let header = &bytes[..ENCRYPTED_HEADER];
+                    self.inner.append(name, &Self::chunk(&key, name, header, start, &plain[..(len - logical) as usize], false)?)?;
+                }
+                return Ok(());
+            }
+            logical += size;
+        }
+        Ok(())
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        self.appends.lock().unwrap().remove(name);
+        self.inner.remove(name)
+    }
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReencryptReport {
+    pub key: u32,
+    pub files_rewritten: usize,
+    pub keys_retired: usize,
+}
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+pub(crate) struct Sha256 {
+    pub(crate) state: [u32; 8],
+    pub(crate) buf: Vec<u8>,
+    pub(crate) len: u64,
+}
+impl Sha256 {
+    pub(crate) const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    pub(crate) fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            buf: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buf.len() == 64 {
+                let block: [u8; 64] = self.buf[..].try_into().unwrap();
+                self.compress(&block);
+                self.buf.clear();
+            }
+        }
+    }
+    pub(crate) fn finish(mut self) -> [u8; 32] {
+        let bits = self.len.wrapping_mul(8);
+        let mut pad = vec![0x80u8];
+        pad.resize((if self.buf.len() < 56 { 56 } else { 120 }) - self.buf.len(), 0);
+        pad.extend_from_slice(&bits.to_be_bytes());
+        let len = self.len;
+        self.update(&pad);
+        self.len = len;
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+    pub(crate) fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, wi) in Self::K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(wi);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(&block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(&block.map(|b| b ^ 0x5c));
+    outer.update(&inner.finish());
+    outer.finish()
+}
+pub(crate) fn chacha20(key: &[u8; 32], nonce: &[u8; 12], data: &mut [u8]) {
+    fn quarter(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+    let word = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        state[4 + i] = word(key, i);
+    }
+    for i in 0..3 {
+        state[13 + i] = word(nonce, i);
+    }
+    for (counter, block) in data.chunks_mut(64).enumerate() {
+        state[12] = counter as u32 + 1;
+        let mut s = state;
+        for _ in 0..10 {
+            quarter(&mut s, 0, 4, 8, 12);
+            quarter(&mut s, 1, 5, 9, 13);
+            quarter(&mut s, 2, 6, 10, 14);
+            quarter(&mut s, 3, 7, 11, 15);
+            quarter(&mut s, 0, 5, 10, 15);
+            quarter(&mut s, 1, 6, 11, 12);
+            quarter(&mut s, 2, 7, 8, 13);
+            quarter(&mut s, 3, 4, 9, 14);
+        }
+        let stream: Vec<u8> = s.iter().zip(&state).flat_map(|(x, y)| x.wrapping_add(*y).to_le_bytes()).collect();
+        for (byte, key) in block.iter_mut().zip(stream) {
+            *byte ^= key;
+        }
+    }
+}
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    match unsafe { browser::host_random(bytes.as_mut_ptr(), N) } {
+        0 => Ok(bytes),
+        rc => Err(KVError::Io(io::Error::other(format!("browser storage: random source failed ({})", rc)))),
+    }
+}
+#[cfg(unix)]
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+#[cfg(windows)]
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    #[link(name = "advapi32")]
+    extern "system" {
+        #[link_name = "SystemFunction036"]
+        fn rtl_gen_random(buf: *mut u8, len: u32) -> u8;
+    }
+    let mut bytes = [0; N];
+    match unsafe { rtl_gen_random(bytes.as_mut_ptr(), N as u32) } {
+        0 => Err(KVError::Io(io::Error::last_os_error())),
+        _ => Ok(bytes),
+    }
+}
+#[cfg(not(any(unix, windows, target_arch = "wasm32")))]
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    Err(KVError::Io(io::Error::new(io::ErrorKind::Unsupported, "no operating system random source on this target")))
+}
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+pub(crate) fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sha256_matches_the_fips_vectors() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        let mut hasher = Sha256::new();
+        for piece in [b'a'; 1000].chunks(7) {
+            hasher.update(piece);
+        }
+        assert_eq!(hex(&hasher.finish()), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        assert_eq!(hex(&hmac_sha256(&[0x0b; 20], b"Hi There")), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+    #[test]
+    fn random_bytes_are_drawn_fresh() {
+        let (a, b): ([u8; 32], [u8; 32]) = (random_bytes().unwrap(), random_bytes().unwrap());
+        assert_ne!(a, b);
+        assert_ne!(a, [0; 32]);
+        let nonces: std::collections::HashSet<[u8; 12]> = (0..256).map(|_| random_bytes().unwrap()).collect();
+        assert_eq!(nonces.len(), 256);
+    }
+    fn encrypted() -> (Arc<MemoryStorage>, EncryptedStorage) {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::open(Arc::clone(&inner) as Arc<dyn Storage>, Arc::new(StaticKey([7; 32])), true).unwrap();
+        (inner, storage)
+    }
+    #[test]
+    fn chunks_round_trip_through_appends_and_truncation() {
+        let (_inner, storage) = encrypted();
+        storage.write("a.snap", b"snapshot").unwrap();
+        assert_eq!(storage.read("a.snap").unwrap(), b"snapshot");
+        for part in [&b"one "[..], b"two ", b"three"] {
+            storage.append("b.wal", part).unwrap();
+        }
+        assert_eq!(storage.read("b.wal").unwrap(), b"one two three");
+        storage.truncate("b.wal", 6).unwrap();
+        storage.append("b.wal", b"!").unwrap();
+        assert_eq!(storage.read("b.wal").unwrap(), b"one tw!");
+    }
+    #[test]
+    fn tampered_chunks_fail_authentication() {
+        let (inner, storage) = encrypted();
+        storage.append("a.wal", b"first").unwrap();
+        storage.append("a.wal", b"second").unwrap();
+        let sealed = inner.read("a.wal").unwrap();
+        for at in [ENCRYPTED_HEADER + 4, ENCRYPTED_HEADER + CHUNK_HEADER, sealed.len() - 1] {
+            let mut flipped = sealed.clone();
+            flipped[at] ^= 1;
+            inner.write("a.wal", &flipped).unwrap();
+            assert!(matches!(storage.read("a.wal"), Err(KVError::Corruption(_))), "flip at {} went unnoticed", at);
+        }
+        let chunks = EncryptedStorage::chunks("a.wal", &sealed).unwrap().0;
+        let swapped = [&sealed[..ENCRYPTED_HEADER], &sealed[chunks[1].clone()], &sealed[chunks[0].clone()]].concat();
+        inner.write("a.wal", &swapped).unwrap();
+        assert!(matches!(storage.read("a.wal"), Err(KVError::Corruption(_))));
+        inner.write("b.wal", &sealed).unwrap();
+        assert!(matches!(storage.read("b.wal"), Err(KVError::Corruption(_))));
+    }
+    #[test]
+    fn plaintext_and_cut_short_files_fail_closed() {
+        let (inner, storage) = encrypted();
+        inner.write("plain.snap", b"cleartext").unwrap();
+        assert!(matches!(storage.read("plain.snap"), Err(KVError::Corruption(_))));
+        assert!(matches!(storage.append("plain.snap", b" more"), Err(KVError::Corruption(_))));
+        assert!(matches!(storage.truncate("plain.snap", 1), Err(KVError::Corruption(_))));
+        assert_eq!(inner.read("plain.snap").unwrap(), b"cleartext");
+        storage.write("a.snap", b"snapshot").unwrap();
+        let sealed = inner.read("a.snap").unwrap();
+        for cut in [&sealed[..ENCRYPTED_HEADER], &sealed[..sealed.len() - 1], &[&sealed[..], b"extra"].concat()] {
+            inner.write("a.snap", cut).unwrap();
+            assert!(matches!(storage.read("a.snap"), Err(KVError::Corruption(_))));
+        }
+        let mut log = sealed.clone();
+        log[ENCRYPTED_HEADER - 1] = LOG_FILE;
+        inner.write("a.snap", &log).unwrap();
+        assert!(matches!(storage.read("a.snap"), Err(KVError::Corruption(_))));
+        storage.append("b.wal", b"one").unwrap();
+        let torn = [inner.read("b.wal").unwrap(), b"torn".to_vec()].concat();
+        inner.write("b.wal", &torn).unwrap();
+        assert_eq!(storage.read("b.wal").unwrap(), b"one");
+        let storage = EncryptedStorage::open(Arc::clone(&inner) as Arc<dyn Storage>, Arc::new(StaticKey([7; 32])), true).unwrap();
+        storage.append("b.wal", b" two").unwrap();
+        assert_eq!(storage.read("b.wal").unwrap(), b"one two");
+        storage.write("c.wal", b"sealed ").unwrap();
+        storage.append("c.wal", b"then logged").unwrap();
+        assert_eq!(storage.read("c.wal").unwrap(), b"sealed then logged");
+        storage.write("a.snap", b"snapshot").unwrap();
+        storage.truncate("a.snap", 4).unwrap();
+        assert_eq!(storage.read("a.snap").unwrap(), b"snap");
+    }
+    #[test]
+    fn plaintext_stores_are_not_adopted_by_a_key() {
+        let inner = Arc::new(MemoryStorage::new());
+        inner.write("wal-0", b"cleartext").unwrap();
+        let opened = EncryptedStorage::open(Arc::clone(&inner) as Arc<dyn Storage>, Arc::new(StaticKey([7; 32])), true);
+        assert!(matches!(opened, Err(KVError::InvalidInput(_))));
+        assert_eq!(inner.list().unwrap(), ["wal-0"]);
+    }
+    #[test]
+    fn chacha20_matches_rfc_8439() {
+        let mut zeros = [0; 64];
+        chacha20(&[0; 32], &[0; 12], &mut zeros);
+        assert_eq!(hex(&zeros), "9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f");
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        chacha20(&key, &nonce, &mut text);
+        assert_eq!(
+            hex(&text),
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d"
+        );
+        chacha20(&key, &nonce, &mut text);
+        assert!(text.starts_with(b"Ladies and Gentlemen"));
+    }
+    #[test]
+    fn keyrings_only_open_under_their_master_key() {
+        let sealed = seal_local(&[1; 32], "m1", b"data keys").unwrap();
+        assert_eq!(open_local(&[1; 32], "m1", &sealed).unwrap(), b"data keys");
+        assert!(matches!(open_local(&[2; 32], "m1", &sealed), Err(KVError::InvalidConfig { option: "master_key", .. })));
+        assert!(matches!(open_local(&[1; 32], "m2", &sealed), Err(KVError::InvalidConfig { option: "master_key", .. })));
+        let mut flipped = sealed.clone();
+        flipped[12] ^= 1;
+        assert!(open_local(&[1; 32], "m1", &flipped).is_err());
+    }
+    #[test]
+    fn key_files_wrap_the_keyring_and_follow_rotation_across_reopens() {
+        let (dir, keys) = (TempDir::new("kms-store"), TempDir::new("kms-keys"));
+        let provider = Arc::new(LocalKeyFile::new(keys.path()));
+        assert!(matches!(provider.current_key(), Err(KVError::InvalidConfig { option: "key_provider", .. })));
+        assert!(matches!(provider.generate("../escape"), Err(KVError::InvalidInput(_))));
+        provider.generate("2026-01").unwrap();
+        assert!(provider.generate("2026-01").is_err());
+        let wrapped = provider.wrap("2026-01", b"data key").unwrap();
+        assert_eq!(provider.unwrap("2026-01", &wrapped).unwrap(), b"data key");
+        assert!(matches!(provider.unwrap("2026-02", &wrapped), Err(KVError::InvalidConfig { option: "key_provider", .. })));
+        let open = || KVStore::builder().path(dir.path()).key_provider(provider.clone()).open();
+        {
+            let store = open().unwrap();
+            let mut tx = store.begin();
+            tx.put("k".to_owned(), "secret".to_owned()).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(store.master_key_id().unwrap().as_deref(), Some("2026-01"));
+        }
+        provider.generate("2026-02").unwrap();
+        assert_eq!(provider.current_key().unwrap(), "2026-02");
+        {
+            let store = open().unwrap();
+            assert_eq!(store.rewrap_keyring().unwrap(), "2026-02");
+            assert_eq!(store.master_key_id().unwrap().as_deref(), Some("2026-02"));
+        }
+        fs::remove_file(keys.path().join("2026-01.key")).unwrap();
+        let store = open().unwrap();
+        assert_eq!(store.begin().get("k").unwrap().as_deref(), Some("secret"));
+        drop(store);
+        fs::remove_file(keys.path().join("2026-02.key")).unwrap();
+        provider.generate("2026-03").unwrap();
+        assert!(matches!(open(), Err(KVError::InvalidConfig { option: "key_provider", .. })));
+    }
+    #[test]
+    fn ct_eq_compares_whole_slices() {
+        assert!(ct_eq(b"tag", b"tag"));
+        assert!(!ct_eq(b"tag", b"tab"));
+        assert!(!ct_eq(b"tag", b"ta"));
+        assert!(ct_eq(b"", b""));
+    }
+}
@@ -0,0 +1,274 @@
+use super::{KVError, KVStore, Transaction};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::sync::Arc;
+pub const KV_OK: c_int = 0;
+pub const KV_NOT_FOUND: c_int = 1;
+pub const KV_ERR_ABORTED: c_int = -1;
+pub const KV_ERR_IO: c_int = -2;
+pub const KV_ERR_CORRUPTION: c_int = -3;
+pub const KV_ERR_INVALID: c_int = -4;
+pub const KV_ERR_NO_MEMORY: c_int = -5;
+pub const KV_ERR_PANIC: c_int = -6;
+pub struct KvStoreHandle(pub(super) Arc<KVStore>);
+pub struct KvTxnHandle(Transaction);
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+fn fail(code: c_int, msg: String) -> c_int {
+    let msg = CString::new(msg).unwrap_or_else(|_| CString::new("error message contained a nul byte").unwrap());
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+    code
+}
+pub(super) fn report(err: KVError) -> c_int {
+    let code = match &err {
+        KVError::TransactionAborted => KV_ERR_ABORTED,
+        KVError::Io(_) => KV_ERR_IO,
+        KVError::Corruption(_) => KV_ERR_CORRUPTION,
+        KVError::OutOfMemoryBudget(_) => KV_ERR_NO_MEMORY,
+        _ => KV_ERR_INVALID,
+    };
+    fail(code, err.to_string())
+}
+/// Runs an entry point's body so that a panic comes back to C as `on_panic` (with the message in `kv_last_error`)
+/// instead of unwinding into, or aborting, the host process.
+fn guarded<T>(entry: &str, on_panic: T, body: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let detail = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+            (Some(msg), _) => msg.to_string(),
+            (_, Some(msg)) => msg.clone(),
+            _ => "non-string panic payload".to_owned(),
+        };
+        fail(KV_ERR_PANIC, format!("{} panicked: {}", entry, detail));
+        on_panic
+    })
+}
+unsafe fn utf8<'a>(ptr: *const u8, len: usize, what: &str) -> Result<&'a str, c_int> {
+    if ptr.is_null() {
+        return Err(fail(KV_ERR_INVALID, format!("{} is null", what)));
+    }
+    std::str::from_utf8(std::slice::from_raw_parts(ptr, len))
+        .map_err(|_| fail(KV_ERR_INVALID, format!("{} is not valid utf-8", what)))
+}
+/// Returns the message for the last error on this thread, or null. Valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn kv_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |msg| msg.as_ptr()))
+}
+#[no_mangle]
+pub extern "C" fn kv_open_memory() -> *mut KvStoreHandle {
+    guarded("kv_open_memory", std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(KvStoreHandle(KVStore::new())))
+    })
+}
+/// # Safety
+/// `path` must be a nul-terminated string and `out` a valid pointer to write the handle to.
+#[no_mangle]
+pub unsafe extern "C" fn kv_open(path: *const c_char, out: *mut *mut KvStoreHandle) -> c_int {
+    guarded("kv_open", KV_ERR_PANIC, || {
+        if path.is_null() || out.is_null() {
+            return fail(KV_ERR_INVALID, "path and out must not be null".to_owned());
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            return fail(KV_ERR_INVALID, "path is not valid utf-8".to_owned());
+        };
+        match KVStore::open(path) {
+            Ok(store) => {
+                *out = Box::into_raw(Box::new(KvStoreHandle(store)));
+                KV_OK
+            }
+            Err(e) => report(e),
+        }
+    })
+}
+/// # Safety
+/// `store` must come from `kv_open`/`kv_open_memory` and is invalid afterwards. Open transactions stay usable.
+#[no_mangle]
+pub unsafe extern "C" fn kv_close(store: *mut KvStoreHandle) {
+    guarded("kv_close", (), || {
+        if !store.is_null() {
+            drop(Box::from_raw(store));
+        }
+    })
+}
+/// # Safety
+/// `store` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn kv_begin(store: *const KvStoreHandle) -> *mut KvTxnHandle {
+    guarded("kv_begin", std::ptr::null_mut(), || {
+        match store.as_ref() {
+            Some(store) => Box::into_raw(Box::new(KvTxnHandle(store.0.begin()))),
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+/// # Safety
+/// `txn` must come from `kv_begin`; it is freed by this call whatever the result.
+#[no_mangle]
+pub unsafe extern "C" fn kv_commit(txn: *mut KvTxnHandle) -> c_int {
+    guarded("kv_commit", KV_ERR_PANIC, || {
+        if txn.is_null() {
+            return fail(KV_ERR_INVALID, "txn is null".to_owned());
+        }
+        match Box::from_raw(txn).0.commit() {
+            Ok(_) => KV_OK,
+            Err(e) => report(e),
+        }
+    })
+}
+/// # Safety
+/// `txn` must come from `kv_begin`; it is freed by this call.
+#[no_mangle]
+pub unsafe extern "C" fn kv_rollback(txn: *mut KvTxnHandle) {
+    guarded("kv_rollback", (), || {
+        if !txn.is_null() {
+            let _ = Box::from_raw(txn).0.rollback();
+        }
+    })
+}
+/// # Safety
+/// `txn` must be live, `key` valid for `key_len` bytes, and `value`/`value_len` valid to write.
+/// The returned buffer must be released with `kv_free`.
+#[no_mangle]
+pub unsafe extern "C" fn kv_get(
+    txn: *const KvTxnHandle,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    guarded("kv_get", KV_ERR_PANIC, || {
+        let (Some(txn), false, false) = (txn.as_ref(), value.is_null(), value_len.is_null()) else {
+            return fail(KV_ERR_INVALID, "txn, value and value_len must not be null".to_owned());
+        };
+        let key = match utf8(key, key_len, "key") {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        match txn.0.get(key) {
+            Ok(Some(found)) => {
+                let bytes = found.into_bytes().into_boxed_slice();
+                *value_len = bytes.len();
+                *value = Box::into_raw(bytes) as *mut u8;
+                KV_OK
+            }
+            Ok(None) => {
+                *value = std::ptr::null_mut();
+                *value_len = 0;
+                KV_NOT_FOUND
+            }
+            Err(e) => report(e),
+        }
+    })
+}
+/// # Safety
+/// `buf`/`len` must be exactly what `kv_get` returned.
+#[no_mangle]
+pub unsafe extern "C" fn kv_free(buf: *mut u8, len: usize) {
+    guarded("kv_free", (), || {
+        if !buf.is_null() {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buf, len)));
+        }
+    })
+}
+/// # Safety
+/// `txn` must be live and `key`/`value` valid for their lengths.
+#[no_mangle]
+pub unsafe extern "C" fn kv_put(
+    txn: *mut KvTxnHandle,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    guarded("kv_put", KV_ERR_PANIC, || {
+        let Some(txn) = txn.as_mut() else {
+            return fail(KV_ERR_INVALID, "txn is null".to_owned());
+        };
+        let (key, value) = match (utf8(key, key_len, "key"), utf8(value, value_len, "value")) {
+            (Ok(key), Ok(value)) => (key, value),
+            (Err(code), _) | (_, Err(code)) => return code,
+        };
+        match txn.0.put(key.to_owned(), value.to_owned()) {
+            Ok(()) => KV_OK,
+            Err(e) => report(e),
+        }
+    })
+}
+/// # Safety
+/// `txn` must be live and `key` valid for `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kv_delete(txn: *mut KvTxnHandle, key: *const u8, key_len: usize) -> c_int {
+    guarded("kv_delete", KV_ERR_PANIC, || {
+        let Some(txn) = txn.as_mut() else {
+            return fail(KV_ERR_INVALID, "txn is null".to_owned());
+        };
+        let key = match utf8(key, key_len, "key") {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        match txn.0.delete(key.to_owned()) {
+            Ok(()) => KV_OK,
+            Err(e) => report(e),
+        }
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    unsafe fn get(txn: *const KvTxnHandle, key: &str) -> (c_int, Option<String>) {
+        let (mut value, mut len) = (std::ptr::null_mut(), 0);
+        let rc = kv_get(txn, key.as_ptr(), key.len(), &mut value, &mut len);
+        let found = (!value.is_null()).then(|| String::from_utf8(std::slice::from_raw_parts(value, len).to_vec()).unwrap());
+        kv_free(value, len);
+        (rc, found)
+    }
+    #[test]
+    fn handles_round_trip_values_and_report_errors() {
+        unsafe {
+            let store = kv_open_memory();
+            let txn = kv_begin(store);
+            assert_eq!(kv_put(txn, "k".as_ptr(), 1, "v".as_ptr(), 1), KV_OK);
+            assert_eq!(kv_commit(txn), KV_OK);
+            let (first, second) = (kv_begin(store), kv_begin(store));
+            assert_eq!(get(first, "k"), (KV_OK, Some("v".to_owned())));
+            assert_eq!(get(first, "missing"), (KV_NOT_FOUND, None));
+            assert_eq!(kv_put(first, "k".as_ptr(), 1, "1".as_ptr(), 1), KV_OK);
+            assert_eq!(kv_delete(second, "k".as_ptr(), 1), KV_OK);
+            assert_eq!(kv_commit(first), KV_OK);
+            assert_eq!(kv_commit(second), KV_OK);
+            let txn = kv_begin(store);
+            assert_eq!(get(txn, "k"), (KV_NOT_FOUND, None));
+            assert_eq!(kv_put(txn, [0xFF].as_ptr(), 1, "v".as_ptr(), 1), KV_ERR_INVALID);
+            assert_eq!(CStr::from_ptr(kv_last_error()).to_str().unwrap(), "key is not valid utf-8");
+            assert_eq!(kv_put(std::ptr::null_mut(), "k".as_ptr(), 1, "v".as_ptr(), 1), KV_ERR_INVALID);
+            kv_rollback(txn);
+            kv_close(store);
+            let mut out = std::ptr::null_mut();
+            assert_eq!(kv_open(std::ptr::null(), &mut out), KV_ERR_INVALID);
+            assert!(out.is_null());
+        }
+    }
+    #[test]
+    fn panics_come_back_as_an_error_code() {
+        unsafe {
+            let store = kv_open_memory();
+            let handle = &*store;
+            let _ = std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        let _held = handle.0.attached.lock().unwrap();
+                        panic!("poisoning the attached-store lock");
+                    })
+                    .join()
+            });
+            assert!(kv_begin(store).is_null());
+            let message = CStr::from_ptr(kv_last_error()).to_str().unwrap();
+            assert!(message.starts_with("kv_begin panicked: "), "{}", message);
+            assert_eq!(guarded("kv_test", KV_ERR_PANIC, || panic!("boom {}", 1)), KV_ERR_PANIC);
+            assert_eq!(CStr::from_ptr(kv_last_error()).to_str().unwrap(), "kv_test panicked: boom 1");
+            assert_eq!(guarded("kv_test", KV_ERR_PANIC, || KV_OK), KV_OK);
+            kv_close(store);
+        }
+    }
+}
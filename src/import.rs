@@ -0,0 +1,466 @@
+use crate::*;
+#[derive(Debug, Default)]
+pub struct RdbImportReport {
+    pub imported: usize,
+    pub expired: usize,
+    pub non_utf8: usize,
+    pub skipped_types: BTreeMap<&'static str, usize>,
+}
+pub(crate) enum RdbLength {
+    Len(u64),
+    Encoded(u8),
+}
+pub(crate) struct RdbReader<R> {
+    pub(crate) inner: R,
+}
+impl<R: Read> RdbReader<R> {
+    pub(crate) fn error(msg: String) -> KVError {
+        KVError::InvalidInput(format!("rdb: {}", msg))
+    }
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    pub(crate) fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub(crate) fn length_or_encoding(&mut self) -> Result<RdbLength> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => RdbLength::Len(u64::from(first & 0x3F)),
+            1 => RdbLength::Len((u64::from(first & 0x3F) << 8) | u64::from(self.byte()?)),
+            2 => match first {
+                0x80 => RdbLength::Len(u64::from(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))),
+                0x81 => RdbLength::Len(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap())),
+                _ => return Err(Self::error(format!("invalid length prefix {:#04x}", first))),
+            },
+            _ => RdbLength::Encoded(first & 0x3F),
+        })
+    }
+    pub(crate) fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            RdbLength::Len(len) => Ok(len),
+            RdbLength::Encoded(_) => Err(Self::error("unexpected encoded length".to_owned())),
+        }
+    }
+    pub(crate) fn string(&mut self) -> Result<Vec<u8>> {
+        match self.length_or_encoding()? {
+            RdbLength::Len(len) => self.bytes(len as usize),
+            RdbLength::Encoded(0) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            RdbLength::Encoded(1) => Ok(i16::from_le_bytes(self.bytes(2)?.try_into().unwrap()).to_string().into_bytes()),
+            RdbLength::Encoded(2) => Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()).to_string().into_bytes()),
+            RdbLength::Encoded(3) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                let compressed = self.bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            RdbLength::Encoded(enc) => Err(Self::error(format!("unknown string encoding {}", enc))),
+        }
+    }
+    pub(crate) fn skip_strings(&mut self, count: u64) -> Result<()> {
+        for _ in 0..count {
+            self.string()?;
+        }
+        Ok(())
+    }
+    pub(crate) fn skip_value(&mut self, value_type: u8) -> Result<&'static str> {
+        Ok(match value_type {
+            1 => {
+                let len = self.length()?;
+                self.skip_strings(len)?;
+                "list"
+            }
+            2 => {
+                let len = self.length()?;
+                self.skip_strings(len)?;
+                "set"
+            }
+            3 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    let score_len = self.byte()?;
+                    if score_len < 253 {
+                        self.bytes(score_len as usize)?;
+                    }
+                }
+                "zset"
+            }
+            4 => {
+                let len = self.length()?;
+                self.skip_strings(len * 2)?;
+                "hash"
+            }
+            5 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.bytes(8)?;
+                }
+                "zset"
+            }
+            9 | 13 | 16 => {
+                self.string()?;
+                "hash"
+            }
+            10 => {
+                self.string()?;
+                "list"
+            }
+            11 | 20 => {
+                self.string()?;
+                "set"
+            }
+            12 | 17 => {
+                self.string()?;
+                "zset"
+            }
+            14 => {
+                let len = self.length()?;
+                self.skip_strings(len)?;
+                "list"
+            }
+            18 => {
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                }
+                "list"
+            }
+            other => return Err(Self::error(format!("unsupported value type {}", other))),
+        })
+    }
+}
+pub(crate) fn lzf_compress(input: &[u8]) -> Vec<u8> {
+    fn literals(out: &mut Vec<u8>, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let (run, rest) = bytes.split_at(bytes.len().min(32));
+            out.push((run.len() - 1) as u8);
+            out.extend_from_slice(run);
+            bytes = rest;
+        }
+    }
+    let mut table = vec![0usize; 1 << 14];
+    let mut out = Vec::with_capacity(input.len() + input.len() / 32 + 1);
+    let (mut i, mut literal) = (0, 0);
+    while i + 2 < input.len() {
+        let hash = u32::from_le_bytes([input[i], input[i + 1], input[i + 2], 0]).wrapping_mul(2_654_435_761) >> 18;
+        let candidate = std::mem::replace(&mut table[hash as usize], i + 1);
+        let matched = candidate.checked_sub(1).filter(|&from| i - from <= 8192 && input[from..from + 3] == input[i..i + 3]);
+        if let Some(from) = matched {
+            let max = (input.len() - i).min(264);
+            let mut len = 3;
+            while len < max && input[from + len] == input[i + len] {
+                len += 1;
+            }
+            literals(&mut out, &input[literal..i]);
+            let (run, offset) = (len - 2, i - from - 1);
+            if run < 7 {
+                out.push((run << 5 | offset >> 8) as u8);
+            } else {
+                out.push((7 << 5 | offset >> 8) as u8);
+                out.push((run - 7) as u8);
+            }
+            out.push(offset as u8);
+            i += len;
+            literal = i;
+        } else {
+            i += 1;
+        }
+    }
+    literals(&mut out, &input[literal..]);
+    out
+}
+pub(crate) fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let bad = || KVError::InvalidInput("rdb: corrupt lzf data".to_owned());
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(bad)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(bad)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(i).ok_or_else(bad)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(offset).ok_or_else(bad)?;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(bad());
+    }
+    Ok(out)
+}
+pub(crate) struct BatchLoader<'a> {
+    pub(crate) store: &'a Arc<KVStore>,
+    pub(crate) tx: Transaction,
+    pub(crate) pending: usize,
+}
+impl<'a> BatchLoader<'a> {
+    pub(crate) const BATCH: usize = 10_000;
+    pub(crate) fn new(store: &'a Arc<KVStore>) -> Self {
+        Self { store, tx: store.begin(), pending: 0 }
+    }
+    pub(crate) fn put(&mut self, keyspace: &str, key: String, value: String) -> Result<()> {
+        let key = if keyspace.is_empty() { key } else { format!("{}{}{}", keyspace, KEYSPACE_SEPARATOR, key) };
+        self.tx.put(key, value)?;
+        self.pending += 1;
+        if self.pending == Self::BATCH {
+            std::mem::replace(&mut self.tx, self.store.begin()).commit()?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+    pub(crate) fn finish(self) -> Result<()> {
+        self.tx.commit().map(|_| ())
+    }
+}
+pub fn import_rdb(store: &Arc<KVStore>, reader: impl Read, keyspace: impl Fn(u64) -> String) -> Result<RdbImportReport> {
+    let mut rdb = RdbReader { inner: io::BufReader::new(reader) };
+    let header = rdb.bytes(9)?;
+    if &header[..5] != b"REDIS" {
+        return Err(RdbReader::<&[u8]>::error("missing REDIS header".to_owned()));
+    }
+    let mut report = RdbImportReport::default();
+    let mut db = 0;
+    let mut expires_at: Option<u64> = None;
+    let mut loader = BatchLoader::new(store);
+    loop {
+        let opcode = rdb.byte()?;
+        match opcode {
+            0xFF => break,
+            0xFE => db = rdb.length()?,
+            0xFD => expires_at = Some(u64::from(u32::from_le_bytes(rdb.bytes(4)?.try_into().unwrap())) * 1000),
+            0xFC => expires_at = Some(u64::from_le_bytes(rdb.bytes(8)?.try_into().unwrap())),
+            0xFB => {
+                rdb.length()?;
+                rdb.length()?;
+            }
+            0xFA => rdb.skip_strings(2)?,
+            0xF8 => {
+                rdb.byte()?;
+            }
+            0xF6 => {
+                rdb.length()?;
+            }
+            0xF7 => rdb.skip_strings(1)?,
+            value_type => {
+                let key = rdb.string()?;
+                let expired = expires_at.take().is_some_and(|at| at <= store.now_millis());
+                if value_type != 0 {
+                    let kind = rdb.skip_value(value_type)?;
+                    *report.skipped_types.entry(kind).or_insert(0) += 1;
+                    continue;
+                }
+                let value = rdb.string()?;
+                if expired {
+                    report.expired += 1;
+                    continue;
+                }
+                let (Ok(key), Ok(value)) = (String::from_utf8(key), String::from_utf8(value)) else {
+                    report.non_utf8 += 1;
+                    continue;
+                };
+                loader.put(&keyspace(db), key, value)?;
+                report.imported += 1;
+            }
+        }
+    }
+    loader.finish()?;
+    Ok(report)
+}
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub keyspaces: BTreeMap<String, usize>,
+    pub non_utf8: usize,
+}
+#[cfg(any(feature = "sled", feature = "redb"))]
+impl MigrationReport {
+    pub(crate) fn copy(&mut self, loader: &mut BatchLoader, keyspace: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+            (Ok(key), Ok(value)) => {
+                loader.put(keyspace, key.to_owned(), value.to_owned())?;
+                *self.keyspaces.entry(keyspace.to_owned()).or_insert(0) += 1;
+            }
+            _ => self.non_utf8 += 1,
+        }
+        Ok(())
+    }
+}
+#[cfg(feature = "sled")]
+pub fn migrate_from_sled<P: AsRef<Path>>(store: &Arc<KVStore>, path: P) -> Result<MigrationReport> {
+    let foreign = |e: sled::Error| KVError::Io(io::Error::other(format!("sled: {}", e)));
+    let db = sled::open(path).map_err(foreign)?;
+    let mut report = MigrationReport::default();
+    let mut loader = BatchLoader::new(store);
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name).map_err(foreign)?;
+        let keyspace = if &*name == b"__sled__default" { String::new() } else { String::from_utf8_lossy(&name).into_owned() };
+        for entry in tree.iter() {
+            let (key, value) = entry.map_err(foreign)?;
+            report.copy(&mut loader, &keyspace, &key, &value)?;
+        }
+    }
+    loader.finish()?;
+    Ok(report)
+}
+#[cfg(feature = "redb")]
+pub fn migrate_from_redb<P: AsRef<Path>>(store: &Arc<KVStore>, path: P) -> Result<MigrationReport> {
+    use redb::{ReadableTable, TableHandle};
+    let foreign = |e: &dyn fmt::Display| KVError::Io(io::Error::other(format!("redb: {}", e)));
+    let db = redb::Database::open(path.as_ref()).map_err(|e| foreign(&e))?;
+    let txn = db.begin_read().map_err(|e| foreign(&e))?;
+    let mut report = MigrationReport::default();
+    let mut loader = BatchLoader::new(store);
+    for handle in txn.list_tables().map_err(|e| foreign(&e))? {
+        let name = handle.name().to_owned();
+        if let Ok(table) = txn.open_table(redb::TableDefinition::<&[u8], &[u8]>::new(&name)) {
+            for entry in table.iter().map_err(|e| foreign(&e))? {
+                let (key, value) = entry.map_err(|e| foreign(&e))?;
+                report.copy(&mut loader, &name, key.value(), value.value())?;
+            }
+        } else {
+            let table = txn
+                .open_table(redb::TableDefinition::<&str, &str>::new(&name))
+                .map_err(|e| foreign(&e))?;
+            for entry in table.iter().map_err(|e| foreign(&e))? {
+                let (key, value) = entry.map_err(|e| foreign(&e))?;
+                report.copy(&mut loader, &name, key.value().as_bytes(), value.value().as_bytes())?;
+            }
+        }
+    }
+    loader.finish()?;
+    Ok(report)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn rdb_string(out: &mut Vec<u8>, s: &[u8]) {
+        out.push(s.len() as u8);
+        out.extend_from_slice(s);
+    }
+    #[test]
+    fn lzf_round_trips_repetitive_and_random_input() {
+        let repetitive = "abcabcabc".repeat(100).into_bytes();
+        let mut noisy = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..5000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            noisy.push((x >> 24) as u8 % 7);
+        }
+        for input in [&repetitive[..], &noisy, b"", b"ab"] {
+            let compressed = lzf_compress(input);
+            assert_eq!(lzf_decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(lzf_compress(&repetitive).len() < repetitive.len() / 10);
+        assert!(lzf_decompress(&[0x20, 0x00], 3).is_err());
+        assert!(lzf_decompress(&lzf_compress(b"abc"), 4).is_err());
+    }
+    #[test]
+    fn rdb_strings_load_into_keyspaces_and_other_types_are_counted() {
+        let long = "abcabcabc".repeat(20);
+        let mut rdb = b"REDIS0009".to_vec();
+        rdb.push(0xFA);
+        rdb_string(&mut rdb, b"redis-ver");
+        rdb_string(&mut rdb, b"7.0.0");
+        rdb.extend_from_slice(&[0xFE, 0, 0xFB, 5, 1]);
+        rdb.push(0);
+        rdb_string(&mut rdb, b"plain");
+        rdb_string(&mut rdb, b"value");
+        rdb.push(0);
+        rdb_string(&mut rdb, b"int");
+        rdb.extend_from_slice(&[0xC1, 0x39, 0x30]);
+        rdb.push(0);
+        rdb_string(&mut rdb, b"compressed");
+        let compressed = lzf_compress(long.as_bytes());
+        rdb.extend_from_slice(&[0xC3, compressed.len() as u8, 0x40 | (long.len() >> 8) as u8, long.len() as u8]);
+        rdb.extend_from_slice(&compressed);
+        rdb.push(0xFC);
+        rdb.extend_from_slice(&1_000u64.to_le_bytes());
+        rdb.push(0);
+        rdb_string(&mut rdb, b"expired");
+        rdb_string(&mut rdb, b"old");
+        rdb.push(0);
+        rdb_string(&mut rdb, b"binary");
+        rdb_string(&mut rdb, &[0xFF, 0xFE]);
+        rdb.push(1);
+        rdb_string(&mut rdb, b"list");
+        rdb.push(2);
+        rdb_string(&mut rdb, b"x");
+        rdb_string(&mut rdb, b"y");
+        rdb.extend_from_slice(&[0xFE, 2, 0]);
+        rdb_string(&mut rdb, b"other");
+        rdb_string(&mut rdb, b"db");
+        rdb.push(0xFF);
+        rdb.extend_from_slice(&[0; 8]);
+        let store = KVStore::new();
+        let report = import_rdb(&store, &rdb[..], |db| if db == 0 { String::new() } else { format!("db{}", db) }).unwrap();
+        assert_eq!((report.imported, report.expired, report.non_utf8), (4, 1, 1));
+        assert_eq!(report.skipped_types, BTreeMap::from([("list", 1)]));
+        let tx = store.begin();
+        assert_eq!(tx.get("plain").unwrap().as_deref(), Some("value"));
+        assert_eq!(tx.get("int").unwrap().as_deref(), Some("12345"));
+        assert_eq!(tx.get("compressed").unwrap().as_deref(), Some(long.as_str()));
+        assert_eq!(tx.get("expired").unwrap(), None);
+        assert_eq!(tx.get(&format!("db2{}other", KEYSPACE_SEPARATOR)).unwrap().as_deref(), Some("db"));
+        assert!(matches!(import_rdb(&store, &b"NOTREDIS0"[..], |_| String::new()), Err(KVError::InvalidInput(_))));
+    }
+    #[cfg(feature = "sled")]
+    #[test]
+    fn migrates_every_sled_tree_into_a_keyspace() {
+        let dir = TempDir::new("sled");
+        {
+            let db = sled::open(dir.path().join("sled")).unwrap();
+            db.insert("plain", "default tree").unwrap();
+            let users = db.open_tree("users").unwrap();
+            users.insert("1", "ada").unwrap();
+            users.insert("2", "grace").unwrap();
+            users.insert(&[0xff][..], "binary key").unwrap();
+            db.flush().unwrap();
+        }
+        let store = KVStore::new();
+        let report = migrate_from_sled(&store, dir.path().join("sled")).unwrap();
+        assert_eq!(report.keyspaces.get("users"), Some(&2));
+        assert_eq!(report.keyspaces.get(""), Some(&1));
+        assert_eq!(report.non_utf8, 1);
+        let tx = store.begin();
+        assert_eq!(tx.get("users:2").unwrap().as_deref(), Some("grace"));
+        assert_eq!(tx.get("plain").unwrap().as_deref(), Some("default tree"));
+    }
+    #[cfg(feature = "redb")]
+    #[test]
+    fn migrates_byte_and_string_redb_tables() {
+        let dir = TempDir::new("redb");
+        let path = dir.path().join("db.redb");
+        {
+            let db = redb::Database::create(&path).unwrap();
+            let txn = db.begin_write().unwrap();
+            {
+                let mut bytes = txn.open_table(redb::TableDefinition::<&[u8], &[u8]>::new("blobs")).unwrap();
+                bytes.insert(&b"a"[..], &b"1"[..]).unwrap();
+                bytes.insert(&b"b"[..], &[0xfe][..]).unwrap();
+                let mut text = txn.open_table(redb::TableDefinition::<&str, &str>::new("names")).unwrap();
+                text.insert("x", "ex").unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        let store = KVStore::new();
+        let report = migrate_from_redb(&store, &path).unwrap();
+        assert_eq!(report.keyspaces.get("blobs"), Some(&1));
+        assert_eq!(report.keyspaces.get("names"), Some(&1));
+        assert_eq!(report.non_utf8, 1);
+        let tx = store.begin();
+        assert_eq!(tx.get("blobs:a").unwrap().as_deref(), Some("1"));
+        assert_eq!(tx.get("names:x").unwrap().as_deref(), Some("ex"));
+    }
+}
@@ -0,0 +1,326 @@
+use super::{read_trace, KVStore, Result, TraceEvent, TraceOp, Version};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    InconsistentRead { txn: u64, key: String, expected: Option<String>, observed: Option<String> },
+    LostUpdate { key: String, txns: (u64, u64) },
+    ConcurrentWrite { key: String, txns: (u64, u64) },
+    Cycle { txns: Vec<u64> },
+    WriteSkew { txns: Vec<u64> },
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IsolationReport {
+    pub committed: usize,
+    pub aborted: usize,
+    pub reads: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+impl IsolationReport {
+    pub fn snapshot_isolation(&self) -> bool {
+        self.anomalies.iter().all(|anomaly| matches!(anomaly, Anomaly::WriteSkew { .. }))
+    }
+    pub fn serializable(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+#[derive(Default)]
+struct Txn {
+    snapshot: Version,
+    reads: Vec<(String, Option<String>)>,
+    writes: BTreeMap<String, Option<String>>,
+    commit: Option<Version>,
+}
+pub struct Checker {
+    store: Arc<KVStore>,
+    path: PathBuf,
+}
+impl Checker {
+    pub fn start(store: &Arc<KVStore>, path: impl AsRef<Path>) -> Result<Checker> {
+        store.start_recording(&path)?;
+        Ok(Checker { store: Arc::clone(store), path: path.as_ref().to_owned() })
+    }
+    pub fn finish(self) -> Result<IsolationReport> {
+        self.store.stop_recording()?;
+        Ok(check(&read_trace(&self.path)?))
+    }
+}
+pub fn check(events: &[TraceEvent]) -> IsolationReport {
+    let mut txns: BTreeMap<u64, Txn> = BTreeMap::new();
+    for event in events {
+        let txn = txns.entry(event.txn).or_default();
+        match &event.op {
+            TraceOp::Begin { snapshot } => txn.snapshot = *snapshot,
+            TraceOp::Get { key, found } if !txn.writes.contains_key(key) => txn.reads.push((key.clone(), found.clone())),
+            TraceOp::Get { .. } => {}
+            TraceOp::Put { key, value } => {
+                txn.writes.insert(key.clone(), Some(value.clone()));
+            }
+            TraceOp::Delete { key } => {
+                txn.writes.insert(key.clone(), None);
+            }
+            TraceOp::Commit { result } => txn.commit = result.as_ref().ok().copied(),
+            TraceOp::Rollback => txn.writes.clear(),
+        }
+    }
+    let mut report = IsolationReport::default();
+    let mut writers: HashMap<&str, Vec<(Version, u64)>> = HashMap::new();
+    for (id, txn) in &txns {
+        match txn.commit {
+            Some(version) => {
+                report.committed += 1;
+                for key in txn.writes.keys() {
+                    writers.entry(key).or_default().push((version, *id));
+                }
+            }
+            None => report.aborted += 1,
+        }
+    }
+    for chain in writers.values_mut() {
+        chain.sort_unstable();
+    }
+    let mut edges: BTreeMap<u64, BTreeSet<(u64, bool)>> = BTreeMap::new();
+    let mut edge = |from: u64, to: u64, anti: bool| {
+        if from != to {
+            edges.entry(from).or_default().insert((to, anti));
+        }
+    };
+    for (key, chain) in &writers {
+        for pair in chain.windows(2) {
+            edge(pair[0].1, pair[1].1, false);
+            let (a, b) = (&txns[&pair[0].1], &txns[&pair[1].1]);
+            if a.commit.unwrap() > b.snapshot {
+                let read = |txn: &Txn| txn.reads.iter().any(|(read, _)| read == key);
+                let txns = (pair[0].1, pair[1].1);
+                report.anomalies.push(match read(a) && read(b) {
+                    true => Anomaly::LostUpdate { key: key.to_string(), txns },
+                    false => Anomaly::ConcurrentWrite { key: key.to_string(), txns },
+                });
+            }
+        }
+    }
+    for (id, txn) in txns.iter().filter(|(_, txn)| txn.commit.is_some()) {
+        for (key, observed) in &txn.reads {
+            report.reads += 1;
+            let chain = writers.get(key.as_str()).map_or(&[][..], Vec::as_slice);
+            let seen = chain.partition_point(|&(version, _)| version <= txn.snapshot);
+            if let Some(&(_, writer)) = seen.checked_sub(1).map(|i| &chain[i]) {
+                edge(writer, *id, false);
+                let expected = txns[&writer].writes[key].clone();
+                if &expected != observed {
+                    report.anomalies.push(Anomaly::InconsistentRead {
+                        txn: *id,
+                        key: key.clone(),
+                        expected,
+                        observed: observed.clone(),
+                    });
+                }
+            }
+            if let Some(&(_, next)) = chain.get(seen) {
+                edge(*id, next, true);
+            }
+        }
+    }
+    for component in components(&edges) {
+        let plain = |from: &u64, to: &u64, anti: bool| !anti && component.contains(from) && component.contains(to);
+        let cycle = find_cycle(&edges, &component, plain).map(|txns| Anomaly::Cycle { txns });
+        let any = |from: &u64, to: &u64, _: bool| component.contains(from) && component.contains(to);
+        let skew = || find_cycle(&edges, &component, any).map(|txns| Anomaly::WriteSkew { txns });
+        report.anomalies.extend(cycle.or_else(skew));
+    }
+    report
+}
+fn components(edges: &BTreeMap<u64, BTreeSet<(u64, bool)>>) -> Vec<BTreeSet<u64>> {
+    let nodes: BTreeSet<u64> = edges.iter().flat_map(|(from, to)| to.iter().map(|(to, _)| *to).chain([*from])).collect();
+    let reach = |start: u64, forward: bool| {
+        let mut seen = BTreeSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            let next: Vec<u64> = match forward {
+                true => edges.get(&node).into_iter().flatten().map(|(to, _)| *to).collect(),
+                false => edges.iter().filter(|(_, to)| to.iter().any(|(to, _)| *to == node)).map(|(from, _)| *from).collect(),
+            };
+            stack.extend(next.into_iter().filter(|next| seen.insert(*next)));
+        }
+        seen
+    };
+    let mut assigned = BTreeSet::new();
+    let mut components = Vec::new();
+    for &node in &nodes {
+        if assigned.contains(&node) {
+            continue;
+        }
+        let component: BTreeSet<u64> = reach(node, true).intersection(&reach(node, false)).copied().collect();
+        assigned.extend(component.iter().copied());
+        if component.len() > 1 {
+            components.push(component);
+        }
+    }
+    components
+}
+fn find_cycle(
+    edges: &BTreeMap<u64, BTreeSet<(u64, bool)>>,
+    component: &BTreeSet<u64>,
+    allowed: impl Fn(&u64, &u64, bool) -> bool,
+) -> Option<Vec<u64>> {
+    for &start in component {
+        let mut parent: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut queue = std::collections::VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            for &(to, _) in edges.get(&node).into_iter().flatten().filter(|(to, anti)| allowed(&node, to, *anti)) {
+                if to == start {
+                    let mut cycle = vec![node];
+                    while let Some(&previous) = parent.get(cycle.last().unwrap()) {
+                        cycle.push(previous);
+                    }
+                    cycle.reverse();
+                    return Some(cycle);
+                }
+                if let std::collections::btree_map::Entry::Vacant(slot) = parent.entry(to) {
+                    slot.insert(node);
+                    queue.push_back(to);
+                }
+            }
+        }
+    }
+    None
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::time::Duration;
+
+    // Builds a history from (txn, op) pairs, one microsecond apart.
+    fn history(ops: Vec<(u64, TraceOp)>) -> Vec<TraceEvent> {
+        ops.into_iter()
+            .enumerate()
+            .map(|(i, (txn, op))| TraceEvent { at: Duration::from_micros(i as u64), took: Duration::ZERO, thread: 1, txn, op })
+            .collect()
+    }
+
+    fn get(key: &str, found: Option<&str>) -> TraceOp {
+        TraceOp::Get { key: key.to_owned(), found: found.map(str::to_owned) }
+    }
+
+    fn put(key: &str, value: &str) -> TraceOp {
+        TraceOp::Put { key: key.to_owned(), value: value.to_owned() }
+    }
+
+    fn commit(version: Version) -> TraceOp {
+        TraceOp::Commit { result: Ok(version) }
+    }
+
+    #[test]
+    fn serial_histories_are_serializable() {
+        let report = check(&history(vec![
+            (1, TraceOp::Begin { snapshot: 0 }),
+            (1, put("x", "1")),
+            (1, commit(1)),
+            (2, TraceOp::Begin { snapshot: 1 }),
+            (2, get("x", Some("1"))),
+            (2, put("x", "2")),
+            (2, get("x", Some("2"))),
+            (2, commit(2)),
+            (3, TraceOp::Begin { snapshot: 2 }),
+            (3, put("y", "1")),
+            (3, TraceOp::Rollback),
+            (4, TraceOp::Begin { snapshot: 2 }),
+            (4, put("y", "1")),
+            (4, TraceOp::Commit { result: Err("precondition failed".to_owned()) }),
+        ]));
+        assert_eq!(report.committed, 2);
+        assert_eq!(report.aborted, 2);
+        assert_eq!(report.reads, 1);
+        assert!(report.serializable());
+        assert!(report.snapshot_isolation());
+    }
+
+    #[test]
+    fn overlapping_writers_break_snapshot_isolation() {
+        let lost = check(&history(vec![
+            (1, TraceOp::Begin { snapshot: 0 }),
+            (2, TraceOp::Begin { snapshot: 0 }),
+            (1, get("x", None)),
+            (2, get("x", None)),
+            (1, put("x", "1")),
+            (2, put("x", "2")),
+            (1, commit(1)),
+            (2, commit(2)),
+        ]));
+        assert!(lost.anomalies.contains(&Anomaly::LostUpdate { key: "x".to_owned(), txns: (1, 2) }));
+        assert!(!lost.snapshot_isolation());
+        let blind = check(&history(vec![
+            (1, TraceOp::Begin { snapshot: 0 }),
+            (2, TraceOp::Begin { snapshot: 0 }),
+            (1, put("x", "1")),
+            (2, put("x", "2")),
+            (1, commit(1)),
+            (2, commit(2)),
+        ]));
+        assert_eq!(blind.anomalies, [Anomaly::ConcurrentWrite { key: "x".to_owned(), txns: (1, 2) }]);
+        assert!(!blind.snapshot_isolation());
+    }
+
+    #[test]
+    fn reads_must_match_the_latest_write_in_their_snapshot() {
+        let report = check(&history(vec![
+            (1, TraceOp::Begin { snapshot: 0 }),
+            (1, put("x", "1")),
+            (1, commit(1)),
+            (2, TraceOp::Begin { snapshot: 1 }),
+            (2, get("x", None)),
+            (2, commit(1)),
+        ]));
+        let expected = Anomaly::InconsistentRead { txn: 2, key: "x".to_owned(), expected: Some("1".to_owned()), observed: None };
+        assert_eq!(report.anomalies, [expected]);
+    }
+
+    #[test]
+    fn write_skew_is_snapshot_isolated_but_not_serializable() {
+        let report = check(&history(vec![
+            (1, TraceOp::Begin { snapshot: 0 }),
+            (2, TraceOp::Begin { snapshot: 0 }),
+            (1, get("x", None)),
+            (1, get("y", None)),
+            (2, get("x", None)),
+            (2, get("y", None)),
+            (1, put("x", "1")),
+            (2, put("y", "1")),
+            (1, commit(1)),
+            (2, commit(2)),
+        ]));
+        assert_eq!(report.anomalies, [Anomaly::WriteSkew { txns: vec![1, 2] }]);
+        assert!(report.snapshot_isolation());
+        assert!(!report.serializable());
+    }
+
+    #[test]
+    fn the_checker_reports_on_a_recorded_run() {
+        let dir = TempDir::new("isolation");
+        let store = KVStore::new();
+        let checker = Checker::start(&store, dir.path().join("run.trace")).unwrap();
+        let mut setup = store.begin();
+        setup.put("x".to_owned(), "0".to_owned()).unwrap();
+        setup.put("y".to_owned(), "0".to_owned()).unwrap();
+        setup.commit().unwrap();
+        let mut first = store.begin();
+        let mut second = store.begin();
+        for txn in [&mut first, &mut second] {
+            assert_eq!(txn.get("x").unwrap().as_deref(), Some("0"));
+            assert_eq!(txn.get("y").unwrap().as_deref(), Some("0"));
+        }
+        first.put("x".to_owned(), "1".to_owned()).unwrap();
+        second.put("y".to_owned(), "1".to_owned()).unwrap();
+        first.commit().unwrap();
+        second.commit().unwrap();
+        let mut after = store.begin();
+        assert_eq!(after.get("x").unwrap().as_deref(), Some("1"));
+        after.rollback().unwrap();
+        let report = checker.finish().unwrap();
+        assert_eq!((report.committed, report.aborted, report.reads), (3, 1, 4));
+        assert!(matches!(report.anomalies.as_slice(), [Anomaly::WriteSkew { txns }] if txns.len() == 2));
+        assert!(report.snapshot_isolation());
+    }
+}
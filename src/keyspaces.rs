@@ -0,0 +1,496 @@
+use crate::*;
+pub const META_KEYSPACE: &str = "__meta";
+#[cfg(feature = "zstd")]
+pub(crate) const DICTIONARY_SAMPLES: usize = 4096;
+pub(crate) fn purge_key(key: &str) -> String {
+    format!("{}{}purge{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, hex(&sha256(key.as_bytes())))
+}
+pub(crate) const PURGE_PENDING: &str = "pending";
+pub(crate) const PURGE_DONE: &str = "done";
+#[cfg(feature = "zstd")]
+pub(crate) fn dictionary_key(keyspace: &str) -> String {
+    format!("{}{}dictionary{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, keyspace)
+}
+pub(crate) fn check_user_key(key: &str) -> Result<()> {
+    match key.starts_with("__") {
+        true => Err(KVError::InvalidInput(format!("cannot write reserved key {:?}", key))),
+        false => Ok(()),
+    }
+}
+pub(crate) const CASE_KEYSPACE: &str = "__case";
+pub(crate) fn case_key(folded: &str) -> String {
+    format!("{}{}{}", CASE_KEYSPACE, KEYSPACE_SEPARATOR, folded)
+}
+pub(crate) fn append_only_key(keyspace: &str) -> String {
+    format!("{}{}append_only{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, keyspace)
+}
+pub(crate) fn is_live(state: &mvcc::State, key: &str) -> bool {
+    matches!(state.map.get(key).and_then(|versions| versions.values().next_back()), Some(Some(_)))
+}
+pub(crate) fn is_append_only(state: &mvcc::State, writes: &Writes, keyspace: &str) -> bool {
+    let marker = append_only_key(keyspace);
+    matches!(writes.get(&marker), Some(Some(_))) || is_live(state, &marker)
+}
+pub(crate) fn hold_key(label: &str, prefix: &str) -> String {
+    format!("{}{}hold{}{}{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, label, KEYSPACE_SEPARATOR, prefix)
+}
+pub(crate) fn held_prefixes(state: &mvcc::State) -> Vec<String> {
+    let holds = hold_key("", "");
+    let holds = &holds[..holds.len() - 1];
+    state
+        .map
+        .range(prefix_range(holds))
+        .filter(|(key, _)| is_live(state, key))
+        .filter_map(|(key, _)| key[holds.len()..].split_once(KEYSPACE_SEPARATOR).map(|(_, prefix)| prefix.to_owned()))
+        .collect()
+}
+pub(crate) fn is_held(held: &[String], key: &str) -> bool {
+    let case = case_key("");
+    let key = key.strip_prefix(case.as_str()).unwrap_or(key);
+    held.iter().any(|prefix| key.starts_with(prefix.as_str()))
+}
+pub(crate) fn check_holds(writes: &Writes, state: &mvcc::State) -> Result<()> {
+    let held = held_prefixes(state);
+    if held.is_empty() {
+        return Ok(());
+    }
+    for (key, value) in writes {
+        if value.is_none() && is_live(state, key) && is_held(&held, key) {
+            return Err(KVError::InvalidInput(format!("{} is under legal hold", key)));
+        }
+    }
+    Ok(())
+}
+pub(crate) fn check_append_only(writes: &Writes, state: &mvcc::State) -> Result<()> {
+    let markers = append_only_key("");
+    if state.map.range(prefix_range(&markers)).next().is_none() && !writes.keys().any(|key| key.starts_with(&markers)) {
+        return Ok(());
+    }
+    for key in writes.keys() {
+        let protected = key.starts_with(&markers) || is_append_only(state, writes, keyspace_of(key));
+        if protected && is_live(state, key) {
+            return Err(KVError::InvalidInput(format!("{} already exists in append-only keyspace {:?}", key, keyspace_of(key))));
+        }
+    }
+    Ok(())
+}
+pub(crate) const TTL_KEYSPACE: &str = "__ttl";
+pub(crate) fn ttl_key(key: &str) -> String {
+    format!("{}{}key{}{}", TTL_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, key)
+}
+pub(crate) fn ttl_entry(deadline: &str, key: &str) -> String {
+    format!("{}{}at{}{}{}{}", TTL_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, deadline, KEYSPACE_SEPARATOR, key)
+}
+pub(crate) const IDEMPOTENCY_KEYSPACE: &str = "__idem";
+pub(crate) const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(3600);
+pub(crate) fn check_preconditions(policies: &Policies, expected: Vec<(String, Precondition)>, state: &mvcc::State) -> Result<()> {
+    let broken: Vec<BrokenPrecondition> = expected
+        .into_iter()
+        .filter_map(|(key, expected)| {
+            let latest = state.map.get(&key).and_then(|versions| versions.latest());
+            let (version, value) = latest.map_or((0, None), |(version, value)| (*version, value.as_deref().map(str::to_owned)));
+            let holds = match &expected {
+                Precondition::Absent => value.is_none(),
+                Precondition::Equals(wanted) => value.as_ref() == Some(wanted),
+                Precondition::VersionIs(wanted) => version == *wanted,
+            };
+            (!holds).then(|| BrokenPrecondition { key, expected, value, version, policies: policies.clone() })
+        })
+        .collect();
+    match broken.is_empty() {
+        true => Ok(()),
+        false => Err(KVError::PreconditionFailed(broken)),
+    }
+}
+pub(crate) fn idempotency_key(token: &str) -> String {
+    format!("{}{}key{}{}", IDEMPOTENCY_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, token)
+}
+pub(crate) fn idempotency_entry(when: u64, token: &str) -> String {
+    format!("{}{}at{}{:020}{}{}", IDEMPOTENCY_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, when, KEYSPACE_SEPARATOR, token)
+}
+pub(crate) fn expire_idempotency_keys(writes: &mut Writes, state: &mvcc::State, now: u64) {
+    let entries = idempotency_entry(0, "");
+    let prefix = &entries[..entries.len() - 21];
+    let cutoff = now.saturating_sub(IDEMPOTENCY_WINDOW.as_millis() as u64);
+    let live = state.map.range(prefix_range(prefix)).filter(|(_, versions)| matches!(versions.latest(), Some((_, Some(_)))));
+    for (entry, _) in live {
+        let Some((when, token)) = entry[prefix.len()..].split_once(KEYSPACE_SEPARATOR) else {
+            continue;
+        };
+        if when.parse::<u64>().map_or(true, |when| when >= cutoff) {
+            break;
+        }
+        writes.insert(entry.to_string(), None);
+        writes.insert(idempotency_key(token), None);
+    }
+}
+pub struct CommitView<'a> {
+    pub(crate) version: Version,
+    pub(crate) writes: &'a Writes,
+    pub(crate) state: &'a mvcc::State,
+}
+impl CommitView<'_> {
+    pub fn version(&self) -> Version {
+        self.version
+    }
+    pub fn writes(&self, keyspace: &str) -> Vec<(&str, Option<&str>)> {
+        self.writes.iter().filter(|(key, _)| keyspace_of(key) == keyspace).map(|(key, value)| (key.as_str(), value.as_deref())).collect()
+    }
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => latest_value(self.state, key),
+        }
+    }
+    pub fn previous(&self, key: &str) -> Option<String> {
+        latest_value(self.state, key)
+    }
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        let mut rows: BTreeMap<String, Option<String>> = self
+            .state
+            .map
+            .range(prefix_range(prefix))
+            .map(|(key, versions)| (key.to_string(), versions.latest().and_then(|(_, value)| value.as_deref().map(str::to_owned))))
+            .collect();
+        rows.extend(self.writes.range(prefix_range(prefix)).map(|(key, value)| (key.clone(), value.clone())));
+        rows.into_iter().filter_map(|(key, value)| Some((key, value?))).collect()
+    }
+}
+pub(crate) fn live_bytes(state: &mvcc::State, keyspace: &str) -> usize {
+    let prefix = format!("{}{}", keyspace, KEYSPACE_SEPARATOR);
+    state.map.range(prefix_range(&prefix)).filter_map(|(key, versions)| Some(key.len() + versions.latest()?.1.as_ref()?.len())).sum()
+}
+pub(crate) fn latest_value(state: &mvcc::State, key: &str) -> Option<String> {
+    state.map.get(key).and_then(|versions| versions.latest()).and_then(|(_, value)| value.as_deref().map(str::to_owned))
+}
+pub(crate) fn maintain_ttls(writes: &mut Writes, state: &mvcc::State) {
+    let (markers, entries) = (ttl_key(""), ttl_entry("", ""));
+    let entries = &entries[..entries.len() - 1];
+    if state.map.range(prefix_range(&markers)).next().is_none() {
+        return;
+    }
+    let held = held_prefixes(state);
+    let mut extra = Writes::new();
+    for (key, value) in writes.iter() {
+        if let Some(due) = key.strip_prefix(entries).filter(|_| value.is_none() && is_live(state, key)) {
+            let (deadline, target) = due.split_once(KEYSPACE_SEPARATOR).unwrap_or((due, ""));
+            let marker = ttl_key(target);
+            let protected = held.iter().any(|prefix| target.starts_with(prefix.as_str()))
+                || is_append_only(state, writes, keyspace_of(target));
+            if writes.contains_key(&marker) || protected || latest_value(state, &marker).as_deref() != Some(deadline) {
+                continue;
+            }
+            extra.insert(target.to_owned(), None);
+            extra.insert(marker, None);
+            if is_live(state, &case_key(target)) {
+                extra.insert(case_key(target), None);
+            }
+        } else if !keyspace_of(key).starts_with("__") {
+            let marker = ttl_key(key);
+            if writes.contains_key(&marker) {
+                continue;
+            }
+            if let Some(deadline) = latest_value(state, &marker) {
+                extra.insert(ttl_entry(&deadline, key), None);
+                extra.insert(marker, None);
+            }
+        }
+    }
+    writes.extend(extra);
+}
+impl Transaction {
+    pub fn put_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.put(key.clone(), value)?;
+        self.expire(&key, ttl)
+    }
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<()> {
+        let deadline = self.store.now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_deadline(key, Some(deadline))
+    }
+    pub fn persist(&mut self, key: &str) -> Result<()> {
+        self.set_deadline(key, None)
+    }
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        if let Some((keyspace, at)) = self.split_key(key) {
+            return self.attached[&keyspace].ttl(&key[at..]);
+        }
+        let key = self.store.fold_key(key).unwrap_or_else(|| key.to_owned());
+        let deadline = self.buffer.get_with(&ttl_key(&key), |key, version| self.store.read_at(key, version))?;
+        Ok(deadline
+            .and_then(|deadline| deadline.parse::<u64>().ok())
+            .map(|deadline| Duration::from_millis(deadline.saturating_sub(self.store.now_millis()))))
+    }
+    pub(crate) fn set_deadline(&mut self, key: &str, deadline: Option<u64>) -> Result<()> {
+        if let Some((keyspace, at)) = self.split_key(key) {
+            return self.attached.get_mut(&keyspace).unwrap().set_deadline(&key[at..], deadline);
+        }
+        if keyspace_of(key).starts_with("__") {
+            return Err(KVError::InvalidInput(format!("cannot expire reserved key {:?}", key)));
+        }
+        let key = self.store.fold_key(key).unwrap_or_else(|| key.to_owned());
+        let marker = ttl_key(&key);
+        if let Some(old) = self.buffer.get_with(&marker, |key, version| self.store.read_at(key, version))? {
+            self.buffer.delete(ttl_entry(&old, &key))?;
+        }
+        match deadline {
+            Some(deadline) => {
+                let deadline = format!("{:020}", deadline);
+                self.buffer.put(ttl_entry(&deadline, &key), String::new())?;
+                self.buffer.put(marker, deadline)?;
+            }
+            None => self.buffer.delete(marker)?,
+        }
+        Ok(())
+    }
+}
+pub(crate) const RENAME_KEYSPACE: &str = "__rename";
+pub(crate) fn rename_key(key: &str) -> String {
+    format!("{}{}from{}{}", RENAME_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, key)
+}
+impl Transaction {
+    pub fn rename(&mut self, old_key: &str, new_key: &str) -> Result<bool> {
+        match (self.split_key(old_key), self.split_key(new_key)) {
+            (Some((keyspace, at)), Some((target, to))) if keyspace == target => {
+                return self.attached.get_mut(&keyspace).unwrap().rename(&old_key[at..], &new_key[to..]);
+            }
+            (None, None) => {}
+            _ => return Err(KVError::InvalidInput(format!("cannot rename {:?} to {:?} across attached stores", old_key, new_key))),
+        }
+        if [old_key, new_key].iter().any(|key| keyspace_of(key).starts_with("__")) {
+            return Err(KVError::InvalidInput(format!("cannot rename reserved key {:?} to {:?}", old_key, new_key)));
+        }
+        let Some(value) = self.get(old_key)? else {
+            return Ok(false);
+        };
+        if old_key == new_key {
+            return Ok(true);
+        }
+        let old = self.store.fold_key(old_key).unwrap_or_else(|| old_key.to_owned());
+        let deadline = self.buffer.get_with(&ttl_key(&old), |key, version| self.store.read_at(key, version))?;
+        self.delete(old_key.to_owned())?;
+        self.put(new_key.to_owned(), value)?;
+        if let Some(deadline) = deadline.and_then(|deadline| deadline.parse().ok()) {
+            self.persist(old_key)?;
+            self.set_deadline(new_key, Some(deadline))?;
+        }
+        let new = self.store.fold_key(new_key).unwrap_or_else(|| new_key.to_owned());
+        self.buffer.put(rename_key(&new), old)?;
+        Ok(true)
+    }
+    pub fn move_to(&mut self, key: &str, keyspace: &str) -> Result<bool> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) {
+            return Err(KVError::InvalidInput(format!("invalid keyspace name {:?}", keyspace)));
+        }
+        let name = key.split_once(KEYSPACE_SEPARATOR).map_or(key, |(_, name)| name);
+        self.rename(key, &format!("{}{}{}", keyspace, KEYSPACE_SEPARATOR, name))
+    }
+}
+impl KVStore {
+    pub fn lineage(&self, key: &str) -> Vec<(String, History)> {
+        let mut lineage = Vec::new();
+        let (mut key, mut until) = (key.to_owned(), Version::MAX);
+        loop {
+            let renamed = self
+                .history(&rename_key(&key))
+                .into_iter()
+                .rev()
+                .find_map(|(version, from)| Some((version, from?)).filter(|&(version, _)| version <= until));
+            let since = renamed.as_ref().map_or(0, |&(version, _)| version);
+            let history = self.history(&key).into_iter().filter(|&(version, _)| version >= since && version <= until).collect();
+            lineage.push((key, history));
+            match renamed {
+                Some((version, from)) if version > 0 => (key, until) = (from, version - 1),
+                _ => break,
+            }
+        }
+        lineage.reverse();
+        lineage
+    }
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.writable()?;
+        let entries = ttl_entry("", "");
+        let start = Bound::Included(entries[..entries.len() - 1].to_owned());
+        let end = Bound::Excluded(ttl_entry(&format!("{:020}", self.now_millis().saturating_add(1)), ""));
+        let mut swept = 0;
+        loop {
+            let due: Writes = self.engine.with(|state| {
+                state
+                    .map
+                    .range((start.clone(), end.clone()))
+                    .filter(|(key, _)| is_live(state, key))
+                    .take(SCAN_CHUNK)
+                    .map(|(key, _)| (key.to_string(), None))
+                    .collect()
+            });
+            let count = due.len();
+            if count == 0 {
+                return Ok(swept);
+            }
+            match self.proposer.get() {
+                Some(proposer) => proposer.propose(due, CommitMeta::default())?,
+                None => self.apply(due, None, None, None)?,
+            };
+            swept += count;
+            if count < SCAN_CHUNK {
+                return Ok(swept);
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn put(store: &Arc<KVStore>, key: &str, value: &str) -> Result<Version> {
+        let mut tx = store.begin();
+        tx.put(key.to_owned(), value.to_owned())?;
+        tx.commit()
+    }
+    #[test]
+    fn append_only_keyspaces_refuse_overwrites_and_deletes_after_a_reopen() {
+        let dir = TempDir::new("worm");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            put(&store, "drafts:1", "v1").unwrap();
+            store.make_append_only("ledger").unwrap();
+            store.make_append_only("ledger").unwrap();
+            assert!(matches!(store.make_append_only("bad:name"), Err(KVError::InvalidInput(_))));
+            put(&store, "ledger:1", "credit 10").unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.append_only_keyspaces(), ["ledger"]);
+        assert!(matches!(put(&store, "ledger:1", "credit 1000"), Err(KVError::InvalidInput(_))));
+        let mut tx = store.begin();
+        tx.delete("ledger:1".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::InvalidInput(_))));
+        let mut tx = store.begin();
+        tx.put_internal(append_only_key("ledger"), "off".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::InvalidInput(_))));
+        put(&store, "ledger:2", "debit 5").unwrap();
+        put(&store, "drafts:1", "v2").unwrap();
+        assert_eq!(store.begin().get("ledger:1").unwrap().as_deref(), Some("credit 10"));
+    }
+    #[test]
+    fn commit_validators_veto_writes_to_their_keyspace() {
+        let store = KVStore::new();
+        put(&store, "accounts:a", "60").unwrap();
+        put(&store, "accounts:b", "40").unwrap();
+        store.set_commit_validator("accounts", |view| {
+            let total: i64 = view.scan_prefix("accounts:").iter().map(|(_, balance)| balance.parse::<i64>().unwrap_or(-1)).sum();
+            match total == 100 && view.writes("accounts").iter().all(|(_, value)| value.is_some()) {
+                true => Ok(()),
+                false => Err(io::Error::other(format!("total is {} at v{}", total, view.version()))),
+            }
+        });
+        let mut tx = store.begin();
+        tx.put("accounts:a".to_owned(), "50".to_owned()).unwrap();
+        tx.put("accounts:b".to_owned(), "50".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let rejected = put(&store, "accounts:a", "70");
+        match rejected {
+            Err(KVError::Rejected { keyspace, reason }) => {
+                assert_eq!(keyspace, "accounts");
+                assert!(reason.to_string().starts_with("total is 120"), "{}", reason);
+            }
+            other => panic!("expected a veto, got {:?}", other),
+        }
+        let mut tx = store.begin();
+        tx.delete("accounts:b".to_owned()).unwrap();
+        tx.put("accounts:a".to_owned(), "100".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::Rejected { .. })));
+        put(&store, "other:x", "anything").unwrap();
+        let snapshot = store.snapshot();
+        assert_eq!((snapshot.get("accounts:a").as_deref(), snapshot.get("accounts:b").as_deref()), (Some("50"), Some("50")));
+        assert!(store.clear_commit_validator("accounts"));
+        assert!(!store.clear_commit_validator("accounts"));
+        put(&store, "accounts:a", "70").unwrap();
+    }
+    #[test]
+    fn the_sweeper_pops_due_keys_from_the_expiry_index() {
+        let dir = TempDir::new("ttl");
+        let clock = MockClock::new(1_000_000);
+        let ttl = |store: &Arc<KVStore>, key: &str| store.begin().ttl(key).unwrap();
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_clock(clock.clone());
+            let mut tx = store.begin();
+            tx.put_with_ttl("session:a".to_owned(), "1".to_owned(), Duration::from_secs(10)).unwrap();
+            tx.put_with_ttl("session:b".to_owned(), "2".to_owned(), Duration::from_secs(10)).unwrap();
+            tx.put_with_ttl("session:c".to_owned(), "3".to_owned(), Duration::from_secs(60)).unwrap();
+            tx.put_with_ttl("session:d".to_owned(), "4".to_owned(), Duration::from_secs(10)).unwrap();
+            tx.put("session:e".to_owned(), "5".to_owned()).unwrap();
+            tx.commit().unwrap();
+            let mut tx = store.begin();
+            tx.persist("session:b").unwrap();
+            tx.expire("session:c", Duration::from_secs(5)).unwrap();
+            tx.put("session:d".to_owned(), "overwritten".to_owned()).unwrap();
+            assert!(matches!(tx.expire("__meta:x", Duration::from_secs(1)), Err(KVError::InvalidInput(_))));
+            tx.commit().unwrap();
+            assert_eq!(ttl(&store, "session:a"), Some(Duration::from_secs(10)));
+            assert_eq!(ttl(&store, "session:b"), None);
+            assert_eq!(ttl(&store, "session:c"), Some(Duration::from_secs(5)));
+            assert_eq!(ttl(&store, "session:d"), None);
+            assert_eq!(store.sweep_expired().unwrap(), 0);
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        store.set_clock(clock.clone());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(store.sweep_expired().unwrap(), 1);
+        assert_eq!(store.begin().get("session:c").unwrap(), None);
+        assert_eq!(ttl(&store, "session:a"), Some(Duration::from_secs(5)));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(store.sweep_expired().unwrap(), 1);
+        assert_eq!(store.sweep_expired().unwrap(), 0);
+        let rows: Vec<String> = store.snapshot().scan("session:".to_owned().."session;".to_owned()).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(rows, ["session:b", "session:d", "session:e"]);
+        assert_eq!(ttl(&store, "session:a"), None);
+    }
+    #[test]
+    fn renames_move_values_and_ttls_and_link_their_history_across_a_reopen() {
+        let dir = TempDir::new("rename");
+        let clock = MockClock::new(1_000_000);
+        let (v1, v2, v3, v4, v5);
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_clock(clock.clone());
+            let changes = store.subscribe();
+            v1 = put(&store, "drafts:x", "1").unwrap();
+            let mut tx = store.begin();
+            tx.put_with_ttl("drafts:x".to_owned(), "2".to_owned(), Duration::from_secs(30)).unwrap();
+            v2 = tx.commit().unwrap();
+            let reader = store.snapshot();
+            let mut tx = store.begin();
+            assert!(!tx.rename("drafts:missing", "drafts:other").unwrap());
+            assert!(tx.rename("drafts:x", "drafts:x").unwrap());
+            assert!(matches!(tx.rename("drafts:x", "__meta:x"), Err(KVError::InvalidInput(_))));
+            assert!(tx.rename("drafts:x", "drafts:y").unwrap());
+            assert_eq!(tx.get("drafts:x").unwrap(), None);
+            assert_eq!(tx.get("drafts:y").unwrap().as_deref(), Some("2"));
+            v3 = tx.commit().unwrap();
+            assert_eq!(reader.get("drafts:x").as_deref(), Some("2"));
+            assert_eq!(reader.get("drafts:y"), None);
+            assert_eq!(store.begin().ttl("drafts:x").unwrap(), None);
+            assert_eq!(store.begin().ttl("drafts:y").unwrap(), Some(Duration::from_secs(30)));
+            v4 = put(&store, "drafts:y", "3").unwrap();
+            let mut tx = store.begin();
+            assert!(matches!(tx.move_to("drafts:y", "bad:name"), Err(KVError::InvalidInput(_))));
+            assert!(tx.move_to("drafts:y", "published").unwrap());
+            v5 = tx.commit().unwrap();
+            let renames: Vec<Vec<(String, String)>> = changes.try_iter().map(|batch| batch.renames()).collect();
+            let expected = [vec![], vec![], vec![("drafts:x".to_owned(), "drafts:y".to_owned())], vec![], vec![("drafts:y".to_owned(), "published:y".to_owned())]];
+            assert_eq!(renames, expected);
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.begin().get("published:y").unwrap().as_deref(), Some("3"));
+        let some = |value: &str| Some(value.to_owned());
+        let lineage = store.lineage("published:y");
+        assert_eq!(
+            lineage,
+            [
+                ("drafts:x".to_owned(), vec![(v1, some("1")), (v2, some("2"))]),
+                ("drafts:y".to_owned(), vec![(v3, some("2")), (v4, some("3"))]),
+                ("published:y".to_owned(), vec![(v5, some("3"))]),
+            ]
+        );
+        assert_eq!(store.lineage("drafts:z"), [("drafts:z".to_owned(), vec![])]);
+    }
+}
@@ -0,0 +1,1013 @@
+use crate::*;
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+pub(crate) fn parse_csv(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(KVError::InvalidInput("csv: unterminated quoted field".to_owned()));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+fn commit_millis(store: &KVStore, version: Version) -> Option<u64> {
+    store.commit_time(version).map(|time| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64))
+}
+pub(crate) fn export_store(store: &Arc<KVStore>, format: &str, history: bool, out: &mut dyn Write) -> Result<()> {
+    match (format, history) {
+        ("json", false) => {
+            let rows = store.begin().scan(..)?;
+            writeln!(out, "[")?;
+            for (i, (key, value)) in rows.iter().enumerate() {
+                let sep = if i + 1 < rows.len() { "," } else { "" };
+                writeln!(out, "  {{\"key\":{},\"value\":{}}}{}", json_escape(key), json_escape(value), sep)?;
+            }
+            writeln!(out, "]")?;
+        }
+        ("json", true) => {
+            let keys = store.all_history();
+            writeln!(out, "[")?;
+            for (i, (key, versions)) in keys.iter().enumerate() {
+                let versions: Vec<String> = versions
+                    .iter()
+                    .map(|(version, value)| {
+                        let value = value.as_deref().map(json_escape).unwrap_or_else(|| "null".to_owned());
+                        match commit_millis(store, *version) {
+                            Some(millis) => format!("{{\"version\":{},\"timestamp\":{},\"value\":{}}}", version, millis, value),
+                            None => format!("{{\"version\":{},\"value\":{}}}", version, value),
+                        }
+                    })
+                    .collect();
+                let sep = if i + 1 < keys.len() { "," } else { "" };
+                writeln!(out, "  {{\"key\":{},\"versions\":[{}]}}{}", json_escape(key), versions.join(","), sep)?;
+            }
+            writeln!(out, "]")?;
+        }
+        ("csv", false) => {
+            writeln!(out, "key,value")?;
+            for (key, value) in store.begin().scan(..)? {
+                writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?;
+            }
+        }
+        ("csv", true) => {
+            writeln!(out, "key,version,deleted,value,timestamp")?;
+            for (key, versions) in store.all_history() {
+                for (version, value) in versions {
+                    let (deleted, value) = match &value {
+                        Some(v) => ("false", v.as_str()),
+                        None => ("true", ""),
+                    };
+                    let millis = commit_millis(store, version).map_or_else(String::new, |millis| millis.to_string());
+                    writeln!(out, "{},{},{},{},{}", csv_field(&key), version, deleted, csv_field(value), millis)?;
+                }
+            }
+        }
+        (other, _) => return Err(KVError::InvalidInput(format!("unknown format {:?}", other))),
+    }
+    Ok(())
+}
+pub(crate) fn import_store(store: &Arc<KVStore>, format: &str, history: bool, input: &str) -> Result<usize> {
+    let invalid = |msg: &str| KVError::InvalidInput(msg.to_owned());
+    let mut batches: BTreeMap<Version, (Option<u64>, Writes)> = BTreeMap::new();
+    let mut live: BTreeMap<String, Option<String>> = BTreeMap::new();
+    match format {
+        "json" => {
+            let Json::Array(items) = Json::parse(input)? else {
+                return Err(invalid("json: expected a top-level array"));
+            };
+            for item in &items {
+                let key = item.get("key").and_then(Json::as_str).ok_or_else(|| invalid("json: entry without a key"))?;
+                let value_of = |v: Option<&Json>| match v {
+                    Some(Json::Str(s)) => Ok(Some(s.clone())),
+                    Some(Json::Null) => Ok(None),
+                    _ => Err(invalid("json: value must be a string or null")),
+                };
+                if history {
+                    let Some(Json::Array(versions)) = item.get("versions") else {
+                        return Err(invalid("json: entry without versions"));
+                    };
+                    for entry in versions {
+                        let version = entry.get("version").and_then(Json::as_u64).ok_or_else(|| invalid("json: bad version"))?;
+                        let timestamp = match entry.get("timestamp") {
+                            Some(t) => Some(t.as_u64().ok_or_else(|| invalid("json: bad timestamp"))?),
+                            None => None,
+                        };
+                        let (committed, writes) = batches.entry(version).or_default();
+                        *committed = committed.or(timestamp);
+                        writes.insert(key.to_owned(), value_of(entry.get("value"))?);
+                    }
+                } else {
+                    live.insert(key.to_owned(), value_of(item.get("value"))?);
+                }
+            }
+        }
+        "csv" => {
+            let rows = parse_csv(input)?;
+            for row in rows.iter().skip(1) {
+                match (history, row.as_slice()) {
+                    (false, [key, value]) => {
+                        live.insert(key.clone(), Some(value.clone()));
+                    }
+                    (true, [key, version, deleted, value, timestamp @ ..]) if timestamp.len() <= 1 => {
+                        let version = version.parse().map_err(|_| invalid("csv: bad version"))?;
+                        let value = if deleted == "true" { None } else { Some(value.clone()) };
+                        let timestamp = match timestamp.first().filter(|t| !t.is_empty()) {
+                            Some(t) => Some(t.parse().map_err(|_| invalid("csv: bad timestamp"))?),
+                            None => None,
+                        };
+                        let (committed, writes) = batches.entry(version).or_default();
+                        *committed = committed.or(timestamp);
+                        writes.insert(key.clone(), value);
+                    }
+                    _ => return Err(invalid("csv: wrong number of columns")),
+                }
+            }
+        }
+        other => return Err(KVError::InvalidInput(format!("unknown format {:?}", other))),
+    }
+    if history {
+        let count = batches.values().map(|(_, writes)| writes.len()).sum();
+        for (version, (committed, writes)) in batches {
+            store.import_version(version, committed.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)), writes)?;
+        }
+        return Ok(count);
+    }
+    let count = live.len();
+    let mut tx = store.begin();
+    for (key, value) in live {
+        match value {
+            Some(v) => tx.put_internal(key, v)?,
+            None => tx.delete_internal(key)?,
+        }
+    }
+    tx.commit()?;
+    Ok(count)
+}
+pub(crate) fn shell_help() {
+    println!("commands:");
+    println!("  open <path>              open (or create) a store directory");
+    println!("  get <key>                read a key");
+    println!("  put <key> <value>        write a key (value is the rest of the line)");
+    println!("  del <key>                delete a key");
+    println!("  scan [from] [to]         list live keys in [from, to)");
+    println!("  match <pattern>          list live keys matching a * / ? glob");
+    println!("  begin | commit | rollback  explicit transaction");
+    println!("  history <key>            every retained version of a key");
+    println!("  stats                    store statistics");
+    println!("  hot [n]                  most frequently accessed keys");
+    println!("  slow [ms]                show slow transactions, or set the threshold");
+    println!("  checkpoint               write a snapshot and start a new wal segment");
+    println!("  export <file>            stream a compressed snapshot of the store to a file");
+    println!("  import <file> [mode]     load an export; mode is replace (default), keep-newer or error");
+    println!("  quit");
+}
+pub(crate) struct Shell {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) tx: Option<Transaction>,
+}
+impl Shell {
+    pub(crate) fn run_line(&mut self, line: &str) -> Result<bool> {
+        let line = line.trim();
+        let (cmd, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match cmd {
+            "" => {}
+            "help" => shell_help(),
+            "quit" | "exit" => return Ok(false),
+            "open" => {
+                if self.tx.is_some() {
+                    println!("commit or rollback the open transaction first");
+                } else if rest.is_empty() {
+                    println!("usage: open <path>");
+                } else {
+                    self.store.close()?;
+                    self.store = KVStore::open(rest)?;
+                    println!("opened {} at version {}", rest, self.store.current_version());
+                }
+            }
+            "begin" => {
+                if self.tx.is_some() {
+                    println!("transaction already open");
+                } else {
+                    let tx = self.store.begin();
+                    println!("snapshot at version {}", tx.buffer.snapshot_version());
+                    self.tx = Some(tx);
+                }
+            }
+            "commit" => match self.tx.take() {
+                Some(tx) => println!("committed, version {}", tx.commit()?),
+                None => println!("no open transaction"),
+            },
+            "rollback" => match self.tx.take() {
+                Some(mut tx) => tx.rollback()?,
+                None => println!("no open transaction"),
+            },
+            "get" => {
+                let value = self.with_tx(|tx| tx.get(rest))?;
+                match value {
+                    Some(v) => println!("{}", v),
+                    None => println!("(nil)"),
+                }
+            }
+            "put" => match rest.split_once(char::is_whitespace) {
+                Some((key, value)) => {
+                    let (key, value) = (key.to_owned(), value.trim_start().to_owned());
+                    self.with_tx(move |tx| tx.put(key, value))?;
+                }
+                None => println!("usage: put <key> <value>"),
+            },
+            "del" => {
+                let key = rest.to_owned();
+                self.with_tx(move |tx| tx.delete(key))?;
+            }
+            "scan" => {
+                let mut bounds = rest.split_whitespace().map(str::to_owned);
+                let from = bounds.next().unwrap_or_default();
+                let rows = match bounds.next() {
+                    Some(to) => self.with_tx(|tx| tx.scan(from..to))?,
+                    None => self.with_tx(|tx| tx.scan(from..))?,
+                };
+                for (key, value) in &rows {
+                    println!("{} = {}", key, value);
+                }
+                println!("({} keys)", rows.len());
+            }
+            "match" => {
+                let rows = self.with_tx(|tx| tx.scan_match(rest))?;
+                for (key, value) in &rows {
+                    println!("{} = {}", key, value);
+                }
+                println!("({} keys)", rows.len());
+            }
+            "history" => {
+                for (version, value) in self.store.history(rest) {
+                    let time = match self.store.commit_time(version) {
+                        Some(time) => {
+                            let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                            let (y, mo, d, h, mi, s) = utc_datetime(secs);
+                            format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, mo, d, h, mi, s)
+                        }
+                        None => "-".repeat(19),
+                    };
+                    match value {
+                        Some(v) => println!("{:>8}  {}  {}", version, time, v),
+                        None => println!("{:>8}  {}  (deleted)", version, time),
+                    }
+                }
+            }
+            "checkpoint" => println!("snapshot written at version {}", self.store.checkpoint()?),
+            "export" => {
+                let report = self.store.export_compressed(io::BufWriter::new(File::create(rest)?))?;
+                println!("exported {} keys at version {} ({} bytes)", report.entries, report.version, report.bytes);
+            }
+            "import" => {
+                let (path, mode) = rest.split_once(char::is_whitespace).unwrap_or((rest, "replace"));
+                let strategy = match mode.trim() {
+                    "replace" => MergeStrategy::Replace,
+                    "keep-newer" => MergeStrategy::KeepNewer,
+                    "error" => MergeStrategy::ErrorOnConflict,
+                    other => return Err(KVError::InvalidInput(format!("unknown import mode {:?}", other))),
+                };
+                let report = self.store.import(io::BufReader::new(File::open(path)?), strategy)?;
+                println!("imported {} of {} keys ({} unchanged)", report.written, report.entries, report.skipped);
+            }
+            "slow" if !rest.is_empty() => {
+                let millis: u64 = rest.parse().map_err(|_| KVError::InvalidInput(format!("invalid threshold {:?}", rest)))?;
+                self.store.set_slow_threshold(Some(Duration::from_millis(millis)));
+            }
+            "slow" => {
+                for slow in self.store.slow_log() {
+                    let version = slow.version.map_or_else(|| "failed".to_owned(), |version| version.to_string());
+                    println!(
+                        "{} txn={} {:>10} {:?} keys={} lock_wait={:?} fsync={:?}",
+                        slow.at, slow.txn, version, slow.duration, slow.keys, slow.lock_wait, slow.fsync
+                    );
+                }
+            }
+            "hot" => {
+                let top_n = match rest {
+                    "" => 10,
+                    n => n.parse().map_err(|_| KVError::InvalidInput(format!("invalid count {:?}", n)))?,
+                };
+                for hot in self.store.hot_keys(top_n) {
+                    println!("{:<32} reads={} writes={}", hot.key, hot.reads, hot.writes);
+                }
+            }
+            "stats" => {
+                let stats = self.store.stats();
+                println!("live keys:       {}", stats.keys);
+                println!("versions:        {}", stats.versions);
+                println!("tombstones:      {}", stats.tombstones);
+                println!("approx bytes:    {}", stats.approx_bytes);
+                if let Some(limit) = self.store.memory_limit() {
+                    println!("memory limit:    {}", limit);
+                }
+                println!("current version: {}", stats.current_version);
+                println!("gc watermark:    {}", stats.gc_watermark);
+                println!("open txns:       {}", stats.open_transactions);
+                if let Some(age) = stats.oldest_transaction {
+                    println!("oldest txn:      {:?}", age);
+                }
+                for (keyspace, count) in &stats.keyspaces {
+                    println!("  {:<20} {}", if keyspace.is_empty() { "(default)" } else { keyspace }, count);
+                }
+            }
+            _ => println!("unknown command {:?}, try `help`", cmd),
+        }
+        Ok(true)
+    }
+    pub(crate) fn with_tx<T>(&mut self, f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+        match &mut self.tx {
+            Some(tx) => f(tx),
+            None => {
+                let mut tx = self.store.begin();
+                let out = f(&mut tx)?;
+                tx.commit()?;
+                Ok(out)
+            }
+        }
+    }
+}
+pub(crate) fn run_shell(path: Option<&str>) -> Result<()> {
+    let store = match path {
+        Some(p) => KVStore::open(p)?,
+        None => KVStore::new(),
+    };
+    let mut shell = Shell { store, tx: None };
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("{}> ", if shell.tx.is_some() { "kv*" } else { "kv" });
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        match shell.run_line(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+    Ok(())
+}
+pub(crate) struct CliArgs {
+    pub(crate) positional: Vec<String>,
+    pub(crate) flags: BTreeMap<String, String>,
+}
+impl CliArgs {
+    pub(crate) fn parse(args: &[String], valued: &[&str]) -> Self {
+        let mut positional = Vec::new();
+        let mut flags = BTreeMap::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if valued.contains(&arg.as_str()) {
+                flags.insert(arg.clone(), iter.next().cloned().unwrap_or_default());
+            } else if arg.starts_with("--") {
+                flags.insert(arg.clone(), String::new());
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Self { positional, flags }
+    }
+    pub(crate) fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
+    }
+    pub(crate) fn has(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+    pub(crate) fn number<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T> {
+        match self.flag(name) {
+            Some(value) => value.parse().map_err(|_| KVError::InvalidInput(format!("{} expects a number, got {:?}", name, value))),
+            None => Ok(default),
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyDistribution {
+    Uniform,
+    Zipfian,
+    Sequential,
+}
+pub(crate) struct BenchConfig {
+    pub(crate) threads: usize,
+    pub(crate) ops: u64,
+    pub(crate) duration: Option<Duration>,
+    pub(crate) reads: u32,
+    pub(crate) keys: u64,
+    pub(crate) distribution: KeyDistribution,
+    pub(crate) value_size: usize,
+    pub(crate) txn_size: usize,
+}
+impl BenchConfig {
+    pub(crate) fn from_cli(cli: &CliArgs) -> Result<Self> {
+        let distribution = match cli.flag("--distribution").unwrap_or("uniform") {
+            "uniform" => KeyDistribution::Uniform,
+            "zipfian" => KeyDistribution::Zipfian,
+            "sequential" => KeyDistribution::Sequential,
+            other => return Err(KVError::InvalidInput(format!("unknown key distribution {:?}", other))),
+        };
+        let config = BenchConfig {
+            threads: cli.number("--threads", 4)?,
+            ops: cli.number("--ops", 100_000)?,
+            duration: cli.flag("--duration").map(|_| cli.number("--duration", 0.0).map(Duration::from_secs_f64)).transpose()?,
+            reads: cli.number("--reads", 50)?,
+            keys: cli.number("--keys", 100_000)?,
+            distribution,
+            value_size: cli.number("--value-size", 100)?,
+            txn_size: cli.number("--txn-size", 1)?,
+        };
+        if config.threads == 0 || config.keys == 0 || config.txn_size == 0 || config.reads > 100 {
+            return Err(KVError::InvalidInput("threads, keys and txn-size must be positive and reads at most 100".to_owned()));
+        }
+        Ok(config)
+    }
+}
+pub(crate) struct BenchRng(pub(crate) u64);
+impl BenchRng {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(BenchRng(u64::from_le_bytes(random_bytes()?) | 1))
+    }
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+pub(crate) struct Zipfian {
+    pub(crate) n: u64,
+    pub(crate) theta: f64,
+    pub(crate) alpha: f64,
+    pub(crate) zetan: f64,
+    pub(crate) eta: f64,
+}
+impl Zipfian {
+    pub(crate) const THETA: f64 = 0.99;
+    pub(crate) fn new(n: u64) -> Self {
+        let theta = Self::THETA;
+        let zetan: f64 = (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum();
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan);
+        Zipfian { n, theta, alpha: 1.0 / (1.0 - theta), zetan, eta }
+    }
+    pub(crate) fn sample(&self, rng: &mut BenchRng) -> u64 {
+        let u = rng.unit();
+        let uz = u * self.zetan;
+        let rank = match uz {
+            _ if uz < 1.0 => 0,
+            _ if uz < 1.0 + 0.5f64.powf(self.theta) => 1,
+            _ => ((self.n as f64) * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64,
+        };
+        fnv1a(&rank.min(self.n - 1).to_string()) % self.n
+    }
+}
+pub(crate) fn bench_key(i: u64) -> String {
+    format!("bench{}{:012}", KEYSPACE_SEPARATOR, i)
+}
+pub(crate) fn percentile(sorted: &[u64], p: f64) -> u64 {
+    match sorted.len() {
+        0 => 0,
+        len => sorted[((len as f64 * p).ceil() as usize).clamp(1, len) - 1],
+    }
+}
+pub(crate) fn run_bench(store: &Arc<KVStore>, config: &BenchConfig, load: bool) -> Result<()> {
+    let value = "v".repeat(config.value_size);
+    println!(
+        "workload: {} threads, {}% reads, {:?} over {} keys, {}-byte values, {} ops/txn",
+        config.threads, config.reads, config.distribution, config.keys, config.value_size, config.txn_size
+    );
+    if load {
+        let started = Instant::now();
+        for chunk in (0..config.keys).collect::<Vec<_>>().chunks(1000) {
+            let mut tx = store.begin();
+            for &i in chunk {
+                tx.put(bench_key(i), value.clone())?;
+            }
+            tx.commit()?;
+        }
+        println!("loaded {} keys in {:.2?}", config.keys, started.elapsed());
+    }
+    let zipfian = (config.distribution == KeyDistribution::Zipfian).then(|| Zipfian::new(config.keys));
+    let remaining = AtomicU64::new(config.ops);
+    let sequence = AtomicU64::new(0);
+    let deadline = config.duration.map(|duration| Instant::now() + duration);
+    let started = Instant::now();
+    let results: Vec<Result<(Vec<u64>, u64, u64)>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut rng = BenchRng::new()?;
+                    let (mut latencies, mut ops, mut conflicts) = (Vec::new(), 0, 0);
+                    loop {
+                        match deadline {
+                            Some(deadline) if Instant::now() >= deadline => break,
+                            Some(_) => {}
+                            None => {
+                                let taken = remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1));
+                                if taken.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        let txn_started = Instant::now();
+                        let mut tx = store.begin();
+                        for _ in 0..config.txn_size {
+                            let i = match &zipfian {
+                                Some(zipfian) => zipfian.sample(&mut rng),
+                                None if config.distribution == KeyDistribution::Sequential => {
+                                    sequence.fetch_add(1, Ordering::Relaxed) % config.keys
+                                }
+                                None => rng.next() % config.keys,
+                            };
+                            match rng.next() % 100 < config.reads as u64 {
+                                true => drop(tx.get(&bench_key(i))?),
+                                false => tx.put(bench_key(i), value.clone())?,
+                            }
+                        }
+                        match tx.commit() {
+                            Ok(_) => {}
+                            Err(KVError::TransactionAborted) => conflicts += 1,
+                            Err(e) => return Err(e),
+                        }
+                        latencies.push(txn_started.elapsed().as_micros() as u64);
+                        ops += config.txn_size as u64;
+                    }
+                    Ok((latencies, ops, conflicts))
+                })
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    let elapsed = started.elapsed().as_secs_f64().max(f64::EPSILON);
+    let (mut latencies, mut ops, mut conflicts) = (Vec::new(), 0, 0);
+    for result in results {
+        let (worker, worker_ops, worker_conflicts) = result?;
+        latencies.extend(worker);
+        ops += worker_ops;
+        conflicts += worker_conflicts;
+    }
+    latencies.sort_unstable();
+    println!(
+        "{} ops in {:.2}s: {:.0} ops/s, {:.0} txn/s, {} conflicts",
+        ops,
+        elapsed,
+        ops as f64 / elapsed,
+        latencies.len() as f64 / elapsed,
+        conflicts
+    );
+    println!(
+        "latency per txn: p50 {}us  p90 {}us  p99 {}us  p99.9 {}us  max {}us",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        latencies.last().copied().unwrap_or(0)
+    );
+    Ok(())
+}
+pub(crate) fn backup_target(spec: &str) -> Result<Box<dyn BackupTarget>> {
+    let Some(rest) = spec.strip_prefix("s3://") else {
+        return Ok(Box::new(DirTarget::new(spec.strip_prefix("file://").unwrap_or(spec))));
+    };
+    let env = |name: &str| {
+        std::env::var(name).map_err(|_| KVError::InvalidInput(format!("{} must be set for s3 targets", name)))
+    };
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    let target = S3Target {
+        endpoint: env("KV_S3_ENDPOINT")?,
+        bucket: bucket.to_owned(),
+        prefix: if prefix.is_empty() || prefix.ends_with('/') { prefix.to_owned() } else { format!("{}/", prefix) },
+        region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+        access_key: env("AWS_ACCESS_KEY_ID")?,
+        secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+        tls: TlsClient { roots: std::env::var_os("KV_S3_CA_FILE").map(fs::read).transpose()?, ..TlsClient::default() },
+    };
+    target.host()?;
+    Ok(Box::new(target))
+}
+pub(crate) fn parse_restore_target(spec: &str) -> Result<VersionOrTimestamp> {
+    let invalid = || KVError::InvalidInput(format!("invalid restore target {:?}", spec));
+    if let Some(secs) = spec.strip_prefix('@') {
+        let secs = secs.parse().map_err(|_| invalid())?;
+        return Ok(VersionOrTimestamp::Timestamp(UNIX_EPOCH + Duration::from_secs(secs)));
+    }
+    if let Some(ago) = spec.strip_prefix('-') {
+        let (amount, unit) = ago.split_at(ago.len().saturating_sub(1));
+        let amount: u64 = amount.parse().map_err(|_| invalid())?;
+        let secs = match unit {
+            "s" => amount,
+            "m" => amount * 60,
+            "h" => amount * 3600,
+            "d" => amount * 86_400,
+            _ => return Err(invalid()),
+        };
+        return Ok(VersionOrTimestamp::Timestamp(SystemTime::now() - Duration::from_secs(secs)));
+    }
+    spec.parse().map(VersionOrTimestamp::Version).map_err(|_| invalid())
+}
+pub(crate) fn migrate(store: &Arc<KVStore>, engine: &str, source: &str) -> Result<MigrationReport> {
+    match engine {
+        #[cfg(feature = "sled")]
+        "sled" => migrate_from_sled(store, source),
+        #[cfg(feature = "redb")]
+        "redb" => migrate_from_redb(store, source),
+        _ => {
+            let _ = (store, source);
+            Err(KVError::InvalidInput(format!(
+                "cannot migrate from {:?}: kvctl supports sled and redb when built with the matching feature",
+                engine
+            )))
+        }
+    }
+}
+pub(crate) fn usage() -> ! {
+    eprintln!("usage:");
+    eprintln!("  kvctl shell [path]");
+    eprintln!("  kvctl export <path> [--format json|csv] [--history] [--out <file>]");
+    eprintln!("  kvctl import <path> <file|-> [--format json|csv] [--history]");
+    eprintln!("  kvctl import-rdb <path> <dump.rdb> [--keyspace <template, default db{{}}>]");
+    eprintln!("  kvctl migrate --from sled|redb <source> <path>");
+    eprintln!("  kvctl backup <path> <dir|s3://bucket/prefix> [--full]");
+    eprintln!("  kvctl verify-backup <dir|s3://bucket/prefix>");
+    eprintln!("  kvctl check <path>");
+    eprintln!("  kvctl salvage <path> <new-path>");
+    eprintln!("  kvctl restore <path|backup> <new-path> --to <version|@unix-secs|-5m> [--backup]");
+    eprintln!("  kvctl bench [path] [--threads N] [--ops N | --duration SECS] [--reads PCT] [--keys N]");
+    eprintln!("              [--distribution uniform|zipfian|sequential] [--value-size BYTES] [--txn-size N]");
+    eprintln!("              [--durability fsync|buffered] [--read-cache-slots N] [--no-load]");
+    eprintln!("  kvctl serve <path> <addr> [--ops-per-sec N] [--bytes-per-sec N]");
+    eprintln!("              [--tls-cert <chain.pem> --tls-key <key.pem> [--client-ca <roots.pem>]]");
+    eprintln!("              [--tokens <file of: token principal keyspace...>] [--grants <file of: principal keyspace...>]");
+    eprintln!("              [--jwt-secret <file>] [--anonymous <keyspace,...>]");
+    eprintln!("  every keyspace is closed until granted; * grants all but the internal __ ones");
+    eprintln!("s3 targets read KV_S3_ENDPOINT (https://host[:port] with the tls feature, or http://host:port for a local");
+    eprintln!("MinIO), AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_REGION and KV_S3_CA_FILE (PEM roots to trust)");
+    std::process::exit(2);
+}
+pub fn run(args: &[String]) -> Result<()> {
+    let cli = CliArgs::parse(
+        &args[1..],
+        &[
+            "--format",
+            "--out",
+            "--to",
+            "--keyspace",
+            "--from",
+            "--threads",
+            "--ops",
+            "--duration",
+            "--reads",
+            "--keys",
+            "--distribution",
+            "--value-size",
+            "--txn-size",
+            "--durability",
+            "--read-cache-slots",
+            "--ops-per-sec",
+            "--bytes-per-sec",
+            "--tls-cert",
+            "--tls-key",
+            "--client-ca",
+            "--tokens",
+            "--grants",
+            "--jwt-secret",
+            "--anonymous",
+        ],
+    );
+    let format = cli.flag("--format").unwrap_or("json");
+    match (args[0].as_str(), cli.positional.as_slice()) {
+        ("shell", [path]) => run_shell(Some(path)),
+        ("shell", []) => run_shell(None),
+        ("export", [path]) => {
+            let store = KVStore::open(path)?;
+            let mut out: Box<dyn Write> = match cli.flag("--out") {
+                Some(file) => Box::new(io::BufWriter::new(File::create(file)?)),
+                None => Box::new(io::BufWriter::new(io::stdout().lock())),
+            };
+            export_store(&store, format, cli.has("--history"), &mut out)?;
+            out.flush()?;
+            Ok(())
+        }
+        ("import", [path, file]) => {
+            let mut input = String::new();
+            if file == "-" {
+                io::stdin().read_to_string(&mut input)?;
+            } else {
+                File::open(file)?.read_to_string(&mut input)?;
+            }
+            let store = KVStore::open(path)?;
+            let count = import_store(&store, format, cli.has("--history"), &input)?;
+            eprintln!("imported {} entries, store at version {}", count, store.current_version());
+            Ok(())
+        }
+        ("backup", [path, spec]) => {
+            let store = KVStore::open(path)?;
+            let target = backup_target(spec)?;
+            let report = store.backup(target.as_ref(), cli.has("--full"))?;
+            for object in &report.uploaded {
+                eprintln!("uploaded {} ({} bytes)", object.name, object.size);
+            }
+            eprintln!("backup chain {} is at version {}", report.chain, store.current_version());
+            Ok(())
+        }
+        ("import-rdb", [path, dump]) => {
+            let store = KVStore::open(path)?;
+            let template = cli.flag("--keyspace").unwrap_or("db{}").to_owned();
+            let report = import_rdb(&store, File::open(dump)?, |db| template.replace("{}", &db.to_string()))?;
+            eprintln!("imported {} strings, store at version {}", report.imported, store.current_version());
+            if report.expired > 0 {
+                eprintln!("skipped {} expired keys", report.expired);
+            }
+            if report.non_utf8 > 0 {
+                eprintln!("skipped {} keys or values that are not utf-8", report.non_utf8);
+            }
+            for (kind, count) in &report.skipped_types {
+                eprintln!("skipped {} {} values (type not supported)", count, kind);
+            }
+            Ok(())
+        }
+        ("migrate", [source, path]) => {
+            let store = KVStore::open(path)?;
+            let report = migrate(&store, cli.flag("--from").unwrap_or_else(|| usage()), source)?;
+            for (keyspace, count) in &report.keyspaces {
+                eprintln!("copied {} keys into {}", count, if keyspace.is_empty() { "(default)" } else { keyspace });
+            }
+            if report.non_utf8 > 0 {
+                eprintln!("skipped {} entries with non utf-8 keys or values", report.non_utf8);
+            }
+            Ok(())
+        }
+        ("verify-backup", [spec]) => {
+            let target = backup_target(spec)?;
+            let chains = verify_backup(target.as_ref())?;
+            let mut failed = false;
+            for chain in &chains {
+                println!(
+                    "chain {}: {} objects, replayed to version {}",
+                    chain.snapshot_version, chain.objects, chain.version
+                );
+                for (keyspace, count) in &chain.keyspaces {
+                    println!("  {:<20} {}", if keyspace.is_empty() { "(default)" } else { keyspace }, count);
+                }
+                for problem in &chain.problems {
+                    println!("  PROBLEM: {}", problem);
+                }
+                failed |= !chain.problems.is_empty();
+            }
+            if chains.is_empty() {
+                println!("no backup chains found");
+            }
+            if failed {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        ("check", [path]) => {
+            if !Path::new(path).is_dir() {
+                return Err(KVError::InvalidInput(format!("{} is not a store directory", path)));
+            }
+            let _lock = lock_dir(Path::new(path))?;
+            let report = check_store(&FsStorage::new(path))?;
+            println!("{}", report.to_json());
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        ("salvage", [src, dst]) => {
+            let _lock = lock_dir(Path::new(src))?;
+            let (store, report) = KVStore::salvage_from(&FsStorage::new(src))?;
+            for skipped in &report.skipped {
+                eprintln!("skipped {}", skipped);
+            }
+            store.save_to(dst)?;
+            eprintln!(
+                "salvaged {} keys ({} wal records) into {} at version {}",
+                report.keys, report.records, dst, report.version
+            );
+            Ok(())
+        }
+        ("restore", [src, dst]) => {
+            let target = parse_restore_target(cli.flag("--to").unwrap_or_else(|| usage()))?;
+            let store = if cli.has("--backup") {
+                let staging = MemoryStorage::new();
+                let backup = backup_target(src)?;
+                BackupManifest::load(backup.as_ref())?.download(backup.as_ref(), &staging)?;
+                KVStore::restore_from(&staging, target)?
+            } else {
+                KVStore::restore_to(src, target)?
+            };
+            let version = store.save_to(dst)?;
+            eprintln!("restored {} to version {}", dst, version);
+            Ok(())
+        }
+        ("bench", positional) if positional.len() <= 1 => {
+            let config = BenchConfig::from_cli(&cli)?;
+            let mut builder = KVStore::builder().read_cache_slots(cli.number("--read-cache-slots", READ_CACHE_SLOTS)?);
+            if let Some(path) = positional.first() {
+                builder = builder.path(path);
+            }
+            builder = match cli.flag("--durability") {
+                Some("fsync") => builder.durability(Durability::Fsync),
+                Some("buffered") => builder.durability(Durability::Buffered),
+                Some(other) => return Err(KVError::InvalidInput(format!("unknown durability {:?}", other))),
+                None => builder,
+            };
+            let store = builder.open()?;
+            run_bench(&store, &config, !cli.has("--no-load"))
+        }
+        ("serve", [path, addr]) => {
+            let rate = |name| cli.flag(name).map(|_| cli.number(name, 0)).transpose();
+            let limit = RateLimit { ops_per_sec: rate("--ops-per-sec")?, bytes_per_sec: rate("--bytes-per-sec")? };
+            let mut config = ServerConfig::new().rate_limit(limit);
+            if let (Some(cert), Some(key)) = (cli.flag("--tls-cert"), cli.flag("--tls-key")) {
+                let identity = TlsIdentity { cert_chain: fs::read(cert)?, key: fs::read(key)? };
+                config = config.tls(TlsServer { identity, client_roots: cli.flag("--client-ca").map(fs::read).transpose()? });
+            }
+            let lines = |flag| -> Result<Vec<Vec<String>>> {
+                let Some(file) = cli.flag(flag) else { return Ok(Vec::new()) };
+                Ok(fs::read_to_string(file)?
+                    .lines()
+                    .map(|line| line.split_whitespace().map(str::to_owned).collect::<Vec<_>>())
+                    .filter(|words| words.first().is_some_and(|word| !word.starts_with('#')))
+                    .collect())
+            };
+            for words in lines("--tokens")? {
+                let [token, principal, granted @ ..] = words.as_slice() else {
+                    return Err(KVError::InvalidInput(format!("--tokens lines need a token and a principal, got {:?}", words)));
+                };
+                config = config.token(token, principal).grant(principal, &granted.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+            for words in lines("--grants")? {
+                config = config.grant(&words[0], &words[1..].iter().map(String::as_str).collect::<Vec<_>>());
+            }
+            if let Some(file) = cli.flag("--jwt-secret") {
+                config = config.jwt_hs256(fs::read(file)?.trim_ascii());
+            }
+            if let Some(anonymous) = cli.flag("--anonymous") {
+                config = config.grant_anonymous(&anonymous.split(',').collect::<Vec<_>>());
+            }
+            let scheme = if config.tls.is_some() { "https" } else { "http" };
+            let store = KVStore::open(path)?;
+            let server = store.serve_http(TcpListener::bind(addr)?, config)?;
+            eprintln!("serving {} on {}://{}", path, scheme, server.local_addr());
+            loop {
+                thread::park();
+            }
+        }
+        _ => usage(),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn shell_writes_land_on_commit_and_survive_a_reopen() {
+        let dir = TempDir::new("shell");
+        let open = format!("open {}", dir.path().display());
+        let mut shell = Shell { store: KVStore::new(), tx: None };
+        assert!(shell.run_line(&open).unwrap());
+        shell.run_line("put greeting hello  world").unwrap();
+        shell.run_line("begin").unwrap();
+        shell.run_line("put pending 1").unwrap();
+        shell.run_line("del greeting").unwrap();
+        assert_eq!(shell.store.begin().get("greeting").unwrap().as_deref(), Some("hello  world"));
+        shell.run_line("rollback").unwrap();
+        assert!(shell.tx.is_none());
+        assert_eq!(shell.store.begin().get("pending").unwrap(), None);
+        shell.run_line("begin").unwrap();
+        shell.run_line("put pending 1").unwrap();
+        shell.run_line(&open).unwrap();
+        shell.run_line("commit").unwrap();
+        shell.run_line("del greeting").unwrap();
+        shell.run_line("no-such-command").unwrap();
+        shell.run_line(&open).unwrap();
+        let tx = shell.store.begin();
+        assert_eq!((tx.get("pending").unwrap().as_deref(), tx.get("greeting").unwrap()), (Some("1"), None));
+        let history = shell.store.history("greeting");
+        assert_eq!(history.iter().map(|(_, value)| value.as_deref()).collect::<Vec<_>>(), [Some("hello  world"), None]);
+        assert!(shell.run_line("get").is_ok());
+        assert!(!shell.run_line("quit").unwrap());
+    }
+    #[test]
+    fn live_exports_round_trip_in_both_formats() {
+        let source = KVStore::new();
+        let mut tx = source.begin();
+        tx.put("plain".to_owned(), "value".to_owned()).unwrap();
+        tx.put("quoted".to_owned(), "a \"b\", c\nd".to_owned()).unwrap();
+        tx.put("gone".to_owned(), "x".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut tx = source.begin();
+        tx.delete("gone".to_owned()).unwrap();
+        tx.commit().unwrap();
+        for format in ["json", "csv"] {
+            let mut out = Vec::new();
+            export_store(&source, format, false, &mut out).unwrap();
+            let target = KVStore::new();
+            assert_eq!(import_store(&target, format, false, std::str::from_utf8(&out).unwrap()).unwrap(), 2, "{}", format);
+            assert_eq!(target.begin().scan(..).unwrap(), source.begin().scan(..).unwrap(), "{}", format);
+        }
+        assert!(matches!(import_store(&KVStore::new(), "csv", false, "key,value\na,b,c\n"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(import_store(&KVStore::new(), "json", false, "[{\"key\":\"a\",\"value\":1}]"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(export_store(&source, "xml", false, &mut Vec::new()), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn history_import_keeps_commit_times() {
+        let source = KVStore::new();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        source.import_version(1, Some(at(1_000)), Writes::from([("a".to_owned(), Some("1".to_owned()))])).unwrap();
+        source.import_version(2, Some(at(2_000)), Writes::from([("a".to_owned(), None), ("b".to_owned(), Some("2".to_owned()))])).unwrap();
+        for format in ["json", "csv"] {
+            let mut out = Vec::new();
+            export_store(&source, format, true, &mut out).unwrap();
+            let target = KVStore::new();
+            assert_eq!(import_store(&target, format, true, std::str::from_utf8(&out).unwrap()).unwrap(), 3);
+            assert_eq!(target.commit_time(1), Some(at(1_000)), "{}", format);
+            assert_eq!(target.commit_time(2), Some(at(2_000)), "{}", format);
+            assert_eq!(target.begin_at_time(at(1_500)).unwrap().get("a").unwrap().as_deref(), Some("1"));
+        }
+        let legacy = "key,version,deleted,value\na,1,false,x\n";
+        let target = KVStore::new();
+        assert_eq!(import_store(&target, "csv", true, legacy).unwrap(), 1);
+        assert_eq!(target.begin().get("a").unwrap().as_deref(), Some("x"));
+    }
+    #[test]
+    fn bench_percentiles_and_zipfian_keys_match_their_definitions() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!([0.5, 0.9, 0.99, 0.999, 1.0].map(|p| percentile(&sorted, p)), [50, 90, 99, 100, 100]);
+        assert_eq!((percentile(&sorted, 0.0), percentile(&[], 0.5)), (1, 0));
+        let zipfian = Zipfian::new(1000);
+        let mut rng = BenchRng(0x2545_f491_4f6c_dd1d);
+        let mut counts = vec![0u32; 1000];
+        for _ in 0..100_000 {
+            counts[zipfian.sample(&mut rng) as usize] += 1;
+        }
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        // Under theta 0.99 over 1000 keys the hottest key takes about 1/zeta(1000) of the draws.
+        let expected = 100_000.0 / zipfian.zetan;
+        assert!((counts[0] as f64 - expected).abs() < expected * 0.1, "hottest key drew {} of ~{:.0}", counts[0], expected);
+        assert!(counts[..10].iter().sum::<u32>() > 30_000);
+        let mut uniform = BenchRng(7);
+        assert!((0..1000).map(|_| uniform.unit()).all(|u| (0.0..1.0).contains(&u)));
+    }
+    #[test]
+    fn bench_runs_configured_workloads_and_rejects_bad_flags() {
+        let args = |line: &str| line.split(' ').map(str::to_owned).collect::<Vec<_>>();
+        let bench = |line: &str| run(&args(line));
+        let cli = CliArgs::parse(&args("--threads 2 --ops 500 --reads 80 --keys 50 --distribution zipfian --txn-size 3"), &["--threads", "--ops", "--reads", "--keys", "--distribution", "--txn-size"]);
+        let config = BenchConfig::from_cli(&cli).unwrap();
+        assert_eq!((config.threads, config.ops, config.reads, config.keys, config.txn_size), (2, 500, 80, 50, 3));
+        assert_eq!((config.distribution, config.value_size, config.duration), (KeyDistribution::Zipfian, 100, None));
+        let store = KVStore::new();
+        run_bench(&store, &config, true).unwrap();
+        assert_eq!(store.begin().scan(..).unwrap().len(), 50);
+        let dir = TempDir::new("bench");
+        let path = dir.path().display().to_string();
+        bench(&format!("bench {} --threads 2 --ops 200 --keys 20 --distribution sequential --durability buffered", path)).unwrap();
+        assert_eq!(KVStore::open(dir.path()).unwrap().begin().scan(..).unwrap().len(), 20);
+        bench("bench --threads 1 --duration 0.05 --keys 10 --reads 100").unwrap();
+        assert!(matches!(bench("bench --distribution pareto"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(bench("bench --reads 101"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(bench("bench --threads 0"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(bench("bench --durability never"), Err(KVError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,5860 @@
+pub use kvstore_mvcc as mvcc;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use mvcc::{Engine, Key, TxnBuffer, Version, VersionMap, VersionSource, Writes};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+pub mod adapter;
+mod audit;
+mod backup;
+#[cfg(target_arch = "wasm32")]
+pub mod browser;
+mod check;
+mod coordinator;
+mod crypto;
+pub mod ffi;
+mod import;
+#[cfg(feature = "isolation-check")]
+pub mod isolation;
+mod json;
+mod keyspaces;
+pub mod kvctl;
+mod lww;
+mod projection;
+#[cfg(feature = "python")]
+pub mod python;
+mod query;
+#[cfg(feature = "raft")]
+pub mod raft;
+mod replication;
+mod schedule;
+mod schema;
+mod server;
+pub mod sim;
+mod storage;
+mod tls;
+mod txn;
+mod wal;
+pub use audit::*;
+pub use backup::*;
+pub use check::*;
+pub use coordinator::*;
+pub use crypto::*;
+pub use import::*;
+pub use json::*;
+pub use keyspaces::*;
+pub use lww::*;
+pub use projection::*;
+pub use query::*;
+pub use replication::*;
+pub use schedule::*;
+pub use schema::*;
+pub use server::*;
+pub use storage::*;
+pub use tls::{PeerTls, TlsClient, TlsIdentity, TlsServer};
+pub use txn::*;
+use wal::*;
+type History = Vec<(Version, Option<String>)>;
+type PointIndex = HashMap<Key, (Version, Option<String>)>;
+fn index_points(map: &VersionMap, keyspace: &str) -> PointIndex {
+    let prefix = format!("{}{}", keyspace, KEYSPACE_SEPARATOR);
+    map.range(prefix_range(&prefix))
+        .filter(|(key, _)| keyspace_of(key) == keyspace)
+        .filter_map(|(key, versions)| versions.latest().map(|(&version, value)| (key.clone(), (version, value.as_deref().map(str::to_owned)))))
+        .collect()
+}
+type Timeline = Vec<(Version, u64)>;
+#[derive(Debug)]
+pub enum KVError {
+    TransactionAborted,
+    Io(io::Error),
+    Corruption(String),
+    InvalidInput(String),
+    NotLeader(Option<String>),
+    OutOfMemoryBudget(LimitExceeded),
+    InvalidConfig { option: &'static str, reason: String },
+    Closed,
+    Locked(PathBuf),
+    ReadOnly,
+    Overloaded(LimitExceeded),
+    PreconditionFailed(Vec<BrokenPrecondition>),
+    Rejected { keyspace: String, reason: Box<dyn std::error::Error + Send + Sync> },
+    KeyTooLarge { len: usize, max: usize },
+    ValueTooLarge { key: String, len: usize, max: usize },
+}
+impl fmt::Display for KVError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KVError::TransactionAborted => write!(f, "transaction aborted"),
+            KVError::Io(e) => write!(f, "i/o error: {}", e),
+            KVError::Corruption(msg) => write!(f, "corrupted store: {}", msg),
+            KVError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            KVError::NotLeader(Some(leader)) => write!(f, "not the leader; leader is {}", leader),
+            KVError::NotLeader(None) => write!(f, "not the leader; no leader is known"),
+            KVError::OutOfMemoryBudget(exceeded) => write!(f, "commit would exceed a memory budget: {}", exceeded),
+            KVError::InvalidConfig { option, reason } => write!(f, "invalid {} option: {}", option, reason),
+            KVError::Closed => write!(f, "store is closed"),
+            KVError::Locked(dir) => write!(f, "{} is locked by another process", dir.display()),
+            KVError::ReadOnly => write!(f, "store is open read-only"),
+            KVError::Overloaded(exceeded) => write!(f, "store is overloaded: {}", exceeded),
+            KVError::PreconditionFailed(broken) => {
+                write!(f, "commit preconditions failed: ")?;
+                for (i, broken) in broken.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", broken)?;
+                }
+                Ok(())
+            }
+            KVError::Rejected { keyspace, reason } => write!(f, "commit rejected by the {:?} keyspace validator: {}", keyspace, reason),
+            KVError::KeyTooLarge { len, max } => write!(f, "key of {} bytes exceeds the {} byte limit", len, max),
+            KVError::ValueTooLarge { key, len, max } => write!(f, "value of {} bytes for {} exceeds the {} byte limit", len, key, max),
+        }
+    }
+}
+impl std::error::Error for KVError {}
+impl From<io::Error> for KVError {
+    fn from(e: io::Error) -> Self {
+        KVError::Io(e)
+    }
+}
+impl From<mvcc::Aborted> for KVError {
+    fn from(_: mvcc::Aborted) -> Self {
+        KVError::TransactionAborted
+    }
+}
+impl From<mvcc::CommitError<KVError>> for KVError {
+    fn from(e: mvcc::CommitError<KVError>) -> Self {
+        match e {
+            mvcc::CommitError::Stale { version, current } => KVError::InvalidInput(format!(
+                "version {} is not newer than the store's current version {}",
+                version, current
+            )),
+            mvcc::CommitError::Log(e) => e,
+        }
+    }
+}
+type Result<T> = std::result::Result<T, KVError>;
+pub type KeyOrder = Arc<dyn Fn(&str, &str) -> std::cmp::Ordering + Send + Sync>;
+pub type Validator = Arc<dyn Fn(&str, &str) -> std::result::Result<(), String> + Send + Sync>;
+type CommitValidator = Arc<dyn Fn(&CommitView) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + Sync>;
+pub const KEYSPACE_SEPARATOR: char = ':';
+pub fn keyspace_of(key: &str) -> &str {
+    key.split_once(KEYSPACE_SEPARATOR).map_or("", |(keyspace, _)| keyspace)
+}
+/// One store's keyspace policies. Values, errors and buffers the store hands out keep a clone, so they redact and
+/// scrub by the policies of the store they came from; reads are lock-free so a `Drop` can consult them anywhere.
+#[derive(Clone, Default)]
+pub(crate) struct Policies(Arc<ArcSwap<BTreeMap<String, KeyspacePolicy>>>);
+impl Policies {
+    pub(crate) fn load(&self) -> Arc<BTreeMap<String, KeyspacePolicy>> {
+        self.0.load_full()
+    }
+    pub(crate) fn update(&self, keyspace: &str, policy: KeyspacePolicy) {
+        self.0.rcu(|policies| {
+            let mut policies = BTreeMap::clone(policies);
+            match policy == KeyspacePolicy::default() {
+                true => policies.remove(keyspace),
+                false => policies.insert(keyspace.to_owned(), policy.clone()),
+            };
+            policies
+        });
+    }
+    pub(crate) fn is_sensitive(&self, key: &str) -> bool {
+        self.0.load().get(keyspace_of(key)).is_some_and(|policy| policy.sensitive)
+    }
+}
+/// Two values are equal whatever store they came from.
+impl PartialEq for Policies {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl Eq for Policies {}
+pub struct Secret(String);
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+impl Drop for Secret {
+    fn drop(&mut self) {
+        mvcc::scrub(std::mem::take(&mut self.0));
+    }
+}
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<secret {} bytes>", self.0.len())
+    }
+}
+struct RedactedValue<'a>(bool, &'a str);
+impl fmt::Debug for RedactedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            true => write!(f, "<redacted {} bytes, sha256 {}>", self.1.len(), &hex(&sha256(self.1.as_bytes()))[..16]),
+            false => write!(f, "{:?}", self.1),
+        }
+    }
+}
+fn redacted<'a>(policies: &Policies, key: &str, value: Option<&'a str>) -> Option<impl fmt::Debug + 'a> {
+    value.map(|value| RedactedValue(policies.is_sensitive(key), value))
+}
+fn debug_writes<'a>(policies: &'a Policies, writes: &'a Writes) -> impl fmt::Debug + 'a {
+    struct Shown<'a>(&'a Policies, &'a Writes);
+    impl fmt::Debug for Shown<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_map().entries(self.1.iter().map(|(key, value)| (key, redacted(self.0, key, value.as_deref())))).finish()
+        }
+    }
+    Shown(policies, writes)
+}
+const WAL_MAGIC: &[u8; 8] = b"KVWAL001";
+const SNAP_MAGIC: &[u8; 8] = b"KVSNAP04";
+const SNAP_MAGIC_V3: &[u8; 8] = b"KVSNAP03";
+const SNAP_MAGIC_V2: &[u8; 8] = b"KVSNAP02";
+const SNAP_MAGIC_V1: &[u8; 8] = b"KVSNAP01";
+fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+#[cfg(target_arch = "wasm32")]
+fn now_millis() -> u64 {
+    unsafe { browser::host_now_millis() as u64 }
+}
+#[cfg(not(target_arch = "wasm32"))]
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+pub fn natural_key_order(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering::{Equal, Greater, Less};
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Equal,
+            (None, Some(_)) => return Less,
+            (Some(_), None) => return Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let end_a = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+                let end_b = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+                let (n, m) = (a[..end_a].trim_start_matches('0'), b[..end_b].trim_start_matches('0'));
+                let order = n.len().cmp(&m.len()).then_with(|| n.cmp(m)).then_with(|| end_a.cmp(&end_b));
+                if order != Equal {
+                    return order;
+                }
+                (a, b) = (&a[end_a..], &b[end_b..]);
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(&y),
+            (Some(x), Some(_)) => (a, b) = (&a[x.len_utf8()..], &b[x.len_utf8()..]),
+        }
+    }
+}
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        now_millis()
+    }
+}
+pub struct MockClock(AtomicU64);
+impl MockClock {
+    pub fn new(start_ms: u64) -> Arc<Self> {
+        Arc::new(MockClock(AtomicU64::new(start_ms)))
+    }
+    pub fn set(&self, ms: u64) {
+        self.0.store(ms, Ordering::SeqCst);
+    }
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+struct Wal {
+    base: Version,
+}
+#[derive(Debug, Clone, Copy)]
+pub enum VersionOrTimestamp {
+    Version(Version),
+    Timestamp(SystemTime),
+}
+#[derive(Clone)]
+pub struct ChangeBatch {
+    pub version: Version,
+    pub timestamp: u64,
+    pub writes: Writes,
+    pub(crate) policies: Policies,
+}
+impl fmt::Debug for ChangeBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeBatch")
+            .field("version", &self.version)
+            .field("timestamp", &self.timestamp)
+            .field("writes", &debug_writes(&self.policies, &self.writes))
+            .finish()
+    }
+}
+impl ChangeBatch {
+    pub fn encode(&self) -> Vec<u8> {
+        WalRecord::encode(self.version, self.timestamp, &self.writes)
+    }
+    pub fn renames(&self) -> Vec<(String, String)> {
+        let prefix = rename_key("");
+        self.writes
+            .range(prefix_range(&prefix))
+            .filter_map(|(key, from)| Some((from.clone()?, key[prefix.len()..].to_owned())))
+            .collect()
+    }
+}
+#[derive(Debug, Clone)]
+pub enum Delivery {
+    Batch(Arc<ChangeBatch>),
+    Lagged(u64),
+}
+struct ListenerSlot {
+    tx: mpsc::SyncSender<Delivery>,
+    missed: u64,
+}
+impl ListenerSlot {
+    fn deliver(&mut self, batch: &Arc<ChangeBatch>) -> bool {
+        if self.missed > 0 {
+            match self.tx.try_send(Delivery::Lagged(self.missed)) {
+                Ok(()) => self.missed = 0,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        match self.tx.try_send(Delivery::Batch(Arc::clone(batch))) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+pub struct Listener {
+    rx: mpsc::Receiver<Delivery>,
+}
+impl Listener {
+    pub fn recv(&self) -> Option<Delivery> {
+        self.rx.recv().ok()
+    }
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Delivery> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+    pub fn try_recv(&self) -> Option<Delivery> {
+        self.rx.try_recv().ok()
+    }
+}
+impl Iterator for Listener {
+    type Item = Delivery;
+    fn next(&mut self) -> Option<Delivery> {
+        self.recv()
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionScheme {
+    Counter,
+    HybridClock,
+}
+fn hybrid_next(last: Version) -> Version {
+    (now_millis() << 16).max(last + 1)
+}
+pub struct HybridVersions(pub Arc<dyn Clock>);
+impl VersionSource for HybridVersions {
+    fn next(&self, last: Version) -> Version {
+        (self.0.now_millis() << 16).max(last + 1)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    KeepAll,
+    KeepFor(Duration),
+    LatestOnly,
+    KeepVersions(usize),
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lzf,
+    Zstd(i32),
+}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyspacePolicy {
+    compression: Option<Compression>,
+    default_ttl: Option<Duration>,
+    retention: Option<Retention>,
+    memory_budget: Option<usize>,
+    sensitive: bool,
+}
+impl KeyspacePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+    pub fn sensitive(mut self, sensitive: bool) -> Self {
+        self.sensitive = sensitive;
+        self
+    }
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+    }
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self.compression {
+            None => buf.push(0),
+            Some(Compression::None) => buf.push(1),
+            Some(Compression::Lzf) => buf.push(2),
+            Some(Compression::Zstd(level)) => {
+                buf.push(3);
+                put_u32(buf, level as u32);
+            }
+        }
+        match self.default_ttl {
+            Some(ttl) => {
+                buf.push(1);
+                put_u64(buf, ttl.as_millis() as u64);
+            }
+            None => buf.push(0),
+        }
+        match self.retention {
+            None => buf.push(0),
+            Some(Retention::KeepAll) => buf.push(1),
+            Some(Retention::KeepFor(window)) => {
+                buf.push(2);
+                put_u64(buf, window.as_millis() as u64);
+            }
+            Some(Retention::LatestOnly) => buf.push(3),
+            Some(Retention::KeepVersions(n)) => {
+                buf.push(4);
+                put_u64(buf, n as u64);
+            }
+        }
+        match self.memory_budget {
+            Some(bytes) => {
+                buf.push(1);
+                put_u64(buf, bytes as u64);
+            }
+            None => buf.push(0),
+        }
+    }
+    fn decode(d: &mut Decoder) -> Result<Self> {
+        let bad = |tag: u8| KVError::Corruption(format!("unknown keyspace policy tag {}", tag));
+        let compression = match d.u8()? {
+            0 => None,
+            1 => Some(Compression::None),
+            2 => Some(Compression::Lzf),
+            3 => Some(Compression::Zstd(d.u32()? as i32)),
+            tag => return Err(bad(tag)),
+        };
+        let default_ttl = match d.u8()? {
+            0 => None,
+            _ => Some(Duration::from_millis(d.u64()?)),
+        };
+        let retention = match d.u8()? {
+            0 => None,
+            1 => Some(Retention::KeepAll),
+            2 => Some(Retention::KeepFor(Duration::from_millis(d.u64()?))),
+            3 => Some(Retention::LatestOnly),
+            4 => Some(Retention::KeepVersions(d.u64()? as usize)),
+            tag => return Err(bad(tag)),
+        };
+        let memory_budget = match d.u8()? {
+            0 => None,
+            _ => Some(d.u64()? as usize),
+        };
+        Ok(KeyspacePolicy { compression, default_ttl, retention, memory_budget, sensitive: false })
+    }
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub version: Version,
+    pub versions_removed: usize,
+    pub segments_rewritten: usize,
+    pub snapshots_rewritten: usize,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    pub versions_removed: usize,
+    pub keys_removed: usize,
+    pub watermark: Version,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExportReport {
+    pub version: Version,
+    pub entries: u64,
+    pub bytes: u64,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    Replace,
+    KeepNewer,
+    ErrorOnConflict,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub source_version: Version,
+    pub version: Version,
+    pub entries: u64,
+    pub written: u64,
+    pub skipped: u64,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OplogReport {
+    pub source_version: Version,
+    pub version: Version,
+    pub batches: u64,
+    pub operations: u64,
+    pub skipped: u64,
+}
+const OPLOG_MAGIC: &[u8; 8] = b"KVOPS001";
+const EXPORT_MAGIC: &[u8; 8] = b"KVEXP001";
+const EXPORT_CHUNK: u8 = 1;
+const EXPORT_PACKED: u8 = 2;
+const EXPORT_END: u8 = 3;
+fn read_export(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => KVError::Corruption("export stream is truncated".to_owned()),
+        _ => KVError::Io(e),
+    })
+}
+fn read_export_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut head = [0; 8];
+    if reader.read(&mut head[..1])? == 0 {
+        return Ok(None);
+    }
+    read_export(reader, &mut head[1..])?;
+    let mut payload = vec![0; u32::from_le_bytes(head[..4].try_into().unwrap()) as usize];
+    read_export(reader, &mut payload)?;
+    if crc32(&payload) != u32::from_le_bytes(head[4..].try_into().unwrap()) {
+        return Err(KVError::Corruption("export frame checksum mismatch".to_owned()));
+    }
+    Ok(Some(payload))
+}
+fn export_rows(rows: &[u8]) -> Result<Writes> {
+    let mut d = Decoder::new(rows);
+    let mut writes = Writes::new();
+    for _ in 0..d.u32()? {
+        writes.insert(d.string()?, Some(d.string()?));
+    }
+    Ok(writes)
+}
+pub(crate) struct Pin {
+    pins: Arc<Mutex<BTreeMap<Version, usize>>>,
+    version: Version,
+}
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.version);
+            }
+        }
+    }
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub version: Version,
+    pub gc: GcReport,
+    pub segments_rewritten: usize,
+    pub bytes_reclaimed: u64,
+    pub values_relocated: usize,
+    pub chain_slots_reclaimed: usize,
+}
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FaultPoint {
+    BeforeWalSync,
+    AfterVersionAllocated,
+    DuringCompaction,
+}
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone)]
+pub enum Fault {
+    Error(String),
+    Delay(Duration),
+}
+#[cfg(feature = "fault-injection")]
+#[derive(Default)]
+pub struct FaultInjector {
+    faults: Mutex<BTreeMap<FaultPoint, (Fault, usize)>>,
+    hits: Mutex<BTreeMap<FaultPoint, usize>>,
+}
+#[cfg(feature = "fault-injection")]
+impl FaultInjector {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+    pub fn inject(&self, point: FaultPoint, fault: Fault, times: usize) {
+        self.faults.lock().unwrap().insert(point, (fault, times));
+    }
+    pub fn clear(&self, point: FaultPoint) {
+        self.faults.lock().unwrap().remove(&point);
+    }
+    pub fn hits(&self, point: FaultPoint) -> usize {
+        self.hits.lock().unwrap().get(&point).copied().unwrap_or(0)
+    }
+    fn hit(&self, point: FaultPoint) -> Result<()> {
+        *self.hits.lock().unwrap().entry(point).or_insert(0) += 1;
+        let fault = {
+            let mut faults = self.faults.lock().unwrap();
+            let Some((fault, times)) = faults.get_mut(&point) else { return Ok(()) };
+            let fault = fault.clone();
+            *times = times.saturating_sub(1);
+            if *times == 0 {
+                faults.remove(&point);
+            }
+            fault
+        };
+        match fault {
+            Fault::Error(message) => Err(KVError::Io(io::Error::other(format!("injected fault at {:?}: {}", point, message)))),
+            Fault::Delay(delay) => {
+                thread::sleep(delay);
+                Ok(())
+            }
+        }
+    }
+}
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    pub keys: usize,
+    pub versions: usize,
+    pub tombstones: usize,
+    pub approx_bytes: usize,
+    pub keyspaces: BTreeMap<String, usize>,
+    pub current_version: Version,
+    pub gc_watermark: Version,
+    pub open_transactions: usize,
+    pub oldest_transaction: Option<Duration>,
+    pub slab: mvcc::SlabUsage,
+}
+fn key_bytes(key: &str) -> usize {
+    key.len() + std::mem::size_of::<String>() + std::mem::size_of::<mvcc::Handle>()
+}
+fn version_bytes<V: AsRef<str>>(value: &Option<V>) -> usize {
+    std::mem::size_of::<Version>() + std::mem::size_of::<Option<mvcc::Value>>() + value.as_ref().map_or(0, |v| v.as_ref().len())
+}
+fn chain_bytes(key: &str, versions: &mvcc::Chain) -> usize {
+    key_bytes(key) + versions.values().map(version_bytes).sum::<usize>()
+}
+fn write_bytes(state: &mvcc::State, writes: &Writes) -> usize {
+    writes
+        .iter()
+        .map(|(key, value)| version_bytes(value) + if state.map.contains_key(key) { 0 } else { key_bytes(key) })
+        .sum()
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    Fail,
+    EvictLeastRecentlyRead,
+}
+#[derive(Default)]
+struct Recency {
+    tick: u64,
+    ticks: BTreeMap<Key, u64>,
+    order: BTreeMap<u64, Key>,
+}
+impl Recency {
+    fn touch(&mut self, key: &str) {
+        self.touch_with(key, || Key::from(key))
+    }
+    /// Like `touch`, but a key seen for the first time shares the version map's allocation instead of copying it.
+    fn touch_key(&mut self, key: &Key) {
+        self.touch_with(key, || key.clone())
+    }
+    fn touch_with(&mut self, key: &str, intern: impl FnOnce() -> Key) {
+        self.tick += 1;
+        let (shared, old) = match self.ticks.get_key_value(key) {
+            Some((shared, &old)) => (shared.clone(), Some(old)),
+            None => (intern(), None),
+        };
+        if let Some(old) = old {
+            self.order.remove(&old);
+        }
+        self.ticks.insert(shared.clone(), self.tick);
+        self.order.insert(self.tick, shared);
+    }
+    fn forget(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+}
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+const SAMPLE_SHIFT: u32 = 6;
+const EXACT_ESTIMATE: usize = 16 << SAMPLE_SHIFT;
+#[derive(Default)]
+struct KeySample {
+    keys: BTreeMap<Key, usize>,
+}
+impl KeySample {
+    fn build(map: &VersionMap) -> Self {
+        let mut sample = KeySample::default();
+        for (key, versions) in map.iter().filter(|(key, _)| KeySample::sampled(key)) {
+            if let Some((_, Some(value))) = versions.latest() {
+                sample.keys.insert(key.clone(), key.len() + value.len());
+            }
+        }
+        sample
+    }
+    fn sampled(key: &str) -> bool {
+        fnv1a(key).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - SAMPLE_SHIFT) == 0
+    }
+    fn update(&mut self, key: &Key, value: Option<&str>) {
+        if Self::sampled(key) {
+            match value {
+                Some(value) => self.keys.insert(key.clone(), key.len() + value.len()),
+                None => self.keys.remove(key.as_str()),
+            };
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeEstimate {
+    pub keys: u64,
+    pub bytes: u64,
+}
+const SLOW_LOG_CAPACITY: usize = 256;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowTxn {
+    pub txn: u64,
+    pub at: u64,
+    pub version: Option<Version>,
+    pub duration: Duration,
+    pub keys: usize,
+    pub lock_wait: Duration,
+    pub fsync: Duration,
+}
+const TRACE_MAGIC: &[u8; 8] = b"KVTRC001";
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOp {
+    Begin { snapshot: Version },
+    Get { key: String, found: Option<String> },
+    Put { key: String, value: String },
+    Delete { key: String },
+    Commit { result: std::result::Result<Version, String> },
+    Rollback,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub at: Duration,
+    pub took: Duration,
+    pub thread: u64,
+    pub txn: u64,
+    pub op: TraceOp,
+}
+impl TraceEvent {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, self.at.as_micros() as u64);
+        put_u64(&mut payload, self.took.as_micros() as u64);
+        put_u64(&mut payload, self.thread);
+        put_u64(&mut payload, self.txn);
+        match &self.op {
+            TraceOp::Begin { snapshot } => {
+                payload.push(0);
+                put_u64(&mut payload, *snapshot);
+            }
+            TraceOp::Get { key, found } => {
+                payload.push(1);
+                put_str(&mut payload, key);
+                payload.push(found.is_some() as u8);
+                if let Some(found) = found {
+                    put_str(&mut payload, found);
+                }
+            }
+            TraceOp::Put { key, value } => {
+                payload.push(2);
+                put_str(&mut payload, key);
+                put_str(&mut payload, value);
+            }
+            TraceOp::Delete { key } => {
+                payload.push(3);
+                put_str(&mut payload, key);
+            }
+            TraceOp::Commit { result: Ok(version) } => {
+                payload.push(4);
+                put_u64(&mut payload, *version);
+            }
+            TraceOp::Commit { result: Err(error) } => {
+                payload.push(5);
+                put_str(&mut payload, error);
+            }
+            TraceOp::Rollback => payload.push(6),
+        }
+        frame(&payload)
+    }
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let mut d = Decoder::new(payload);
+        let (at, took) = (Duration::from_micros(d.u64()?), Duration::from_micros(d.u64()?));
+        let (thread, txn) = (d.u64()?, d.u64()?);
+        let op = match d.u8()? {
+            0 => TraceOp::Begin { snapshot: d.u64()? },
+            1 => {
+                let key = d.string()?;
+                let found = match d.u8()? {
+                    0 => None,
+                    _ => Some(d.string()?),
+                };
+                TraceOp::Get { key, found }
+            }
+            2 => TraceOp::Put { key: d.string()?, value: d.string()? },
+            3 => TraceOp::Delete { key: d.string()? },
+            4 => TraceOp::Commit { result: Ok(d.u64()?) },
+            5 => TraceOp::Commit { result: Err(d.string()?) },
+            6 => TraceOp::Rollback,
+            tag => return Err(KVError::Corruption(format!("unknown trace event {}", tag))),
+        };
+        Ok(TraceEvent { at, took, thread, txn, op })
+    }
+}
+struct Recorder {
+    started: Instant,
+    out: Mutex<(io::BufWriter<File>, Option<io::Error>)>,
+}
+fn trace_thread() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    THREAD.with(|thread| *thread)
+}
+fn trace_value(policies: &Policies, key: &str, value: &str) -> String {
+    match policies.is_sensitive(key) {
+        true => format!("{:?}", RedactedValue(true, value)),
+        false => value.to_owned(),
+    }
+}
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Vec<TraceEvent>> {
+    let mut reader = io::BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    read_export(&mut reader, &mut magic)?;
+    if &magic != TRACE_MAGIC {
+        return Err(KVError::Corruption("not an operation trace".to_owned()));
+    }
+    let mut events = Vec::new();
+    while let Some(payload) = read_export_frame(&mut reader)? {
+        events.push(TraceEvent::decode(&payload)?);
+    }
+    Ok(events)
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub event: usize,
+    pub txn: u64,
+    pub expected: TraceOp,
+    pub actual: TraceOp,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub events: usize,
+    pub transactions: usize,
+    pub recorded: Duration,
+    pub elapsed: Duration,
+    pub divergences: Vec<Divergence>,
+}
+pub fn replay_trace(store: &Arc<KVStore>, events: &[TraceEvent]) -> Result<ReplayReport> {
+    let started = Instant::now();
+    let mut report = ReplayReport { recorded: events.last().map_or(Duration::ZERO, |event| event.at), ..ReplayReport::default() };
+    let mut open: HashMap<u64, Transaction> = HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        report.events += 1;
+        let txn = match &event.op {
+            TraceOp::Begin { .. } => {
+                report.transactions += 1;
+                open.insert(event.txn, store.begin());
+                continue;
+            }
+            _ => match open.get_mut(&event.txn) {
+                Some(txn) => txn,
+                None => return Err(KVError::Corruption(format!("trace event {} uses transaction {} before it begins", i, event.txn))),
+            },
+        };
+        let actual = match &event.op {
+            TraceOp::Get { key, .. } => TraceOp::Get { key: key.clone(), found: txn.get(key)?.map(|value| trace_value(&store.policies, key, &value)) },
+            TraceOp::Put { key, value } => {
+                txn.put(key.clone(), value.clone())?;
+                event.op.clone()
+            }
+            TraceOp::Delete { key } => {
+                txn.delete(key.clone())?;
+                event.op.clone()
+            }
+            TraceOp::Commit { result } => {
+                let replayed = open.remove(&event.txn).unwrap().commit().map_err(|e| e.to_string());
+                match (result, &replayed) {
+                    (Ok(_), Ok(_)) => event.op.clone(),
+                    _ => TraceOp::Commit { result: replayed },
+                }
+            }
+            TraceOp::Rollback => {
+                txn.rollback()?;
+                TraceOp::Rollback
+            }
+            TraceOp::Begin { .. } => unreachable!(),
+        };
+        if actual != event.op {
+            report.divergences.push(Divergence { event: i, txn: event.txn, expected: event.op.clone(), actual });
+        }
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+#[derive(Debug, Clone)]
+pub struct LongTxn {
+    pub id: u64,
+    pub snapshot: Version,
+    pub open_for: Duration,
+    pub backtrace: Option<Arc<std::backtrace::Backtrace>>,
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub drained: usize,
+    pub aborted: Vec<u64>,
+    pub in_doubt: Vec<u64>,
+    pub checkpoint: Option<Version>,
+    pub elapsed: Duration,
+}
+struct OpenTxn {
+    snapshot: Version,
+    opened: Instant,
+    backtrace: Option<Arc<std::backtrace::Backtrace>>,
+}
+struct TxnTicket {
+    store: Arc<KVStore>,
+    id: u64,
+}
+impl Drop for TxnTicket {
+    fn drop(&mut self) {
+        self.store.open_txns.lock().unwrap().remove(&self.id);
+    }
+}
+const ADMISSION_POLL: Duration = Duration::from_millis(5);
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdmissionLimits {
+    wal_backlog: Option<u64>,
+    memory: Option<usize>,
+    wal_segments: Option<usize>,
+    wait: Option<Duration>,
+}
+impl AdmissionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn wal_backlog(mut self, bytes: u64) -> Self {
+        self.wal_backlog = Some(bytes);
+        self
+    }
+    pub fn memory(mut self, bytes: usize) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+    pub fn wal_segments(mut self, segments: usize) -> Self {
+        self.wal_segments = Some(segments);
+        self
+    }
+    pub fn block_for(mut self, wait: Duration) -> Self {
+        self.wait = Some(wait);
+        self
+    }
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WritePressure {
+    pub wal_backlog: u64,
+    pub memory: usize,
+    pub wal_segments: usize,
+}
+impl WritePressure {
+    fn exceeds(&self, limits: &AdmissionLimits) -> Option<LimitExceeded> {
+        let exceeded = |limit, usage: u64, max: u64| LimitExceeded { limit, keyspace: None, usage, requested: 0, max };
+        if let Some(limit) = limits.wal_backlog.filter(|&limit| self.wal_backlog > limit) {
+            return Some(exceeded(Limit::WalBacklog, self.wal_backlog, limit));
+        }
+        if let Some(limit) = limits.memory.filter(|&limit| self.memory > limit) {
+            return Some(exceeded(Limit::Memtable, self.memory as u64, limit as u64));
+        }
+        if let Some(limit) = limits.wal_segments.filter(|&limit| self.wal_segments > limit) {
+            return Some(exceeded(Limit::WalSegments, self.wal_segments as u64, limit as u64));
+        }
+        None
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    MemoryLimit,
+    KeyspaceBudget,
+    WalBacklog,
+    Memtable,
+    WalSegments,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub keyspace: Option<String>,
+    pub usage: u64,
+    pub requested: u64,
+    pub max: u64,
+}
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            Limit::MemoryLimit => {
+                write!(f, "{} bytes in use plus {} more exceeds the memory limit of {} bytes", self.usage, self.requested, self.max)
+            }
+            Limit::KeyspaceBudget => write!(
+                f,
+                "keyspace {:?} holds {} bytes; {} more exceeds its budget of {} bytes",
+                self.keyspace.as_deref().unwrap_or(""),
+                self.usage,
+                self.requested,
+                self.max
+            ),
+            Limit::WalBacklog => write!(f, "wal backlog of {} bytes exceeds {}", self.usage, self.max),
+            Limit::Memtable => write!(f, "memtable of {} bytes exceeds {}", self.usage, self.max),
+            Limit::WalSegments => write!(f, "{} wal segments awaiting compaction exceed {}", self.usage, self.max),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyspaceUsage {
+    pub keyspace: String,
+    pub bytes: usize,
+    pub budget: Option<usize>,
+    pub store_bytes: usize,
+    pub store_limit: Option<usize>,
+}
+impl KeyspaceUsage {
+    pub fn remaining(&self) -> Option<usize> {
+        let keyspace = self.budget.map(|budget| budget.saturating_sub(self.bytes));
+        let store = self.store_limit.map(|limit| limit.saturating_sub(self.store_bytes));
+        keyspace.into_iter().chain(store).min()
+    }
+    pub fn used_fraction(&self) -> Option<f64> {
+        let keyspace = self.budget.map(|budget| self.bytes as f64 / budget.max(1) as f64);
+        let store = self.store_limit.map(|limit| self.store_bytes as f64 / limit.max(1) as f64);
+        keyspace.into_iter().chain(store).reduce(f64::max)
+    }
+}
+struct Staged {
+    version: Version,
+    segment: Version,
+    record: Vec<u8>,
+    batch: Option<Arc<ChangeBatch>>,
+}
+#[derive(Default)]
+struct StagedQueue {
+    pending: Vec<Staged>,
+    written: Vec<Staged>,
+    durable: Version,
+    flushing: bool,
+    stopped: bool,
+    failed: Option<(io::ErrorKind, String)>,
+}
+#[derive(Default)]
+struct CommitPipeline {
+    queue: Mutex<StagedQueue>,
+    queued: Condvar,
+    flushed: Condvar,
+    writer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+impl CommitPipeline {
+    fn start_writer(self: &Arc<Self>, write: impl Fn(&[Staged]) -> Result<()> + Send + 'static) {
+        let pipeline = Arc::clone(self);
+        *self.writer.lock().unwrap() = Some(thread::spawn(move || pipeline.run_writer(write)));
+    }
+    fn run_writer(&self, write: impl Fn(&[Staged]) -> Result<()>) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.pending.is_empty() || queue.failed.is_some() {
+                queue.pending.clear();
+                if queue.stopped {
+                    return;
+                }
+                queue = self.queued.wait(queue).unwrap();
+                continue;
+            }
+            queue.flushing = true;
+            let staged = std::mem::take(&mut queue.pending);
+            drop(queue);
+            let written = write(&staged);
+            queue = self.queue.lock().unwrap();
+            queue.flushing = false;
+            match written {
+                Ok(()) => {
+                    queue.durable = staged.last().map_or(queue.durable, |staged| staged.version);
+                    queue.written.extend(staged);
+                }
+                Err(KVError::Io(e)) => queue.failed = Some((e.kind(), e.to_string())),
+                Err(e) => queue.failed = Some((io::ErrorKind::Other, e.to_string())),
+            }
+            self.flushed.notify_all();
+        }
+    }
+    fn stop(&self) {
+        self.queue.lock().unwrap().stopped = true;
+        self.queued.notify_all();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+    fn failure(&self) -> Result<()> {
+        match &self.queue.lock().unwrap().failed {
+            Some((kind, reason)) => Err(KVError::Io(io::Error::new(*kind, format!("store failed after a wal write error: {}", reason)))),
+            None => Ok(()),
+        }
+    }
+}
+#[derive(Default)]
+struct CommitTrace {
+    lock_wait: Duration,
+    fsync: Duration,
+    replayed: Option<Version>,
+    applied_through: Option<u64>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Applied(Version),
+    Skipped { applied_through: u64 },
+}
+const HOT_KEY_SLOTS: usize = 256;
+const HOT_KEY_SAMPLE: u64 = 16;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    pub key: String,
+    pub reads: u64,
+    pub writes: u64,
+}
+#[derive(Default)]
+struct HotKeys {
+    ticks: AtomicU64,
+    slots: Mutex<HashMap<String, HotKey>>,
+}
+impl HotKeys {
+    fn record(&self, key: &str, write: bool) {
+        let tick = self.ticks.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(17);
+        if !tick.is_multiple_of(HOT_KEY_SAMPLE) || keyspace_of(key).starts_with("__") {
+            return;
+        }
+        let mut slots = self.slots.lock().unwrap();
+        let mut floor = (0, 0);
+        if !slots.contains_key(key) && slots.len() >= HOT_KEY_SLOTS {
+            let coldest = slots.values().min_by_key(|slot| slot.reads + slot.writes).unwrap().key.clone();
+            let evicted = slots.remove(&coldest).unwrap();
+            floor = (evicted.reads, evicted.writes);
+        }
+        let slot = slots.entry(key.to_owned()).or_insert_with(|| HotKey { key: key.to_owned(), reads: floor.0, writes: floor.1 });
+        if write {
+            slot.writes += HOT_KEY_SAMPLE;
+        } else {
+            slot.reads += HOT_KEY_SAMPLE;
+        }
+    }
+}
+const READ_CACHE_SLOTS: usize = 256;
+const MAX_KEY_BYTES: usize = 64 << 10;
+const MAX_VALUE_BYTES: usize = 64 << 20;
+const SCAN_CHUNK: usize = 1024;
+const MULTI_GET_FANOUT_MIN: usize = 128;
+struct CachedValue {
+    key: Key,
+    version: Version,
+    value: Option<String>,
+    policies: Policies,
+}
+impl Drop for CachedValue {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take().filter(|_| self.policies.is_sensitive(&self.key)) {
+            mvcc::scrub(value);
+        }
+    }
+}
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+struct Bloom {
+    bits: Vec<AtomicU64>,
+    capacity: usize,
+    inserted: AtomicUsize,
+}
+impl Bloom {
+    fn build<'a>(keys: impl ExactSizeIterator<Item = &'a str>) -> Self {
+        Self::build_for(keys.len(), keys)
+    }
+    /// Built only from keys that still hold a value in some retained version, so deleted and collected keys stop
+    /// answering "maybe" and `inserted` starts again from the live count.
+    fn live(map: &VersionMap) -> Self {
+        let live: Vec<&str> = map.iter().filter(|(_, versions)| versions.values().any(Option::is_some)).map(|(key, _)| key.as_str()).collect();
+        Self::build(live.into_iter())
+    }
+    fn build_for<'a>(expected: usize, keys: impl Iterator<Item = &'a str>) -> Self {
+        let capacity = (expected * 2).max(1024);
+        let words = (capacity * BLOOM_BITS_PER_KEY).div_ceil(64);
+        let bloom = Self { bits: (0..words).map(|_| AtomicU64::new(0)).collect(), capacity, inserted: AtomicUsize::new(0) };
+        for key in keys {
+            bloom.insert(key);
+        }
+        bloom
+    }
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let h1 = fnv1a(key);
+        let h2 = (h1 ^ (h1 >> 31)).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+    fn insert(&self, key: &str) -> bool {
+        for bit in self.positions(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.inserted.fetch_add(1, Ordering::Relaxed) + 1 > self.capacity
+    }
+    fn may_contain(&self, key: &str) -> bool {
+        self.positions(key).all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+}
+/// Direct-mapped key → latest value cache. Each slot is an `ArcSwapOption`, so a hit is an atomic load plus a key
+/// compare with no lock taken, and fills or invalidations publish a new entry without ever blocking readers. The only
+/// cost left on a hit is copying the value out, since reads hand back an owned `String`.
+struct ReadCache {
+    slots: Vec<ArcSwapOption<CachedValue>>,
+    policies: Policies,
+}
+impl ReadCache {
+    fn new(slots: usize, policies: Policies) -> Self {
+        Self { slots: (0..slots).map(|_| ArcSwapOption::empty()).collect(), policies }
+    }
+    fn slot(&self, key: &str) -> &ArcSwapOption<CachedValue> {
+        &self.slots[fnv1a(key) as usize % self.slots.len()]
+    }
+    fn get(&self, key: &str, version: Version) -> Option<Option<String>> {
+        let slot = self.slot(key).load();
+        let entry = slot.as_deref().filter(|entry| entry.key == key && entry.version <= version)?;
+        Some(entry.value.clone())
+    }
+    fn fill(&self, key: &Key, version: Version, value: Option<String>) {
+        let policies = self.policies.clone();
+        self.slot(key).store(Some(Arc::new(CachedValue { key: key.clone(), version, value, policies })));
+    }
+    fn invalidate(&self, key: &str) {
+        let slot = self.slot(key);
+        if slot.load().as_deref().is_some_and(|entry| entry.key == key) {
+            slot.rcu(|entry| entry.clone().filter(|entry| entry.key != key));
+        }
+    }
+    fn clear(&self) {
+        for slot in &self.slots {
+            slot.store(None);
+        }
+    }
+}
+pub const MAIN_BRANCH: &str = "main";
+#[derive(Clone)]
+pub struct BranchConflict {
+    pub key: String,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+    pub(crate) policies: Policies,
+}
+impl fmt::Debug for BranchConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BranchConflict")
+            .field("key", &self.key)
+            .field("base", &redacted(&self.policies, &self.key, self.base.as_deref()))
+            .field("ours", &redacted(&self.policies, &self.key, self.ours.as_deref()))
+            .field("theirs", &redacted(&self.policies, &self.key, self.theirs.as_deref()))
+            .finish()
+    }
+}
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BranchMergeReport {
+    pub version: Version,
+    pub applied: usize,
+    pub conflicts: usize,
+}
+trait Proposer: Send + Sync {
+    fn propose(&self, writes: Writes, meta: CommitMeta) -> Result<Version>;
+    fn repair(&self, _key: &str, _version: Version) -> Option<Option<String>> {
+        None
+    }
+}
+pub struct KVStore {
+    engine: Engine<Mutex<mvcc::State>>,
+    storage: Option<Arc<dyn Storage>>,
+    wal: Option<Mutex<Wal>>,
+    feed: Mutex<Vec<mpsc::Sender<Arc<ChangeBatch>>>>,
+    listeners: Mutex<Vec<ListenerSlot>>,
+    proposer: OnceLock<Arc<dyn Proposer>>,
+    timeline: Mutex<Timeline>,
+    prepared: Mutex<BTreeMap<u64, (Writes, CommitMeta)>>,
+    attached: Mutex<BTreeMap<String, Arc<KVStore>>>,
+    coordinator: OnceLock<Coordinator>,
+    parent: Option<(Arc<KVStore>, Version)>,
+    branches: Mutex<BTreeMap<String, Arc<KVStore>>>,
+    gc_watermark: AtomicU64,
+    memory: AtomicUsize,
+    memory_limit: AtomicUsize,
+    evict: AtomicBool,
+    recency: Mutex<Recency>,
+    cache: ReadCache,
+    bloom: RwLock<Bloom>,
+    indexes: Mutex<BTreeMap<String, IndexDef>>,
+    views: Mutex<BTreeMap<String, ViewDef>>,
+    validators: RwLock<BTreeMap<String, Validator>>,
+    commit_validators: RwLock<BTreeMap<String, CommitValidator>>,
+    schemas: RwLock<BTreeMap<String, Arc<Schema>>>,
+    audit: AtomicBool,
+    retention: RwLock<BTreeMap<String, Retention>>,
+    default_retention: RwLock<Retention>,
+    policies: Policies,
+    keyspace_usage: Mutex<BTreeMap<String, usize>>,
+    clock: RwLock<Arc<dyn Clock>>,
+    key_orders: RwLock<BTreeMap<String, KeyOrder>>,
+    case_folded: RwLock<BTreeSet<String>>,
+    point_index: RwLock<BTreeMap<String, PointIndex>>,
+    closed: AtomicBool,
+    dir_lock: Mutex<Option<File>>,
+    source: Option<Arc<dyn Storage>>,
+    recycle_wal: bool,
+    max_key_bytes: AtomicUsize,
+    max_value_bytes: AtomicUsize,
+    manifest: Mutex<()>,
+    pins: Arc<Mutex<BTreeMap<Version, usize>>>,
+    quarantine: RwLock<Quarantine>,
+    hot: HotKeys,
+    slow_threshold: AtomicU64,
+    slow_log: Mutex<std::collections::VecDeque<SlowTxn>>,
+    recorder: RwLock<Option<Arc<Recorder>>>,
+    open_txns: Mutex<BTreeMap<u64, OpenTxn>>,
+    admission: RwLock<AdmissionLimits>,
+    pipeline: Arc<CommitPipeline>,
+    encryption: Option<Arc<EncryptedStorage>>,
+    wal_backlog: AtomicU64,
+    wal_segments: AtomicUsize,
+    next_txn: AtomicU64,
+    draining: AtomicU64,
+    fenced: AtomicU64,
+    sample: RwLock<KeySample>,
+    backfilling: AtomicBool,
+    #[cfg(feature = "fault-injection")]
+    faults: Arc<RwLock<Option<Arc<FaultInjector>>>>,
+}
+#[derive(Default)]
+pub struct KVStoreBuilder {
+    path: Option<PathBuf>,
+    durability: Option<Durability>,
+    read_cache_slots: Option<usize>,
+    gc_interval: Option<Duration>,
+    expiry_interval: Option<Duration>,
+    follow_interval: Option<Duration>,
+    read_only: bool,
+    direct_io: bool,
+    wal_preallocate: u64,
+    recycle_wal: bool,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+}
+impl KVStoreBuilder {
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+    pub fn read_cache_slots(mut self, slots: usize) -> Self {
+        self.read_cache_slots = Some(slots);
+        self
+    }
+    pub fn gc_interval(mut self, interval: Duration) -> Self {
+        self.gc_interval = Some(interval);
+        self
+    }
+    pub fn expiry_interval(mut self, interval: Duration) -> Self {
+        self.expiry_interval = Some(interval);
+        self
+    }
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    pub fn follow_wal(mut self, interval: Duration) -> Self {
+        self.follow_interval = Some(interval);
+        self
+    }
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+    pub fn wal_preallocate(mut self, bytes: u64) -> Self {
+        self.wal_preallocate = bytes;
+        self
+    }
+    pub fn recycle_wal(mut self, enabled: bool) -> Self {
+        self.recycle_wal = enabled;
+        self
+    }
+    pub fn max_key_bytes(mut self, bytes: usize) -> Self {
+        self.max_key_bytes = Some(bytes);
+        self
+    }
+    pub fn max_value_bytes(mut self, bytes: usize) -> Self {
+        self.max_value_bytes = Some(bytes);
+        self
+    }
+    pub fn master_key(self, key: [u8; 32]) -> Self {
+        self.key_provider(Arc::new(StaticKey(key)))
+    }
+    pub fn key_provider(mut self, provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(provider);
+        self
+    }
+    pub fn open(self) -> Result<Arc<KVStore>> {
+        let invalid = |option, reason: String| Err(KVError::InvalidConfig { option, reason });
+        let read_cache_slots = self.read_cache_slots.unwrap_or(READ_CACHE_SLOTS);
+        if read_cache_slots == 0 || read_cache_slots > 1 << 16 {
+            return invalid("read_cache_slots", format!("{} is outside 1..=65536", read_cache_slots));
+        }
+        if self.max_key_bytes == Some(0) {
+            return invalid("max_key_bytes", "the limit must be non-zero".to_owned());
+        }
+        if self.gc_interval.is_some_and(|interval| interval.is_zero()) {
+            return invalid("gc_interval", "interval must be non-zero".to_owned());
+        }
+        match self.expiry_interval {
+            Some(interval) if interval.is_zero() => return invalid("expiry_interval", "interval must be non-zero".to_owned()),
+            Some(_) if self.read_only => return invalid("expiry_interval", "a read-only store cannot expire keys".to_owned()),
+            _ => {}
+        }
+        match self.follow_interval {
+            Some(interval) if interval.is_zero() => return invalid("follow_wal", "interval must be non-zero".to_owned()),
+            Some(_) if !self.read_only => return invalid("follow_wal", "only a read-only store follows the log".to_owned()),
+            _ => {}
+        }
+        if let Some(path) = self.path.as_ref().filter(|path| path.exists() && !path.is_dir()) {
+            return invalid("path", format!("{} is not a directory", path.display()));
+        }
+        let mut store = match &self.path {
+            Some(_) if self.read_only && self.durability.is_some() => {
+                return invalid("durability", "a read-only store never writes its log".to_owned());
+            }
+            Some(path) if self.read_only => KVStore::load_encrypted(Arc::new(FsStorage::new(path)), false, self.key_provider)?,
+            Some(path) => KVStore::open_dir(
+                FsStorage::with_durability(path, self.durability.unwrap_or_default())
+                    .direct_io(self.direct_io)
+                    .preallocate(self.wal_preallocate),
+                self.key_provider,
+            )?,
+            None if self.read_only => return invalid("read_only", "a read-only store needs a path".to_owned()),
+            None if self.direct_io => return invalid("direct_io", "an in-memory store writes no files".to_owned()),
+            None if self.key_provider.is_some() => return invalid("key_provider", "an in-memory store writes no files".to_owned()),
+            None if self.durability.is_some() => {
+                return invalid("durability", "an in-memory store has no log to sync; set a path".to_owned());
+            }
+            None => KVStore::from_parts(VersionMap::new(), Vec::new(), 0, None, BTreeMap::new()),
+        };
+        store.cache = ReadCache::new(read_cache_slots, store.policies.clone());
+        store.recycle_wal = self.recycle_wal;
+        store.set_size_limits(self.max_key_bytes.unwrap_or(MAX_KEY_BYTES), self.max_value_bytes.unwrap_or(MAX_VALUE_BYTES));
+        let store = Arc::new(store);
+        if let Some(interval) = self.gc_interval {
+            spawn_every(Arc::downgrade(&store), interval, |store| {
+                store.gc();
+            });
+        }
+        if let Some(interval) = self.expiry_interval {
+            spawn_every(Arc::downgrade(&store), interval, |store| {
+                let _ = store.sweep_expired();
+            });
+        }
+        if let Some(interval) = self.follow_interval {
+            store.follow_wal(interval)?;
+        }
+        store.build_indexes();
+        Ok(store)
+    }
+}
+fn spawn_every(store: Weak<KVStore>, interval: Duration, task: impl Fn(&KVStore) + Send + 'static) {
+    thread::spawn(move || {
+        let mut due = Instant::now() + interval;
+        loop {
+            thread::sleep(due.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+            let Some(store) = store.upgrade().filter(|store| !store.is_closed()) else {
+                break;
+            };
+            if Instant::now() >= due {
+                due += interval;
+                task(&store);
+            }
+        }
+    });
+}
+fn map_bytes(map: &VersionMap) -> usize {
+    map.iter().map(|(key, versions)| chain_bytes(key, versions)).sum()
+}
+impl KVStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::from_parts(VersionMap::new(), Vec::new(), 0, None, BTreeMap::new()))
+    }
+    fn from_parts(
+        data: VersionMap,
+        timeline: Timeline,
+        version: Version,
+        storage: Option<(Arc<dyn Storage>, Wal)>,
+        prepared: BTreeMap<u64, (Writes, CommitMeta)>,
+    ) -> Self {
+        let (storage, wal) = storage.map_or((None, None), |(storage, wal)| (Some(storage), Some(Mutex::new(wal))));
+        let pipeline = Arc::new(CommitPipeline::default());
+        let policies = Policies::default();
+        #[cfg(feature = "fault-injection")]
+        let faults: Arc<RwLock<Option<Arc<FaultInjector>>>> = Arc::new(RwLock::new(None));
+        if let Some(storage) = storage.clone() {
+            #[cfg(feature = "fault-injection")]
+            let faults = Arc::clone(&faults);
+            pipeline.start_writer(move |staged| {
+                #[cfg(feature = "fault-injection")]
+                if let Some(injector) = faults.read().unwrap().clone() {
+                    injector.hit(FaultPoint::BeforeWalSync)?;
+                }
+                write_staged(storage.as_ref(), staged)
+            });
+        }
+        Self {
+            timeline: Mutex::new(timeline),
+            memory: AtomicUsize::new(map_bytes(&data)),
+            memory_limit: AtomicUsize::new(usize::MAX),
+            evict: AtomicBool::new(false),
+            recency: Mutex::new(Recency::default()),
+            cache: ReadCache::new(READ_CACHE_SLOTS, policies.clone()),
+            bloom: RwLock::new(Bloom::build(data.keys().map(Key::as_str))),
+            sample: RwLock::new(KeySample::build(&data)),
+            indexes: Mutex::new(load_indexes(&data)),
+            views: Mutex::new(load_views(&data)),
+            validators: RwLock::new(BTreeMap::new()),
+            commit_validators: RwLock::new(BTreeMap::new()),
+            schemas: RwLock::new(BTreeMap::new()),
+            audit: AtomicBool::new(false),
+            retention: RwLock::new(BTreeMap::new()),
+            default_retention: RwLock::new(Retention::KeepAll),
+            policies,
+            keyspace_usage: Mutex::new(BTreeMap::new()),
+            clock: RwLock::new(Arc::new(SystemClock)),
+            key_orders: RwLock::new(BTreeMap::new()),
+            case_folded: RwLock::new(BTreeSet::new()),
+            point_index: RwLock::new(BTreeMap::new()),
+            closed: AtomicBool::new(false),
+            dir_lock: Mutex::new(None),
+            source: None,
+            recycle_wal: false,
+            max_key_bytes: AtomicUsize::new(MAX_KEY_BYTES),
+            max_value_bytes: AtomicUsize::new(MAX_VALUE_BYTES),
+            manifest: Mutex::new(()),
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+            quarantine: RwLock::new(Quarantine::new()),
+            hot: HotKeys::default(),
+            slow_threshold: AtomicU64::new(u64::MAX),
+            slow_log: Mutex::new(std::collections::VecDeque::new()),
+            recorder: RwLock::new(None),
+            open_txns: Mutex::new(BTreeMap::new()),
+            admission: RwLock::new(AdmissionLimits::default()),
+            pipeline,
+            encryption: None,
+            wal_backlog: AtomicU64::new(0),
+            wal_segments: AtomicUsize::new(0),
+            next_txn: AtomicU64::new(now_millis() << 16),
+            draining: AtomicU64::new(u64::MAX),
+            fenced: AtomicU64::new(0),
+            backfilling: AtomicBool::new(false),
+            #[cfg(feature = "fault-injection")]
+            faults,
+            engine: Engine::new(data, version),
+            storage,
+            wal,
+            feed: Mutex::new(Vec::new()),
+            listeners: Mutex::new(Vec::new()),
+            proposer: OnceLock::new(),
+            prepared: Mutex::new(prepared),
+            attached: Mutex::new(BTreeMap::new()),
+            coordinator: OnceLock::new(),
+            parent: None,
+            branches: Mutex::new(BTreeMap::new()),
+            gc_watermark: AtomicU64::new(0),
+        }
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let store = Arc::new(Self::open_dir(FsStorage::new(path), None)?);
+        store.build_indexes();
+        Ok(store)
+    }
+    fn open_dir(storage: FsStorage, key_provider: Option<Arc<dyn KeyProvider>>) -> Result<Self> {
+        fs::create_dir_all(&storage.dir)?;
+        let lock = lock_dir(&storage.dir)?;
+        let mut store = Self::load_encrypted(Arc::new(storage), true, key_provider)?;
+        store.dir_lock = Mutex::new(Some(lock));
+        Ok(store)
+    }
+    fn load_encrypted(storage: Arc<dyn Storage>, writable: bool, key_provider: Option<Arc<dyn KeyProvider>>) -> Result<Self> {
+        let Some(provider) = key_provider else {
+            return Self::load(storage, writable);
+        };
+        let encryption = Arc::new(EncryptedStorage::open(storage, provider, writable)?);
+        let mut store = Self::load(Arc::clone(&encryption) as Arc<dyn Storage>, writable)?;
+        store.encryption = Some(encryption);
+        Ok(store)
+    }
+    pub fn open_migrated<P: AsRef<Path>>(path: P, migrations: &Migrations) -> Result<Arc<Self>> {
+        let store = Self::open(path)?;
+        migrations.apply(&store)?;
+        Ok(store)
+    }
+    pub fn open_storage(storage: Arc<dyn Storage>) -> Result<Arc<Self>> {
+        let store = Arc::new(Self::load(storage, true)?);
+        store.build_indexes();
+        Ok(store)
+    }
+    pub fn builder() -> KVStoreBuilder {
+        KVStoreBuilder::default()
+    }
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        Self::load(Arc::new(FsStorage::new(path)), false).map(Arc::new)
+    }
+    fn load(storage: Arc<dyn Storage>, writable: bool) -> Result<Self> {
+        let names = storage.list()?;
+        if names.iter().any(|name| name == KEYRING) {
+            return Err(KVError::InvalidInput("store is encrypted; key required".to_owned()));
+        }
+        let mut manifest = StoreManifest::read(storage.as_ref())?;
+        for name in manifest.files("snap").into_iter().chain(manifest.files("wal")).map(|(_, name)| name) {
+            if !names.contains(&name) {
+                return Err(KVError::Corruption(format!("manifest lists missing file {}", name)));
+            }
+        }
+        let (snapshot_version, mut data, mut timeline) = match storage_files(storage.as_ref(), "snap")?.last() {
+            Some((_, name)) => decode_snapshot(&storage.read(name)?)?,
+            None => (0, VersionMap::new(), Vec::new()),
+        };
+        let segments = storage_files(storage.as_ref(), "wal")?;
+        let mut last_version = snapshot_version;
+        let mut tail_empty = true;
+        let mut prepared = BTreeMap::new();
+        let mut quarantine = load_quarantine(storage.as_ref())?;
+        for (i, (_, name)) in segments.iter().enumerate() {
+            let bytes = storage.read(name)?;
+            let mut segment = parse_segment(&bytes, name)?;
+            let last = i + 1 == segments.len();
+            if segment.torn {
+                let (frames, damaged, end) = salvage_frames(&bytes);
+                if !last || frames.len() > segment.records.len() + segment.markers.len() {
+                    for range in damaged.into_iter().filter(|range| !last || range.start < end) {
+                        let reason = format!("damaged record in {} at offset {}", name, range.start);
+                        let Some((version, keys)) = damaged_keys(&bytes[range.clone()]) else {
+                            return Err(KVError::Corruption(reason));
+                        };
+                        if version <= snapshot_version {
+                            continue;
+                        }
+                        if writable {
+                            storage.write(&format!("{}.{}.bad", name, range.start), &bytes[range])?;
+                        }
+                        for key in keys {
+                            quarantine.insert(key, Quarantined { version, reason: reason.clone() });
+                        }
+                    }
+                    (segment.records, segment.markers) = decode_frames(frames)?;
+                    segment.valid_len = end as u64;
+                }
+            }
+            if writable && last && segment.valid_len < bytes.len() as u64 {
+                storage.discard_tail(name, segment.valid_len)?;
+            }
+            tail_empty = segment.valid_len == 0;
+            for marker in segment.markers {
+                match marker {
+                    TxnMarker::Prepared(id, writes, meta) => prepared.insert(id, (writes, meta)),
+                    TxnMarker::Aborted(id) => prepared.remove(&id),
+                };
+            }
+            for record in segment.records {
+                if let Some(id) = record.txn {
+                    prepared.remove(&id);
+                }
+                if record.version <= snapshot_version {
+                    continue;
+                }
+                last_version = last_version.max(record.version);
+                timeline.push((record.version, record.timestamp));
+                for (key, value) in record.writes {
+                    data.insert(key, record.version, value);
+                }
+            }
+        }
+        quarantine.retain(|key, entry| {
+            data.get(key).and_then(|versions| versions.latest()).is_none_or(|(v, _)| *v < entry.version)
+        });
+        if !writable {
+            let mut store = Self::from_parts(data, timeline, last_version, None, BTreeMap::new());
+            store.source = Some(storage);
+            store.quarantine = RwLock::new(quarantine);
+            for (keyspace, policy) in manifest.policies {
+                store.install_policy(&keyspace, policy);
+            }
+            store.audit = AtomicBool::new(manifest.audit);
+            store.resume_purges();
+            return Ok(store);
+        }
+        save_quarantine(storage.as_ref(), &quarantine)?;
+        let base = segments.last().map_or(snapshot_version, |(base, _)| *base);
+        if tail_empty {
+            storage.append(&segment_name(base), WAL_MAGIC)?;
+        }
+        if manifest.segments.insert(base) || !names.iter().any(|name| name == MANIFEST) {
+            manifest.save(storage.as_ref())?;
+        }
+        let mut store = Self::from_parts(data, timeline, last_version, Some((storage, Wal { base })), prepared);
+        store.quarantine = RwLock::new(quarantine);
+        store.wal_segments = AtomicUsize::new(manifest.segments.len());
+        for (keyspace, policy) in manifest.policies {
+            store.install_policy(&keyspace, policy);
+        }
+        store.audit = AtomicBool::new(manifest.audit);
+        store.resume_purges();
+        Ok(store)
+    }
+    pub fn quarantined(&self) -> Vec<(String, Version)> {
+        self.quarantine.read().unwrap().iter().map(|(key, entry)| (key.clone(), entry.version)).collect()
+    }
+    fn check_quarantine(&self, key: &str, version: Version) -> Result<()> {
+        let quarantine = self.quarantine.read().unwrap();
+        let Some(entry) = quarantine.get(key).filter(|entry| version >= entry.version).cloned() else {
+            return Ok(());
+        };
+        drop(quarantine);
+        if self.modified_at(key, version).is_some_and(|v| v >= entry.version) || self.read_repair(key, entry.version)? {
+            return Ok(());
+        }
+        Err(KVError::Corruption(format!("{} is quarantined: {}", key, entry.reason)))
+    }
+    fn read_repair(&self, key: &str, version: Version) -> Result<bool> {
+        let Some(value) = self.proposer.get().and_then(|proposer| proposer.repair(key, version)) else {
+            return Ok(false);
+        };
+        self.settled(|state| {
+            let added = version_bytes(&value) + if state.map.contains_key(key) { 0 } else { key_bytes(key) };
+            self.bloom.read().unwrap().insert(key);
+            state.map.insert(key.to_owned(), version, value);
+            let (shared, versions) = state.map.get_key_value(key).unwrap();
+            let (&latest, value) = versions.latest().unwrap();
+            self.sample.write().unwrap().update(shared, value.as_deref());
+            self.memory.fetch_add(added, Ordering::Relaxed);
+            self.cache.invalidate(key);
+            if let Some(entries) = self.point_index.write().unwrap().get_mut(keyspace_of(key)) {
+                entries.insert(shared.clone(), (latest, value.as_deref().map(str::to_owned)));
+            }
+        });
+        let mut quarantine = self.quarantine.write().unwrap();
+        quarantine.remove(key);
+        if let Some(storage) = &self.storage {
+            save_quarantine(storage.as_ref(), &quarantine)?;
+        }
+        Ok(true)
+    }
+    pub fn restore_to<P: AsRef<Path>>(path: P, target: VersionOrTimestamp) -> Result<Arc<Self>> {
+        Self::restore_from(&FsStorage::new(path), target)
+    }
+    pub fn restore_from(storage: &dyn Storage, target: VersionOrTimestamp) -> Result<Arc<Self>> {
+        let mut records = Vec::new();
+        let segments = storage_files(storage, "wal")?;
+        for (_, name) in &segments {
+            records.extend(parse_segment(&storage.read(name)?, name)?.records);
+        }
+        let target = match target {
+            VersionOrTimestamp::Version(version) => version,
+            VersionOrTimestamp::Timestamp(time) => {
+                let millis = time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+                records.iter().filter(|r| r.timestamp <= millis).map(|r| r.version).max().unwrap_or(0)
+            }
+        };
+        let snapshots = storage_files(storage, "snap")?;
+        let (snapshot_version, mut data, mut timeline) = match snapshots.iter().rev().find(|(v, _)| *v <= target) {
+            Some((_, name)) => decode_snapshot(&storage.read(name)?)?,
+            None => {
+                let oldest = snapshots.first().into_iter().chain(segments.first()).map(|(base, _)| *base).min();
+                if let Some(oldest) = oldest.filter(|&oldest| oldest > 0) {
+                    return Err(KVError::InvalidInput(format!("version {} predates oldest backup base {}", target, oldest)));
+                }
+                (0, VersionMap::new(), Vec::new())
+            }
+        };
+        let mut last_version = snapshot_version;
+        for record in records.into_iter().filter(|r| r.version > snapshot_version && r.version <= target) {
+            last_version = last_version.max(record.version);
+            timeline.push((record.version, record.timestamp));
+            for (key, value) in record.writes {
+                data.insert(key, record.version, value);
+            }
+        }
+        Ok(Arc::new(Self::from_parts(data, timeline, last_version, None, BTreeMap::new())))
+    }
+    pub fn salvage_from(storage: &dyn Storage) -> Result<(Arc<Self>, SalvageReport)> {
+        let mut report = SalvageReport::default();
+        let (mut base, snapshots) = (None, scan_files(storage, "snap")?);
+        for (_, name) in snapshots.iter().rev() {
+            match storage.read(name).and_then(|bytes| decode_snapshot(&bytes)) {
+                Ok(snapshot) if base.is_none() => base = Some(snapshot),
+                Ok(_) => {}
+                Err(e) => report.skipped.push(format!("{}: {}", name, e)),
+            }
+        }
+        if base.is_none() && !snapshots.is_empty() {
+            report.skipped.push("no readable snapshot, rebuilding from the wal alone".to_owned());
+        }
+        let (snapshot_version, mut data, mut timeline) = base.unwrap_or((0, VersionMap::new(), Vec::new()));
+        let mut records = BTreeMap::new();
+        for (_, name) in scan_files(storage, "wal")? {
+            let bytes = match storage.read(&name) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    report.skipped.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            };
+            if !bytes.is_empty() && !bytes.starts_with(WAL_MAGIC) {
+                report.skipped.push(format!("{}: bad segment header", name));
+            }
+            let (frames, damaged, _) = salvage_frames(&bytes);
+            for range in damaged {
+                report.skipped.push(format!("{}: {} unreadable bytes at offset {}", name, range.len(), range.start));
+            }
+            for payload in frames.into_iter().filter(|payload| !payload.starts_with(&[0; 8])) {
+                match WalRecord::decode(payload) {
+                    Ok(record) if record.version > snapshot_version => {
+                        records.insert(record.version, record);
+                    }
+                    Ok(_) => {}
+                    Err(e) => report.skipped.push(format!("{}: {}", name, e)),
+                }
+            }
+        }
+        let mut last_version = snapshot_version;
+        for (version, record) in records {
+            if version > last_version + 1 {
+                report.skipped.push(format!("versions {}..={} are not recoverable", last_version + 1, version - 1));
+            }
+            last_version = version;
+            timeline.push((version, record.timestamp));
+            for (key, value) in record.writes {
+                data.insert(key, version, value);
+            }
+            report.records += 1;
+        }
+        let store = Arc::new(Self::from_parts(data, timeline, last_version, None, BTreeMap::new()));
+        report.version = last_version;
+        report.keys = store.keyspace_counts().values().sum();
+        Ok((store, report))
+    }
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<Version> {
+        fs::create_dir_all(path.as_ref())?;
+        self.save_to_storage(&FsStorage::new(path))
+    }
+    pub fn save_to_storage(&self, storage: &dyn Storage) -> Result<Version> {
+        if !scan_files(storage, "snap")?.is_empty() || !scan_files(storage, "wal")?.is_empty() {
+            return Err(KVError::InvalidInput("target storage already contains a store".to_owned()));
+        }
+        let (version, bytes) = self.settled(|state| {
+            (state.version, encode_snapshot(state.version, &state.map, &self.timeline.lock().unwrap(), &self.compression()))
+        });
+        storage.write(&snapshot_name(version), &bytes)?;
+        let policies = self.keyspace_policies();
+        let audit = self.audit.load(Ordering::Relaxed);
+        StoreManifest { snapshots: BTreeSet::from([version]), segments: BTreeSet::new(), policies, audit }.save(storage)?;
+        Ok(version)
+    }
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<Version> {
+        let Some(storage) = &self.storage else {
+            return self.save_to(path);
+        };
+        let dir = path.as_ref();
+        fs::create_dir_all(dir)?;
+        if fs::read_dir(dir)?.next().is_some() {
+            return Err(KVError::InvalidInput(format!("backup target {} is not empty", dir.display())));
+        }
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.copy_live_files(storage.as_ref(), dir) {
+                Err(KVError::Io(e)) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    fs::remove_dir_all(dir)?;
+                    fs::create_dir_all(dir)?;
+                }
+                result => return result,
+            }
+        }
+    }
+    fn copy_live_files(&self, storage: &dyn Storage, dir: &Path) -> Result<Version> {
+        let (version, manifest, tail) = self.settled(|state| -> Result<_> {
+            let _guard = self.manifest.lock().unwrap();
+            let tail = self.wal.as_ref().map(|wal| wal.lock().unwrap().base);
+            Ok((state.version, StoreManifest::read(storage)?, tail))
+        })?;
+        let _pin = self.pin(version);
+        let copy = |name: &str| -> Result<()> {
+            let (_, mut stream) = storage.read_stream(name)?;
+            let mut file = File::create(dir.join(name))?;
+            io::copy(&mut stream, &mut file)?;
+            file.sync_all()?;
+            Ok(())
+        };
+        let mut backup = StoreManifest { policies: manifest.policies.clone(), audit: manifest.audit, ..StoreManifest::default() };
+        let snapshot = manifest.snapshots.range(..=version).next_back().copied();
+        if let Some(v) = snapshot {
+            copy(&snapshot_name(v))?;
+            backup.snapshots.insert(v);
+        }
+        let bases: Vec<Version> = manifest.segments.iter().copied().collect();
+        for (i, &base) in bases.iter().enumerate() {
+            if bases.get(i + 1).is_some_and(|next| *next <= snapshot.unwrap_or(0)) {
+                continue;
+            }
+            let name = segment_name(base);
+            if Some(base) == tail {
+                let bytes = storage.read(&name)?;
+                let mut segment = WAL_MAGIC.to_vec();
+                for payload in segment_frames(&bytes).0 {
+                    let record = !payload.starts_with(&[0; 8]);
+                    if record && u64::from_le_bytes(payload[..8].try_into().unwrap()) > version {
+                        break;
+                    }
+                    segment.extend_from_slice(&frame(payload));
+                }
+                let mut file = File::create(dir.join(&name))?;
+                file.write_all(&segment)?;
+                file.sync_all()?;
+            } else {
+                copy(&name)?;
+            }
+            backup.segments.insert(base);
+        }
+        if storage.list()?.iter().any(|name| name == QUARANTINE) {
+            copy(QUARANTINE)?;
+        }
+        backup.save(&FsStorage::new(dir))?;
+        Ok(version)
+    }
+    pub fn export<W: Write>(&self, writer: W) -> Result<ExportReport> {
+        self.export_to(writer, false)
+    }
+    pub fn export_compressed<W: Write>(&self, writer: W) -> Result<ExportReport> {
+        self.export_to(writer, true)
+    }
+    fn export_to<W: Write>(&self, mut writer: W, compress: bool) -> Result<ExportReport> {
+        let version = self.current_version();
+        let _pin = self.pin(version);
+        let mut report = ExportReport { version, ..ExportReport::default() };
+        let mut header = EXPORT_MAGIC.to_vec();
+        put_u64(&mut header, version);
+        header.push(compress as u8);
+        writer.write_all(&header)?;
+        report.bytes += header.len() as u64;
+        let mut emit = |chunk: &[(String, String)], report: &mut ExportReport| -> Result<()> {
+            let mut payload = Vec::new();
+            put_u32(&mut payload, chunk.len() as u32);
+            for (key, value) in chunk {
+                put_str(&mut payload, key);
+                put_str(&mut payload, value);
+            }
+            let payload = match compress {
+                true => {
+                    let mut packed = vec![EXPORT_PACKED];
+                    put_u32(&mut packed, payload.len() as u32);
+                    packed.extend_from_slice(&lzf_compress(&payload));
+                    packed
+                }
+                false => [&[EXPORT_CHUNK][..], &payload].concat(),
+            };
+            let framed = frame(&payload);
+            writer.write_all(&framed)?;
+            report.entries += chunk.len() as u64;
+            report.bytes += framed.len() as u64;
+            Ok(())
+        };
+        if self.parent.is_some() {
+            let rows = self.fold_at(.., version, Vec::new(), |mut rows, key, value| {
+                rows.push((key.to_owned(), value.to_owned()));
+                rows
+            });
+            for chunk in rows.chunks(SCAN_CHUNK) {
+                emit(chunk, &mut report)?;
+            }
+        } else {
+            let mut start = Bound::Unbounded;
+            loop {
+                let (chunk, last) = self.engine.with(|state| {
+                    let mut chunk = Vec::new();
+                    let mut last = None;
+                    for (key, versions) in state.map.range((start.clone(), Bound::Unbounded)).take(SCAN_CHUNK) {
+                        if let Some((_, Some(value))) = versions.at(version) {
+                            chunk.push((key.to_string(), value.to_string()));
+                        }
+                        last = Some(key.to_string());
+                    }
+                    (chunk, last)
+                });
+                if !chunk.is_empty() {
+                    emit(&chunk, &mut report)?;
+                }
+                match last {
+                    Some(key) => start = Bound::Excluded(key),
+                    None => break,
+                }
+            }
+        }
+        let mut end = vec![EXPORT_END];
+        put_u64(&mut end, report.entries);
+        let framed = frame(&end);
+        writer.write_all(&framed)?;
+        writer.flush()?;
+        report.bytes += framed.len() as u64;
+        Ok(report)
+    }
+    pub fn import<R: Read>(self: &Arc<Self>, mut reader: R, strategy: MergeStrategy) -> Result<ImportReport> {
+        let mut header = [0; 17];
+        read_export(&mut reader, &mut header)?;
+        if header[..8] != EXPORT_MAGIC[..] {
+            return Err(KVError::Corruption("not a snapshot export".to_owned()));
+        }
+        let source_version = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let mut report = ImportReport { source_version, version: self.current_version(), ..ImportReport::default() };
+        loop {
+            let payload = read_export_frame(&mut reader)?.ok_or_else(|| KVError::Corruption("export stream is truncated".to_owned()))?;
+            let mut d = Decoder::new(&payload);
+            match d.u8()? {
+                EXPORT_CHUNK => self.import_chunk(&payload[1..], strategy, &mut report)?,
+                EXPORT_PACKED => {
+                    let len = d.u32()? as usize;
+                    self.import_chunk(&lzf_decompress(&payload[5..], len)?, strategy, &mut report)?
+                }
+                EXPORT_END => {
+                    let entries = d.u64()?;
+                    if entries != report.entries {
+                        return Err(KVError::Corruption(format!(
+                            "export ends after {} of {} entries",
+                            report.entries, entries
+                        )));
+                    }
+                    return Ok(report);
+                }
+                tag => return Err(KVError::Corruption(format!("unknown export frame {}", tag))),
+            }
+        }
+    }
+    pub fn export_oplog<W: Write>(&self, mut writer: W, after: Version) -> Result<OplogReport> {
+        let backlog = self.settled(|state| match after < self.gc_watermark.load(Ordering::Relaxed) {
+            true => Err(KVError::InvalidInput(format!("history after version {} has been collected", after))),
+            false => Ok(self.backlog_after(state, after)),
+        })?;
+        let mut report = OplogReport { source_version: after, version: self.current_version(), ..OplogReport::default() };
+        writer.write_all(OPLOG_MAGIC)?;
+        for batch in &backlog {
+            writer.write_all(&batch.encode())?;
+            report.source_version = batch.version;
+            report.batches += 1;
+            report.operations += batch.writes.len() as u64;
+        }
+        writer.flush()?;
+        Ok(report)
+    }
+    pub fn apply_oplog<R: Read>(self: &Arc<Self>, mut reader: R) -> Result<OplogReport> {
+        let mut magic = [0; 8];
+        read_export(&mut reader, &mut magic)?;
+        let mut report = OplogReport { version: self.current_version(), ..OplogReport::default() };
+        if &magic == OPLOG_MAGIC {
+            while let Some(payload) = read_export_frame(&mut reader)? {
+                let record = WalRecord::decode(&payload)?;
+                if record.version <= report.source_version {
+                    return Err(KVError::Corruption(format!(
+                        "operation log goes back from version {} to {}",
+                        report.source_version, record.version
+                    )));
+                }
+                report.source_version = record.version;
+                self.replay_batch(record.writes, &mut report)?;
+            }
+            return Ok(report);
+        }
+        if &magic != EXPORT_MAGIC {
+            return Err(KVError::Corruption("not an operation log".to_owned()));
+        }
+        let mut header = [0; 9];
+        read_export(&mut reader, &mut header)?;
+        report.source_version = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let mut entries = 0;
+        loop {
+            let payload = read_export_frame(&mut reader)?.ok_or_else(|| KVError::Corruption("export stream is truncated".to_owned()))?;
+            let mut d = Decoder::new(&payload);
+            let writes = match d.u8()? {
+                EXPORT_CHUNK => export_rows(&payload[1..])?,
+                EXPORT_PACKED => export_rows(&lzf_decompress(&payload[5..], d.u32()? as usize)?)?,
+                EXPORT_END => match d.u64()? {
+                    total if total == entries => return Ok(report),
+                    total => return Err(KVError::Corruption(format!("export ends after {} of {} entries", entries, total))),
+                },
+                tag => return Err(KVError::Corruption(format!("unknown export frame {}", tag))),
+            };
+            entries += writes.len() as u64;
+            self.replay_batch(writes, &mut report)?;
+        }
+    }
+    fn replay_batch(self: &Arc<Self>, writes: Writes, report: &mut OplogReport) -> Result<()> {
+        let mut tx = self.begin();
+        for (key, value) in writes {
+            if matches!(keyspace_of(&key), INDEX_KEYSPACE | AUDIT_KEYSPACE) {
+                report.skipped += 1;
+                continue;
+            }
+            match value {
+                Some(value) => {
+                    self.validate(&key, &value)?;
+                    tx.buffer.put(key, value)?;
+                }
+                None => tx.buffer.delete(key)?,
+            }
+            report.operations += 1;
+        }
+        report.version = tx.commit()?;
+        report.batches += 1;
+        Ok(())
+    }
+    fn import_chunk(self: &Arc<Self>, rows: &[u8], strategy: MergeStrategy, report: &mut ImportReport) -> Result<()> {
+        let mut d = Decoder::new(rows);
+        let mut tx = self.begin();
+        let snapshot = tx.buffer.snapshot_version();
+        for _ in 0..d.u32()? {
+            let (key, value) = (d.string()?, d.string()?);
+            report.entries += 1;
+            if matches!(keyspace_of(&key), INDEX_KEYSPACE | AUDIT_KEYSPACE) {
+                continue;
+            }
+            let existing = self.read_at(&key, snapshot);
+            let keep = match strategy {
+                _ if existing.as_ref() == Some(&value) => true,
+                MergeStrategy::Replace => false,
+                MergeStrategy::KeepNewer => self.modified_at(&key, snapshot).is_some_and(|v| v > report.source_version),
+                MergeStrategy::ErrorOnConflict if existing.is_some() => {
+                    return Err(KVError::InvalidInput(format!("import conflicts with existing key {:?}", key)))
+                }
+                MergeStrategy::ErrorOnConflict => false,
+            };
+            if keep {
+                report.skipped += 1;
+                continue;
+            }
+            self.validate(&key, &value)?;
+            tx.buffer.put(key, value)?;
+            report.written += 1;
+        }
+        report.version = tx.commit()?;
+        Ok(())
+    }
+    fn modified_at(&self, key: &str, version: Version) -> Option<Version> {
+        match self.engine.with(|state| state.map.get(key).and_then(|versions| versions.at(version)).map(|(v, _)| *v)) {
+            None => self.parent.as_ref().and_then(|(parent, fork)| parent.modified_at(key, version.min(*fork))),
+            found => found,
+        }
+    }
+    fn pin(&self, version: Version) -> Pin {
+        *self.pins.lock().unwrap().entry(version).or_default() += 1;
+        Pin { pins: Arc::clone(&self.pins), version }
+    }
+    pub fn begin(self: &Arc<Self>) -> Transaction {
+        self.begin_at(Version::MAX)
+    }
+    pub fn begin_at(self: &Arc<Self>, version: Version) -> Transaction {
+        let (buffer, pin) = self.engine.with(|state| {
+            let version = version.min(state.version);
+            (TxnBuffer::new(version), self.pin(version))
+        });
+        self.transaction(buffer, pin)
+    }
+    fn transaction(self: &Arc<Self>, mut buffer: TxnBuffer, pin: Pin) -> Transaction {
+        let open_started = Instant::now();
+        let policies = self.policies.clone();
+        buffer.scrub_with(move |key| policies.is_sensitive(key));
+        let attached = self.attached.lock().unwrap().iter().map(|(name, store)| (name.clone(), store.begin())).collect();
+        let id = self.next_txn.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "txn-backtrace")]
+        let backtrace = Some(Arc::new(std::backtrace::Backtrace::force_capture()));
+        #[cfg(not(feature = "txn-backtrace"))]
+        let backtrace = None;
+        let open = OpenTxn { snapshot: buffer.snapshot_version(), opened: Instant::now(), backtrace };
+        let snapshot = open.snapshot;
+        self.open_txns.lock().unwrap().insert(id, open);
+        self.record(id, open_started, || TraceOp::Begin { snapshot });
+        Transaction {
+            store: Arc::clone(self),
+            buffer,
+            attached,
+            misses: Mutex::new(BTreeSet::new()),
+            meta: CommitMeta::default(),
+            pin: Some(pin),
+            started: Instant::now(),
+            ticket: TxnTicket { store: Arc::clone(self), id },
+        }
+    }
+    pub fn long_running(&self, threshold: Duration) -> Vec<LongTxn> {
+        let mut long: Vec<LongTxn> = self
+            .open_txns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, txn)| LongTxn { id, snapshot: txn.snapshot, open_for: txn.opened.elapsed(), backtrace: txn.backtrace.clone() })
+            .filter(|txn| txn.open_for >= threshold)
+            .collect();
+        long.sort_by_key(|txn| std::cmp::Reverse(txn.open_for));
+        long
+    }
+    pub fn attach<P: AsRef<Path>>(self: &Arc<Self>, name: &str, path: P) -> Result<Arc<KVStore>> {
+        if name.is_empty() || name.contains(KEYSPACE_SEPARATOR) || name.starts_with("__") {
+            return Err(KVError::InvalidInput(format!("invalid keyspace name {:?}", name)));
+        }
+        if self.attached.lock().unwrap().contains_key(name) {
+            return Err(KVError::InvalidInput(format!("keyspace {} is already attached", name)));
+        }
+        let store = KVStore::open(path)?;
+        let coordinator = match self.coordinator.get() {
+            Some(coordinator) => coordinator,
+            None => {
+                let storage = self.storage.clone().unwrap_or_else(|| Arc::new(MemoryStorage::new()));
+                let _ = self.coordinator.set(Coordinator::open_storage(storage)?);
+                self.coordinator.get().unwrap()
+            }
+        };
+        coordinator.recover(&[self, &store])?;
+        let mut attached = self.attached.lock().unwrap();
+        if attached.contains_key(name) {
+            return Err(KVError::InvalidInput(format!("keyspace {} is already attached", name)));
+        }
+        attached.insert(name.to_owned(), Arc::clone(&store));
+        Ok(store)
+    }
+    pub fn attached(&self) -> Vec<String> {
+        self.attached.lock().unwrap().keys().cloned().collect()
+    }
+    pub fn branch(self: &Arc<Self>, name: &str) -> Result<Arc<KVStore>> {
+        let mut branches = self.branches.lock().unwrap();
+        if name == MAIN_BRANCH || branches.contains_key(name) {
+            return Err(KVError::InvalidInput(format!("branch {} already exists", name)));
+        }
+        let branch = self.fork();
+        branches.insert(name.to_owned(), Arc::clone(&branch));
+        Ok(branch)
+    }
+    pub fn fork(self: &Arc<Self>) -> Arc<KVStore> {
+        let (fork, next) = self.engine.with(|state| (state.version, Arc::clone(&state.next)));
+        let mut child = Self::from_parts(VersionMap::new(), Vec::new(), fork, None, BTreeMap::new());
+        child.parent = Some((Arc::clone(self), fork));
+        child.engine.with(|state| state.next = next);
+        Arc::new(child)
+    }
+    pub fn get_branch(&self, name: &str) -> Option<Arc<KVStore>> {
+        self.branches.lock().unwrap().get(name).cloned()
+    }
+    pub fn list_branches(&self) -> Vec<(String, Version)> {
+        self.branches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, branch)| (name.clone(), branch.fork_version().unwrap_or(0)))
+            .collect()
+    }
+    pub fn drop_branch(&self, name: &str) -> Result<()> {
+        self.branches
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| KVError::InvalidInput(format!("no branch named {}", name)))
+    }
+    pub fn merge(
+        self: &Arc<Self>,
+        from: &str,
+        into: &str,
+        mut resolver: impl FnMut(&BranchConflict) -> Option<String>,
+    ) -> Result<BranchMergeReport> {
+        let lookup = |name: &str| match name {
+            MAIN_BRANCH => Ok(Arc::clone(self)),
+            _ => self.get_branch(name).ok_or_else(|| KVError::InvalidInput(format!("no branch named {}", name))),
+        };
+        let (source, target) = (lookup(from)?, lookup(into)?);
+        if Arc::ptr_eq(&source, &target) {
+            return Err(KVError::InvalidInput(format!("cannot merge {} into itself", from)));
+        }
+        let fork = source.fork_version().into_iter().chain(target.fork_version()).min().unwrap();
+        let mut keys = BTreeSet::new();
+        for store in [self, &source, &target] {
+            store.settled(|state| {
+                keys.extend(
+                    state
+                        .map
+                        .iter()
+                        .filter(|(_, versions)| versions.range(fork + 1..).next().is_some())
+                        .map(|(key, _)| key.to_string()),
+                )
+            });
+        }
+        let theirs_tx = source.begin();
+        let mut tx = target.begin();
+        let mut report = BranchMergeReport::default();
+        for key in keys.into_iter().filter(|key| keyspace_of(key) != AUDIT_KEYSPACE) {
+            let base = self.read_at(&key, fork);
+            let theirs = theirs_tx.get(&key)?;
+            let ours = tx.get(&key)?;
+            if theirs == base || theirs == ours {
+                continue;
+            }
+            let value = if ours == base {
+                theirs
+            } else {
+                report.conflicts += 1;
+                resolver(&BranchConflict { key: key.clone(), base, ours: ours.clone(), theirs, policies: self.policies.clone() })
+            };
+            if value == ours {
+                continue;
+            }
+            match value {
+                Some(v) => tx.put_internal(key, v)?,
+                None => tx.delete_internal(key)?,
+            }
+            report.applied += 1;
+        }
+        report.version = tx.commit()?;
+        Ok(report)
+    }
+    pub fn fork_version(&self) -> Option<Version> {
+        self.parent.as_ref().map(|(_, fork)| *fork)
+    }
+    fn read_at(&self, key: &str, version: Version) -> Option<String> {
+        match self.read_shortcut(key, version) {
+            Some(value) => value,
+            None => self.engine.with(|state| self.read_chain(state, key, version)),
+        }
+    }
+    /// Reads several keys at one version, taking the engine lock once for every key the shortcuts could not answer.
+    fn read_many_at(&self, keys: &[&str], version: Version) -> Vec<Option<String>> {
+        let mut values = vec![None; keys.len()];
+        let mut locked = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            match self.read_shortcut(key, version) {
+                Some(value) => values[i] = value,
+                None => locked.push(i),
+            }
+        }
+        if !locked.is_empty() {
+            self.engine.with(|state| {
+                for i in locked {
+                    values[i] = self.read_chain(state, keys[i], version);
+                }
+            });
+        }
+        values
+    }
+    /// Answers a point read from the branch parent, the bloom filter, a point index or the read cache; `None` means
+    /// the version chain has to be searched under the engine lock.
+    fn read_shortcut(&self, key: &str, version: Version) -> Option<Option<String>> {
+        if self.evict.load(Ordering::Relaxed) {
+            self.recency.lock().unwrap().touch(key);
+        }
+        self.hot.record(key, false);
+        if let Some((parent, fork)) = &self.parent {
+            return Some(match self.engine.lookup_at(key, version) {
+                Some(value) => value,
+                None => parent.read_at(key, version.min(*fork)),
+            });
+        }
+        if !self.bloom.read().unwrap().may_contain(key) {
+            return Some(None);
+        }
+        if let Some(value) = self.point_lookup(key, version) {
+            return Some(value);
+        }
+        self.cache.get(key, version)
+    }
+    fn read_chain(&self, state: &mvcc::State, key: &str, version: Version) -> Option<String> {
+        let (shared, versions) = state.map.get_key_value(key)?;
+        let (&latest, value) = versions.iter().next_back()?;
+        if latest <= version {
+            self.cache.fill(shared, latest, value.as_deref().map(str::to_owned));
+            return value.as_deref().map(str::to_owned);
+        }
+        versions.at(version)?.1.as_deref().map(str::to_owned)
+    }
+    fn scan_at<R: RangeBounds<String> + Clone>(&self, range: R, version: Version) -> Vec<(String, String)> {
+        let Some((parent, fork)) = &self.parent else {
+            return self.engine.scan_at(range, version);
+        };
+        let mut merged: BTreeMap<String, String> = parent.scan_at(range.clone(), version.min(*fork)).into_iter().collect();
+        for (key, value) in self.engine.scan_raw_at(range, version) {
+            match value {
+                Some(v) => merged.insert(key, v),
+                None => merged.remove(&key),
+            };
+        }
+        merged.into_iter().collect()
+    }
+    fn fold_at<R: RangeBounds<String> + Clone, A>(
+        &self,
+        range: R,
+        version: Version,
+        init: A,
+        mut f: impl FnMut(A, &str, &str) -> A,
+    ) -> A {
+        if self.parent.is_some() {
+            return self.scan_at(range, version).iter().fold(init, |acc, (key, value)| f(acc, key, value));
+        }
+        self.engine.with(|state| {
+            state.map.range(range).fold(init, |acc, (key, versions)| match versions.at(version) {
+                Some((_, Some(value))) => f(acc, key, value),
+                _ => acc,
+            })
+        })
+    }
+    fn filtered_at<R: RangeBounds<String> + Clone>(&self, range: R, version: Version, filter: &ValueFilter) -> Vec<(String, String)> {
+        self.fold_at(range, version, Vec::new(), |mut rows, key, value| {
+            if filter.matches(value) {
+                rows.push((key.to_owned(), value.to_owned()));
+            }
+            rows
+        })
+    }
+    fn split_points(&self, range: (Bound<String>, Bound<String>), parts: usize) -> Vec<String> {
+        let mut root = self;
+        while let Some((parent, _)) = &root.parent {
+            root = parent;
+        }
+        root.engine.with(|state| {
+            let total = state.map.range(range.clone()).count();
+            if parts < 2 || total < parts {
+                return Vec::new();
+            }
+            let step = total.div_ceil(parts);
+            state.map.range(range).skip(step).step_by(step).map(|(key, _)| key.to_string()).collect()
+        })
+    }
+    fn fold_chunked<A>(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        version: Version,
+        init: A,
+        mut f: impl FnMut(A, &str, &str) -> A,
+    ) -> A {
+        if self.parent.is_some() {
+            return self.fold_at(range, version, init, f);
+        }
+        let (mut start, end) = range;
+        let mut acc = init;
+        loop {
+            let (next, last) = self.engine.with(|state| {
+                let mut acc = acc;
+                let mut seen = 0;
+                let mut last = None;
+                for (key, versions) in state.map.range((start, end.clone())).take(SCAN_CHUNK) {
+                    if let Some((_, Some(value))) = versions.at(version) {
+                        acc = f(acc, key, value);
+                    }
+                    seen += 1;
+                    last = Some(key);
+                }
+                (acc, last.filter(|_| seen == SCAN_CHUNK).map(Key::to_string))
+            });
+            acc = next;
+            match last {
+                Some(key) => start = Bound::Excluded(key),
+                None => return acc,
+            }
+        }
+    }
+    pub fn begin_at_time(self: &Arc<Self>, time: SystemTime) -> Result<Transaction> {
+        let millis = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let timeline = self.timeline.lock().unwrap();
+        let version = match timeline.partition_point(|&(_, t)| t <= millis) {
+            0 if timeline.first().is_some_and(|&(first, _)| first > 1) => {
+                return Err(KVError::InvalidInput(format!(
+                    "no commit times are recorded before version {}",
+                    timeline[0].0
+                )))
+            }
+            0 => 0,
+            i => timeline[i - 1].0,
+        };
+        drop(timeline);
+        Ok(self.begin_at(version))
+    }
+    pub fn commit_time(&self, version: Version) -> Option<SystemTime> {
+        let timeline = self.timeline.lock().unwrap();
+        let i = timeline.binary_search_by_key(&version, |&(v, _)| v).ok()?;
+        Some(UNIX_EPOCH + Duration::from_millis(timeline[i].1))
+    }
+    pub fn current_version(&self) -> Version {
+        self.engine.current_version()
+    }
+    pub fn set_version_scheme(&self, scheme: VersionScheme) {
+        self.set_version_source(match scheme {
+            VersionScheme::Counter => Arc::new(mvcc::Counter),
+            VersionScheme::HybridClock => Arc::new(HybridVersions(Arc::clone(&self.clock.read().unwrap()))),
+        })
+    }
+    pub fn set_version_source(&self, source: Arc<dyn VersionSource>) {
+        self.engine.with(|state| state.next = source)
+    }
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        Snapshot { store: Arc::clone(self), version: self.current_version() }
+    }
+    pub fn get_at(&self, keys: &[&str], version: Version) -> Result<Vec<Option<String>>> {
+        let pin = self.pin(version.min(self.current_version()));
+        keys.iter()
+            .map(|key| {
+                let key = self.fold_key(key).unwrap_or_else(|| (*key).to_owned());
+                self.check_quarantine(&key, pin.version)?;
+                Ok(self.read_at(&key, pin.version))
+            })
+            .collect()
+    }
+    pub fn get_latest(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        self.get_at(keys, self.current_version())
+    }
+    pub fn scan_continue<R: RangeBounds<String>>(
+        self: &Arc<Self>,
+        range: R,
+        cursor: &ScanCursor,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        if cursor.version > self.current_version() || cursor.version < self.gc_watermark.load(Ordering::Relaxed) {
+            return Err(KVError::InvalidInput(format!("scan cursor at v{} has expired", cursor.version)));
+        }
+        let snapshot = Snapshot { store: Arc::clone(self), version: cursor.version };
+        let (_, end) = self.fold_range(&range);
+        Ok(snapshot.page(Bound::Excluded(cursor.after.clone()), end, limit))
+    }
+    pub fn query(self: &Arc<Self>) -> Query {
+        self.snapshot().query()
+    }
+    pub fn subscribe(&self) -> mpsc::Receiver<Arc<ChangeBatch>> {
+        let (tx, rx) = mpsc::channel();
+        self.feed.lock().unwrap().push(tx);
+        rx
+    }
+    pub fn apply_batch(&self, source: &str, seq: u64, writes: Writes) -> Result<BatchOutcome> {
+        if source.is_empty() || source.contains('\n') {
+            return Err(KVError::InvalidInput(format!("invalid batch source {:?}", source)));
+        }
+        if self.is_read_only() {
+            return Err(KVError::ReadOnly);
+        }
+        if self.is_fenced() {
+            return Err(KVError::NotLeader(None));
+        }
+        writes.keys().try_for_each(|key| check_user_key(key))?;
+        self.admit()?;
+        let meta = CommitMeta { source: Some((source.to_owned(), seq)), ..CommitMeta::default() };
+        if let Some(proposer) = self.proposer.get() {
+            return proposer.propose(writes, meta).map(BatchOutcome::Applied);
+        }
+        let mut trace = CommitTrace::default();
+        let version = self.apply_traced(writes, meta, None, None, None, &mut trace)?;
+        Ok(match trace.applied_through {
+            Some(applied_through) => BatchOutcome::Skipped { applied_through },
+            None => BatchOutcome::Applied(version),
+        })
+    }
+    pub fn applied_through(&self, source: &str) -> Option<u64> {
+        self.engine.read_at(&source_key(source), self.current_version()).and_then(|seq| seq.parse().ok())
+    }
+    pub fn listen(&self, capacity: usize) -> Result<Listener> {
+        if capacity == 0 {
+            return Err(KVError::InvalidInput("listener capacity must be non-zero".to_owned()));
+        }
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        self.listeners.lock().unwrap().push(ListenerSlot { tx, missed: 0 });
+        Ok(Listener { rx })
+    }
+    fn subscribe_after(&self, after: Version) -> (CatchUp, mpsc::Receiver<Arc<ChangeBatch>>) {
+        self.settled(|state| {
+            if after < self.gc_watermark.load(Ordering::Relaxed) {
+                let snapshot = encode_snapshot(state.version, &state.map, &self.timeline.lock().unwrap(), &self.compression());
+                return (CatchUp::Snapshot(state.version, snapshot), self.subscribe());
+            }
+            (CatchUp::Backlog(self.backlog_after(state, after)), self.subscribe())
+        })
+    }
+    fn backlog_after(&self, state: &mvcc::State, after: Version) -> Vec<ChangeBatch> {
+        let mut batches: BTreeMap<Version, Writes> = BTreeMap::new();
+        for (key, versions) in &state.map {
+            for (version, value) in versions.range((Bound::Excluded(after), Bound::Unbounded)) {
+                batches.entry(*version).or_default().insert(key.to_string(), value.as_deref().map(str::to_owned));
+            }
+        }
+        let timeline = self.timeline.lock().unwrap();
+        batches
+            .into_iter()
+            .map(|(version, writes)| {
+                let timestamp = timeline.binary_search_by_key(&version, |&(v, _)| v).map_or(0, |i| timeline[i].1);
+                ChangeBatch { version, timestamp, writes, policies: self.policies.clone() }
+            })
+            .collect()
+    }
+    pub fn history(&self, key: &str) -> History {
+        let mut history = match &self.parent {
+            Some((parent, fork)) => parent.history(key).into_iter().filter(|(v, _)| v <= fork).collect(),
+            None => Vec::new(),
+        };
+        self.settled(|state| {
+            if let Some(versions) = state.map.get(key) {
+                history.extend(versions.iter().map(|(v, val)| (*v, val.as_deref().map(str::to_owned))));
+            }
+        });
+        history
+    }
+    pub fn hot_keys(&self, top_n: usize) -> Vec<HotKey> {
+        let mut hot: Vec<HotKey> = self.hot.slots.lock().unwrap().values().cloned().collect();
+        hot.sort_by(|a, b| (b.reads + b.writes).cmp(&(a.reads + a.writes)).then_with(|| a.key.cmp(&b.key)));
+        hot.truncate(top_n);
+        hot
+    }
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.engine.with(|state| {
+            let mut stats = Stats {
+                current_version: state.version,
+                gc_watermark: self.gc_watermark.load(Ordering::Relaxed),
+                approx_bytes: self.memory_usage(),
+                slab: mvcc::slab_usage(),
+                ..Stats::default()
+            };
+            for (key, versions) in &state.map {
+                stats.versions += versions.len();
+                stats.tombstones += versions.values().filter(|value| value.is_none()).count();
+                if matches!(versions.values().next_back(), Some(Some(_))) {
+                    stats.keys += 1;
+                    *stats.keyspaces.entry(keyspace_of(key).to_owned()).or_insert(0) += 1;
+                }
+            }
+            stats
+        });
+        {
+            let open = self.open_txns.lock().unwrap();
+            stats.open_transactions = open.len();
+            stats.oldest_transaction = open.values().map(|txn| txn.opened.elapsed()).max();
+        }
+        if self.parent.is_some() {
+            stats.keyspaces.clear();
+            let live = self.scan_at(.., stats.current_version);
+            stats.keys = live.len();
+            for (key, _) in live {
+                *stats.keyspaces.entry(keyspace_of(&key).to_owned()).or_insert(0) += 1;
+            }
+        }
+        stats
+    }
+    pub fn memory_usage(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+    pub fn memory_limit(&self) -> Option<usize> {
+        Some(self.memory_limit.load(Ordering::Relaxed)).filter(|&limit| limit != usize::MAX)
+    }
+    pub fn usage(&self, keyspace: &str) -> KeyspaceUsage {
+        let tracked = self.keyspace_usage.lock().unwrap().get(keyspace).copied();
+        let bytes = tracked.unwrap_or_else(|| self.engine.with(|state| live_bytes(state, keyspace)));
+        KeyspaceUsage {
+            keyspace: keyspace.to_owned(),
+            bytes,
+            budget: self.policies.load().get(keyspace).and_then(|policy| policy.memory_budget),
+            store_bytes: self.memory_usage(),
+            store_limit: self.memory_limit(),
+        }
+    }
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.memory_limit.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+    pub fn size_limits(&self) -> (usize, usize) {
+        (self.max_key_bytes.load(Ordering::Relaxed), self.max_value_bytes.load(Ordering::Relaxed))
+    }
+    pub fn set_size_limits(&self, max_key_bytes: usize, max_value_bytes: usize) {
+        self.max_key_bytes.store(max_key_bytes.max(1), Ordering::Relaxed);
+        self.max_value_bytes.store(max_value_bytes, Ordering::Relaxed);
+    }
+    pub fn set_admission(&self, limits: AdmissionLimits) {
+        *self.admission.write().unwrap() = limits;
+    }
+    pub fn write_pressure(&self) -> WritePressure {
+        WritePressure {
+            wal_backlog: self.wal_backlog.load(Ordering::Relaxed),
+            memory: self.memory_usage(),
+            wal_segments: self.wal_segments.load(Ordering::Relaxed),
+        }
+    }
+    fn admit(&self) -> Result<()> {
+        let limits = self.admission.read().unwrap().clone();
+        if limits == AdmissionLimits::default() {
+            return Ok(());
+        }
+        let deadline = limits.wait.map(|wait| Instant::now() + wait);
+        loop {
+            let Some(exceeded) = self.write_pressure().exceeds(&limits) else {
+                return Ok(());
+            };
+            match deadline {
+                Some(deadline) if Instant::now() < deadline && !self.is_closed() => {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()).min(ADMISSION_POLL));
+                }
+                _ => return Err(KVError::Overloaded(exceeded)),
+            }
+        }
+    }
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        Some(self.slow_threshold.load(Ordering::Relaxed)).filter(|&micros| micros != u64::MAX).map(Duration::from_micros)
+    }
+    pub fn set_slow_threshold(&self, threshold: Option<Duration>) {
+        let micros = threshold.map_or(u64::MAX, |threshold| threshold.as_micros().min(u64::MAX as u128 - 1) as u64);
+        self.slow_threshold.store(micros, Ordering::Relaxed);
+    }
+    pub fn slow_log(&self) -> Vec<SlowTxn> {
+        self.slow_log.lock().unwrap().iter().cloned().collect()
+    }
+    fn record_slow(&self, txn: u64, started: Instant, keys: usize, trace: CommitTrace, version: Option<Version>) {
+        let duration = started.elapsed();
+        if duration.as_micros() < self.slow_threshold.load(Ordering::Relaxed) as u128 {
+            return;
+        }
+        let mut log = self.slow_log.lock().unwrap();
+        if log.len() == SLOW_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(SlowTxn { txn, at: self.now_millis(), version, duration, keys, lock_wait: trace.lock_wait, fsync: trace.fsync });
+    }
+    pub fn start_recording(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        out.write_all(TRACE_MAGIC)?;
+        let recorder = Recorder { started: Instant::now(), out: Mutex::new((out, None)) };
+        if let Some(previous) = self.recorder.write().unwrap().replace(Arc::new(recorder)) {
+            previous.out.lock().unwrap().0.flush()?;
+        }
+        Ok(())
+    }
+    pub fn stop_recording(&self) -> Result<bool> {
+        let Some(recorder) = self.recorder.write().unwrap().take() else {
+            return Ok(false);
+        };
+        let mut out = recorder.out.lock().unwrap();
+        if let Some(e) = out.1.take() {
+            return Err(KVError::Io(e));
+        }
+        out.0.flush()?;
+        Ok(true)
+    }
+    fn record(&self, txn: u64, started: Instant, op: impl FnOnce() -> TraceOp) {
+        let Some(recorder) = self.recorder.read().unwrap().clone() else {
+            return;
+        };
+        let took = started.elapsed();
+        let at = started.saturating_duration_since(recorder.started);
+        let event = TraceEvent { at, took, thread: trace_thread(), txn, op: op() };
+        let mut out = recorder.out.lock().unwrap();
+        if out.1.is_none() {
+            if let Err(e) = out.0.write_all(&event.encode()) {
+                out.1 = Some(e);
+            }
+        }
+    }
+    pub fn set_memory_policy(&self, policy: MemoryPolicy) {
+        self.engine.with(|state| {
+            let evict = policy == MemoryPolicy::EvictLeastRecentlyRead;
+            if evict && !self.evict.load(Ordering::Relaxed) {
+                let mut recency = self.recency.lock().unwrap();
+                for (key, versions) in &state.map {
+                    if matches!(versions.values().next_back(), Some(Some(_))) && !recency.ticks.contains_key(key.as_str()) {
+                        recency.touch_key(key);
+                    }
+                }
+            }
+            self.evict.store(evict, Ordering::Relaxed);
+        })
+    }
+    fn pick_victims(&self, writes: &Writes) -> Vec<String> {
+        self.engine.with(|state| {
+            let used = self.memory.load(Ordering::Relaxed) + write_bytes(state, writes);
+            let mut over = used.saturating_sub(self.memory_limit.load(Ordering::Relaxed));
+            let mut recency = self.recency.lock().unwrap();
+            let mut victims = Vec::new();
+            let mut stale = Vec::new();
+            let held = held_prefixes(state);
+            for key in recency.order.values() {
+                if over == 0 {
+                    break;
+                }
+                let pinned = is_append_only(state, &Writes::new(), keyspace_of(key)) || is_held(&held, key);
+                if writes.contains_key(&**key) || pinned {
+                    continue;
+                }
+                match state.map.get(key) {
+                    Some(versions) if matches!(versions.values().next_back(), Some(Some(_))) => {
+                        over = over.saturating_sub(chain_bytes(key, versions));
+                        victims.push(key.to_string());
+                    }
+                    _ => stale.push(key.clone()),
+                }
+            }
+            for key in stale {
+                recency.forget(&key);
+            }
+            victims
+        })
+    }
+    pub fn keyspace_counts(&self) -> BTreeMap<String, usize> {
+        self.stats().keyspaces
+    }
+    #[cfg(feature = "fault-injection")]
+    pub fn install_faults(&self, injector: Option<Arc<FaultInjector>>) {
+        *self.faults.write().unwrap() = injector;
+    }
+    #[cfg(feature = "fault-injection")]
+    fn fault(&self, point: FaultPoint) -> Result<()> {
+        match self.faults.read().unwrap().clone() {
+            Some(injector) => injector.hit(point),
+            None => Ok(()),
+        }
+    }
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write().unwrap() = clock;
+    }
+    pub fn now_millis(&self) -> u64 {
+        self.clock.read().unwrap().now_millis()
+    }
+    pub fn set_retention(&self, keyspace: &str, retention: Retention) {
+        self.retention.write().unwrap().insert(keyspace.to_owned(), retention);
+    }
+    pub fn clear_retention(&self, keyspace: &str) -> bool {
+        self.retention.write().unwrap().remove(keyspace).is_some()
+    }
+    pub fn set_default_retention(&self, retention: Retention) {
+        *self.default_retention.write().unwrap() = retention;
+    }
+    pub fn set_keyspace_policy(&self, keyspace: &str, policy: KeyspacePolicy) -> Result<()> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) {
+            return Err(KVError::InvalidInput(format!("invalid keyspace {:?}", keyspace)));
+        }
+        #[cfg(not(feature = "zstd"))]
+        if matches!(policy.compression, Some(Compression::Zstd(_))) {
+            return Err(KVError::InvalidConfig { option: "compression", reason: "zstd needs the zstd feature".to_owned() });
+        }
+        self.update_manifest(|_| Ok(()), |manifest| {
+            match policy == KeyspacePolicy::default() {
+                true => manifest.policies.remove(keyspace),
+                false => manifest.policies.insert(keyspace.to_owned(), policy.clone()),
+            };
+        })?;
+        self.install_policy(keyspace, policy);
+        Ok(())
+    }
+    pub fn keyspace_policy(&self, keyspace: &str) -> KeyspacePolicy {
+        self.policies.load().get(keyspace).cloned().unwrap_or_default()
+    }
+    pub fn keyspace_policies(&self) -> BTreeMap<String, KeyspacePolicy> {
+        BTreeMap::clone(&self.policies.load())
+    }
+    pub fn is_sensitive(&self, key: &str) -> bool {
+        self.policies.is_sensitive(key)
+    }
+    fn install_policy(&self, keyspace: &str, policy: KeyspacePolicy) {
+        match policy.retention {
+            Some(retention) => self.set_retention(keyspace, retention),
+            None => {
+                self.clear_retention(keyspace);
+            }
+        }
+        self.engine.with(|state| {
+            let mut usage = self.keyspace_usage.lock().unwrap();
+            match policy.memory_budget {
+                Some(_) => usage.insert(keyspace.to_owned(), live_bytes(state, keyspace)),
+                None => usage.remove(keyspace),
+            };
+            self.policies.update(keyspace, policy);
+        });
+    }
+    fn compression(&self) -> BTreeMap<String, Compression> {
+        let policies = self.policies.load();
+        policies.iter().filter_map(|(keyspace, policy)| Some((keyspace.clone(), policy.compression?))).collect()
+    }
+    fn default_ttl(&self, keyspace: &str) -> Option<Duration> {
+        self.policies.load().get(keyspace)?.default_ttl
+    }
+    fn keyspace_growth(&self, writes: &Writes, state: &mvcc::State) -> Result<Vec<(String, isize)>> {
+        let usage = self.keyspace_usage.lock().unwrap();
+        if usage.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut growth: BTreeMap<&str, isize> = BTreeMap::new();
+        for (key, value) in writes.iter().filter(|(key, _)| usage.contains_key(keyspace_of(key))) {
+            let old = latest_value(state, key).map_or(0, |old| key.len() + old.len());
+            let new = value.as_ref().map_or(0, |new| key.len() + new.len());
+            *growth.entry(keyspace_of(key)).or_default() += new as isize - old as isize;
+        }
+        let policies = self.policies.load();
+        for (keyspace, delta) in growth.iter().filter(|(_, delta)| **delta > 0) {
+            let budget = policies.get(*keyspace).and_then(|policy| policy.memory_budget).unwrap_or(usize::MAX);
+            if usage[*keyspace].saturating_add(*delta as usize) > budget {
+                return Err(KVError::OutOfMemoryBudget(LimitExceeded {
+                    limit: Limit::KeyspaceBudget,
+                    keyspace: Some((*keyspace).to_owned()),
+                    usage: usage[*keyspace] as u64,
+                    requested: *delta as u64,
+                    max: budget as u64,
+                }));
+            }
+        }
+        Ok(growth.into_iter().map(|(keyspace, delta)| (keyspace.to_owned(), delta)).collect())
+    }
+    pub fn gc(&self) -> GcReport {
+        self.collect_garbage(&CompactionLimits::default(), &AtomicBool::new(false))
+    }
+    fn collect_garbage(&self, limits: &CompactionLimits, stopped: &AtomicBool) -> GcReport {
+        let policies = self.retention.read().unwrap().clone();
+        let default = *self.default_retention.read().unwrap();
+        let mut report = GcReport { watermark: self.gc_watermark.load(Ordering::Relaxed), ..GcReport::default() };
+        if policies.values().all(|retention| *retention == Retention::KeepAll) && default == Retention::KeepAll {
+            return report;
+        }
+        let forked = self.branches.lock().unwrap().values().filter_map(|branch| branch.fork_version()).min();
+        // Cutoffs are fixed when the pass starts; commits landing between batches only add newer versions.
+        let (version, pinned) = self.settled(|state| {
+            (state.version, forked.into_iter().chain(self.pins.lock().unwrap().keys().next().copied()).min())
+        });
+        let now = self.now_millis();
+        let mut cutoffs: BTreeMap<String, Option<(Version, Option<usize>)>> = BTreeMap::new();
+        self.in_batches(limits, stopped, |state, range| {
+            for (key, _) in state.map.range(range.clone()) {
+                let keyspace = keyspace_of(key);
+                if cutoffs.contains_key(keyspace) {
+                    continue;
+                }
+                let retention = policies.get(keyspace).copied().or((!keyspace.starts_with("__")).then_some(default));
+                let plan = retention.filter(|retention| *retention != Retention::KeepAll && !is_append_only(state, &Writes::new(), keyspace)).map(|retention| {
+                    let cutoff = match retention {
+                        Retention::KeepFor(keep) => {
+                            let limit = now.saturating_sub(keep.as_millis() as u64);
+                            let timeline = self.timeline.lock().unwrap();
+                            timeline[..timeline.partition_point(|&(_, t)| t <= limit)].last().map_or(0, |&(version, _)| version)
+                        }
+                        _ => version,
+                    };
+                    let keep = match retention {
+                        Retention::KeepVersions(n) => Some(n.max(1)),
+                        _ => None,
+                    };
+                    (pinned.map_or(cutoff, |pinned| cutoff.min(pinned)), keep)
+                });
+                cutoffs.insert(keyspace.to_owned(), plan);
+            }
+            let held = held_prefixes(state);
+            let mut emptied = Vec::new();
+            let mut watermark = report.watermark;
+            state.map.for_each_mut_in(range, |key, mut versions| {
+                let Some(&Some((cutoff, keep))) = cutoffs.get(keyspace_of(key)) else { return };
+                if is_held(&held, key) {
+                    return;
+                }
+                let cutoff = match keep {
+                    Some(keep) => match versions.keys().nth_back(keep - 1) {
+                        Some(&oldest) => oldest.min(cutoff),
+                        None => return,
+                    },
+                    None => cutoff,
+                };
+                if versions.range(..cutoff).next().is_none() {
+                    return;
+                }
+                let before = chain_bytes(key, &versions);
+                let mut removed = versions.drain_through(cutoff);
+                let visible = removed.pop_last();
+                report.versions_removed += removed.len();
+                if self.policies.is_sensitive(key) {
+                    removed.into_iter().filter_map(|(_, value)| value).for_each(mvcc::Value::scrub);
+                }
+                match visible {
+                    Some((version, Some(value))) => {
+                        versions.insert(version, Some(value));
+                    }
+                    Some(_) => report.versions_removed += 1,
+                    None => {}
+                }
+                self.memory.fetch_sub(before - chain_bytes(key, &versions), Ordering::Relaxed);
+                self.cache.invalidate(key);
+                watermark = watermark.max(cutoff);
+                if versions.is_empty() {
+                    emptied.push(key.to_string());
+                }
+            });
+            // Raised before the engine lock is released, so nothing can start reading at a collected version.
+            report.watermark = watermark;
+            self.gc_watermark.fetch_max(watermark, Ordering::Relaxed);
+            for key in emptied {
+                let versions = state.map.remove(&key).unwrap();
+                self.memory.fetch_sub(chain_bytes(&key, &versions), Ordering::Relaxed);
+                report.keys_removed += 1;
+            }
+        });
+        report
+    }
+    /// Walks the whole map in batches of `SCAN_CHUNK` keys, holding the engine lock (once in-flight commits have
+    /// settled) for one batch at a time so commits interleave with the pass.
+    fn in_batches(&self, limits: &CompactionLimits, stopped: &AtomicBool, mut batch: impl FnMut(&mut mvcc::State, (Bound<String>, Bound<String>))) {
+        let mut start = Bound::Unbounded;
+        while !stopped.load(Ordering::Acquire) {
+            let started = Instant::now();
+            let last = self.settled(|state| {
+                let (i, (last, _)) = state.map.range((start.clone(), Bound::Unbounded)).take(SCAN_CHUNK).enumerate().last()?;
+                let last = last.to_string();
+                batch(state, (start.clone(), Bound::Included(last.clone())));
+                (i + 1 == SCAN_CHUNK).then_some(last)
+            });
+            let Some(last) = last else { return };
+            start = Bound::Excluded(last);
+            limits.pace(started);
+        }
+    }
+    pub fn checkpoint(&self) -> Result<Version> {
+        self.writable()?;
+        self.storage()?;
+        self.gc();
+        self.write_snapshot()
+    }
+    fn encryption(&self) -> Result<&Arc<EncryptedStorage>> {
+        self.encryption.as_ref().ok_or_else(|| KVError::InvalidInput("store is not encrypted".to_owned()))
+    }
+    pub fn rotate_master_key(&self, key: [u8; 32]) -> Result<()> {
+        self.set_key_provider(Arc::new(StaticKey(key))).map(drop)
+    }
+    pub fn set_key_provider(&self, provider: Arc<dyn KeyProvider>) -> Result<String> {
+        self.writable()?;
+        self.encryption()?.rewrap(Some(provider))
+    }
+    pub fn rewrap_keyring(&self) -> Result<String> {
+        self.writable()?;
+        self.encryption()?.rewrap(None)
+    }
+    pub fn master_key_id(&self) -> Result<Option<String>> {
+        self.encryption()?.master_key_id()
+    }
+    pub fn reencrypt(&self) -> Result<ReencryptReport> {
+        self.writable()?;
+        let encryption = self.encryption()?;
+        let key = encryption.rotate_data_key()?;
+        self.checkpoint()?;
+        self.remove_obsolete_files()?;
+        let mut report = ReencryptReport { key, ..ReencryptReport::default() };
+        let names = encryption.list()?;
+        for name in names.iter().filter(|name| *name != LOCK_FILE && !name.ends_with(".tmp")) {
+            if self.is_closed() {
+                return Err(KVError::Closed);
+            }
+            let rewritten = self.settled(|_| -> Result<bool> {
+                if !encryption.list()?.contains(name) || encryption.key_of(name)? == Some(key) {
+                    return Ok(false);
+                }
+                let bytes = encryption.read(name)?;
+                encryption.write(name, &bytes)?;
+                Ok(true)
+            })?;
+            report.files_rewritten += rewritten as usize;
+        }
+        let live = self.settled(|_| -> Result<BTreeSet<u32>> {
+            let names = encryption.list()?;
+            names.iter().filter(|name| *name != LOCK_FILE).map(|name| encryption.key_of(name)).filter_map(Result::transpose).collect()
+        })?;
+        report.keys_retired = encryption.retire_keys(&live)?;
+        Ok(report)
+    }
+    pub fn reencrypt_in_background(self: &Arc<Self>) -> thread::JoinHandle<Result<ReencryptReport>> {
+        let store = Arc::downgrade(self);
+        thread::spawn(move || store.upgrade().ok_or(KVError::Closed)?.reencrypt())
+    }
+    pub fn compact(&self) -> Result<CompactionReport> {
+        self.run_compaction(&CompactionLimits::default(), &AtomicBool::new(false))
+    }
+    fn run_compaction(&self, limits: &CompactionLimits, stopped: &AtomicBool) -> Result<CompactionReport> {
+        self.writable()?;
+        let storage = self.storage()?;
+        let gc = self.collect_garbage(limits, stopped);
+        let values_relocated = self.relocate_values(limits, stopped);
+        let mut chain_slots_reclaimed = 0;
+        while !stopped.load(Ordering::Acquire) {
+            let started = Instant::now();
+            if let Some(reclaimed) = self.engine.with(|state| state.map.compact_step(SCAN_CHUNK)) {
+                chain_slots_reclaimed = reclaimed;
+                break;
+            }
+            limits.pace(started);
+        }
+        let version = self.write_snapshot()?;
+        let segments = storage_files(storage.as_ref(), "wal")?;
+        let mut jobs = Vec::new();
+        for pair in segments.windows(2).filter(|pair| pair[1].0 <= version) {
+            jobs.push((storage.read_stream(&pair[0].1)?.0, pair[0].0, pair[0].1.clone()));
+        }
+        jobs.sort_by_key(|&(size, base, _)| (size, std::cmp::Reverse(base)));
+        let queue = Mutex::new(jobs.into_iter());
+        let report = Mutex::new(CompactionReport { version, gc, values_relocated, chain_slots_reclaimed, ..CompactionReport::default() });
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..limits.jobs.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        while !stopped.load(Ordering::Acquire) {
+                            let Some((size, _, name)) = queue.lock().unwrap().next() else { break };
+                            if let Some(limiter) = &limits.io {
+                                limiter.acquire(size * 2);
+                            }
+                            let started = Instant::now();
+                            let reclaimed = self.rewrite_segment(storage, &name)?;
+                            limits.pace(started);
+                            if reclaimed > 0 {
+                                let mut report = report.lock().unwrap();
+                                report.segments_rewritten += 1;
+                                report.bytes_reclaimed += reclaimed;
+                            }
+                        }
+                        Ok::<_, KVError>(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+        })?;
+        Ok(report.into_inner().unwrap())
+    }
+    fn relocate_values(&self, limits: &CompactionLimits, stopped: &AtomicBool) -> usize {
+        let mut relocated = 0;
+        self.in_batches(limits, stopped, |state, range| {
+            state.map.for_each_mut_in(range, |_, mut versions| {
+                for value in versions.values_mut().filter(|value| value.fragmented()) {
+                    value.relocate();
+                    relocated += 1;
+                }
+            })
+        });
+        relocated
+    }
+    pub fn purge(&self, key: &str) -> Result<PurgeReport> {
+        self.writable()?;
+        if key.is_empty() || keyspace_of(key).starts_with("__") {
+            return Err(KVError::InvalidInput(format!("{:?} cannot be purged", key)));
+        }
+        let folded = self.fold_key(key);
+        let key = folded.as_deref().unwrap_or(key);
+        if self.engine.with(|state| is_held(&held_prefixes(state), key)) {
+            return Err(KVError::InvalidInput(format!("{} is under legal hold", key)));
+        }
+        let mut writes = Writes::from([(key.to_owned(), None), (purge_key(key), Some(PURGE_PENDING.to_owned()))]);
+        if let Some(folded) = &folded {
+            writes.insert(case_key(folded), None);
+        }
+        let version = self.apply(writes, None, None, None)?;
+        let mut report = PurgeReport { version, ..PurgeReport::default() };
+        let compression = self.compression();
+        let erased = self.settled(|state| {
+            // Compaction writes snapshots under the same engine-then-manifest lock order, so no snapshot can be
+            // encoded before the erase and saved after the rewrite below.
+            let _guard = self.manifest.lock().unwrap();
+            if is_held(&held_prefixes(state), key) {
+                return Ok(false);
+            }
+            report.versions_removed = self.erase_history(state, key, version);
+            if let Some(folded) = &folded {
+                report.versions_removed += self.erase_history(state, &case_key(folded), version);
+            }
+            let Some(storage) = &self.storage else { return Ok(true) };
+            for (_, name) in storage_files(storage.as_ref(), "snap")? {
+                let (snapshot, mut data, timeline) = decode_snapshot(&storage.read(&name)?)?;
+                let erased = [Some(key.to_owned()), folded.as_deref().map(case_key)];
+                if erased.iter().flatten().fold(false, |found, key| data.remove(key).is_some() | found) {
+                    storage.write(&name, &encode_snapshot(snapshot, &data, &timeline, &compression))?;
+                    report.snapshots_rewritten += 1;
+                }
+            }
+            Ok::<_, KVError>(true)
+        })?;
+        if !erased {
+            self.apply(Writes::from([(purge_key(key), None)]), None, None, None)?;
+            return Err(KVError::InvalidInput(format!("{} is under legal hold", key)));
+        }
+        if let Some(storage) = &self.storage {
+            let active = self.rotate_wal()?;
+            for (base, name) in storage_files(storage.as_ref(), "wal")? {
+                if base < active {
+                    report.segments_rewritten += (self.rewrite_segment(storage, &name)? > 0) as usize;
+                }
+            }
+        }
+        self.apply(Writes::from([(purge_key(key), Some(PURGE_DONE.to_owned()))]), None, None, None)?;
+        Ok(report)
+    }
+    fn erase_history(&self, state: &mut mvcc::State, key: &str, through: Version) -> usize {
+        let Some(mut versions) = state.map.get_mut(key) else { return 0 };
+        let before = chain_bytes(key, &versions);
+        let removed = versions.drain_through(through);
+        let after = chain_bytes(key, &versions);
+        if versions.is_empty() {
+            state.map.remove(key);
+        }
+        self.memory.fetch_sub(before - after, Ordering::Relaxed);
+        self.cache.invalidate(key);
+        self.recency.lock().unwrap().forget(key);
+        if let Some(entries) = self.point_index.write().unwrap().get_mut(keyspace_of(key)) {
+            if entries.get(key).is_some_and(|(version, _)| *version <= through) {
+                entries.remove(key);
+            }
+        }
+        let count = removed.len();
+        removed.into_iter().filter_map(|(_, value)| value).for_each(mvcc::Value::scrub);
+        count
+    }
+    fn resume_purges(&self) {
+        self.engine.with(|state| {
+            let prefix = purge_key("");
+            let prefix = &prefix[..prefix.len() - 64];
+            let pending: HashMap<String, Version> = state
+                .map
+                .range(prefix_range(prefix))
+                .filter_map(|(marker, versions)| match versions.latest() {
+                    Some((version, Some(value))) if value == PURGE_PENDING => Some((marker[prefix.len()..].to_owned(), *version)),
+                    _ => None,
+                })
+                .collect();
+            if pending.is_empty() {
+                return;
+            }
+            let held = held_prefixes(state);
+            let keys: Vec<(String, Version)> = state
+                .map
+                .keys()
+                .filter(|key| !is_held(&held, key))
+                .filter_map(|key| Some((key.to_string(), *pending.get(&hex(&sha256(key.as_bytes())))?)))
+                .collect();
+            for (key, version) in keys {
+                self.erase_history(state, &key, version);
+            }
+        });
+    }
+    fn rewrite_segment(&self, storage: &Arc<dyn Storage>, name: &str) -> Result<u64> {
+        let bytes = storage.read(name)?;
+        let rewritten = self.settled(|state| {
+            let mut rewritten = WAL_MAGIC.to_vec();
+            for payload in segment_frames(&bytes).0 {
+                if payload.starts_with(&[0; 8]) {
+                    rewritten.extend_from_slice(&frame(payload));
+                    continue;
+                }
+                let mut record = WalRecord::decode(payload)?;
+                record.writes.retain(|key, _| {
+                    state.map.get(key).is_some_and(|versions| versions.get(&record.version).is_some())
+                });
+                rewritten.extend_from_slice(&WalRecord::encode_txn(
+                    record.version,
+                    record.timestamp,
+                    &record.writes,
+                    record.txn,
+                ));
+            }
+            Ok::<_, KVError>(rewritten)
+        })?;
+        if rewritten.len() >= bytes.len() {
+            return Ok(0);
+        }
+        let _guard = self.manifest.lock().unwrap();
+        if !storage_files(storage.as_ref(), "wal")?.iter().any(|(_, live)| live == name) {
+            return Ok(0);
+        }
+        storage.write(name, &rewritten)?;
+        Ok((bytes.len() - rewritten.len()) as u64)
+    }
+    fn write_snapshot(&self) -> Result<Version> {
+        self.settled(|state| {
+            #[cfg(feature = "fault-injection")]
+            self.fault(FaultPoint::DuringCompaction)?;
+            self.rotate_wal_locked(state.version)?;
+            *self.bloom.write().unwrap() = Bloom::live(&state.map);
+            let bytes = encode_snapshot(state.version, &state.map, &self.timeline.lock().unwrap(), &self.compression());
+            // Written before the engine lock is released so a concurrent purge either sees this snapshot or
+            // erased the key before it was encoded.
+            self.update_manifest(|storage| storage.write(&snapshot_name(state.version), &bytes), |manifest| {
+                manifest.snapshots.insert(state.version);
+            })?;
+            Ok(state.version)
+        })
+    }
+    pub fn import_version(&self, version: Version, committed: Option<SystemTime>, writes: Writes) -> Result<()> {
+        self.writable()?;
+        let timestamp = committed.map(|time| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64));
+        self.apply(writes, Some(version), timestamp, None).map(|_| ())
+    }
+    pub fn prepare(&self, id: u64, writes: Writes) -> Result<()> {
+        self.prepare_with(id, writes, CommitMeta::default())
+    }
+    pub(crate) fn prepare_with(&self, id: u64, writes: Writes, meta: CommitMeta) -> Result<()> {
+        self.writable()?;
+        if self.proposer.get().is_some() {
+            return Err(KVError::InvalidInput("replicated stores cannot take part in two-phase commit".to_owned()));
+        }
+        self.settled(|_| {
+            if self.is_closed() {
+                return Err(KVError::Closed);
+            }
+            let mut prepared = self.prepared.lock().unwrap();
+            if prepared.contains_key(&id) {
+                return Err(KVError::InvalidInput(format!("transaction {} is already prepared", id)));
+            }
+            if let (Some(storage), Some(wal)) = (&self.storage, &self.wal) {
+                let wal = wal.lock().unwrap();
+                storage.append(&segment_name(wal.base), &TxnMarker::encode_prepared(id, &writes, &meta))?;
+            }
+            prepared.insert(id, (writes, meta));
+            Ok(())
+        })
+    }
+    pub fn commit_prepared(&self, id: u64) -> Result<Option<Version>> {
+        self.writable()?;
+        let Some((writes, meta)) = self.prepared.lock().unwrap().get(&id).cloned() else {
+            return Ok(None);
+        };
+        self.apply_traced(writes, meta, None, None, Some(id), &mut CommitTrace::default()).map(Some)
+    }
+    pub fn abort_prepared(&self, id: u64) -> Result<bool> {
+        self.writable()?;
+        self.settled(|_| {
+            if self.is_closed() {
+                return Err(KVError::Closed);
+            }
+            let mut prepared = self.prepared.lock().unwrap();
+            if !prepared.contains_key(&id) {
+                return Ok(false);
+            }
+            if let (Some(storage), Some(wal)) = (&self.storage, &self.wal) {
+                let wal = wal.lock().unwrap();
+                storage.append(&segment_name(wal.base), &TxnMarker::encode_aborted(id))?;
+            }
+            prepared.remove(&id);
+            Ok(true)
+        })
+    }
+    pub fn in_doubt(&self) -> Vec<u64> {
+        self.prepared.lock().unwrap().keys().copied().collect()
+    }
+    pub fn close(&self) -> Result<()> {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let attached: Vec<Arc<KVStore>> = self.attached.lock().unwrap().values().cloned().collect();
+        for store in attached {
+            store.close()?;
+        }
+        let closed = self.settled(|_| {
+            self.feed.lock().unwrap().clear();
+            self.listeners.lock().unwrap().clear();
+            let flushed = self.storage.as_ref().map_or(Ok(()), |storage| storage.flush());
+            self.dir_lock.lock().unwrap().take();
+            flushed
+        });
+        self.pipeline.stop();
+        closed
+    }
+    pub fn shutdown(&self, deadline: Duration) -> Result<ShutdownReport> {
+        if self.is_closed() {
+            return Err(KVError::Closed);
+        }
+        let started = Instant::now();
+        let cutoff = self.next_txn.load(Ordering::Relaxed);
+        self.draining.store(cutoff, Ordering::Release);
+        let in_flight = |open: &BTreeMap<u64, OpenTxn>| open.range(..cutoff).map(|(&id, _)| id).collect::<Vec<u64>>();
+        let waited = in_flight(&self.open_txns.lock().unwrap()).len();
+        let aborted = loop {
+            let pending = in_flight(&self.open_txns.lock().unwrap());
+            if pending.is_empty() || started.elapsed() >= deadline {
+                break pending;
+            }
+            thread::sleep(ADMISSION_POLL.min(deadline.saturating_sub(started.elapsed())));
+        };
+        self.draining.store(0, Ordering::Release);
+        let mut report = ShutdownReport { drained: waited - aborted.len(), aborted, ..ShutdownReport::default() };
+        let mut result = self.stop_recording().map(drop);
+        if self.storage.is_some() && !self.is_read_only() {
+            self.settled(|_| ());
+            result = result.and_then(|()| self.checkpoint()).map(|version| report.checkpoint = Some(version));
+        }
+        report.in_doubt = self.in_doubt();
+        result = result.and(self.close());
+        report.elapsed = started.elapsed();
+        result.map(|()| report)
+    }
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.source.is_some()
+    }
+    fn writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(KVError::ReadOnly);
+        }
+        self.pipeline.failure()
+    }
+    fn settled<R>(&self, f: impl FnOnce(&mut mvcc::State) -> R) -> R {
+        let mut f = Some(f);
+        loop {
+            let done = self.engine.with(|state| {
+                let idle = state.allocated == state.version || self.pipeline.failure().is_err();
+                idle.then(|| f.take().unwrap()(state))
+            });
+            if let Some(result) = done {
+                return result;
+            }
+            let _ = self.await_durable(self.engine.with(|state| state.allocated));
+        }
+    }
+    fn await_durable(&self, version: Version) -> Result<()> {
+        let durable = {
+            let mut queue = self.pipeline.queue.lock().unwrap();
+            while queue.failed.is_none() && queue.durable < version && (queue.flushing || !queue.pending.is_empty()) {
+                queue = self.pipeline.flushed.wait(queue).unwrap();
+            }
+            queue.durable >= version
+        };
+        self.publish_written();
+        match durable {
+            true => Ok(()),
+            false => self.pipeline.failure(),
+        }
+    }
+    fn publish_written(&self) {
+        self.engine.with(|state| {
+            let written = std::mem::take(&mut self.pipeline.queue.lock().unwrap().written);
+            let mut feed = self.feed.lock().unwrap();
+            let mut listeners = self.listeners.lock().unwrap();
+            for staged in written {
+                state.version = state.version.max(staged.version);
+                if let Some(batch) = &staged.batch {
+                    feed.retain(|subscriber| subscriber.send(Arc::clone(batch)).is_ok());
+                    listeners.retain_mut(|listener| listener.deliver(batch));
+                }
+            }
+        });
+    }
+    pub fn refresh(&self) -> Result<Version> {
+        let Some(storage) = &self.source else {
+            return Err(KVError::InvalidInput("only read-only stores follow their files".to_owned()));
+        };
+        if let Some((version, name)) = storage_files(storage.as_ref(), "snap")?.pop() {
+            if version > self.current_version() {
+                let (version, map, timeline) = decode_snapshot(&storage.read(&name)?)?;
+                self.engine.with(|state| {
+                    if version > state.version {
+                        self.replace_state(state, version, map, timeline);
+                    }
+                });
+            }
+        }
+        let segments = storage_files(storage.as_ref(), "wal")?;
+        for (i, (_, name)) in segments.iter().enumerate() {
+            if segments.get(i + 1).is_some_and(|(next, _)| *next <= self.current_version()) {
+                continue;
+            }
+            for record in parse_segment(&storage.read(name)?, name)?.records {
+                if record.version > self.current_version() {
+                    self.apply(record.writes, Some(record.version), Some(record.timestamp), None)?;
+                }
+            }
+        }
+        Ok(self.current_version())
+    }
+    pub fn follow_wal(self: &Arc<Self>, interval: Duration) -> Result<()> {
+        if !self.is_read_only() {
+            return Err(KVError::InvalidInput("only read-only stores follow their files".to_owned()));
+        }
+        if interval.is_zero() {
+            return Err(KVError::InvalidInput("follow interval must be non-zero".to_owned()));
+        }
+        spawn_every(Arc::downgrade(self), interval, |store| {
+            let _ = store.refresh();
+        });
+        Ok(())
+    }
+    fn install_snapshot(&self, version: Version, map: VersionMap, timeline: Timeline) -> Result<()> {
+        self.settled(|state| {
+            let snapshot = encode_snapshot(version, &map, &timeline, &self.compression());
+            self.update_manifest(|storage| storage.write(&snapshot_name(version), &snapshot), |manifest| {
+                manifest.snapshots.insert(version);
+            })?;
+            self.rotate_wal_locked(version)?;
+            self.replace_state(state, version, map, timeline);
+            Ok(())
+        })
+    }
+    fn replace_state(&self, state: &mut mvcc::State, version: Version, map: VersionMap, timeline: Timeline) {
+        self.memory.store(map_bytes(&map), Ordering::Relaxed);
+        self.cache.clear();
+        *self.bloom.write().unwrap() = Bloom::build(map.keys().map(Key::as_str));
+        *self.sample.write().unwrap() = KeySample::build(&map);
+        for (keyspace, entries) in self.point_index.write().unwrap().iter_mut() {
+            *entries = index_points(&map, keyspace);
+        }
+        *self.indexes.lock().unwrap() = load_indexes(&map);
+        *self.views.lock().unwrap() = load_views(&map);
+        state.map = map;
+        state.version = version;
+        state.allocated = version;
+        *self.timeline.lock().unwrap() = timeline;
+    }
+    fn storage(&self) -> Result<&Arc<dyn Storage>> {
+        self.storage
+            .as_ref()
+            .ok_or_else(|| KVError::InvalidInput("in-memory store has no files".to_owned()))
+    }
+    fn update_manifest(
+        &self,
+        write: impl FnOnce(&dyn Storage) -> Result<()>,
+        update: impl FnOnce(&mut StoreManifest),
+    ) -> Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let _guard = self.manifest.lock().unwrap();
+        write(storage.as_ref())?;
+        let mut manifest = StoreManifest::read(storage.as_ref())?;
+        update(&mut manifest);
+        self.wal_segments.store(manifest.segments.len(), Ordering::Relaxed);
+        manifest.save(storage.as_ref())
+    }
+    pub fn remove_obsolete_files(&self) -> Result<Vec<String>> {
+        let storage = self.storage()?;
+        self.settled(|_| {
+            let _guard = self.manifest.lock().unwrap();
+            let manifest = StoreManifest::read(storage.as_ref())?;
+            let live: BTreeSet<String> =
+                manifest.files("snap").into_iter().chain(manifest.files("wal")).map(|(_, name)| name).collect();
+            let mut removed = Vec::new();
+            for ext in ["snap", "wal"] {
+                for (_, name) in scan_files(storage.as_ref(), ext)? {
+                    if !live.contains(&name) {
+                        storage.remove(&name)?;
+                        removed.push(name);
+                    }
+                }
+            }
+            Ok(removed)
+        })
+    }
+    fn rotate_wal(&self) -> Result<Version> {
+        self.settled(|state| self.rotate_wal_locked(state.version).map(|_| state.version))
+    }
+    fn rotate_wal_locked(&self, version: Version) -> Result<()> {
+        if let (Some(storage), Some(wal)) = (&self.storage, &self.wal) {
+            let mut wal = wal.lock().unwrap();
+            if wal.base != version {
+                let mut segment = WAL_MAGIC.to_vec();
+                for (id, (writes, meta)) in self.prepared.lock().unwrap().iter() {
+                    segment.extend_from_slice(&TxnMarker::encode_prepared(*id, writes, meta));
+                }
+                let name = segment_name(version);
+                if self.recycle_wal {
+                    let snapshot = storage_files(storage.as_ref(), "snap")?.last().map_or(0, |(v, _)| *v);
+                    let segments = storage_files(storage.as_ref(), "wal")?;
+                    if let Some(pair) = segments.windows(2).find(|pair| pair[1].0 <= snapshot) {
+                        self.update_manifest(|_| Ok(()), |manifest| {
+                            manifest.segments.remove(&pair[0].0);
+                        })?;
+                        storage.recycle(&pair[0].1, &name)?;
+                    }
+                }
+                self.update_manifest(|storage| storage.append(&name, &segment), |manifest| {
+                    manifest.segments.insert(version);
+                })?;
+                *wal = Wal { base: version };
+                self.wal_backlog.store(segment.len() as u64, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+    fn apply(&self, writes: Writes, at: Option<Version>, timestamp: Option<u64>, txn: Option<u64>) -> Result<Version> {
+        self.apply_traced(writes, CommitMeta::default(), at, timestamp, txn, &mut CommitTrace::default())
+    }
+    fn apply_traced(
+        &self,
+        mut writes: Writes,
+        meta: CommitMeta,
+        at: Option<Version>,
+        timestamp: Option<u64>,
+        txn: Option<u64>,
+        trace: &mut CommitTrace,
+    ) -> Result<Version> {
+        let entered = Instant::now();
+        let evict = self.evict.load(Ordering::Relaxed) && self.parent.is_none();
+        let victims = if evict && at.is_none() { self.pick_victims(&writes) } else { Vec::new() };
+        for key in &victims {
+            writes.insert(key.clone(), None);
+        }
+        self.pipeline.failure()?;
+        let pipelined = self.storage.is_some() && self.wal.is_some();
+        let stage = |version: Version, writes: &mut Writes, state: &mvcc::State| {
+            trace.lock_wait = entered.elapsed();
+            if self.is_closed() {
+                return Err(KVError::Closed);
+            }
+            #[cfg(feature = "fault-injection")]
+            self.fault(FaultPoint::AfterVersionAllocated)?;
+            if at.is_none() && writes.keys().any(|key| keyspace_of(key) == AUDIT_KEYSPACE) {
+                return Err(KVError::InvalidInput(format!("the {} keyspace is written only by the audit log", AUDIT_KEYSPACE)));
+            }
+            let when = timestamp.unwrap_or_else(|| self.now_millis());
+            if let Some(token) = meta.idempotency {
+                if let Some(original) = latest_value(state, &idempotency_key(&token)).and_then(|version| version.parse().ok()) {
+                    trace.replayed = Some(original);
+                    return Err(KVError::TransactionAborted);
+                }
+                expire_idempotency_keys(writes, state, when);
+                writes.insert(idempotency_key(&token), Some(version.to_string()));
+                writes.insert(idempotency_entry(when, &token), Some(String::new()));
+            }
+            if let Some((source, seq)) = meta.source {
+                let last = latest_value(state, &source_key(&source)).and_then(|last| last.parse().ok());
+                if let Some(last) = last.filter(|&last| last >= seq) {
+                    trace.applied_through = Some(last);
+                    return Err(KVError::TransactionAborted);
+                }
+                writes.insert(source_key(&source), Some(seq.to_string()));
+            }
+            check_preconditions(&self.policies, meta.expected, state)?;
+            maintain_ttls(writes, state);
+            check_append_only(writes, state)?;
+            check_holds(writes, state)?;
+            if at.is_none() {
+                self.check_commit(version, writes, state)?;
+            }
+            let view_changes = self.maintain_views(writes, state);
+            let index_changes = self.maintain_indexes(writes, state);
+            if self.audit.load(Ordering::Relaxed) {
+                self.record_audit(version, when, (meta.actor, meta.txn), writes, state)?;
+            }
+            let growth = self.keyspace_growth(writes, state)?;
+            let added = write_bytes(state, writes);
+            let freed: usize = victims
+                .iter()
+                .filter_map(|key| state.map.get_key_value(key))
+                .map(|(key, versions)| chain_bytes(key, versions) + version_bytes::<String>(&None))
+                .sum();
+            let limit = self.memory_limit.load(Ordering::Relaxed);
+            let memory = self.memory.load(Ordering::Relaxed);
+            if memory.saturating_add(added) > limit.saturating_add(freed) {
+                return Err(KVError::OutOfMemoryBudget(LimitExceeded {
+                    limit: Limit::MemoryLimit,
+                    keyspace: None,
+                    usage: memory.saturating_sub(freed) as u64,
+                    requested: added as u64,
+                    max: limit as u64,
+                }));
+            }
+            let mut prepared = self.prepared.lock().unwrap();
+            if let Some(id) = txn.filter(|id| !prepared.contains_key(id)) {
+                return Err(KVError::InvalidInput(format!("transaction {} is no longer prepared", id)));
+            }
+            let mut overfull = false;
+            for key in writes.keys() {
+                self.cache.invalidate(key);
+                if !state.map.contains_key(key) {
+                    overfull |= self.bloom.read().unwrap().insert(key);
+                }
+            }
+            for key in writes.keys() {
+                self.hot.record(key, true);
+            }
+            if overfull {
+                let keys = state.map.keys().map(Key::as_str).chain(writes.keys().map(String::as_str).filter(|key| !state.map.contains_key(key)));
+                *self.bloom.write().unwrap() = Bloom::build_for(state.map.len() + writes.len(), keys);
+            }
+            let mut timeline = self.timeline.lock().unwrap();
+            let latest = timeline.last().map_or(0, |&(_, t)| t);
+            let timestamp = when.max(latest);
+            let record = pipelined.then(|| WalRecord::encode_txn(version, timestamp, writes, txn));
+            if let Some(id) = txn {
+                prepared.remove(&id);
+            }
+            if !view_changes.is_empty() {
+                let mut views = self.views.lock().unwrap();
+                for (target, def) in view_changes {
+                    match def {
+                        Some(def) => views.insert(target, def),
+                        None => views.remove(&target),
+                    };
+                }
+            }
+            if !index_changes.is_empty() {
+                let mut indexes = self.indexes.lock().unwrap();
+                for (name, def) in index_changes {
+                    match def {
+                        Some(def) => indexes.insert(name, def),
+                        None => indexes.remove(&name),
+                    };
+                }
+            }
+            self.memory.fetch_add(added, Ordering::Relaxed);
+            if !growth.is_empty() {
+                let mut usage = self.keyspace_usage.lock().unwrap();
+                for (keyspace, delta) in growth {
+                    if let Some(bytes) = usage.get_mut(&keyspace) {
+                        *bytes = bytes.saturating_add_signed(delta);
+                    }
+                }
+            }
+            timeline.push((version, timestamp));
+            let mut feed = self.feed.lock().unwrap();
+            let mut listeners = self.listeners.lock().unwrap();
+            let batch = (!feed.is_empty() || !listeners.is_empty())
+                .then(|| Arc::new(ChangeBatch { version, timestamp, writes: writes.clone(), policies: self.policies.clone() }));
+            match (record, batch) {
+                (Some(record), batch) => {
+                    let segment = self.wal.as_ref().map_or(0, |wal| wal.lock().unwrap().base);
+                    self.wal_backlog.fetch_add(record.len() as u64, Ordering::Relaxed);
+                    self.pipeline.queue.lock().unwrap().pending.push(Staged { version, segment, record, batch });
+                    self.pipeline.queued.notify_one();
+                }
+                (None, Some(batch)) => {
+                    feed.retain(|subscriber| subscriber.send(Arc::clone(&batch)).is_ok());
+                    listeners.retain_mut(|listener| listener.deliver(&batch));
+                }
+                (None, None) => {}
+            }
+            // The side tables keep the version map's interned keys, so they are filled in once the writes are in it.
+            Ok(move |keys: &[Key], state: &mvcc::State| {
+                let latest = |key: &Key| state.map.get(key).and_then(|versions| versions.latest()).and_then(|(_, value)| value.as_deref());
+                let mut sample = self.sample.write().unwrap();
+                for key in keys {
+                    sample.update(key, latest(key));
+                }
+                drop(sample);
+                let mut point_index = self.point_index.write().unwrap();
+                if !point_index.is_empty() {
+                    for key in keys {
+                        if let Some(entries) = point_index.get_mut(keyspace_of(key)) {
+                            entries.insert(key.clone(), (version, latest(key).map(str::to_owned)));
+                        }
+                    }
+                }
+                drop(point_index);
+                if evict {
+                    let mut recency = self.recency.lock().unwrap();
+                    for key in keys {
+                        match latest(key) {
+                            Some(_) => recency.touch_key(key),
+                            None => recency.forget(key),
+                        }
+                    }
+                }
+            })
+        };
+        let committed = match pipelined {
+            true => self.engine.stage(writes, at, false, stage),
+            false => self.engine.commit(writes, at, stage),
+        };
+        if pipelined && (trace.replayed.is_some() || trace.applied_through.is_some()) {
+            self.await_durable(self.engine.with(|state| state.allocated))?;
+        }
+        if let Some(original) = trace.replayed {
+            return Ok(original);
+        }
+        if trace.applied_through.is_some() {
+            return Ok(self.current_version());
+        }
+        let version = committed?;
+        if pipelined {
+            let syncing = Instant::now();
+            self.await_durable(version)?;
+            trace.fsync = syncing.elapsed();
+        }
+        if !victims.is_empty() {
+            self.engine.with(|state| {
+                for key in &victims {
+                    if state.map.get(key).and_then(|versions| versions.iter().next_back()) == Some((&version, &None)) {
+                        let versions = state.map.remove(key).unwrap();
+                        self.memory.fetch_sub(chain_bytes(key, &versions), Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+        Ok(version)
+    }
+    #[cfg(feature = "zstd")]
+    pub fn train_dictionary(self: &Arc<Self>, keyspace: &str, max_size: usize) -> Result<usize> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) {
+            return Err(KVError::InvalidInput(format!("invalid keyspace {:?}", keyspace)));
+        }
+        let prefix = format!("{}{}", keyspace, KEYSPACE_SEPARATOR);
+        let samples: Vec<String> = self.engine.with(|state| {
+            let live = || state.map.range(prefix_range(&prefix)).filter_map(|(_, versions)| versions.latest()?.1.as_deref().map(str::to_owned));
+            let stride = live().count() / DICTIONARY_SAMPLES + 1;
+            live().step_by(stride).collect()
+        });
+        if samples.is_empty() {
+            return Err(KVError::InvalidInput(format!("keyspace {:?} has no values to train on", keyspace)));
+        }
+        let dictionary = zstd::dict::from_samples(&samples, max_size)?;
+        let mut tx = self.begin();
+        tx.put_internal(dictionary_key(keyspace), hex(&dictionary))?;
+        tx.commit()?;
+        Ok(dictionary.len())
+    }
+    pub fn make_append_only(self: &Arc<Self>, keyspace: &str) -> Result<()> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) {
+            return Err(KVError::InvalidInput(format!("invalid keyspace {:?}", keyspace)));
+        }
+        let mut tx = self.begin();
+        if tx.get(&append_only_key(keyspace))?.is_none() {
+            tx.put_internal(append_only_key(keyspace), String::new())?;
+        }
+        tx.commit().map(|_| ())
+    }
+    pub fn append_only_keyspaces(self: &Arc<Self>) -> Vec<String> {
+        let prefix = append_only_key("");
+        self.snapshot().scan(prefix_range(&prefix)).into_iter().map(|(key, _)| key[prefix.len()..].to_owned()).collect()
+    }
+    pub fn hold(self: &Arc<Self>, prefix: &str, label: &str) -> Result<()> {
+        if label.is_empty() || label.contains(KEYSPACE_SEPARATOR) {
+            return Err(KVError::InvalidInput(format!("invalid hold label {:?}", label)));
+        }
+        let mut tx = self.begin();
+        tx.put_internal(hold_key(label, prefix), String::new())?;
+        tx.commit().map(|_| ())
+    }
+    pub fn release(self: &Arc<Self>, prefix: &str, label: &str) -> Result<bool> {
+        let mut tx = self.begin();
+        if tx.get(&hold_key(label, prefix))?.is_none() {
+            return Ok(false);
+        }
+        tx.delete_internal(hold_key(label, prefix))?;
+        tx.commit().map(|_| true)
+    }
+    pub fn holds(self: &Arc<Self>) -> Vec<(String, String)> {
+        let holds = hold_key("", "");
+        let holds = &holds[..holds.len() - 1];
+        self.snapshot()
+            .scan(prefix_range(holds))
+            .into_iter()
+            .filter_map(|(key, _)| key[holds.len()..].split_once(KEYSPACE_SEPARATOR).map(|(label, prefix)| (prefix.to_owned(), label.to_owned())))
+            .collect()
+    }
+    pub fn enable_audit(&self) -> Result<()> {
+        self.writable()?;
+        self.update_manifest(|_| Ok(()), |manifest| manifest.audit = true)?;
+        self.audit.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    pub fn audit_since(self: &Arc<Self>, after: Version) -> Result<Vec<AuditEntry>> {
+        let prefix = format!("{}{}", AUDIT_KEYSPACE, KEYSPACE_SEPARATOR);
+        let start = format!("{}{:020}{}", prefix, after.saturating_add(1), KEYSPACE_SEPARATOR);
+        let end = prefix_range(&prefix).1;
+        self.snapshot().scan((Bound::Included(start), end)).iter().map(|(key, value)| AuditEntry::parse(&key[prefix.len()..], value)).collect()
+    }
+    pub fn verify_audit(self: &Arc<Self>) -> Result<usize> {
+        let entries = self.audit_since(0)?;
+        let mut head = String::new();
+        for entry in &entries {
+            if entry.chain(&head) != entry.hash {
+                return Err(KVError::Corruption(format!("audit chain broken at version {} key {}", entry.version, entry.key)));
+            }
+            head = entry.hash.clone();
+        }
+        Ok(entries.len())
+    }
+    fn record_audit(
+        &self,
+        version: Version,
+        when: u64,
+        (actor, txn): (Option<String>, Option<u64>),
+        writes: &mut Writes,
+        state: &mvcc::State,
+    ) -> Result<()> {
+        let prefix = format!("{}{}", AUDIT_KEYSPACE, KEYSPACE_SEPARATOR);
+        if writes.keys().any(|key| key.starts_with(&prefix)) {
+            return Ok(());
+        }
+        let mut head = match state.map.range(prefix_range(&prefix)).next_back() {
+            Some((key, versions)) => match versions.values().next_back() {
+                Some(Some(value)) => AuditEntry::parse(&key[prefix.len()..], value)?.hash,
+                _ => String::new(),
+            },
+            None => String::new(),
+        };
+        let digest = |value: &Option<String>| value.as_ref().map(|value| hex(&sha256(value.as_bytes())));
+        let mut entries = Writes::new();
+        for (key, value) in writes.iter().filter(|(key, _)| keyspace_of(key) != INDEX_KEYSPACE) {
+            let old = state.map.get(key).and_then(|versions| versions.values().next_back()).and_then(|value| value.as_deref().map(str::to_owned));
+            let mut entry = AuditEntry {
+                version,
+                key: key.clone(),
+                when,
+                actor: actor.clone(),
+                txn,
+                old_hash: digest(&old),
+                new_hash: digest(value),
+                hash: String::new(),
+            };
+            entry.hash = entry.chain(&head);
+            head = entry.hash.clone();
+            entries.insert(format!("{}{:020}{}{}", prefix, version, KEYSPACE_SEPARATOR, key), Some(entry.encode()));
+        }
+        writes.extend(entries);
+        Ok(())
+    }
+    pub fn create_index(self: &Arc<Self>, name: &str, prefix: &str, path: &str) -> Result<()> {
+        self.create_covering_index(name, prefix, path, &[])
+    }
+    pub fn create_covering_index(self: &Arc<Self>, name: &str, prefix: &str, path: &str, include: &[&str]) -> Result<()> {
+        if let Some(bad) = include.iter().find(|field| !field.starts_with('$') || field.contains(['\n', '\t'])) {
+            return Err(KVError::InvalidInput(format!("invalid covered field {:?}", bad)));
+        }
+        let include = include.iter().map(|field| (*field).to_owned()).collect();
+        self.define_index(name, IndexDef { prefix: prefix.to_owned(), path: path.to_owned(), kind: IndexKind::Value, include })
+    }
+    pub fn create_text_index(self: &Arc<Self>, name: &str, prefix: &str, path: &str) -> Result<()> {
+        let def = IndexDef { prefix: prefix.to_owned(), path: path.to_owned(), kind: IndexKind::FullText, include: Vec::new() };
+        self.define_index(name, def)
+    }
+    pub fn search(self: &Arc<Self>, query: &str) -> Vec<String> {
+        self.snapshot().search(query)
+    }
+    pub fn set_key_order(
+        &self,
+        keyspace: &str,
+        order: impl Fn(&str, &str) -> std::cmp::Ordering + Send + Sync + 'static,
+    ) -> Result<()> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) || keyspace.starts_with("__") {
+            return Err(KVError::InvalidInput(format!("cannot order keyspace {:?}", keyspace)));
+        }
+        self.key_orders.write().unwrap().insert(keyspace.to_owned(), Arc::new(order));
+        Ok(())
+    }
+    pub fn enable_point_index(&self, keyspace: &str) {
+        self.engine.with(|state| {
+            self.point_index.write().unwrap().insert(keyspace.to_owned(), index_points(&state.map, keyspace));
+        })
+    }
+    pub fn disable_point_index(&self, keyspace: &str) -> bool {
+        self.point_index.write().unwrap().remove(keyspace).is_some()
+    }
+    fn point_lookup(&self, key: &str, version: Version) -> Option<Option<String>> {
+        let index = self.point_index.read().unwrap();
+        if index.is_empty() {
+            return None;
+        }
+        match index.get(keyspace_of(key))?.get(key) {
+            Some((latest, value)) if *latest <= version => Some(value.clone()),
+            Some(_) => None,
+            None => Some(None),
+        }
+    }
+    pub fn set_case_insensitive(&self, keyspace: &str) -> Result<()> {
+        if keyspace.is_empty() || keyspace.contains(KEYSPACE_SEPARATOR) || keyspace.starts_with("__") {
+            return Err(KVError::InvalidInput(format!("cannot fold keys of keyspace {:?}", keyspace)));
+        }
+        self.case_folded.write().unwrap().insert(keyspace.to_owned());
+        Ok(())
+    }
+    fn is_case_folded(&self, key: &str) -> bool {
+        let folded = self.case_folded.read().unwrap();
+        !folded.is_empty() && folded.contains(keyspace_of(key))
+    }
+    fn fold_key(&self, key: &str) -> Option<String> {
+        if !self.is_case_folded(key) {
+            return None;
+        }
+        let (keyspace, rest) = key.split_once(KEYSPACE_SEPARATOR)?;
+        Some(format!("{}{}{}", keyspace, KEYSPACE_SEPARATOR, rest.to_lowercase()))
+    }
+    fn fold_range<R: RangeBounds<String>>(&self, range: &R) -> (Bound<String>, Bound<String>) {
+        let fold = |bound: Bound<&String>| match bound {
+            Bound::Included(key) => Bound::Included(self.fold_key(key).unwrap_or_else(|| key.clone())),
+            Bound::Excluded(key) => Bound::Excluded(self.fold_key(key).unwrap_or_else(|| key.clone())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        (fold(range.start_bound()), fold(range.end_bound()))
+    }
+    fn order_keys<V>(&self, entries: &mut [(String, V)]) {
+        let orders = self.key_orders.read().unwrap();
+        if orders.is_empty() {
+            return;
+        }
+        entries.sort_by(|(a, _), (b, _)| {
+            let keyspace = keyspace_of(a);
+            match orders.get(keyspace) {
+                Some(order) if keyspace == keyspace_of(b) => order(a, b),
+                _ => a.cmp(b),
+            }
+        });
+    }
+    pub fn set_validator(
+        &self,
+        keyspace: &str,
+        validator: impl Fn(&str, &str) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.validators.write().unwrap().insert(keyspace.to_owned(), Arc::new(validator));
+    }
+    pub fn clear_validator(&self, keyspace: &str) -> bool {
+        self.validators.write().unwrap().remove(keyspace).is_some()
+    }
+    pub fn set_commit_validator<E: std::error::Error + Send + Sync + 'static>(
+        &self,
+        keyspace: &str,
+        validator: impl Fn(&CommitView) -> std::result::Result<(), E> + Send + Sync + 'static,
+    ) {
+        let validator: CommitValidator = Arc::new(move |view| validator(view).map_err(|e| Box::new(e) as _));
+        self.commit_validators.write().unwrap().insert(keyspace.to_owned(), validator);
+    }
+    pub fn clear_commit_validator(&self, keyspace: &str) -> bool {
+        self.commit_validators.write().unwrap().remove(keyspace).is_some()
+    }
+    fn check_commit(&self, version: Version, writes: &Writes, state: &mvcc::State) -> Result<()> {
+        let validators = self.commit_validators.read().unwrap();
+        if validators.is_empty() {
+            return Ok(());
+        }
+        let view = CommitView { version, writes, state };
+        for (keyspace, validator) in validators.iter() {
+            if writes.keys().any(|key| keyspace_of(key) == keyspace) {
+                validator(&view).map_err(|reason| KVError::Rejected {
+                    keyspace: keyspace.clone(),
+                    reason: match self.keyspace_policy(keyspace).sensitive {
+                        true => "<redacted for a sensitive keyspace>".into(),
+                        false => reason,
+                    },
+                })?;
+            }
+        }
+        Ok(())
+    }
+    pub fn register_schema(&self, keyspace: &str, schema: Schema) {
+        self.schemas.write().unwrap().insert(keyspace.to_owned(), Arc::new(schema));
+    }
+    fn schema(&self, key: &str) -> Option<Arc<Schema>> {
+        self.schemas.read().unwrap().get(keyspace_of(key)).cloned()
+    }
+    fn check_key_size(&self, key: &str) -> Result<()> {
+        let max = self.max_key_bytes.load(Ordering::Relaxed);
+        match key.len() > max {
+            true => Err(KVError::KeyTooLarge { len: key.len(), max }),
+            false => Ok(()),
+        }
+    }
+    fn validate(&self, key: &str, value: &str) -> Result<()> {
+        self.check_key_size(key)?;
+        let max = self.max_value_bytes.load(Ordering::Relaxed);
+        if value.len() > max {
+            let key = key.chars().take(64).collect();
+            return Err(KVError::ValueTooLarge { key, len: value.len(), max });
+        }
+        let keyspace = keyspace_of(key);
+        let Some(validator) = self.validators.read().unwrap().get(keyspace).cloned() else {
+            return Ok(());
+        };
+        validator(key, value).map_err(|reason| {
+            let reason = match self.policies.is_sensitive(key) {
+                true => "<redacted for a sensitive keyspace>".to_owned(),
+                false => reason.to_string(),
+            };
+            KVError::InvalidInput(format!("value for {} rejected by the {:?} keyspace validator: {}", key, keyspace, reason))
+        })
+    }
+    fn define_index(self: &Arc<Self>, name: &str, def: IndexDef) -> Result<()> {
+        let path = &def.path;
+        if name.is_empty() || name.contains(KEYSPACE_SEPARATOR) || !path.starts_with('$') {
+            return Err(KVError::InvalidInput(format!("invalid index {:?} on {:?}", name, path)));
+        }
+        if self.indexes.lock().unwrap().contains_key(name) {
+            return Err(KVError::InvalidInput(format!("index {} already exists", name)));
+        }
+        let mut tx = self.begin();
+        tx.put_internal(index_def_key(name), def.encode())?;
+        tx.put_internal(index_build_key(name), String::new())?;
+        tx.commit()?;
+        self.build_indexes();
+        Ok(())
+    }
+    pub fn drop_index(self: &Arc<Self>, name: &str) -> Result<()> {
+        if !self.indexes.lock().unwrap().contains_key(name) {
+            return Err(KVError::InvalidInput(format!("no index named {}", name)));
+        }
+        let mut tx = self.begin();
+        tx.delete_internal(index_def_key(name))?;
+        tx.delete_internal(index_build_key(name))?;
+        tx.commit().map(|_| ())
+    }
+    pub fn rebuild_index(self: &Arc<Self>, name: &str) -> Result<()> {
+        let Some(def) = self.indexes.lock().unwrap().get(name).cloned() else {
+            return Err(KVError::InvalidInput(format!("no index named {}", name)));
+        };
+        let mut tx = self.begin();
+        tx.put_internal(index_def_key(name), def.encode())?;
+        tx.put_internal(index_build_key(name), String::new())?;
+        tx.commit()?;
+        self.build_indexes();
+        Ok(())
+    }
+    pub fn index_ready(&self, name: &str) -> bool {
+        let version = self.current_version();
+        self.engine.read_at(&index_def_key(name), version).is_some()
+            && self.engine.read_at(&index_build_key(name), version).is_none()
+    }
+    fn building_indexes(&self) -> Vec<(String, String)> {
+        let prefix = index_build_key("");
+        self.engine
+            .scan_at(prefix_range(&prefix), self.current_version())
+            .into_iter()
+            .map(|(key, cursor)| (key[prefix.len()..].to_owned(), cursor))
+            .collect()
+    }
+    fn build_indexes(self: &Arc<Self>) {
+        if self.is_read_only() || self.backfilling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let store = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            let Some(store) = store.upgrade().filter(|store| !store.is_closed()) else {
+                break;
+            };
+            let pending = store.building_indexes();
+            if pending.is_empty() {
+                store.backfilling.store(false, Ordering::Release);
+                if store.building_indexes().is_empty() || store.backfilling.swap(true, Ordering::AcqRel) {
+                    break;
+                }
+                continue;
+            }
+            for (name, cursor) in pending {
+                let mut tx = store.begin();
+                if tx.put_internal(index_build_key(&name), cursor).is_err() || tx.commit().is_err() {
+                    break;
+                }
+            }
+            drop(store);
+            thread::sleep(INDEX_BACKFILL_PAUSE);
+        });
+    }
+    pub fn indexes(&self) -> Vec<(String, IndexDef)> {
+        self.indexes.lock().unwrap().iter().map(|(name, def)| (name.clone(), def.clone())).collect()
+    }
+    fn maintain_indexes(&self, writes: &mut Writes, state: &mvcc::State) -> Vec<(String, Option<IndexDef>)> {
+        let changes: Vec<(String, Option<IndexDef>)> = writes
+            .iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix(INDEX_KEYSPACE)?.strip_prefix(KEYSPACE_SEPARATOR)?;
+                (!name.contains(KEYSPACE_SEPARATOR)).then(|| (name.to_owned(), value.as_deref().and_then(IndexDef::decode)))
+            })
+            .collect();
+        let indexes = self.indexes.lock().unwrap();
+        if indexes.is_empty() && changes.is_empty() {
+            return changes;
+        }
+        let latest = |key: &str| state.map.get(key).and_then(|versions| versions.values().next_back()).and_then(|value| value.as_deref().map(str::to_owned));
+        let build_prefix = index_build_key("");
+        let building: Vec<(String, Option<IndexDef>, String)> = writes
+            .iter()
+            .filter_map(|(key, cursor)| {
+                let name = key.strip_prefix(&build_prefix)?;
+                let def = match changes.iter().find(|(changed, _)| changed == name) {
+                    Some((_, def)) => def.clone(),
+                    None => indexes.get(name).cloned(),
+                };
+                Some((name.to_owned(), def, cursor.clone()?))
+            })
+            .collect();
+        let mut extra = Writes::new();
+        for (name, def) in indexes.iter().filter(|(name, _)| !changes.iter().any(|(changed, _)| changed == *name)) {
+            for (key, value) in writes.iter().filter(|(key, _)| keyspace_of(key) != INDEX_KEYSPACE) {
+                let (old, new) = (def.entries(name, key, &latest(key)), def.entries(name, key, value));
+                extra.extend(old.keys().filter(|entry| !new.contains_key(*entry)).map(|entry| (entry.clone(), None)));
+                extra.extend(
+                    new.into_iter().filter(|(entry, covered)| old.get(entry) != Some(covered)).map(|(entry, covered)| (entry, Some(covered))),
+                );
+            }
+        }
+        drop(indexes);
+        for (name, def) in &changes {
+            let entries = format!("{}{}{}{}", INDEX_KEYSPACE, KEYSPACE_SEPARATOR, name, KEYSPACE_SEPARATOR);
+            for (key, versions) in state.map.range(prefix_range(&entries)) {
+                if matches!(versions.values().next_back(), Some(Some(_))) {
+                    extra.insert(key.to_string(), None);
+                }
+            }
+            let Some(def) = def else { continue };
+            let online = building.iter().any(|(building, _, _)| building == name);
+            for (key, _) in state.map.range(prefix_range(&def.prefix)).filter(|_| !online) {
+                if !writes.contains_key(key.as_str()) {
+                    extra.extend(def.entries(name, key, &latest(key)).into_iter().map(|(entry, covered)| (entry, Some(covered))));
+                }
+            }
+            for (key, value) in writes.iter().filter(|(key, _)| keyspace_of(key) != INDEX_KEYSPACE) {
+                extra.extend(def.entries(name, key, value).into_iter().map(|(entry, covered)| (entry, Some(covered))));
+            }
+        }
+        for (name, def, cursor) in building {
+            let Some(def) = def else {
+                extra.insert(index_build_key(&name), None);
+                continue;
+            };
+            let start = if cursor.is_empty() { Bound::Included(def.prefix.clone()) } else { Bound::Excluded(cursor) };
+            let (_, end) = prefix_range(&def.prefix);
+            let batch = state.map.range((start, end)).filter(|(key, _)| keyspace_of(key) != INDEX_KEYSPACE);
+            let mut last = None;
+            let mut count = 0;
+            for (key, _) in batch.take(SCAN_CHUNK) {
+                count += 1;
+                last = Some(key.to_string());
+                if writes.contains_key(key.as_str()) {
+                    continue;
+                }
+                for (entry, covered) in def.entries(&name, key, &latest(key)) {
+                    if extra.contains_key(&entry) || latest(&entry).as_ref() != Some(&covered) {
+                        extra.insert(entry, Some(covered));
+                    }
+                }
+            }
+            extra.insert(index_build_key(&name), last.filter(|_| count == SCAN_CHUNK));
+        }
+        writes.extend(extra);
+        changes
+    }
+    fn define_view(&self, target: &str, def: ViewDef) -> Result<()> {
+        if target.is_empty() || keyspace_of(target).starts_with("__") || target.starts_with(def.prefix.as_str()) {
+            return Err(KVError::InvalidInput(format!("{:?} cannot hold a view over {:?}", target, def.prefix)));
+        }
+        if let ViewAggregate::LastN(0) = def.aggregate {
+            return Err(KVError::InvalidInput("a last-n view must keep at least one key".to_owned()));
+        }
+        if self.views.lock().unwrap().contains_key(target) {
+            return Err(KVError::InvalidInput(format!("view {} already exists", target)));
+        }
+        self.writable()?;
+        self.apply(Writes::from([(view_def_key(target), Some(def.encode()))]), None, None, None).map(|_| ())
+    }
+    pub fn drop_view(&self, target: &str) -> Result<()> {
+        if !self.views.lock().unwrap().contains_key(target) {
+            return Err(KVError::InvalidInput(format!("no view named {}", target)));
+        }
+        self.writable()?;
+        self.apply(Writes::from([(view_def_key(target), None)]), None, None, None).map(|_| ())
+    }
+    pub fn views(&self) -> Vec<(String, ViewDef)> {
+        self.views.lock().unwrap().iter().map(|(target, def)| (target.clone(), def.clone())).collect()
+    }
+    fn maintain_views(&self, writes: &mut Writes, state: &mvcc::State) -> Vec<(String, Option<ViewDef>)> {
+        let defs = view_def_key("");
+        let changes: Vec<(String, Option<ViewDef>)> = writes
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&defs)?.to_owned(), value.as_deref().and_then(ViewDef::decode))))
+            .collect();
+        let views = self.views.lock().unwrap();
+        if views.is_empty() && changes.is_empty() {
+            return changes;
+        }
+        let mut extra = Writes::new();
+        for (target, def) in views.iter().filter(|(target, _)| !changes.iter().any(|(changed, _)| changed == *target)) {
+            let touched: Vec<_> = writes
+                .iter()
+                .filter(|(key, _)| def.covers(key))
+                .map(|(key, value)| (key.as_str(), latest_value(state, key), value.as_deref()))
+                .collect();
+            if !touched.is_empty() {
+                let current = writes.get(target).cloned().unwrap_or_else(|| latest_value(state, target));
+                extra.insert(target.clone(), Some(def.update(current.as_deref(), &touched)));
+            }
+        }
+        drop(views);
+        for (target, def) in &changes {
+            extra.insert(target.clone(), def.as_ref().map(|def| def.compute(state, writes)));
+        }
+        writes.extend(extra);
+        changes
+    }
+    fn all_history(&self) -> Vec<(String, History)> {
+        self.settled(|state| {
+            state
+                .map
+                .iter()
+                .map(|(key, versions)| (key.to_string(), versions.iter().map(|(v, val)| (*v, val.as_deref().map(str::to_owned))).collect()))
+                .collect()
+        })
+    }
+}
+impl Drop for KVStore {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(tag: &str) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("kvstore-{}-{}-{}", tag, std::process::id(), seq));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn batch(pairs: &[(&str, &str)]) -> Writes {
+        pairs.iter().map(|(key, value)| ((*key).to_owned(), Some((*value).to_owned()))).collect()
+    }
+    #[test]
+    fn apply_batch_skips_replayed_sequence_numbers() {
+        let dir = TempDir::new("batch");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let applied = store.apply_batch("feed", 1, batch(&[("a", "1")])).unwrap();
+            assert!(matches!(applied, BatchOutcome::Applied(_)));
+            assert_eq!(store.apply_batch("feed", 1, batch(&[("a", "stale")])).unwrap(), BatchOutcome::Skipped { applied_through: 1 });
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.applied_through("feed"), Some(1));
+        assert!(matches!(store.apply_batch("feed", 2, batch(&[("a", "2")])).unwrap(), BatchOutcome::Applied(_)));
+        assert_eq!(store.apply_batch("feed", 2, batch(&[("a", "stale")])).unwrap(), BatchOutcome::Skipped { applied_through: 2 });
+        assert_eq!(store.begin().get("a").unwrap().as_deref(), Some("2"));
+        assert!(store.snapshot().scan(prefix_range("__meta\0")).is_empty());
+    }
+    fn put(store: &Arc<KVStore>, key: &str, value: &str) -> Version {
+        let mut tx = store.begin();
+        tx.put(key.to_owned(), value.to_owned()).unwrap();
+        tx.commit().unwrap()
+    }
+    #[test]
+    fn the_installed_clock_stamps_commits() {
+        let store = KVStore::new();
+        let clock = MockClock::new(42_000);
+        store.set_clock(clock.clone());
+        assert_eq!(store.now_millis(), 42_000);
+        let first = put(&store, "ks:k", "1");
+        clock.advance(Duration::from_secs(3));
+        let second = put(&store, "ks:k", "2");
+        assert_eq!(store.commit_time(first), Some(UNIX_EPOCH + Duration::from_millis(42_000)));
+        assert_eq!(store.commit_time(second), Some(UNIX_EPOCH + Duration::from_millis(45_000)));
+        store.set_clock(Arc::new(SystemClock));
+        assert!(store.now_millis().abs_diff(now_millis()) < 1_000);
+    }
+    #[test]
+    fn custom_version_sources_number_commits_and_survive_a_reopen() {
+        struct Tens;
+        impl VersionSource for Tens {
+            fn next(&self, last: Version) -> Version {
+                (last / 10 + 1) * 10
+            }
+        }
+        let dir = TempDir::new("version-source");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_version_source(Arc::new(Tens));
+            assert_eq!(put(&store, "ks:k", "1"), 10);
+            assert_eq!(put(&store, "ks:k", "2"), 20);
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.current_version(), 20);
+        assert_eq!(store.history("ks:k").into_iter().map(|(version, _)| version).collect::<Vec<_>>(), [10, 20]);
+        assert_eq!(put(&store, "ks:k", "3"), 21);
+        assert_eq!(store.begin_at(15).get("ks:k").unwrap().as_deref(), Some("1"));
+    }
+    #[test]
+    fn hybrid_versions_follow_the_clock_and_never_go_backwards() {
+        let store = KVStore::new();
+        let clock = MockClock::new(1_000_000);
+        store.set_clock(clock.clone());
+        store.set_version_scheme(VersionScheme::HybridClock);
+        let first = put(&store, "ks:k", "1");
+        assert_eq!(first, 1_000_000 << 16);
+        assert_eq!(lww::HlcTimestamp::from_version(first).wall_ms, 1_000_000);
+        let second = put(&store, "ks:k", "2");
+        assert_eq!(second, first + 1);
+        clock.set(500_000);
+        let third = put(&store, "ks:k", "3");
+        assert!(third > second);
+        clock.set(2_000_000);
+        assert_eq!(put(&store, "ks:k", "4"), 2_000_000 << 16);
+        assert_eq!(store.begin_at(second).get("ks:k").unwrap().as_deref(), Some("2"));
+        store.set_version_scheme(VersionScheme::Counter);
+        assert_eq!(put(&store, "ks:k", "5"), (2_000_000 << 16) + 1);
+    }
+    #[test]
+    fn reads_at_a_wall_clock_time_survive_a_reopen() {
+        let dir = TempDir::new("at-time");
+        let at = |ms| UNIX_EPOCH + Duration::from_millis(ms);
+        let (first, last) = {
+            let store = KVStore::open(dir.path()).unwrap();
+            let clock = MockClock::new(10_000);
+            store.set_clock(clock.clone());
+            let first = put(&store, "ks:k", "1");
+            clock.set(20_000);
+            put(&store, "ks:k", "2");
+            clock.set(15_000);
+            let last = put(&store, "ks:k", "3");
+            (first, last)
+        };
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.commit_time(first), Some(at(10_000)));
+        assert_eq!(store.commit_time(last), Some(at(20_000)), "commit times must not run backwards");
+        let read = |ms| store.begin_at_time(at(ms)).unwrap().get("ks:k").unwrap();
+        assert_eq!(read(5_000), None);
+        assert_eq!(read(15_000).as_deref(), Some("1"));
+        assert_eq!(read(20_000).as_deref(), Some("3"));
+    }
+    #[test]
+    fn attached_stores_commit_together_and_keep_their_own_files() {
+        let dir = TempDir::new("attach");
+        {
+            let store = KVStore::open(dir.path().join("main")).unwrap();
+            store.attach("users", dir.path().join("users")).unwrap();
+            assert!(matches!(store.attach("users", dir.path().join("other")), Err(KVError::InvalidInput(_))));
+            assert!(matches!(store.attach("__meta", dir.path().join("other")), Err(KVError::InvalidInput(_))));
+            assert_eq!(store.attached(), vec!["users".to_owned()]);
+            let mut tx = store.begin();
+            tx.put("users:1".to_owned(), "ada".to_owned()).unwrap();
+            tx.put("local".to_owned(), "here".to_owned()).unwrap();
+            tx.commit().unwrap();
+            let keys: Vec<String> = store.begin().scan(..).unwrap().into_iter().map(|(key, _)| key).collect();
+            assert_eq!(keys, ["local", "users:1"]);
+        }
+        let users = KVStore::open(dir.path().join("users")).unwrap();
+        assert_eq!(users.begin().get("1").unwrap().as_deref(), Some("ada"));
+        assert_eq!(users.begin().get("local").unwrap(), None);
+        let store = KVStore::open(dir.path().join("main")).unwrap();
+        assert_eq!(store.begin().get("local").unwrap().as_deref(), Some("here"));
+        assert_eq!(store.begin().get("users:1").unwrap(), None);
+    }
+    #[test]
+    fn branches_share_history_but_not_later_writes() {
+        let store = KVStore::new();
+        put(&store, "ks:shared", "base");
+        let fork = store.current_version();
+        let branch = store.branch("experiment").unwrap();
+        assert!(matches!(store.branch("experiment"), Err(KVError::InvalidInput(_))));
+        assert!(matches!(store.branch(MAIN_BRANCH), Err(KVError::InvalidInput(_))));
+        assert_eq!(store.list_branches(), vec![("experiment".to_owned(), fork)]);
+        put(&branch, "ks:shared", "branch");
+        put(&store, "ks:main", "only main");
+        assert_eq!(branch.begin().get("ks:main").unwrap(), None);
+        assert_eq!(store.begin().get("ks:shared").unwrap().as_deref(), Some("base"));
+        assert_eq!(store.get_branch("experiment").unwrap().begin().get("ks:shared").unwrap().as_deref(), Some("branch"));
+        store.drop_branch("experiment").unwrap();
+        assert!(store.list_branches().is_empty());
+        assert!(matches!(store.drop_branch("experiment"), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn merges_apply_the_branch_diff_and_ask_about_conflicts() {
+        let store = KVStore::new();
+        put(&store, "ks:both", "base");
+        put(&store, "ks:gone", "base");
+        put(&store, "ks:same", "base");
+        let branch = store.branch("feature").unwrap();
+        put(&store, "ks:both", "ours");
+        put(&store, "ks:same", "agreed");
+        put(&branch, "ks:both", "theirs");
+        put(&branch, "ks:same", "agreed");
+        put(&branch, "ks:new", "added");
+        let mut tx = branch.begin();
+        tx.delete("ks:gone".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut asked = Vec::new();
+        let report = store
+            .merge("feature", MAIN_BRANCH, |conflict| {
+                asked.push(conflict.clone());
+                Some(format!("{}+{}", conflict.ours.as_deref()?, conflict.theirs.as_deref()?))
+            })
+            .unwrap();
+        assert_eq!((report.applied, report.conflicts), (3, 1));
+        assert_eq!(report.version, store.current_version());
+        assert_eq!(asked.len(), 1);
+        assert_eq!((asked[0].key.as_str(), asked[0].base.as_deref()), ("ks:both", Some("base")));
+        let tx = store.begin();
+        assert_eq!(tx.get("ks:both").unwrap().as_deref(), Some("ours+theirs"));
+        assert_eq!(tx.get("ks:new").unwrap().as_deref(), Some("added"));
+        assert_eq!(tx.get("ks:gone").unwrap(), None);
+        assert_eq!(tx.get("ks:same").unwrap().as_deref(), Some("agreed"));
+        assert!(matches!(store.merge(MAIN_BRANCH, MAIN_BRANCH, |_| None), Err(KVError::InvalidInput(_))));
+        assert!(matches!(store.merge("missing", MAIN_BRANCH, |_| None), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn forks_start_from_the_parent_and_then_diverge() {
+        let fixture = KVStore::new();
+        let mut tx = fixture.begin();
+        for i in 0..100 {
+            tx.put(format!("ks:{:03}", i), i.to_string()).unwrap();
+        }
+        let fork_version = tx.commit().unwrap();
+        let fork = fixture.fork();
+        assert_eq!((fork.current_version(), fork.fork_version()), (fork_version, Some(fork_version)));
+        let mut tx = fork.begin();
+        tx.delete("ks:000".to_owned()).unwrap();
+        tx.put("ks:100".to_owned(), "100".to_owned()).unwrap();
+        assert!(tx.commit().unwrap() > fork_version);
+        put(&fixture, "ks:001", "changed");
+        let grandchild = fork.fork();
+        put(&grandchild, "ks:002", "deepest");
+        let tx = fork.begin();
+        assert_eq!(tx.get("ks:000").unwrap(), None);
+        assert_eq!(tx.get("ks:001").unwrap().as_deref(), Some("1"));
+        assert_eq!(tx.get("ks:002").unwrap().as_deref(), Some("2"));
+        let keys: Vec<String> = tx.scan(..).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!((keys.len(), keys[0].as_str(), keys[99].as_str()), (100, "ks:001", "ks:100"));
+        assert_eq!(fixture.begin().get("ks:000").unwrap().as_deref(), Some("0"));
+        assert_eq!(fixture.begin().get("ks:100").unwrap(), None);
+        assert_eq!(grandchild.begin().get("ks:100").unwrap().as_deref(), Some("100"));
+    }
+    #[test]
+    fn stats_count_live_keys_versions_and_open_transactions() {
+        let store = KVStore::new();
+        put(&store, "users:1", "ada");
+        put(&store, "users:1", "grace");
+        put(&store, "users:2", "alan");
+        let last = put(&store, "orders:1", "book");
+        let mut tx = store.begin();
+        tx.delete("users:2".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let reader = store.begin();
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.versions, stats.tombstones), (2, 5, 1));
+        assert_eq!(stats.keyspaces, BTreeMap::from([("orders".to_owned(), 1), ("users".to_owned(), 1)]));
+        assert_eq!(stats.current_version, last + 1);
+        assert_eq!(stats.open_transactions, 1);
+        assert!(stats.oldest_transaction.is_some() && stats.approx_bytes > 0);
+        drop(reader);
+        let fork = store.fork();
+        put(&fork, "orders:2", "pen");
+        let stats = fork.stats();
+        assert_eq!((stats.keys, stats.open_transactions), (3, 0));
+        assert_eq!(stats.keyspaces["orders"], 2);
+    }
+    #[test]
+    fn commits_over_the_memory_cap_fail_and_leave_the_store_alone() {
+        let store = KVStore::new();
+        put(&store, "ks:small", "x");
+        let used = store.memory_usage();
+        assert!(used > 0);
+        store.set_memory_limit(Some(used + 200));
+        assert_eq!(store.memory_limit(), Some(used + 200));
+        let mut tx = store.begin();
+        tx.put("ks:big".to_owned(), "v".repeat(1000)).unwrap();
+        match tx.commit() {
+            Err(KVError::OutOfMemoryBudget(exceeded)) => {
+                assert_eq!((exceeded.limit, exceeded.max), (Limit::MemoryLimit, (used + 200) as u64));
+                assert!(exceeded.requested >= 1000);
+            }
+            other => panic!("expected the memory cap to refuse the commit, got {:?}", other),
+        }
+        assert_eq!(store.memory_usage(), used);
+        assert_eq!(store.begin().get("ks:big").unwrap(), None);
+        put(&store, "ks:fits", "y");
+        assert!(store.memory_usage() > used);
+        store.set_memory_limit(None);
+        put(&store, "ks:big", &"v".repeat(1000));
+        assert!(store.memory_usage() > used + 1000);
+    }
+    #[test]
+    fn usage_reports_budgets_before_a_limit_error_does() {
+        let store = KVStore::new();
+        put(&store, "logs:1", "0123456789");
+        let logs = store.usage("logs");
+        assert_eq!((logs.keyspace.as_str(), logs.bytes, logs.budget, logs.store_limit), ("logs", 16, None, None));
+        assert_eq!((logs.remaining(), logs.used_fraction()), (None, None));
+        store.set_keyspace_policy("tenant", KeyspacePolicy::new().memory_budget(100)).unwrap();
+        put(&store, "tenant:a", &"x".repeat(42));
+        let tenant = store.usage("tenant");
+        assert_eq!((tenant.bytes, tenant.budget, tenant.remaining()), (50, Some(100), Some(50)));
+        assert_eq!(tenant.used_fraction(), Some(0.5));
+        store.set_memory_limit(Some(store.memory_usage() + 10));
+        let tenant = store.usage("tenant");
+        assert_eq!(tenant.remaining(), Some(10));
+        assert!(tenant.used_fraction().unwrap() > 0.9);
+        store.set_memory_limit(None);
+        let mut tx = store.begin();
+        tx.put("tenant:b".to_owned(), "y".repeat(60)).unwrap();
+        let Err(KVError::OutOfMemoryBudget(exceeded)) = tx.commit() else { panic!("expected the tenant budget to refuse the write") };
+        assert_eq!(exceeded, LimitExceeded { limit: Limit::KeyspaceBudget, keyspace: Some("tenant".to_owned()), usage: 50, requested: 68, max: 100 });
+        assert_eq!(exceeded.to_string(), r#"keyspace "tenant" holds 50 bytes; 68 more exceeds its budget of 100 bytes"#);
+        assert!(KVError::OutOfMemoryBudget(exceeded).to_string().contains("budget of 100 bytes"));
+        assert_eq!(store.usage("tenant").bytes, 50);
+    }
+    #[test]
+    fn the_cache_policy_evicts_the_least_recently_read_keys() {
+        let store = KVStore::new();
+        store.set_memory_policy(MemoryPolicy::EvictLeastRecentlyRead);
+        for key in ["ks:a", "ks:b", "ks:c"] {
+            put(&store, key, &"v".repeat(100));
+        }
+        store.set_memory_limit(Some(store.memory_usage() + 50));
+        assert!(store.begin().get("ks:a").unwrap().is_some());
+        put(&store, "ks:d", &"v".repeat(100));
+        let tx = store.begin();
+        assert_eq!(tx.get("ks:b").unwrap(), None, "the oldest unread key goes first");
+        assert!(tx.get("ks:a").unwrap().is_some());
+        assert!(tx.get("ks:d").unwrap().is_some());
+        assert!(store.memory_usage() <= store.memory_limit().unwrap());
+        store.set_memory_policy(MemoryPolicy::Fail);
+        let mut tx = store.begin();
+        tx.put("ks:e".to_owned(), "v".repeat(100)).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::OutOfMemoryBudget(_))));
+    }
+    #[test]
+    fn the_read_cache_serves_the_latest_value_and_commits_invalidate_it() {
+        let store = KVStore::new();
+        put(&store, "ks:hot", "1");
+        let old = store.begin();
+        let second = put(&store, "ks:hot", "2");
+        assert_eq!(store.begin().get("ks:hot").unwrap().as_deref(), Some("2"));
+        assert_eq!(store.cache.get("ks:hot", Version::MAX), Some(Some("2".to_owned())));
+        assert_eq!(store.cache.get("ks:hot", second - 1), None);
+        assert_eq!(old.get("ks:hot").unwrap().as_deref(), Some("1"));
+        put(&store, "ks:hot", "3");
+        assert_eq!(store.cache.get("ks:hot", Version::MAX), None);
+        assert_eq!(store.begin().get("ks:hot").unwrap().as_deref(), Some("3"));
+    }
+    #[test]
+    fn cached_reads_never_go_back_in_time_under_concurrent_commits() {
+        let store = KVStore::builder().read_cache_slots(4).open().unwrap();
+        put(&store, "ks:hot", "0");
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (store, done) = (Arc::clone(&store), Arc::clone(&done));
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let tx = store.begin();
+                        let seen: u64 = tx.get("ks:hot").unwrap().unwrap().parse().unwrap();
+                        assert!(seen >= last, "read {} after {}", seen, last);
+                        last = seen;
+                    }
+                    last
+                })
+            })
+            .collect();
+        for i in 1..=300 {
+            put(&store, "ks:hot", &i.to_string());
+            assert_eq!(store.begin().get("ks:hot").unwrap(), Some(i.to_string()), "a commit left a stale cache entry");
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            assert!(reader.join().unwrap() <= 300);
+        }
+        assert_eq!(store.begin().get("ks:hot").unwrap().as_deref(), Some("300"));
+        assert_eq!(store.cache.get("ks:hot", Version::MAX), Some(Some("300".to_owned())));
+    }
+    #[test]
+    fn the_bloom_filter_tracks_live_keys_and_grows_with_them() {
+        let dir = TempDir::new("bloom");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let capacity = store.bloom.read().unwrap().capacity;
+            let mut tx = store.begin();
+            for i in 0..capacity + 1 {
+                tx.put(format!("ks:{}", i), "v".to_owned()).unwrap();
+            }
+            tx.commit().unwrap();
+            assert!(store.bloom.read().unwrap().capacity > capacity);
+            store.checkpoint().unwrap();
+            put(&store, "ks:after", "v");
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let bloom = store.bloom.read().unwrap();
+        assert!((0..1000).all(|i| bloom.may_contain(&format!("ks:{}", i))));
+        assert!(bloom.may_contain("ks:after"));
+        let false_positives = (0..1000).filter(|i| bloom.may_contain(&format!("missing:{}", i))).count();
+        assert!(false_positives < 50, "{} false positives in 1000 misses", false_positives);
+        drop(bloom);
+        assert_eq!(store.begin().get("missing:1").unwrap(), None);
+    }
+    #[test]
+    fn commits_land_between_the_batches_of_a_compaction_pass() {
+        let dir = TempDir::new("compact-batches");
+        let store = KVStore::open(dir.path()).unwrap();
+        store.set_retention("ks", Retention::LatestOnly);
+        let keys = SCAN_CHUNK * 3;
+        let key = |i: usize| format!("ks:{:05}", i);
+        for round in 0..2 {
+            let mut tx = store.begin();
+            for i in 0..keys {
+                tx.put(key(i), round.to_string()).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let limits = CompactionLimits { cpu: Some(0.05), ..CompactionLimits::default() };
+        thread::scope(|scope| {
+            let compaction = scope.spawn(|| store.run_compaction(&limits, &AtomicBool::new(false)));
+            // The first batch collected while the last is not can only be seen if the lock is released mid-pass.
+            wait_until("the first batch to be collected", || store.history(&key(0)).len() == 1);
+            let version = put(&store, "other:k", "v");
+            assert_eq!(store.history(&key(keys - 1)).len(), 2, "the commit waited for the whole pass");
+            let report = compaction.join().unwrap().unwrap();
+            assert_eq!(report.gc.versions_removed, keys);
+            assert!(report.version >= version);
+        });
+        assert_eq!(store.history(&key(keys - 1)).len(), 1);
+        assert_eq!(store.begin().get(&key(keys - 1)).unwrap().as_deref(), Some("1"));
+    }
+    #[test]
+    fn compaction_rebuilds_the_bloom_filter_from_live_keys() {
+        let dir = TempDir::new("bloom-compact");
+        let store = KVStore::open(dir.path()).unwrap();
+        store.set_retention("ks", Retention::LatestOnly);
+        let mut tx = store.begin();
+        for i in 0..2000 {
+            tx.put(format!("ks:{}", i), "v".to_owned()).unwrap();
+        }
+        tx.commit().unwrap();
+        let mut tx = store.begin();
+        for i in 0..2000 {
+            tx.delete(format!("ks:{}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+        put(&store, "ks:kept", "v");
+        let (inserted, capacity) = {
+            let bloom = store.bloom.read().unwrap();
+            assert!(bloom.may_contain("ks:7"), "deleted keys stay in the filter until compaction");
+            (bloom.inserted.load(Ordering::Relaxed), bloom.capacity)
+        };
+        store.compact().unwrap();
+        let bloom = store.bloom.read().unwrap();
+        assert!(!bloom.may_contain("ks:7"));
+        assert!(bloom.may_contain("ks:kept"));
+        let stale = (0..2000).filter(|i| bloom.may_contain(&format!("ks:{}", i))).count();
+        assert!(stale < 100, "{} deleted keys still probe positive", stale);
+        assert!(bloom.inserted.load(Ordering::Relaxed) < inserted);
+        assert!(bloom.capacity < capacity);
+        drop(bloom);
+        assert_eq!(store.begin().get("ks:7").unwrap(), None);
+        assert_eq!(store.begin().get("ks:kept").unwrap().as_deref(), Some("v"));
+    }
+    #[test]
+    fn time_based_retention_drops_versions_superseded_before_the_window() {
+        let store = KVStore::new();
+        let clock = MockClock::new(1_000);
+        store.set_clock(clock.clone());
+        put(&store, "ks:k", "1");
+        put(&store, "other:k", "1");
+        clock.set(1_500);
+        put(&store, "ks:gone", "x");
+        let mut tx = store.begin();
+        tx.delete("ks:gone".to_owned()).unwrap();
+        tx.commit().unwrap();
+        clock.set(2_000);
+        let kept = put(&store, "ks:k", "2");
+        put(&store, "other:k", "2");
+        clock.set(5_000);
+        let latest = put(&store, "ks:k", "3");
+        store.set_retention("ks", Retention::KeepFor(Duration::from_secs(2)));
+        let report = store.gc();
+        assert_eq!((report.versions_removed, report.keys_removed), (3, 1));
+        let versions: Vec<Version> = store.history("ks:k").into_iter().map(|(version, _)| version).collect();
+        assert_eq!(versions, [kept, latest]);
+        assert!(store.history("ks:gone").is_empty());
+        assert_eq!(store.history("other:k").len(), 2);
+        assert_eq!(store.begin_at(kept).get("ks:k").unwrap().as_deref(), Some("2"));
+    }
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn injected_faults_fail_commits_without_losing_earlier_ones() {
+        let dir = TempDir::new("faults");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let injector = FaultInjector::new();
+            store.install_faults(Some(Arc::clone(&injector)));
+            put(&store, "ks:before", "1");
+            injector.inject(FaultPoint::BeforeWalSync, Fault::Error("disk full".to_owned()), 1);
+            let mut tx = store.begin();
+            tx.put("ks:lost".to_owned(), "x".to_owned()).unwrap();
+            assert!(matches!(tx.commit(), Err(KVError::Io(_))));
+            let mut tx = store.begin();
+            tx.put("ks:lost".to_owned(), "y".to_owned()).unwrap();
+            assert!(tx.commit().is_err(), "a failed wal write must stop the store");
+            assert_eq!(injector.hits(FaultPoint::BeforeWalSync), 2);
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.begin().get("ks:lost").unwrap(), None);
+        assert_eq!(store.begin().get("ks:before").unwrap().as_deref(), Some("1"));
+        let injector = FaultInjector::new();
+        store.install_faults(Some(Arc::clone(&injector)));
+        injector.inject(FaultPoint::AfterVersionAllocated, Fault::Error("crash".to_owned()), 1);
+        let mut tx = store.begin();
+        tx.put("ks:lost".to_owned(), "z".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::Io(_))));
+        injector.inject(FaultPoint::DuringCompaction, Fault::Error("crash".to_owned()), 1);
+        assert!(store.checkpoint().is_err());
+        injector.inject(FaultPoint::BeforeWalSync, Fault::Delay(Duration::from_millis(20)), 1);
+        let started = Instant::now();
+        put(&store, "ks:after", "2");
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        store.checkpoint().unwrap();
+        assert_eq!(injector.hits(FaultPoint::DuringCompaction), 2);
+        drop(store);
+        let store = KVStore::open(dir.path()).unwrap();
+        let tx = store.begin();
+        assert_eq!(tx.get("ks:lost").unwrap(), None);
+        assert_eq!((tx.get("ks:before").unwrap().as_deref(), tx.get("ks:after").unwrap().as_deref()), (Some("1"), Some("2")));
+    }
+    #[test]
+    fn custom_key_orders_sort_scans_within_their_keyspace() {
+        let store = KVStore::new();
+        let numeric = |key: &str| key.rsplit(|c: char| !c.is_ascii_digit()).next().and_then(|n| n.parse::<u64>().ok());
+        store.set_key_order("files", move |a, b| numeric(a).cmp(&numeric(b)).then_with(|| a.cmp(b))).unwrap();
+        assert!(matches!(store.set_key_order("__meta", |a, b| a.cmp(b)), Err(KVError::InvalidInput(_))));
+        let mut tx = store.begin();
+        for key in ["files:file10", "files:file2", "files:file1", "logs:10", "logs:2"] {
+            tx.put(key.to_owned(), String::new()).unwrap();
+        }
+        tx.commit().unwrap();
+        let keys = |range| store.begin().scan(range).unwrap().into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(prefix_range("files:")), ["files:file1", "files:file2", "files:file10"]);
+        assert_eq!(keys(prefix_range("logs:")), ["logs:10", "logs:2"]);
+        assert_eq!(keys(prefix_range(""))[..3], ["files:file1", "files:file2", "files:file10"]);
+    }
+    #[test]
+    fn case_insensitive_keyspaces_match_any_casing_and_keep_the_original() {
+        let store = KVStore::new();
+        store.set_case_insensitive("users").unwrap();
+        assert!(matches!(store.set_case_insensitive("__meta"), Err(KVError::InvalidInput(_))));
+        put(&store, "users:Ada", "1");
+        put(&store, "users:ALAN", "2");
+        put(&store, "other:Ada", "3");
+        let tx = store.begin();
+        assert_eq!(tx.get("users:ada").unwrap().as_deref(), Some("1"));
+        assert_eq!(tx.get("users:ADA").unwrap().as_deref(), Some("1"));
+        assert_eq!(tx.get("other:ada").unwrap(), None);
+        put(&store, "users:aDa", "4");
+        let rows = store.begin().scan(prefix_range("users:")).unwrap();
+        assert_eq!(rows, [("users:aDa".to_owned(), "4".to_owned()), ("users:ALAN".to_owned(), "2".to_owned())]);
+        let mut tx = store.begin();
+        tx.delete("users:alan".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.begin().scan(prefix_range("users:")).unwrap().len(), 1);
+    }
+    #[test]
+    fn recency_shares_one_copy_of_each_key() {
+        let mut recency = Recency::default();
+        recency.touch("ks:a");
+        recency.touch("ks:b");
+        recency.touch("ks:a");
+        let order: Vec<&str> = recency.order.values().map(|key| &**key).collect();
+        assert_eq!(order, ["ks:b", "ks:a"]);
+        let (key, _) = recency.ticks.get_key_value("ks:a").unwrap();
+        assert!(key.ptr_eq(recency.order.values().last().unwrap()));
+        assert_eq!(Key::strong_count(key), 2);
+        recency.forget("ks:a");
+        recency.forget("ks:missing");
+        assert_eq!((recency.ticks.len(), recency.order.len()), (1, 1));
+    }
+    #[test]
+    fn side_tables_share_the_version_maps_key_allocation() {
+        let store = KVStore::new();
+        store.enable_point_index("cfg");
+        store.set_memory_policy(MemoryPolicy::EvictLeastRecentlyRead);
+        put(&store, "cfg:a", "1");
+        put(&store, "users:a", "2");
+        assert_eq!(store.begin().get("users:a").unwrap().as_deref(), Some("2"));
+        store.engine.with(|state| {
+            let indexed = state.map.key("cfg:a").unwrap();
+            let point_index = store.point_index.read().unwrap();
+            assert!(point_index["cfg"].get_key_value("cfg:a").unwrap().0.ptr_eq(indexed));
+            let recency = store.recency.lock().unwrap();
+            assert!(recency.ticks.get_key_value("cfg:a").unwrap().0.ptr_eq(indexed));
+            let cached = state.map.key("users:a").unwrap();
+            assert!(recency.ticks.get_key_value("users:a").unwrap().0.ptr_eq(cached));
+            assert!(store.cache.slot("users:a").load().as_ref().unwrap().key.ptr_eq(cached));
+        });
+    }
+    #[test]
+    fn point_indexes_follow_commits_and_defer_older_reads_to_the_chain() {
+        let store = KVStore::new();
+        put(&store, "cfg:a", "1");
+        store.enable_point_index("cfg");
+        assert_eq!(store.point_lookup("cfg:a", Version::MAX), Some(Some("1".to_owned())));
+        assert_eq!(store.point_lookup("cfg:missing", Version::MAX), Some(None));
+        assert_eq!(store.point_lookup("other:a", Version::MAX), None);
+        let old = store.begin();
+        put(&store, "cfg:a", "2");
+        let mut tx = store.begin();
+        tx.put("cfg:b".to_owned(), "3".to_owned()).unwrap();
+        tx.delete("cfg:a".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.point_lookup("cfg:b", Version::MAX), Some(Some("3".to_owned())));
+        assert_eq!(store.point_lookup("cfg:a", Version::MAX), Some(None));
+        assert_eq!(old.get("cfg:a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.begin().get("cfg:a").unwrap(), None);
+        assert!(store.disable_point_index("cfg"));
+        assert!(!store.disable_point_index("cfg"));
+        assert_eq!(store.begin().get("cfg:b").unwrap().as_deref(), Some("3"));
+    }
+    #[test]
+    fn close_flushes_refuses_new_work_and_releases_the_directory() {
+        let dir = TempDir::new("close");
+        let store = KVStore::builder().path(dir.path()).durability(Durability::Buffered).open().unwrap();
+        put(&store, "ks:k", "1");
+        let mut open = store.begin();
+        open.put("ks:late".to_owned(), "x".to_owned()).unwrap();
+        store.close().unwrap();
+        store.close().unwrap();
+        assert!(store.is_closed());
+        assert!(matches!(open.commit(), Err(KVError::Closed)));
+        let mut tx = store.begin();
+        tx.put("ks:k".to_owned(), "2".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::Closed)));
+        let reopened = KVStore::open(dir.path()).unwrap();
+        let tx = reopened.begin();
+        assert_eq!((tx.get("ks:k").unwrap().as_deref(), tx.get("ks:late").unwrap()), (Some("1"), None));
+        drop(store);
+    }
+    #[test]
+    fn shutdown_drains_open_transactions_then_checkpoints() {
+        let dir = TempDir::new("shutdown");
+        let store = KVStore::open(dir.path()).unwrap();
+        put(&store, "ks:k", "1");
+        let mut finishing = store.begin();
+        finishing.put("ks:finished".to_owned(), "yes".to_owned()).unwrap();
+        let mut stuck = store.begin();
+        stuck.put("ks:stuck".to_owned(), "no".to_owned()).unwrap();
+        let committer = thread::spawn({
+            let store = Arc::clone(&store);
+            move || {
+                wait_until("the drain to start", || store.draining.load(Ordering::Acquire) != u64::MAX);
+                let mut late = store.begin();
+                late.put("ks:late".to_owned(), "no".to_owned()).unwrap();
+                assert!(matches!(late.commit(), Err(KVError::Closed)));
+                finishing.commit().unwrap()
+            }
+        });
+        let report = store.shutdown(Duration::from_millis(300)).unwrap();
+        let committed = committer.join().unwrap();
+        assert_eq!((report.drained, report.aborted.as_slice()), (1, [stuck.id()].as_slice()));
+        assert_eq!(report.checkpoint, Some(committed));
+        assert!(report.in_doubt.is_empty());
+        assert!(report.elapsed >= Duration::from_millis(300));
+        assert!(store.is_closed());
+        assert!(matches!(stuck.commit(), Err(KVError::Closed)));
+        assert!(matches!(store.shutdown(Duration::ZERO), Err(KVError::Closed)));
+        let reopened = KVStore::open(dir.path()).unwrap();
+        let tx = reopened.begin();
+        assert_eq!(tx.get("ks:finished").unwrap().as_deref(), Some("yes"));
+        assert_eq!((tx.get("ks:stuck").unwrap(), tx.get("ks:late").unwrap()), (None, None));
+        let memory = KVStore::new();
+        put(&memory, "ks:k", "1");
+        let report = memory.shutdown(Duration::from_secs(10)).unwrap();
+        assert_eq!((report.drained, report.aborted.len(), report.checkpoint), (0, 0, None));
+        assert!(report.elapsed < Duration::from_secs(10));
+    }
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    #[test]
+    fn read_only_followers_see_the_writers_commits_across_checkpoints() {
+        let dir = TempDir::new("follower");
+        let writer = KVStore::open(dir.path()).unwrap();
+        put(&writer, "ks:a", "1");
+        let follower = KVStore::builder().path(dir.path()).read_only(true).follow_wal(Duration::from_millis(5)).open().unwrap();
+        assert_eq!(follower.begin().get("ks:a").unwrap().as_deref(), Some("1"));
+        let version = put(&writer, "ks:b", "2");
+        wait_until("the follower to replay the log", || follower.current_version() >= version);
+        writer.checkpoint().unwrap();
+        let version = put(&writer, "ks:a", "3");
+        wait_until("the follower to cross the checkpoint", || follower.current_version() >= version);
+        let tx = follower.begin();
+        assert_eq!((tx.get("ks:a").unwrap().as_deref(), tx.get("ks:b").unwrap().as_deref()), (Some("3"), Some("2")));
+        let mut tx = follower.begin();
+        assert!(tx.put("ks:c".to_owned(), "x".to_owned()).and_then(|_| tx.commit().map(|_| ())).is_err());
+        assert!(matches!(follower.checkpoint(), Err(KVError::ReadOnly)));
+    }
+    #[test]
+    fn compaction_purges_collected_tombstones_from_the_log() {
+        let dir = TempDir::new("compact");
+        let segment = || fs::metadata(dir.path().join(segment_name(0))).unwrap().len();
+        let (deleted, kept) = {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_retention("ks", Retention::LatestOnly);
+            for i in 0..50 {
+                put(&store, &format!("ks:{}", i), &"x".repeat(100));
+            }
+            let mut tx = store.begin();
+            for i in 0..40 {
+                tx.delete(format!("ks:{}", i)).unwrap();
+            }
+            let deleted = tx.commit().unwrap();
+            let kept = put(&store, "ks:49", "last");
+            let before = segment();
+            let report = store.compact().unwrap();
+            assert_eq!(report.version, kept);
+            assert_eq!(report.gc.keys_removed, 40);
+            assert_eq!(report.segments_rewritten, 1);
+            assert_eq!(report.bytes_reclaimed, before - segment());
+            assert_eq!(store.compact().unwrap().segments_rewritten, 0);
+            (deleted, kept)
+        };
+        let store = KVStore::open(dir.path()).unwrap();
+        let tx = store.begin();
+        assert!((0..40).all(|i| tx.get(&format!("ks:{}", i)).unwrap().is_none()));
+        assert_eq!(tx.get("ks:40").unwrap(), Some("x".repeat(100)));
+        assert_eq!(tx.get("ks:49").unwrap().as_deref(), Some("last"));
+        assert_eq!(store.current_version(), kept);
+        assert!(store.commit_time(deleted).is_some());
+    }
+    #[test]
+    fn exports_stream_in_chunks_and_reject_truncation() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        for i in 0..2 * SCAN_CHUNK + 10 {
+            tx.put(format!("ks:{:05}", i), "value".repeat(20)).unwrap();
+        }
+        tx.commit().unwrap();
+        let version = put(&store, "ks:00000", "changed");
+        let (mut plain, mut packed) = (Vec::new(), Vec::new());
+        let report = store.export(&mut plain).unwrap();
+        assert_eq!(report.version, version);
+        assert_eq!((report.entries, report.bytes), (2 * SCAN_CHUNK as u64 + 10, plain.len() as u64));
+        assert_eq!(store.export_compressed(&mut packed).unwrap().bytes, packed.len() as u64);
+        assert!(packed.len() * 4 < plain.len(), "{} compressed bytes for {} plain", packed.len(), plain.len());
+        for bytes in [&plain, &packed] {
+            let copy = KVStore::new();
+            let imported = copy.import(&bytes[..], MergeStrategy::ErrorOnConflict).unwrap();
+            assert_eq!((imported.source_version, imported.entries), (version, report.entries));
+            assert_eq!(copy.begin().get("ks:00000").unwrap().as_deref(), Some("changed"));
+            assert_eq!(copy.begin().get("ks:02057").unwrap(), Some("value".repeat(20)));
+            match KVStore::new().import(&bytes[..bytes.len() - 1], MergeStrategy::Replace) {
+                Err(KVError::Corruption(_)) => {}
+                other => panic!("expected a truncated export to fail, got {:?}", other),
+            }
+        }
+    }
+    #[test]
+    fn imports_resolve_existing_keys_by_strategy() {
+        let source = KVStore::new();
+        for i in 0..10 {
+            put(&source, "ks:filler", &i.to_string());
+        }
+        put(&source, "ks:same", "s");
+        put(&source, "ks:old", "exported");
+        put(&source, "ks:new", "exported");
+        put(&source, "ks:fresh", "exported");
+        let mut export = Vec::new();
+        source.export(&mut export).unwrap();
+        let target = || {
+            let store = KVStore::new();
+            put(&store, "ks:same", "s");
+            put(&store, "ks:old", "local");
+            store
+        };
+        let get = |store: &Arc<KVStore>, key: &str| store.begin().get(key).unwrap();
+        let store = target();
+        let report = store.import(&export[..], MergeStrategy::Replace).unwrap();
+        assert_eq!((report.entries, report.written, report.skipped), (5, 4, 1));
+        assert_eq!(get(&store, "ks:old").as_deref(), Some("exported"));
+        // The target's own edits land after the export's version, so KeepNewer keeps them.
+        let store = target();
+        for _ in 0..source.current_version() {
+            put(&store, "ks:filler", "local");
+        }
+        put(&store, "ks:new", "local");
+        let report = store.import(&export[..], MergeStrategy::KeepNewer).unwrap();
+        assert_eq!((report.written, report.skipped), (2, 3));
+        assert_eq!(get(&store, "ks:filler").as_deref(), Some("local"));
+        assert_eq!(get(&store, "ks:old").as_deref(), Some("exported"));
+        assert_eq!(get(&store, "ks:new").as_deref(), Some("local"));
+        assert_eq!(get(&store, "ks:fresh").as_deref(), Some("exported"));
+        let store = target();
+        let before = store.current_version();
+        match store.import(&export[..], MergeStrategy::ErrorOnConflict) {
+            Err(KVError::InvalidInput(reason)) => assert!(reason.contains("ks:old"), "{}", reason),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!((store.current_version(), get(&store, "ks:fresh")), (before, None));
+        let empty = KVStore::new();
+        put(&empty, "ks:same", "s");
+        assert_eq!(empty.import(&export[..], MergeStrategy::ErrorOnConflict).unwrap().skipped, 1);
+    }
+    #[test]
+    fn read_only_opens_leave_every_file_untouched() {
+        let dir = TempDir::new("read-only");
+        let files = || -> BTreeMap<String, Vec<u8>> {
+            let storage = FsStorage::new(dir.path());
+            storage.list().unwrap().into_iter().filter(|name| name != LOCK_FILE).map(|name| (name.clone(), storage.read(&name).unwrap())).collect()
+        };
+        let writer = KVStore::open(dir.path()).unwrap();
+        put(&writer, "ks:a", "1");
+        writer.checkpoint().unwrap();
+        let version = put(&writer, "ks:b", "2");
+        let segment = dir.path().join(segment_name(version - 1));
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(b"torn").unwrap();
+        let before = files();
+        let reader = KVStore::open_read_only(dir.path()).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.current_version(), version);
+        assert_eq!(reader.begin().get("ks:b").unwrap().as_deref(), Some("2"));
+        let mut tx = reader.begin();
+        tx.put("ks:c".to_owned(), "3".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(KVError::ReadOnly)));
+        assert_eq!(reader.refresh().unwrap(), version);
+        assert_eq!(files(), before, "a read-only open must not repair or rewrite files");
+        assert!(matches!(writer.refresh(), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn multi_key_reads_see_one_version() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        tx.put("ks:a".to_owned(), "a1".to_owned()).unwrap();
+        tx.put("ks:b".to_owned(), "b1".to_owned()).unwrap();
+        let first = tx.commit().unwrap();
+        let mut tx = store.begin();
+        tx.put("ks:a".to_owned(), "a2".to_owned()).unwrap();
+        tx.delete("ks:b".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let at = |version| store.get_at(&["ks:a", "ks:b", "ks:missing"], version).unwrap();
+        assert_eq!(at(first), [Some("a1".to_owned()), Some("b1".to_owned()), None]);
+        assert_eq!(at(0), [None, None, None]);
+        assert_eq!(at(Version::MAX), [Some("a2".to_owned()), None, None]);
+        assert_eq!(store.get_latest(&["ks:b", "ks:a"]).unwrap(), [None, Some("a2".to_owned())]);
+        assert!(store.get_latest(&[]).unwrap().is_empty());
+        store.set_case_insensitive("CI").unwrap();
+        put(&store, "CI:Key", "v");
+        assert_eq!(store.get_latest(&["CI:KEY", "CI:key"]).unwrap(), [Some("v".to_owned()), Some("v".to_owned())]);
+    }
+    #[test]
+    fn hot_backups_capture_one_version_while_writes_continue() {
+        let (dir, target) = (TempDir::new("hot-backup"), TempDir::new("hot-backup-copy"));
+        let store = KVStore::open(dir.path()).unwrap();
+        for i in 0..100 {
+            put(&store, &format!("ks:{:04}", i), "v");
+        }
+        store.checkpoint().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (store, stop) = (Arc::clone(&store), Arc::clone(&stop));
+            thread::spawn(move || {
+                let mut i = 100;
+                while !stop.load(Ordering::Relaxed) {
+                    put(&store, &format!("ks:{:04}", i), "v");
+                    i += 1;
+                }
+            })
+        };
+        wait_until("the writer is running", || store.current_version() > 150);
+        let version = store.backup_to(target.path().join("copy")).unwrap();
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        assert!(store.current_version() > version);
+        let copy = KVStore::open(target.path().join("copy")).unwrap();
+        assert_eq!(copy.current_version(), version);
+        let snapshot = copy.snapshot();
+        assert_eq!(snapshot.scan(..).len() as u64, version);
+        assert_eq!(snapshot.scan(..), store.begin_at(version).scan(..).unwrap());
+        assert!(matches!(store.backup_to(target.path().join("copy")), Err(KVError::InvalidInput(_))));
+        let memory = KVStore::new();
+        let saved = put(&memory, "ks:a", "1");
+        assert_eq!(memory.backup_to(target.path().join("memory")).unwrap(), saved);
+        assert_eq!(KVStore::open(target.path().join("memory")).unwrap().begin().get("ks:a").unwrap().as_deref(), Some("1"));
+    }
+    #[test]
+    fn sampled_hot_keys_rank_by_estimated_traffic() {
+        let store = KVStore::new();
+        for i in 0..300 {
+            put(&store, &format!("cold:{:03}", i), "x");
+        }
+        put(&store, "hot:read", "x");
+        for i in 0..800 {
+            put(&store, "hot:write", &i.to_string());
+        }
+        let tx = store.begin();
+        for i in 0..1600 {
+            tx.get("hot:read").unwrap();
+            tx.get(&format!("cold:{:03}", i % 300)).unwrap();
+        }
+        let hot = store.hot_keys(2);
+        assert_eq!(hot.iter().map(|hot| hot.key.as_str()).collect::<Vec<_>>(), ["hot:read", "hot:write"]);
+        let (reads, writes) = (hot[0].reads, hot[1].writes);
+        assert!((1200..=2000).contains(&reads), "estimated {} reads of 1600", reads);
+        assert!((600..=1000).contains(&writes), "estimated {} writes of 800", writes);
+        assert!(hot[0].writes <= HOT_KEY_SAMPLE && hot[1].reads == 0);
+        assert!(store.hot_keys(usize::MAX).len() <= HOT_KEY_SLOTS);
+        assert!(store.hot_keys(usize::MAX).iter().all(|hot| !hot.key.starts_with("__")));
+    }
+    #[test]
+    fn slow_transactions_land_in_a_bounded_log() {
+        let store = KVStore::new();
+        let clock = MockClock::new(5_000);
+        store.set_clock(clock.clone());
+        assert_eq!(store.slow_threshold(), None);
+        put(&store, "a", "1");
+        assert!(store.slow_log().is_empty());
+        store.set_slow_threshold(Some(Duration::from_millis(20)));
+        assert_eq!(store.slow_threshold(), Some(Duration::from_millis(20)));
+        put(&store, "a", "2");
+        assert!(store.slow_log().is_empty());
+        let mut slow = store.begin();
+        slow.get("a").unwrap();
+        slow.put("a".to_owned(), "3".to_owned()).unwrap();
+        slow.put("b".to_owned(), "3".to_owned()).unwrap();
+        let id = slow.id();
+        thread::sleep(Duration::from_millis(30));
+        let version = slow.commit().unwrap();
+        let mut doomed = store.begin();
+        doomed.expect("a", Precondition::Absent);
+        doomed.put("a".to_owned(), "4".to_owned()).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert!(matches!(doomed.commit(), Err(KVError::PreconditionFailed(_))));
+        let log = store.slow_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].txn, log[0].version, log[0].keys, log[0].at), (id, Some(version), 2, 5_000));
+        assert!(log[0].duration >= Duration::from_millis(30));
+        assert_eq!(log[1].version, None);
+        store.set_slow_threshold(Some(Duration::ZERO));
+        for i in 0..SLOW_LOG_CAPACITY + 10 {
+            clock.set(i as u64);
+            put(&store, "a", &i.to_string());
+        }
+        let log = store.slow_log();
+        assert_eq!(log.len(), SLOW_LOG_CAPACITY);
+        assert_eq!(log.last().unwrap().at, SLOW_LOG_CAPACITY as u64 + 9);
+        store.set_slow_threshold(None);
+        put(&store, "a", "6");
+        assert_eq!(store.slow_log().len(), SLOW_LOG_CAPACITY);
+    }
+    #[test]
+    fn transaction_ids_are_unique_across_threads_and_reopens() {
+        let dir = TempDir::new("txn-ids");
+        let mut ids = BTreeSet::new();
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    let store = Arc::clone(&store);
+                    thread::spawn(move || (0..200).map(|_| store.begin().id()).collect::<Vec<_>>())
+                })
+                .collect();
+            for worker in workers {
+                ids.extend(worker.join().unwrap());
+            }
+            assert_eq!(ids.len(), 1600);
+            let tx = store.begin();
+            assert_eq!(tx.id(), tx.id());
+            assert!(tx.id() > *ids.last().unwrap());
+            ids.insert(tx.id());
+        }
+        thread::sleep(Duration::from_millis(2));
+        let store = KVStore::open(dir.path()).unwrap();
+        assert!(store.begin().id() > *ids.last().unwrap());
+    }
+    #[test]
+    fn slow_listeners_get_a_lag_notice_instead_of_blocking_commits() {
+        let store = KVStore::new();
+        assert!(matches!(store.listen(0), Err(KVError::InvalidInput(_))));
+        let slow = store.listen(2).unwrap();
+        let fast = store.listen(16).unwrap();
+        let dropped = store.listen(1).unwrap();
+        drop(dropped);
+        let versions: Vec<Version> = (0..5).map(|i| put(&store, &format!("k{}", i), "v")).collect();
+        let batch = |delivery: Option<Delivery>| match delivery {
+            Some(Delivery::Batch(batch)) => batch,
+            _ => panic!("expected a batch"),
+        };
+        for (i, &version) in versions.iter().enumerate() {
+            let delivered = batch(fast.try_recv());
+            assert_eq!(delivered.version, version);
+            assert!(delivered.writes.contains_key(&format!("k{}", i)));
+        }
+        assert_eq!(batch(slow.try_recv()).version, versions[0]);
+        assert_eq!(batch(slow.try_recv()).version, versions[1]);
+        assert!(slow.try_recv().is_none());
+        let latest = put(&store, "k5", "v");
+        assert!(matches!(slow.try_recv(), Some(Delivery::Lagged(3))));
+        assert_eq!(batch(slow.recv_timeout(Duration::from_secs(1))).version, latest);
+        assert_eq!(batch(fast.try_recv()).version, latest);
+        assert_eq!(store.listeners.lock().unwrap().len(), 2);
+    }
+    #[test]
+    fn admission_limits_fail_or_hold_writes_until_pressure_drops() {
+        let dir = TempDir::new("admission");
+        let store = KVStore::open(dir.path()).unwrap();
+        store.set_admission(AdmissionLimits::new().wal_backlog(4 << 10));
+        put(&store, "big", &"x".repeat(8 << 10));
+        let pressure = store.write_pressure();
+        assert!(pressure.wal_backlog > 4 << 10 && pressure.wal_segments >= 1);
+        let mut tx = store.begin();
+        tx.put("next".to_owned(), "1".to_owned()).unwrap();
+        match tx.commit() {
+            Err(KVError::Overloaded(exceeded)) => {
+                assert_eq!((exceeded.limit, exceeded.max), (Limit::WalBacklog, 4 << 10));
+                assert_eq!(exceeded.usage, pressure.wal_backlog);
+            }
+            other => panic!("expected Overloaded, got {:?}", other),
+        }
+        assert_eq!(store.snapshot().get("next"), None);
+        store.begin().commit().unwrap();
+        store.checkpoint().unwrap();
+        assert!(store.write_pressure().wal_backlog <= 4 << 10);
+        put(&store, "next", "2");
+        put(&store, "big", &"y".repeat(8 << 10));
+        store.set_admission(AdmissionLimits::new().wal_backlog(4 << 10).block_for(Duration::from_secs(10)));
+        let checkpointer = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                store.checkpoint().unwrap();
+            })
+        };
+        let started = Instant::now();
+        put(&store, "next", "3");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        checkpointer.join().unwrap();
+        store.set_admission(AdmissionLimits::new());
+        put(&store, "big", &"z".repeat(8 << 10));
+        put(&store, "next", "4");
+    }
+    #[test]
+    fn pipelined_commits_publish_in_version_order_and_survive_a_reopen() {
+        let dir = TempDir::new("pipeline");
+        let store = KVStore::open(dir.path()).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (store, done) = (Arc::clone(&store), Arc::clone(&done));
+            thread::spawn(move || {
+                let mut seen = Vec::new();
+                while !done.load(Ordering::Acquire) {
+                    let snapshot = store.snapshot();
+                    seen.push((snapshot.version(), snapshot.scan("t".to_owned().."u".to_owned()).len()));
+                }
+                seen
+            })
+        };
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    (0..100)
+                        .map(|i| {
+                            let mut tx = store.begin();
+                            tx.put(format!("t{}:{:03}", t, i), String::new()).unwrap();
+                            tx.commit().unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut versions: Vec<Version> = writers.into_iter().flat_map(|writer| writer.join().unwrap()).collect();
+        done.store(true, Ordering::Release);
+        let seen = reader.join().unwrap();
+        versions.sort();
+        versions.dedup();
+        assert_eq!(versions.len(), 800);
+        // Versions publish in order: a snapshot sees every commit at or below its version and none above.
+        for (version, visible) in seen {
+            assert_eq!(versions.iter().filter(|&&committed| committed <= version).count(), visible, "gap below v{}", version);
+        }
+        let expected = store.snapshot().scan("t".to_owned().."u".to_owned());
+        drop(store);
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.snapshot().scan("t".to_owned().."u".to_owned()), expected);
+        assert_eq!(expected.len(), 800);
+    }
+    #[test]
+    fn acknowledged_commits_survive_a_crash_of_the_wal_writer() {
+        let dir = TempDir::new("wal-writer");
+        let crashed = TempDir::new("wal-writer-crash");
+        let store = KVStore::open(dir.path()).unwrap();
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        put(&store, &format!("t{}:{:02}", t, i), &i.to_string());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        // Copy the files while the store is still open, as a power cut would leave them.
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), crashed.path().join(entry.file_name())).unwrap();
+        }
+        let acknowledged = store.snapshot().scan("t".to_owned().."u".to_owned());
+        assert_eq!(acknowledged.len(), 200);
+        store.close().unwrap();
+        let mut tx = store.begin();
+        tx.put("late".to_owned(), "x".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::Closed)));
+        drop(store);
+        let recovered = KVStore::open(crashed.path()).unwrap();
+        assert_eq!(recovered.snapshot().scan("t".to_owned().."u".to_owned()), acknowledged);
+        put(&recovered, "after", "crash");
+        let reopened = KVStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.snapshot().scan("t".to_owned().."u".to_owned()), acknowledged);
+        assert_eq!(reopened.snapshot().get("late"), None);
+    }
+    #[test]
+    fn keyspace_policies_persist_and_apply_after_a_reopen() {
+        let dir = TempDir::new("policies");
+        let cache = KeyspacePolicy::new().default_ttl(Duration::from_secs(30)).retention(Retention::LatestOnly).memory_budget(64);
+        let docs = KeyspacePolicy::new().compression(Compression::Lzf);
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            assert!(matches!(store.set_keyspace_policy("bad:name", docs.clone()), Err(KVError::InvalidInput(_))));
+            store.set_keyspace_policy("cache", cache.clone()).unwrap();
+            store.set_keyspace_policy("docs", docs.clone()).unwrap();
+            store.set_keyspace_policy("gone", docs.clone()).unwrap();
+            store.set_keyspace_policy("gone", KeyspacePolicy::new()).unwrap();
+            put(&store, "docs:1", &"text ".repeat(100));
+            store.checkpoint().unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let clock = MockClock::new(1_000);
+        store.set_clock(clock.clone());
+        assert_eq!(store.keyspace_policies().into_iter().collect::<Vec<_>>(), [("cache".to_owned(), cache.clone()), ("docs".to_owned(), docs)]);
+        assert_eq!(store.keyspace_policy("other"), KeyspacePolicy::default());
+        assert_eq!(store.snapshot().get("docs:1"), Some("text ".repeat(100)));
+        put(&store, "cache:a", "1");
+        put(&store, "cache:a", "22");
+        put(&store, "other:a", "1");
+        put(&store, "other:a", "2");
+        assert_eq!(store.begin().ttl("cache:a").unwrap(), Some(Duration::from_secs(30)));
+        assert_eq!(store.begin().ttl("other:a").unwrap(), None);
+        let mut tx = store.begin();
+        tx.put("cache:b".to_owned(), "x".repeat(64)).unwrap();
+        match tx.commit() {
+            Err(KVError::OutOfMemoryBudget(exceeded)) => {
+                assert_eq!((exceeded.limit, exceeded.keyspace.as_deref(), exceeded.max), (Limit::KeyspaceBudget, Some("cache"), 64));
+            }
+            other => panic!("expected the cache budget to refuse the write, got {:?}", other),
+        }
+        put(&store, "other:b", &"x".repeat(64));
+        store.gc();
+        assert_eq!((store.history("cache:a").len(), store.history("other:a").len()), (1, 2));
+        let mut tx = store.begin();
+        tx.delete("cache:a".to_owned()).unwrap();
+        tx.commit().unwrap();
+        put(&store, "cache:b", &"x".repeat(50));
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(store.sweep_expired().unwrap(), 1);
+        assert_eq!(store.snapshot().get("cache:b"), None);
+    }
+    #[test]
+    fn sensitive_keyspaces_keep_values_out_of_debug_output_and_errors() {
+        let dir = TempDir::new("redaction");
+        let leaked = |text: String| assert!(!text.contains("hunter"), "value leaked into {:?}", text);
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_keyspace_policy("vault", KeyspacePolicy::new().sensitive(true)).unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert!(store.keyspace_policy("vault").is_sensitive() && store.is_sensitive("vault:pw") && !store.is_sensitive("plain:pw"));
+        let listener = store.listen(4).unwrap();
+        put(&store, "vault:pw", "hunter2");
+        put(&store, "plain:pw", "visible");
+        let Some(Delivery::Batch(batch)) = listener.try_recv() else { panic!("expected a batch") };
+        let shown = format!("{:?}", batch);
+        assert!(shown.contains("vault:pw") && shown.contains("<redacted 7 bytes"), "{}", shown);
+        leaked(shown);
+        let Some(Delivery::Batch(batch)) = listener.try_recv() else { panic!("expected a batch") };
+        assert!(format!("{:?}", batch).contains("visible"));
+        let mut tx = store.begin();
+        tx.expect("vault:pw", Precondition::Absent);
+        tx.put("vault:pw".to_owned(), "next".to_owned()).unwrap();
+        leaked(format!("{:?}", tx.commit().unwrap_err()));
+        store.set_validator("vault", |_, value| Err(format!("{} is too weak", value)));
+        let mut tx = store.begin();
+        let err = tx.put("vault:pw".to_owned(), "hunter2".to_owned()).and_then(|()| tx.commit().map(drop)).unwrap_err();
+        assert!(err.to_string().contains("<redacted for a sensitive keyspace>"), "{}", err);
+        leaked(err.to_string());
+        store.set_commit_validator("vault", |view| Err(io::Error::other(format!("saw {:?}", view.get("vault:pw")))));
+        store.clear_validator("vault");
+        let mut tx = store.begin();
+        tx.put("vault:pw".to_owned(), "hunter3".to_owned()).unwrap();
+        leaked(format!("{:?}", tx.commit().unwrap_err()));
+        leaked(format!("{:?}", Secret::from("hunter2".to_owned())));
+    }
+    #[test]
+    fn sensitive_policies_belong_to_their_own_store() {
+        let (vault, plain) = (KVStore::new(), KVStore::new());
+        vault.set_keyspace_policy("vault", KeyspacePolicy::new().sensitive(true)).unwrap();
+        assert!(vault.is_sensitive("vault:pw") && !plain.is_sensitive("vault:pw"));
+        for store in [&vault, &plain] {
+            put(store, "vault:pw", "hunter2");
+        }
+        let page = |store: &Arc<KVStore>| format!("{:?}", store.snapshot().scan_page(.."~".to_owned(), 10));
+        assert!(!page(&vault).contains("hunter2") && page(&plain).contains("hunter2"));
+        let rejected = |store: &Arc<KVStore>| {
+            store.set_commit_validator("vault", |_| Err(io::Error::other("saw hunter2")));
+            let mut tx = store.begin();
+            tx.put("vault:pw".to_owned(), "next".to_owned()).unwrap();
+            tx.commit().unwrap_err().to_string()
+        };
+        assert!(!rejected(&vault).contains("hunter2") && rejected(&plain).contains("hunter2"));
+        vault.set_keyspace_policy("vault", KeyspacePolicy::new()).unwrap();
+        assert!(!vault.is_sensitive("vault:pw"));
+    }
+    #[test]
+    fn secrets_round_trip_without_showing_their_value() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        tx.put_secret("creds:db".to_owned(), Secret::new("hunter2".to_owned())).unwrap();
+        tx.commit().unwrap();
+        let secret = store.begin().get_secret("creds:db").unwrap().unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{:?}", secret), "<secret 7 bytes>");
+        assert!(store.begin().get_secret("creds:none").unwrap().is_none());
+    }
+    #[test]
+    fn operation_logs_replay_in_order_onto_another_store() {
+        let source = KVStore::new();
+        put(&source, "users:1", "ada");
+        put(&source, "users:2", "alan");
+        let mid = source.current_version();
+        let mut tx = source.begin();
+        tx.delete("users:1".to_owned()).unwrap();
+        tx.put("orders:1".to_owned(), "book".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut log = Vec::new();
+        let exported = source.export_oplog(&mut log, 0).unwrap();
+        assert_eq!((exported.batches, exported.operations, exported.source_version), (3, 4, source.current_version()));
+        let target = KVStore::new();
+        let applied = target.apply_oplog(&log[..]).unwrap();
+        assert_eq!((applied.batches, applied.operations, applied.source_version), (3, 4, source.current_version()));
+        assert_eq!(target.begin().scan(..).unwrap(), source.begin().scan(..).unwrap());
+        let strict = KVStore::new();
+        strict.set_validator("orders", |_, _| Err("closed".to_owned()));
+        assert!(matches!(strict.apply_oplog(&log[..]), Err(KVError::InvalidInput(_))));
+        let tx = strict.begin();
+        assert_eq!((tx.get("users:1").unwrap().as_deref(), tx.get("orders:1").unwrap()), (Some("ada"), None));
+        let partial = KVStore::new();
+        partial.apply_oplog(&log[..]).unwrap();
+        put(&source, "users:3", "grace");
+        let mut tail = Vec::new();
+        assert_eq!(source.export_oplog(&mut tail, exported.source_version).unwrap().batches, 1);
+        assert_eq!(partial.apply_oplog(&tail[..]).unwrap().operations, 1);
+        assert_eq!(partial.begin().scan(..).unwrap(), source.begin().scan(..).unwrap());
+        let mut early = Vec::new();
+        source.export_oplog(&mut early, mid).unwrap();
+        let backwards = [&tail[..], &early[OPLOG_MAGIC.len()..]].concat();
+        assert!(matches!(KVStore::new().apply_oplog(&backwards[..]), Err(KVError::Corruption(_))));
+        assert!(matches!(KVStore::new().apply_oplog(&b"NOTALOG0"[..]), Err(KVError::Corruption(_))));
+        let mut export = Vec::new();
+        source.export(&mut export).unwrap();
+        let restored = KVStore::new();
+        assert_eq!(restored.apply_oplog(&export[..]).unwrap().operations, 3);
+        assert_eq!(restored.begin().scan(..).unwrap(), source.begin().scan(..).unwrap());
+    }
+    #[test]
+    fn recorded_traces_replay_against_a_fresh_store() {
+        let dir = TempDir::new("trace");
+        let path = dir.path().join("calls.trace");
+        let store = KVStore::new();
+        put(&store, "before", "not recorded");
+        store.start_recording(&path).unwrap();
+        let mut first = store.begin();
+        let mut second = store.begin();
+        first.put("a".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(second.get("a").unwrap(), None);
+        first.commit().unwrap();
+        second.put("b".to_owned(), "2".to_owned()).unwrap();
+        second.delete("a".to_owned()).unwrap();
+        second.commit().unwrap();
+        let mut abandoned = store.begin();
+        abandoned.put("c".to_owned(), "3".to_owned()).unwrap();
+        abandoned.rollback().unwrap();
+        thread::spawn({
+            let store = Arc::clone(&store);
+            move || assert_eq!(store.begin().get("b").unwrap().as_deref(), Some("2"))
+        })
+        .join()
+        .unwrap();
+        assert!(store.stop_recording().unwrap());
+        assert!(!store.stop_recording().unwrap());
+        put(&store, "after", "not recorded");
+        let events = read_trace(&path).unwrap();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|event| match event.op {
+                TraceOp::Begin { .. } => "begin",
+                TraceOp::Get { .. } => "get",
+                TraceOp::Put { .. } => "put",
+                TraceOp::Delete { .. } => "delete",
+                TraceOp::Commit { .. } => "commit",
+                TraceOp::Rollback => "rollback",
+            })
+            .collect();
+        assert_eq!(kinds, ["begin", "begin", "put", "get", "commit", "put", "delete", "commit", "begin", "put", "rollback", "begin", "get"]);
+        assert_eq!(events.iter().filter(|event| event.txn == events[0].txn).count(), 3);
+        assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        assert_ne!(events.last().unwrap().thread, events[0].thread);
+        let fresh = KVStore::new();
+        let report = replay_trace(&fresh, &events).unwrap();
+        assert_eq!((report.events, report.transactions), (13, 4));
+        assert!(report.divergences.is_empty(), "{:?}", report.divergences);
+        assert_eq!(fresh.begin().scan(..).unwrap(), [("b".to_owned(), "2".to_owned())]);
+        let seeded = KVStore::new();
+        put(&seeded, "a", "old");
+        let report = replay_trace(&seeded, &events).unwrap();
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!((report.divergences[0].event, &report.divergences[0].actual), (3, &TraceOp::Get { key: "a".to_owned(), found: Some("old".to_owned()) }));
+        assert!(matches!(replay_trace(&fresh, &events[2..]), Err(KVError::Corruption(_))));
+        fs::write(&path, b"garbage!").unwrap();
+        assert!(matches!(read_trace(&path), Err(KVError::Corruption(_))));
+    }
+    #[test]
+    fn oversized_keys_and_values_are_refused_before_they_are_buffered() {
+        let store = KVStore::new();
+        assert_eq!(store.size_limits(), (MAX_KEY_BYTES, MAX_VALUE_BYTES));
+        store.set_size_limits(8, 4);
+        let mut tx = store.begin();
+        tx.put("ks:12345".to_owned(), "1234".to_owned()).unwrap();
+        match tx.put("ks:123456".to_owned(), "v".to_owned()) {
+            Err(KVError::KeyTooLarge { len, max }) => assert_eq!((len, max), (9, 8)),
+            other => panic!("expected a key size error, got {:?}", other),
+        }
+        assert!(matches!(tx.delete("ks:123456".to_owned()), Err(KVError::KeyTooLarge { len: 9, max: 8 })));
+        match tx.put("ks:k".to_owned(), "12345".to_owned()) {
+            Err(error @ KVError::ValueTooLarge { .. }) => {
+                assert_eq!(error.to_string(), "value of 5 bytes for ks:k exceeds the 4 byte limit")
+            }
+            other => panic!("expected a value size error, got {:?}", other),
+        }
+        tx.commit().unwrap();
+        assert_eq!(store.begin().get("ks:12345").unwrap().as_deref(), Some("1234"));
+        assert_eq!(store.begin().get("ks:k").unwrap(), None);
+        store.set_size_limits(200, 2);
+        let long = "k".repeat(100);
+        match store.begin().put(long.clone(), "123".to_owned()) {
+            Err(KVError::ValueTooLarge { key, len: 3, max: 2 }) => assert_eq!(key, long[..64]),
+            other => panic!("expected a value size error, got {:?}", other),
+        }
+        store.set_size_limits(0, 0);
+        assert_eq!(store.size_limits(), (1, 0));
+        assert!(matches!(store.begin().put("ab".to_owned(), String::new()), Err(KVError::KeyTooLarge { len: 2, max: 1 })));
+        store.begin().put("a".to_owned(), String::new()).unwrap();
+    }
+    #[test]
+    fn gc_keeps_versions_visible_to_open_transactions() {
+        let store = KVStore::new();
+        put(&store, "ks:k", "1");
+        let reader = store.begin();
+        put(&store, "ks:k", "2");
+        put(&store, "ks:k", "3");
+        store.set_retention("ks", Retention::LatestOnly);
+        store.gc();
+        assert_eq!(reader.get("ks:k").unwrap().as_deref(), Some("1"));
+        let mut rolled_back = store.begin();
+        rolled_back.rollback().unwrap();
+        drop(reader);
+        assert!(store.gc().versions_removed > 0);
+        assert_eq!(store.history("ks:k").len(), 1);
+        drop(rolled_back);
+    }
+    #[test]
+    fn default_retention_respects_a_concurrent_open_transaction() {
+        let store = KVStore::new();
+        store.set_default_retention(Retention::KeepVersions(1));
+        put(&store, "ks:k", "0");
+        let (opened, release) = (mpsc::channel(), mpsc::channel::<()>());
+        let reader = {
+            let store = Arc::clone(&store);
+            let (opened, release) = (opened.0, release.1);
+            thread::spawn(move || {
+                let tx = store.begin();
+                let first = tx.get("ks:k").unwrap();
+                opened.send(()).unwrap();
+                release.recv().unwrap();
+                (first, tx.get("ks:k").unwrap())
+            })
+        };
+        opened.1.recv().unwrap();
+        for i in 1..20 {
+            put(&store, "ks:k", &i.to_string());
+            store.gc();
+        }
+        release.0.send(()).unwrap();
+        let (first, last) = reader.join().unwrap();
+        assert_eq!((first.as_deref(), last.as_deref()), (Some("0"), Some("0")));
+        store.gc();
+        assert_eq!(store.history("ks:k").len(), 1);
+    }
+    #[test]
+    fn apply_batch_rejects_reserved_keys() {
+        let store = KVStore::new();
+        let forged = batch(&[("ok", "1"), ("__meta:source:other", "99")]);
+        assert!(matches!(store.apply_batch("feed", 1, forged), Err(KVError::InvalidInput(_))));
+        assert_eq!(store.applied_through("feed"), None);
+        assert_eq!(store.applied_through("other"), None);
+    }
+    #[test]
+    fn builder_validates_read_cache_slots() {
+        let rejected = KVStore::builder().read_cache_slots(0).open();
+        assert!(matches!(rejected, Err(KVError::InvalidConfig { option: "read_cache_slots", .. })));
+        let store = KVStore::builder().read_cache_slots(4).open().unwrap();
+        assert_eq!(store.cache.slots.len(), 4);
+        put(&store, "ks:k", "1");
+        assert_eq!(store.begin().get("ks:k").unwrap().as_deref(), Some("1"));
+    }
+    #[test]
+    fn builder_names_the_option_that_conflicts() {
+        let dir = TempDir::new("builder");
+        let file = dir.path().join("file");
+        fs::write(&file, b"not a store").unwrap();
+        let rejected = |builder: KVStoreBuilder| match builder.open() {
+            Err(KVError::InvalidConfig { option, .. }) => option,
+            other => panic!("expected an invalid config, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(rejected(KVStore::builder().max_key_bytes(0)), "max_key_bytes");
+        assert_eq!(rejected(KVStore::builder().gc_interval(Duration::ZERO)), "gc_interval");
+        assert_eq!(rejected(KVStore::builder().read_only(true)), "read_only");
+        assert_eq!(rejected(KVStore::builder().durability(Durability::Buffered)), "durability");
+        assert_eq!(rejected(KVStore::builder().direct_io(true)), "direct_io");
+        assert_eq!(rejected(KVStore::builder().follow_wal(Duration::from_secs(1))), "follow_wal");
+        assert_eq!(rejected(KVStore::builder().path(&file)), "path");
+        assert_eq!(rejected(KVStore::builder().path(dir.path()).read_only(true).expiry_interval(Duration::from_secs(1))), "expiry_interval");
+        let store = KVStore::builder().path(dir.path().join("store")).durability(Durability::Buffered).max_value_bytes(4).open().unwrap();
+        assert_eq!(store.size_limits().1, 4);
+        let mut tx = store.begin();
+        assert!(matches!(tx.put("ks:k".to_owned(), "too long".to_owned()), Err(KVError::ValueTooLarge { .. })));
+    }
+    #[test]
+    fn encrypted_stores_refuse_to_open_without_a_key() {
+        let dir = TempDir::new("no-key");
+        let store = KVStore::builder().path(dir.path()).master_key([3; 32]).open().unwrap();
+        put(&store, "ks:k", "secret");
+        drop(store);
+        for opened in [KVStore::open(dir.path()), KVStore::open_read_only(dir.path())] {
+            match opened {
+                Err(KVError::InvalidInput(message)) => assert_eq!(message, "store is encrypted; key required"),
+                _ => panic!("an encrypted store opened without its key"),
+            }
+        }
+        let store = KVStore::builder().path(dir.path()).master_key([3; 32]).open().unwrap();
+        assert_eq!(store.begin().get("ks:k").unwrap().as_deref(), Some("secret"));
+    }
+    #[test]
+    fn holds_cover_tombstoned_keys() {
+        let store = KVStore::new();
+        put(&store, "ks:k", "1");
+        let mut tx = store.begin();
+        tx.delete("ks:k".to_owned()).unwrap();
+        tx.commit().unwrap();
+        store.hold("ks:", "case-7").unwrap();
+        assert!(matches!(store.purge("ks:k"), Err(KVError::InvalidInput(_))));
+        store.set_retention("ks", Retention::LatestOnly);
+        store.gc();
+        assert_eq!(store.history("ks:k").len(), 2);
+        assert!(store.release("ks:", "case-7").unwrap());
+        assert_eq!(store.purge("ks:k").unwrap().versions_removed, 3);
+        assert!(store.history("ks:k").is_empty());
+    }
+    #[test]
+    fn purge_racing_compaction_leaves_no_snapshot_copy() {
+        let dir = TempDir::new("purge-race");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+            let compactor = {
+                let (store, stop) = (Arc::clone(&store), Arc::clone(&stop));
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        store.compact().unwrap();
+                    }
+                })
+            };
+            for i in 0..20 {
+                let key = format!("ks:k{}", i);
+                put(&store, &key, "secret");
+                put(&store, "ks:other", &i.to_string());
+                store.purge(&key).unwrap();
+            }
+            stop.store(true, Ordering::Relaxed);
+            compactor.join().unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        for i in 0..20 {
+            assert!(store.history(&format!("ks:k{}", i)).is_empty());
+        }
+        let storage = FsStorage::new(dir.path());
+        for (_, name) in storage_files(&storage, "snap").unwrap() {
+            let (_, data, _) = decode_snapshot(&storage.read(&name).unwrap()).unwrap();
+            assert!(data.keys().all(|key| !key.starts_with("ks:k")), "{} kept a purged key", name);
+        }
+    }
+}
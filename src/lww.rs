@@ -0,0 +1,440 @@
+use crate::*;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HlcTimestamp {
+    pub wall_ms: u64,
+    pub logical: u32,
+}
+impl HlcTimestamp {
+    pub fn to_version(self) -> Version {
+        self.wall_ms << 16 | u64::from(self.logical.min(0xffff))
+    }
+    pub fn from_version(version: Version) -> Self {
+        HlcTimestamp { wall_ms: version >> 16, logical: (version & 0xffff) as u32 }
+    }
+}
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.wall_ms, self.logical)
+    }
+}
+#[derive(Default)]
+pub struct HybridClock {
+    pub(crate) last: Mutex<HlcTimestamp>,
+}
+impl HybridClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn now(&self) -> HlcTimestamp {
+        let mut last = self.last.lock().unwrap();
+        let wall_ms = now_millis();
+        *last = if wall_ms > last.wall_ms {
+            HlcTimestamp { wall_ms, logical: 0 }
+        } else {
+            HlcTimestamp { wall_ms: last.wall_ms, logical: last.logical + 1 }
+        };
+        *last
+    }
+    pub fn observe(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        let mut last = self.last.lock().unwrap();
+        let wall_ms = now_millis().max(last.wall_ms).max(remote.wall_ms);
+        let logical = match (wall_ms == last.wall_ms, wall_ms == remote.wall_ms) {
+            (true, true) => last.logical.max(remote.logical) + 1,
+            (true, false) => last.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        *last = HlcTimestamp { wall_ms, logical };
+        *last
+    }
+}
+pub const LWW_KEYSPACE: &str = "__lww";
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WriteStamp {
+    pub time: HlcTimestamp,
+    pub node: u64,
+}
+impl fmt::Display for WriteStamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.time, self.node)
+    }
+}
+impl std::str::FromStr for WriteStamp {
+    type Err = KVError;
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('.').collect();
+        match parts.as_slice() {
+            [wall_ms, logical, node] => match (wall_ms.parse(), logical.parse(), node.parse()) {
+                (Ok(wall_ms), Ok(logical), Ok(node)) => Ok(WriteStamp { time: HlcTimestamp { wall_ms, logical }, node }),
+                _ => Err(KVError::Corruption(format!("bad write stamp {:?}", s))),
+            },
+            _ => Err(KVError::Corruption(format!("bad write stamp {:?}", s))),
+        }
+    }
+}
+pub const VCLOCK_KEYSPACE: &str = "__vclock";
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorClock(pub(crate) BTreeMap<u64, u64>);
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get(&self, node: u64) -> u64 {
+        self.0.get(&node).copied().unwrap_or(0)
+    }
+    pub fn increment(&mut self, node: u64) {
+        *self.0.entry(node).or_insert(0) += 1;
+    }
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (&node, &count) in &other.0 {
+            let entry = self.0.entry(node).or_insert(0);
+            *entry = (*entry).max(count);
+        }
+    }
+    pub fn compare(&self, other: &VectorClock) -> Option<std::cmp::Ordering> {
+        let nodes: BTreeSet<u64> = self.0.keys().chain(other.0.keys()).copied().collect();
+        let (mut less, mut greater) = (false, false);
+        for node in nodes {
+            match self.get(node).cmp(&other.get(node)) {
+                std::cmp::Ordering::Less => less = true,
+                std::cmp::Ordering::Greater => greater = true,
+                std::cmp::Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(std::cmp::Ordering::Equal),
+            (true, false) => Some(std::cmp::Ordering::Less),
+            (false, true) => Some(std::cmp::Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+impl fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (node, count)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", node, count)?;
+        }
+        Ok(())
+    }
+}
+impl std::str::FromStr for VectorClock {
+    type Err = KVError;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut clock = BTreeMap::new();
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let (node, count) = part
+                .split_once('=')
+                .and_then(|(n, c)| Some((n.parse().ok()?, c.parse().ok()?)))
+                .ok_or_else(|| KVError::Corruption(format!("bad vector clock {:?}", s)))?;
+            clock.insert(node, count);
+        }
+        Ok(VectorClock(clock))
+    }
+}
+#[derive(Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: String,
+    pub value: Option<String>,
+    pub stamp: WriteStamp,
+    pub clock: Option<VectorClock>,
+    pub(crate) policies: Policies,
+}
+impl fmt::Debug for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Change")
+            .field("key", &self.key)
+            .field("value", &redacted(&self.policies, &self.key, self.value.as_deref()))
+            .field("stamp", &self.stamp)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+pub enum Resolution {
+    KeepLocal,
+    TakeRemote,
+    Merge(Option<String>),
+}
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    pub applied: usize,
+    pub kept_local: usize,
+    pub merged: usize,
+    pub conflicts: usize,
+}
+pub fn last_write_wins(local: &Change, remote: &Change) -> Resolution {
+    if remote.stamp > local.stamp {
+        Resolution::TakeRemote
+    } else {
+        Resolution::KeepLocal
+    }
+}
+pub type Resolver = Box<dyn Fn(&Change, &Change) -> Resolution + Send + Sync>;
+pub struct LwwStore {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) node: u64,
+    pub(crate) clock: HybridClock,
+    pub(crate) resolver: Resolver,
+    pub(crate) vector_clocks: bool,
+}
+impl LwwStore {
+    pub fn new(store: Arc<KVStore>, node: u64) -> Self {
+        Self::with_resolver(store, node, last_write_wins)
+    }
+    pub fn with_resolver(
+        store: Arc<KVStore>,
+        node: u64,
+        resolver: impl Fn(&Change, &Change) -> Resolution + Send + Sync + 'static,
+    ) -> Self {
+        let clock = HybridClock::new();
+        let prefix = Self::stamp_key("");
+        let newest = store.settled(|state| {
+            state
+                .map
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .filter_map(|(_, stamps)| stamps.values().next_back()?.as_deref()?.parse::<WriteStamp>().ok())
+                .max()
+        });
+        if let Some(stamp) = newest {
+            clock.observe(stamp.time);
+        }
+        Self { store, node, clock, resolver: Box::new(resolver), vector_clocks: false }
+    }
+    pub fn with_vector_clocks(mut self) -> Self {
+        self.vector_clocks = true;
+        self
+    }
+    pub fn store(&self) -> &Arc<KVStore> {
+        &self.store
+    }
+    pub(crate) fn stamp_key(key: &str) -> String {
+        format!("{}{}{}", LWW_KEYSPACE, KEYSPACE_SEPARATOR, key)
+    }
+    pub(crate) fn clock_key(key: &str) -> String {
+        format!("{}{}{}", VCLOCK_KEYSPACE, KEYSPACE_SEPARATOR, key)
+    }
+    pub(crate) fn check_key(key: &str) -> Result<()> {
+        match keyspace_of(key) {
+            LWW_KEYSPACE | VCLOCK_KEYSPACE => {
+                Err(KVError::InvalidInput(format!("keyspace {} is reserved", keyspace_of(key))))
+            }
+            _ => Ok(()),
+        }
+    }
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.begin().get(key)
+    }
+    pub fn write(&self, writes: Writes) -> Result<WriteStamp> {
+        let stamp = WriteStamp { time: self.clock.now(), node: self.node };
+        let mut tx = self.store.begin();
+        for (key, value) in writes {
+            Self::check_key(&key)?;
+            tx.put_internal(Self::stamp_key(&key), stamp.to_string())?;
+            if self.vector_clocks {
+                let mut clock = match tx.get(&Self::clock_key(&key))? {
+                    Some(clock) => clock.parse()?,
+                    None => VectorClock::new(),
+                };
+                clock.increment(self.node);
+                tx.put_internal(Self::clock_key(&key), clock.to_string())?;
+            }
+            match value {
+                Some(value) => tx.put(key, value)?,
+                None => tx.delete(key)?,
+            }
+        }
+        tx.commit()?;
+        Ok(stamp)
+    }
+    pub fn put(&self, key: String, value: String) -> Result<WriteStamp> {
+        self.write(Writes::from([(key, Some(value))]))
+    }
+    pub fn delete(&self, key: String) -> Result<WriteStamp> {
+        self.write(Writes::from([(key, None)]))
+    }
+    pub fn changes_since(&self, after: Version) -> Result<(Vec<Change>, Version)> {
+        let prefix = Self::stamp_key("");
+        self.store.settled(|state| {
+            let mut changes = Vec::new();
+            for (meta, stamps) in state.map.range(prefix.clone()..).take_while(|(k, _)| k.starts_with(&prefix)) {
+                let key = &meta[prefix.len()..];
+                for (version, stamp) in stamps.range((Bound::Excluded(after), Bound::Unbounded)) {
+                    let Some(stamp) = stamp else { continue };
+                    let at = |key: &str| {
+                        state
+                            .map
+                            .get(key)
+                            .and_then(|versions| versions.at(*version))
+                            .and_then(|(_, value)| value.as_deref().map(str::to_owned))
+                    };
+                    let clock = at(&Self::clock_key(key)).map(|clock| clock.parse()).transpose()?;
+                    let policies = self.store.policies.clone();
+                    changes.push(Change { key: key.to_owned(), value: at(key), stamp: stamp.parse()?, clock, policies });
+                }
+            }
+            changes.sort_by_key(|change| change.stamp);
+            Ok((changes, state.version))
+        })
+    }
+    pub fn merge_from(&self, changes: impl IntoIterator<Item = Change>) -> Result<MergeReport> {
+        let mut report = MergeReport::default();
+        let mut tx = self.store.begin();
+        for remote in changes {
+            Self::check_key(&remote.key)?;
+            self.clock.observe(remote.stamp.time);
+            let stamp_key = Self::stamp_key(&remote.key);
+            let local = match tx.get(&stamp_key)? {
+                Some(stamp) => Some(Change {
+                    key: remote.key.clone(),
+                    value: tx.get(&remote.key)?,
+                    stamp: stamp.parse()?,
+                    clock: tx.get(&Self::clock_key(&remote.key))?.map(|clock| clock.parse()).transpose()?,
+                    policies: self.store.policies.clone(),
+                }),
+                None => None,
+            };
+            let clock = match (&local, &remote.clock) {
+                (Some(Change { clock: Some(local), .. }), Some(remote)) => {
+                    let mut merged = local.clone();
+                    merged.merge(remote);
+                    Some(merged)
+                }
+                (_, remote) => remote.clone(),
+            };
+            let causal = match (&local, &remote.clock) {
+                (Some(Change { clock: Some(local), .. }), Some(remote)) => Some(local.compare(remote)),
+                _ => None,
+            };
+            let (value, stamp) = match &local {
+                Some(local) if local.stamp == remote.stamp => continue,
+                Some(_) if causal == Some(Some(std::cmp::Ordering::Equal)) => continue,
+                Some(_) if causal == Some(Some(std::cmp::Ordering::Greater)) => {
+                    report.kept_local += 1;
+                    continue;
+                }
+                Some(_) if causal == Some(Some(std::cmp::Ordering::Less)) => (remote.value, remote.stamp),
+                Some(local) => {
+                    if causal == Some(None) {
+                        report.conflicts += 1;
+                    }
+                    match (self.resolver)(local, &remote) {
+                        Resolution::KeepLocal if causal == Some(None) => {
+                            if let Some(clock) = clock {
+                                tx.put_internal(Self::clock_key(&remote.key), clock.to_string())?;
+                            }
+                            report.kept_local += 1;
+                            continue;
+                        }
+                        Resolution::KeepLocal => {
+                            report.kept_local += 1;
+                            continue;
+                        }
+                        Resolution::TakeRemote => (remote.value, remote.stamp),
+                        Resolution::Merge(value) => {
+                            report.merged += 1;
+                            let newest = local.stamp.max(remote.stamp);
+                            let time = HlcTimestamp { logical: newest.time.logical + 1, ..newest.time };
+                            (value, WriteStamp { time, node: newest.node })
+                        }
+                    }
+                }
+                None => (remote.value, remote.stamp),
+            };
+            tx.put_internal(stamp_key, stamp.to_string())?;
+            if let Some(clock) = clock {
+                tx.put_internal(Self::clock_key(&remote.key), clock.to_string())?;
+            }
+            match value {
+                Some(value) => tx.put(remote.key, value)?,
+                None => tx.delete(remote.key)?,
+            }
+            report.applied += 1;
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn sync(from: &LwwStore, to: &LwwStore) -> MergeReport {
+        let (changes, _) = from.changes_since(0).unwrap();
+        to.merge_from(changes).unwrap()
+    }
+    #[test]
+    fn nodes_converge_on_the_last_write() {
+        let (a, b) = (LwwStore::new(KVStore::new(), 1), LwwStore::new(KVStore::new(), 2));
+        a.put("ks:k".into(), "from a".into()).unwrap();
+        a.put("ks:gone".into(), "soon".into()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        b.put("ks:k".into(), "from b".into()).unwrap();
+        b.put("ks:b".into(), "only b".into()).unwrap();
+        assert_eq!(sync(&a, &b).kept_local, 1);
+        sync(&b, &a);
+        a.delete("ks:gone".into()).unwrap();
+        sync(&a, &b);
+        for node in [&a, &b] {
+            assert_eq!(node.get("ks:k").unwrap().as_deref(), Some("from b"));
+            assert_eq!(node.get("ks:b").unwrap().as_deref(), Some("only b"));
+            assert_eq!(node.get("ks:gone").unwrap(), None);
+        }
+        assert_eq!(sync(&a, &b).applied, 0);
+        let (_, seen) = a.changes_since(0).unwrap();
+        assert!(a.changes_since(seen).unwrap().0.is_empty());
+    }
+    #[test]
+    fn merges_advance_the_local_clock_past_remote_stamps() {
+        let (a, b) = (LwwStore::new(KVStore::new(), 1), LwwStore::new(KVStore::new(), 2));
+        let ahead = HlcTimestamp { wall_ms: now_millis() + 60_000, logical: 0 };
+        a.clock.observe(ahead);
+        a.put("ks:k".into(), "future".into()).unwrap();
+        sync(&a, &b);
+        let stamp = b.put("ks:k".into(), "later".into()).unwrap();
+        assert!(stamp.time > ahead);
+        sync(&b, &a);
+        assert_eq!(a.get("ks:k").unwrap().as_deref(), Some("later"));
+    }
+    #[test]
+    fn vector_clocks_order_parse_and_merge() {
+        let a: VectorClock = "1=2,2=1".parse().unwrap();
+        let b: VectorClock = "1=1,2=3".parse().unwrap();
+        assert_eq!(a.compare(&b), None);
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(merged.to_string(), "1=2,2=3");
+        assert_eq!(a.compare(&merged), Some(std::cmp::Ordering::Less));
+        assert_eq!(merged.compare(&b), Some(std::cmp::Ordering::Greater));
+        assert_eq!(VectorClock::new().compare(&"3=0".parse().unwrap()), Some(std::cmp::Ordering::Equal));
+        assert!("1=x".parse::<VectorClock>().is_err());
+    }
+    #[test]
+    fn vector_clocks_separate_causal_updates_from_conflicts() {
+        let a = LwwStore::new(KVStore::new(), 1).with_vector_clocks();
+        let b = LwwStore::new(KVStore::new(), 2).with_vector_clocks();
+        a.put("ks:seq".into(), "a1".into()).unwrap();
+        sync(&a, &b);
+        b.put("ks:seq".into(), "b2".into()).unwrap();
+        let report = sync(&b, &a);
+        assert_eq!((report.applied, report.conflicts), (1, 0));
+        assert_eq!(a.get("ks:seq").unwrap().as_deref(), Some("b2"));
+        a.put("ks:both".into(), "a".into()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        b.put("ks:both".into(), "b".into()).unwrap();
+        let (changes, _) = b.changes_since(0).unwrap();
+        let report = a.merge_from(changes.into_iter().filter(|change| change.key == "ks:both")).unwrap();
+        assert_eq!(report.conflicts, 1);
+        assert_eq!(a.get("ks:both").unwrap().as_deref(), Some("b"));
+        let clock: VectorClock = a.store().begin().get(&LwwStore::clock_key("ks:both")).unwrap().unwrap().parse().unwrap();
+        assert_eq!((clock.get(1), clock.get(2)), (1, 1));
+    }
+    #[test]
+    fn reserved_keyspaces_are_rejected() {
+        let node = LwwStore::new(KVStore::new(), 1);
+        assert!(matches!(node.put(LwwStore::stamp_key("k"), "x".into()), Err(KVError::InvalidInput(_))));
+        let (changes, _) = node.changes_since(0).unwrap();
+        assert!(changes.is_empty());
+    }
+}
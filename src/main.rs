@@ -0,0 +1,37 @@
+use kvstore::KVStore;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = kvstore::kvctl::run(&args) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let store = KVStore::new();
+    let store1 = Arc::clone(&store);
+    let t1 = thread::spawn(move || {
+        let mut tx = store1.begin();
+        tx.put("name".to_owned(), "Hlib Voznenko".to_owned()).unwrap();
+        tx.put("code".to_owned(), "044-off".to_owned()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        tx.commit().unwrap();
+    });
+    let store2 = Arc::clone(&store);
+    let t2 = thread::spawn(move || {
+        let tx = store2.begin();
+        thread::sleep(Duration::from_millis(50));
+        println!("Читання до коміту — name: {:?}", tx.get("name").unwrap());
+        println!("Читання до коміту — code: {:?}", tx.get("code").unwrap());
+    });
+    t1.join().unwrap();
+    t2.join().unwrap();
+
+    let final_tx = store.begin();
+    println!("Фінальний стан сховища:");
+    println!("  name = {:?}", final_tx.get("name").unwrap());
+    println!("  code = {:?}", final_tx.get("code").unwrap());
+}
@@ -0,0 +1,213 @@
+use crate::*;
+pub(crate) const PROJECTION_POLL: Duration = Duration::from_millis(100);
+pub(crate) fn projection_key(name: &str) -> String {
+    format!("{}{}projection{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, name)
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProjectionEvent<'a> {
+    pub version: Version,
+    pub timestamp: u64,
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+pub(crate) type ProjectionFold = Arc<dyn Fn(&mut Transaction, &ProjectionEvent) -> Result<()> + Send + Sync>;
+pub struct Projection {
+    pub(crate) name: String,
+    pub(crate) prefix: String,
+    pub(crate) fold: ProjectionFold,
+}
+impl Projection {
+    pub fn new(
+        name: &str,
+        prefix: &str,
+        fold: impl Fn(&mut Transaction, &ProjectionEvent) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Projection { name: name.to_owned(), prefix: prefix.to_owned(), fold: Arc::new(fold) }
+    }
+    pub fn start(self, store: &Arc<KVStore>) -> Result<ProjectionHandle> {
+        if self.name.is_empty() {
+            return Err(KVError::InvalidInput("projection name must not be empty".to_owned()));
+        }
+        store.writable()?;
+        let checkpoint = store.projection_checkpoint(&self.name)?;
+        let (catch_up, feed) = store.subscribe_after(checkpoint);
+        let backlog = match catch_up {
+            CatchUp::Backlog(backlog) => backlog,
+            CatchUp::Snapshot(version, _) => {
+                let rows = store.scan_at(prefix_range(&self.prefix), version);
+                let writes = rows.into_iter().map(|(key, value)| (key, Some(value))).collect();
+                vec![ChangeBatch { version, timestamp: 0, writes, policies: store.policies.clone() }]
+            }
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let position = Arc::new(AtomicU64::new(checkpoint));
+        let failure = Arc::new(Mutex::new(None));
+        let worker = {
+            let (store, stopped, position, failure) = (Arc::downgrade(store), Arc::clone(&stopped), Arc::clone(&position), Arc::clone(&failure));
+            thread::spawn(move || {
+                let step = |batch: &ChangeBatch| -> Result<bool> {
+                    let Some(store) = store.upgrade().filter(|store| !store.is_closed()) else {
+                        return Ok(false);
+                    };
+                    self.step(&store, batch, &position)?;
+                    Ok(true)
+                };
+                let run = || -> Result<()> {
+                    for batch in &backlog {
+                        if !step(batch)? {
+                            return Ok(());
+                        }
+                    }
+                    while !stopped.load(Ordering::Acquire) {
+                        match feed.recv_timeout(PROJECTION_POLL) {
+                            Ok(batch) if !step(&batch)? => break,
+                            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    Ok(())
+                };
+                if let Err(e) = run() {
+                    *failure.lock().unwrap() = Some(e.to_string());
+                }
+            })
+        };
+        Ok(ProjectionHandle { stopped, position, failure, worker: Some(worker) })
+    }
+    pub(crate) fn step(&self, store: &Arc<KVStore>, batch: &ChangeBatch, position: &AtomicU64) -> Result<()> {
+        let checkpoint = projection_key(&self.name);
+        if batch.version <= position.load(Ordering::Acquire) || batch.writes.contains_key(&checkpoint) {
+            return Ok(());
+        }
+        let changes: Vec<_> = batch
+            .writes
+            .iter()
+            .filter(|(key, _)| key.starts_with(self.prefix.as_str()) && !keyspace_of(key).starts_with("__"))
+            .collect();
+        if !changes.is_empty() {
+            loop {
+                let mut tx = store.begin();
+                for (key, value) in &changes {
+                    let event = ProjectionEvent { version: batch.version, timestamp: batch.timestamp, key, value: value.as_deref() };
+                    (self.fold)(&mut tx, &event)?;
+                }
+                tx.buffer.put(checkpoint.clone(), batch.version.to_string())?;
+                match tx.commit() {
+                    Ok(_) => break,
+                    Err(KVError::TransactionAborted) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        position.store(batch.version, Ordering::Release);
+        Ok(())
+    }
+}
+pub struct ProjectionHandle {
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) position: Arc<AtomicU64>,
+    pub(crate) failure: Arc<Mutex<Option<String>>>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl ProjectionHandle {
+    pub fn position(&self) -> Version {
+        self.position.load(Ordering::Acquire)
+    }
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+    pub fn wait_for(&self, version: Version, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.position() < version {
+            if Instant::now() >= deadline || self.worker.as_ref().is_none_or(|worker| worker.is_finished()) {
+                return self.position() >= version;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+}
+impl Drop for ProjectionHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+impl KVStore {
+    pub fn projection_checkpoint(&self, name: &str) -> Result<Version> {
+        match self.read_at(&projection_key(name), self.current_version()) {
+            Some(checkpoint) => checkpoint.parse().map_err(|_| KVError::Corruption(format!("bad checkpoint for projection {}", name))),
+            None => Ok(0),
+        }
+    }
+    pub fn reset_projection(&self, name: &str) -> Result<()> {
+        self.writable()?;
+        self.apply(Writes::from([(projection_key(name), None)]), None, None, None).map(|_| ())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Keeps totals:<customer> as the sum of that customer's order amounts, stored as "<customer> <amount>".
+    fn totals() -> Projection {
+        Projection::new("totals", "orders:", |tx, event| {
+            let Some((customer, amount)) = event.value.and_then(|value| value.split_once(' ')) else {
+                return Err(KVError::InvalidInput(format!("bad order {}", event.key)));
+            };
+            let key = format!("totals:{}", customer);
+            let total: u64 = tx.get(&key)?.map_or(0, |total| total.parse().unwrap());
+            tx.put(key, (total + amount.parse::<u64>().unwrap()).to_string())
+        })
+    }
+    fn order(store: &Arc<KVStore>, id: u32, value: &str) -> Version {
+        let mut tx = store.begin();
+        tx.put(format!("orders:{}", id), value.to_owned()).unwrap();
+        tx.commit().unwrap()
+    }
+    fn total(store: &Arc<KVStore>, customer: &str) -> Option<String> {
+        store.begin().get(&format!("totals:{}", customer)).unwrap()
+    }
+    #[test]
+    fn projections_fold_commits_and_resume_from_their_checkpoint() {
+        let dir = TempDir::new("projection");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            assert!(matches!(Projection::new("", "orders:", |_, _| Ok(())).start(&store), Err(KVError::InvalidInput(_))));
+            order(&store, 1, "ada 10");
+            let handle = totals().start(&store).unwrap();
+            let second = order(&store, 2, "ada 5");
+            let mut tx = store.begin();
+            tx.put("other:1".to_owned(), "ignored".to_owned()).unwrap();
+            let last = tx.commit().unwrap();
+            assert!(handle.wait_for(last, Duration::from_secs(10)));
+            assert_eq!(total(&store, "ada").as_deref(), Some("15"));
+            assert_eq!(store.projection_checkpoint("totals").unwrap(), second);
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let version = order(&store, 3, "alan 7");
+        let handle = totals().start(&store).unwrap();
+        assert!(handle.wait_for(version, Duration::from_secs(10)));
+        assert_eq!((total(&store, "ada").as_deref(), total(&store, "alan").as_deref()), (Some("15"), Some("7")));
+        let broken = order(&store, 4, "malformed");
+        assert!(!handle.wait_for(broken, Duration::from_secs(10)));
+        assert_eq!(handle.failure().as_deref(), Some("invalid input: bad order orders:4"));
+        assert_eq!(handle.position(), version);
+        drop(handle);
+        let mut tx = store.begin();
+        tx.put("orders:4".to_owned(), "ada 1".to_owned()).unwrap();
+        let fixed = tx.commit().unwrap();
+        assert_eq!(store.projection_checkpoint("totals").unwrap(), version);
+        let mut tx = store.begin();
+        tx.delete("totals:ada".to_owned()).unwrap();
+        tx.delete("totals:alan".to_owned()).unwrap();
+        tx.commit().unwrap();
+        store.reset_projection("totals").unwrap();
+        assert_eq!(store.projection_checkpoint("totals").unwrap(), 0);
+        let handle = totals().start(&store).unwrap();
+        assert!(!handle.wait_for(fixed, Duration::from_secs(10)));
+        assert!(handle.failure().unwrap().contains("orders:4"));
+        assert_eq!(total(&store, "ada").as_deref(), Some("15"));
+    }
+}
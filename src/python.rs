@@ -0,0 +1,160 @@
+use super::{KVError, KVStore, Transaction};
+use pyo3::exceptions::{PyIOError, PyMemoryError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::ops::Bound as RangeBound;
+use std::sync::Arc;
+fn to_py(err: KVError) -> PyErr {
+    match err {
+        KVError::Io(e) => PyIOError::new_err(e.to_string()),
+        KVError::InvalidInput(msg) => PyValueError::new_err(msg),
+        other @ KVError::InvalidConfig { .. } => PyValueError::new_err(other.to_string()),
+        other @ KVError::OutOfMemoryBudget(_) => PyMemoryError::new_err(other.to_string()),
+        other => PyRuntimeError::new_err(other.to_string()),
+    }
+}
+fn bounds(start: Option<String>, end: Option<String>) -> (RangeBound<String>, RangeBound<String>) {
+    (
+        start.map_or(RangeBound::Unbounded, RangeBound::Included),
+        end.map_or(RangeBound::Unbounded, RangeBound::Excluded),
+    )
+}
+#[pyclass(name = "KVStore")]
+struct PyStore {
+    store: Arc<KVStore>,
+}
+#[pymethods]
+impl PyStore {
+    #[new]
+    #[pyo3(signature = (path=None))]
+    fn new(path: Option<&str>) -> PyResult<Self> {
+        let store = match path {
+            Some(path) => KVStore::open(path).map_err(to_py)?,
+            None => KVStore::new(),
+        };
+        Ok(Self { store })
+    }
+    fn transaction(&self) -> PyTransaction {
+        PyTransaction { tx: Some(self.store.begin()) }
+    }
+    fn get(&self, key: &str) -> PyResult<Option<String>> {
+        self.store.begin().get(key).map_err(to_py)
+    }
+    #[pyo3(signature = (start=None, end=None))]
+    fn scan(&self, start: Option<String>, end: Option<String>) -> PyResult<ScanIter> {
+        let rows = self.store.begin().scan(bounds(start, end)).map_err(to_py)?;
+        Ok(ScanIter { rows: rows.into_iter() })
+    }
+    fn checkpoint(&self) -> PyResult<u64> {
+        self.store.checkpoint().map_err(to_py)
+    }
+    #[getter]
+    fn version(&self) -> u64 {
+        self.store.current_version()
+    }
+}
+#[pyclass(name = "Transaction")]
+struct PyTransaction {
+    tx: Option<Transaction>,
+}
+impl PyTransaction {
+    fn tx(&mut self) -> PyResult<&mut Transaction> {
+        self.tx.as_mut().ok_or_else(|| PyRuntimeError::new_err("transaction already finished"))
+    }
+}
+#[pymethods]
+impl PyTransaction {
+    fn get(&mut self, key: &str) -> PyResult<Option<String>> {
+        self.tx()?.get(key).map_err(to_py)
+    }
+    fn put(&mut self, key: String, value: String) -> PyResult<()> {
+        self.tx()?.put(key, value).map_err(to_py)
+    }
+    fn delete(&mut self, key: String) -> PyResult<()> {
+        self.tx()?.delete(key).map_err(to_py)
+    }
+    #[pyo3(signature = (start=None, end=None))]
+    fn scan(&mut self, start: Option<String>, end: Option<String>) -> PyResult<ScanIter> {
+        let rows = self.tx()?.scan(bounds(start, end)).map_err(to_py)?;
+        Ok(ScanIter { rows: rows.into_iter() })
+    }
+    fn commit(&mut self) -> PyResult<u64> {
+        match self.tx.take() {
+            Some(tx) => tx.commit().map_err(to_py),
+            None => Err(PyRuntimeError::new_err("transaction already finished")),
+        }
+    }
+    fn rollback(&mut self) -> PyResult<()> {
+        if let Some(mut tx) = self.tx.take() {
+            tx.rollback().map_err(to_py)?;
+        }
+        Ok(())
+    }
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    #[pyo3(signature = (exc_type, _exc_value, _traceback))]
+    fn __exit__(
+        &mut self,
+        exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        match exc_type {
+            None if self.tx.is_some() => {
+                self.commit()?;
+            }
+            _ => self.rollback()?,
+        }
+        Ok(false)
+    }
+}
+#[pyclass]
+struct ScanIter {
+    rows: std::vec::IntoIter<(String, String)>,
+}
+#[pymethods]
+impl ScanIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    fn __next__(&mut self) -> Option<(String, String)> {
+        self.rows.next()
+    }
+}
+#[pymodule]
+fn kvstore(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyStore>()?;
+    m.add_class::<PyTransaction>()?;
+    m.add_class::<ScanIter>()?;
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    #[test]
+    fn module_round_trips_through_python() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "kvstore").unwrap();
+            kvstore(&module).unwrap();
+            let globals = pyo3::types::PyDict::new(py);
+            globals.set_item("kvstore", module).unwrap();
+            let script = CString::new(
+                "store = kvstore.KVStore()\n\
+                 with store.transaction() as tx:\n    tx.put('a', '1')\n    tx.put('b', '2')\n\
+                 assert store.get('a') == '1'\n\
+                 assert list(store.scan('a', 'b')) == [('a', '1')]\n\
+                 tx = store.transaction()\n\
+                 tx.delete('a')\n\
+                 tx.rollback()\n\
+                 assert store.get('a') == '1'\n\
+                 try:\n    tx.commit()\n    raise AssertionError('commit after rollback')\n\
+                 except RuntimeError:\n    pass\n\
+                 assert store.version == 1\n",
+            )
+            .unwrap();
+            py.run(&script, Some(&globals), None).unwrap();
+        });
+    }
+}
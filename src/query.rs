@@ -0,0 +1,1305 @@
+use crate::*;
+pub(crate) const INDEX_KEYSPACE: &str = "__idx";
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDef {
+    pub prefix: String,
+    pub path: String,
+    pub kind: IndexKind,
+    pub include: Vec<String>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    Value,
+    FullText,
+}
+impl IndexDef {
+    pub(crate) fn encode(&self) -> String {
+        match self.kind {
+            IndexKind::Value if self.include.is_empty() => format!("{}\n{}", self.prefix, self.path),
+            IndexKind::Value => format!("{}\n{}\ninclude\t{}", self.prefix, self.path, self.include.join("\t")),
+            IndexKind::FullText => format!("{}\n{}\nfulltext", self.prefix, self.path),
+        }
+    }
+    pub(crate) fn decode(value: &str) -> Option<Self> {
+        let (prefix, path) = value.split_once('\n')?;
+        let (path, kind, include) = match path.split_once('\n') {
+            Some((path, "fulltext")) => (path, IndexKind::FullText, Vec::new()),
+            Some((path, include)) => {
+                let mut fields = include.split('\t');
+                if fields.next() != Some("include") {
+                    return None;
+                }
+                (path, IndexKind::Value, fields.map(str::to_owned).collect())
+            }
+            None => (path, IndexKind::Value, Vec::new()),
+        };
+        Some(Self { prefix: prefix.to_owned(), path: path.to_owned(), kind, include })
+    }
+    pub(crate) fn entries(&self, name: &str, key: &str, value: &Option<String>) -> BTreeMap<String, String> {
+        let Some(value) = value.as_deref().filter(|_| key.starts_with(&self.prefix)) else {
+            return BTreeMap::new();
+        };
+        let json = Json::parse(value);
+        let covered = match (&json, self.include.is_empty()) {
+            (Ok(json), false) => {
+                let fields = self.include.iter().filter_map(|field| Some((field.clone(), json.path(field)?.clone())));
+                Json::Object(fields.collect()).to_string()
+            }
+            _ => String::new(),
+        };
+        let encoded: Vec<String> = match self.kind {
+            IndexKind::Value => json.ok().and_then(|json| json.path(&self.path).and_then(encode_index_value)).into_iter().collect(),
+            IndexKind::FullText => {
+                let text = match &json {
+                    Ok(json) => json.path(&self.path).and_then(Json::as_str),
+                    Err(_) if self.path == "$" => Some(value),
+                    Err(_) => None,
+                };
+                text.map(tokenize).unwrap_or_default().into_iter().map(|token| encode_index_value(&Json::Str(token)).unwrap()).collect()
+            }
+        };
+        encoded
+            .into_iter()
+            .map(|encoded| (format!("{}{}", index_value_prefix(name, &encoded), key), covered.clone()))
+            .collect()
+    }
+}
+pub(crate) fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|token| !token.is_empty()).map(str::to_lowercase).collect()
+}
+pub(crate) const INDEX_BACKFILL_PAUSE: Duration = Duration::from_millis(1);
+pub(crate) const REGEX_MAX_PROGRAM: usize = 1 << 16;
+pub(crate) const REGEX_MAX_DEPTH: usize = 128;
+pub(crate) fn source_key(source: &str) -> String {
+    format!("{}{}source{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, source)
+}
+pub(crate) fn index_build_key(name: &str) -> String {
+    format!("{}{}index_build{}{}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, name)
+}
+pub(crate) fn index_def_key(name: &str) -> String {
+    format!("{}{}{}", INDEX_KEYSPACE, KEYSPACE_SEPARATOR, name)
+}
+pub(crate) fn index_value_prefix(name: &str, encoded: &str) -> String {
+    format!("{}{}{}{}{}\0", INDEX_KEYSPACE, KEYSPACE_SEPARATOR, name, KEYSPACE_SEPARATOR, encoded)
+}
+pub(crate) fn encode_index_value(value: &Json) -> Option<String> {
+    match value {
+        Json::Null => Some("0".to_owned()),
+        Json::Bool(b) => Some(format!("1{}", *b as u8)),
+        Json::Num(n) => {
+            let bits = n.to_bits();
+            Some(format!("2{:016x}", if bits >> 63 == 1 { !bits } else { bits | 1 << 63 }))
+        }
+        Json::Str(s) => Some(format!("3{}", s)),
+        _ => None,
+    }
+}
+pub(crate) fn index_range<R: RangeBounds<Json>>(
+    name: &str,
+    def: Option<String>,
+    building: bool,
+    range: &R,
+) -> Result<(Bound<String>, Bound<String>)> {
+    if !def.and_then(|def| IndexDef::decode(&def)).is_some_and(|def| def.kind == IndexKind::Value) {
+        return Err(KVError::InvalidInput(format!("no value index named {}", name)));
+    }
+    if building {
+        return Err(KVError::InvalidInput(format!("index {} is still being built", name)));
+    }
+    let entries = |value: &Json| match encode_index_value(value) {
+        Some(encoded) => Ok(prefix_range(&index_value_prefix(name, &encoded))),
+        None => Err(KVError::InvalidInput(format!("index {} cannot range over {}", name, value))),
+    };
+    let bounded = match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(value) | Bound::Excluded(value), _) | (_, Bound::Included(value) | Bound::Excluded(value)) => {
+            encode_index_value(value).map_or_else(String::new, |encoded| encoded[..1].to_owned())
+        }
+        _ => String::new(),
+    };
+    let all = prefix_range(&format!("{}{}{}{}{}", INDEX_KEYSPACE, KEYSPACE_SEPARATOR, name, KEYSPACE_SEPARATOR, bounded));
+    let start = match range.start_bound() {
+        Bound::Included(value) => entries(value)?.0,
+        Bound::Excluded(value) => match entries(value)?.1 {
+            Bound::Excluded(end) => Bound::Included(end),
+            end => end,
+        },
+        Bound::Unbounded => all.0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(value) => entries(value)?.1,
+        Bound::Excluded(value) => match entries(value)?.0 {
+            Bound::Included(start) => Bound::Excluded(start),
+            start => start,
+        },
+        Bound::Unbounded => all.1,
+    };
+    Ok((start, end))
+}
+pub(crate) fn index_entry_key(entry: &str) -> Option<String> {
+    entry.split_once('\0').map(|(_, key)| key.to_owned())
+}
+pub(crate) fn load_indexes(data: &VersionMap) -> BTreeMap<String, IndexDef> {
+    let prefix = format!("{}{}", INDEX_KEYSPACE, KEYSPACE_SEPARATOR);
+    data.range(prefix_range(&prefix))
+        .filter_map(|(key, versions)| {
+            let name = &key[prefix.len()..];
+            let value = versions.values().next_back()?.as_deref()?;
+            (!name.contains(KEYSPACE_SEPARATOR)).then(|| Some((name.to_owned(), IndexDef::decode(value)?)))?
+        })
+        .collect()
+}
+pub(crate) fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        if let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return (Bound::Included(prefix.to_owned()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_owned()), Bound::Unbounded)
+}
+pub(crate) fn glob_prefix(pattern: &str) -> &str {
+    &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())]
+}
+pub(crate) fn glob_match(pattern: &str, key: &str) -> bool {
+    let (pattern, key): (Vec<char>, Vec<char>) = (pattern.chars().collect(), key.chars().collect());
+    let (mut p, mut k) = (0, 0);
+    let mut star = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                Some((sp, sk)) => {
+                    star = Some((sp, sk + 1));
+                    p = sp + 1;
+                    k = sk + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+#[derive(Debug, Clone)]
+pub(crate) enum RegexNode {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Vec<Vec<RegexNode>>),
+    Repeat(Box<RegexNode>, usize, Option<usize>),
+}
+/// One step of a compiled [`Regex`]. `Split` forks the thread and `Jump` moves it without reading anything.
+#[derive(Debug, Clone)]
+pub(crate) enum RegexInst {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+/// A regular expression run as a Pike VM: every alternative advances in lockstep over the text, so matching takes
+/// time linear in the text and memory in the pattern, however the pattern nests its repetitions.
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pub(crate) pattern: String,
+    pub(crate) program: Vec<RegexInst>,
+}
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let mut parser = RegexParser { chars: pattern.chars().collect(), pos: 0, depth: 0, pattern };
+        let root = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        let mut compiler = RegexCompiler { program: Vec::new() };
+        if compiler.alternatives(&root).and_then(|_| compiler.push(RegexInst::Match)).is_none() {
+            return Err(KVError::InvalidInput(format!(
+                "regex {:?}: repetitions expand past {} instructions",
+                pattern, REGEX_MAX_PROGRAM
+            )));
+        }
+        Ok(Regex { pattern: pattern.to_owned(), program: compiler.program })
+    }
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+    pub fn is_match(&self, text: &str) -> bool {
+        let mut current = RegexThreads::new(self.program.len());
+        let mut next = RegexThreads::new(self.program.len());
+        let mut chars = text.chars().peekable();
+        let mut at = 0;
+        loop {
+            let end = chars.peek().is_none();
+            // An unanchored search starts a fresh attempt at every position.
+            if self.follow(&mut current, 0, at == 0, end) {
+                return true;
+            }
+            let Some(c) = chars.next() else { return false };
+            let end = chars.peek().is_none();
+            for i in 0..current.runnable.len() {
+                let pc = current.runnable[i];
+                let reads = match &self.program[pc] {
+                    RegexInst::Char(expected) => *expected == c,
+                    RegexInst::Any => true,
+                    RegexInst::Class(ranges, negated) => ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c)) != *negated,
+                    _ => false,
+                };
+                if reads && self.follow(&mut next, pc + 1, false, end) {
+                    return true;
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+            at += 1;
+        }
+    }
+    /// Adds the thread at `pc` and everything it reaches without reading, reporting whether one of them matched.
+    fn follow(&self, threads: &mut RegexThreads, pc: usize, start: bool, end: bool) -> bool {
+        let pending = &mut threads.pending;
+        pending.push(pc);
+        while let Some(pc) = pending.pop() {
+            if std::mem::replace(&mut threads.seen[pc], true) {
+                continue;
+            }
+            threads.visited.push(pc);
+            match self.program[pc] {
+                RegexInst::Match => {
+                    pending.clear();
+                    return true;
+                }
+                RegexInst::Jump(to) => pending.push(to),
+                RegexInst::Split(a, b) => pending.extend([b, a]),
+                RegexInst::Start if start => pending.push(pc + 1),
+                RegexInst::End if end => pending.push(pc + 1),
+                RegexInst::Start | RegexInst::End => {}
+                _ => threads.runnable.push(pc),
+            }
+        }
+        false
+    }
+}
+/// The threads at one position of the text, each held once however many paths led to it.
+pub(crate) struct RegexThreads {
+    pub(crate) seen: Vec<bool>,
+    pub(crate) visited: Vec<usize>,
+    pub(crate) runnable: Vec<usize>,
+    pub(crate) pending: Vec<usize>,
+}
+impl RegexThreads {
+    pub(crate) fn new(len: usize) -> Self {
+        RegexThreads { seen: vec![false; len], visited: Vec::new(), runnable: Vec::new(), pending: Vec::new() }
+    }
+    pub(crate) fn clear(&mut self) {
+        for pc in self.visited.drain(..) {
+            self.seen[pc] = false;
+        }
+        self.runnable.clear();
+    }
+}
+pub(crate) struct RegexCompiler {
+    pub(crate) program: Vec<RegexInst>,
+}
+impl RegexCompiler {
+    pub(crate) fn push(&mut self, inst: RegexInst) -> Option<usize> {
+        (self.program.len() < REGEX_MAX_PROGRAM).then(|| {
+            self.program.push(inst);
+            self.program.len() - 1
+        })
+    }
+    pub(crate) fn alternatives(&mut self, alts: &[Vec<RegexNode>]) -> Option<()> {
+        let mut exits = Vec::new();
+        for (i, seq) in alts.iter().enumerate() {
+            let split = match i + 1 < alts.len() {
+                true => Some(self.push(RegexInst::Split(0, 0))?),
+                false => None,
+            };
+            for node in seq {
+                self.node(node)?;
+            }
+            if let Some(split) = split {
+                exits.push(self.push(RegexInst::Jump(0))?);
+                self.program[split] = RegexInst::Split(split + 1, self.program.len());
+            }
+        }
+        let end = self.program.len();
+        for exit in exits {
+            self.program[exit] = RegexInst::Jump(end);
+        }
+        Some(())
+    }
+    pub(crate) fn node(&mut self, node: &RegexNode) -> Option<()> {
+        match node {
+            RegexNode::Char(c) => self.push(RegexInst::Char(*c)).map(drop),
+            RegexNode::Any => self.push(RegexInst::Any).map(drop),
+            RegexNode::Class(ranges, negated) => self.push(RegexInst::Class(ranges.clone(), *negated)).map(drop),
+            RegexNode::Start => self.push(RegexInst::Start).map(drop),
+            RegexNode::End => self.push(RegexInst::End).map(drop),
+            RegexNode::Group(alts) => self.alternatives(alts),
+            RegexNode::Repeat(inner, min, max) => {
+                let start = self.program.len();
+                self.node(inner)?;
+                if self.program.len() == start {
+                    // Repeating something that compiles to nothing is nothing, however many times.
+                    return Some(());
+                }
+                self.program.truncate(start);
+                for _ in 0..*min {
+                    self.node(inner)?;
+                }
+                let mut splits = Vec::new();
+                match max {
+                    None => {
+                        let split = self.push(RegexInst::Split(0, 0))?;
+                        self.node(inner)?;
+                        self.push(RegexInst::Jump(split))?;
+                        splits.push(split);
+                    }
+                    Some(max) => {
+                        for _ in *min..*max {
+                            splits.push(self.push(RegexInst::Split(0, 0))?);
+                            self.node(inner)?;
+                        }
+                    }
+                }
+                let end = self.program.len();
+                for split in splits {
+                    self.program[split] = RegexInst::Split(split + 1, end);
+                }
+                Some(())
+            }
+        }
+    }
+}
+pub(crate) struct RegexParser<'a> {
+    pub(crate) chars: Vec<char>,
+    pub(crate) pos: usize,
+    pub(crate) depth: usize,
+    pub(crate) pattern: &'a str,
+}
+impl RegexParser<'_> {
+    pub(crate) fn error(&self, msg: &str) -> KVError {
+        KVError::InvalidInput(format!("regex {:?}: {} at {}", self.pattern, msg, self.pos))
+    }
+    pub(crate) fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    pub(crate) fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        self.pos += found as usize;
+        found
+    }
+    pub(crate) fn alternatives(&mut self) -> Result<Vec<Vec<RegexNode>>> {
+        let mut alts = vec![Vec::new()];
+        while let Some(c) = self.peek() {
+            match c {
+                '|' => {
+                    self.pos += 1;
+                    alts.push(Vec::new());
+                }
+                ')' => break,
+                _ => {
+                    let atom = self.atom()?;
+                    let atom = self.quantifier(atom)?;
+                    alts.last_mut().unwrap().push(atom);
+                }
+            }
+        }
+        Ok(alts)
+    }
+    pub(crate) fn atom(&mut self) -> Result<RegexNode> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        Ok(match c {
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(self.error("unsupported group flag"));
+                }
+                if self.depth == REGEX_MAX_DEPTH {
+                    return Err(self.error("groups nest too deeply"));
+                }
+                self.depth += 1;
+                let alts = self.alternatives()?;
+                self.depth -= 1;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed ("));
+                }
+                RegexNode::Group(alts)
+            }
+            '.' => RegexNode::Any,
+            '^' => RegexNode::Start,
+            '$' => RegexNode::End,
+            '[' => self.class()?,
+            '\\' => self.escape()?,
+            '*' | '+' | '?' | '{' => return Err(self.error("nothing to repeat")),
+            c => RegexNode::Char(c),
+        })
+    }
+    pub(crate) fn escape(&mut self) -> Result<RegexNode> {
+        let c = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+        self.pos += 1;
+        let digits = vec![('0', '9')];
+        let word = vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+        let space = vec![(' ', ' '), ('\t', '\r')];
+        Ok(match c {
+            'd' | 'D' => RegexNode::Class(digits, c == 'D'),
+            'w' | 'W' => RegexNode::Class(word, c == 'W'),
+            's' | 'S' => RegexNode::Class(space, c == 'S'),
+            'n' => RegexNode::Char('\n'),
+            't' => RegexNode::Char('\t'),
+            c => RegexNode::Char(c),
+        })
+    }
+    pub(crate) fn class(&mut self) -> Result<RegexNode> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unclosed ["))?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                match self.escape()? {
+                    RegexNode::Class(more, false) => {
+                        ranges.extend(more);
+                        continue;
+                    }
+                    RegexNode::Char(c) => c,
+                    _ => return Err(self.error("negated class inside []")),
+                }
+            } else {
+                c
+            };
+            let hi = match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&hi)) if hi != ']' => {
+                    self.pos += 2;
+                    hi
+                }
+                _ => lo,
+            };
+            if hi < lo {
+                return Err(self.error("reversed range"));
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(RegexNode::Class(ranges, negated))
+    }
+    pub(crate) fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+    pub(crate) fn quantifier(&mut self, atom: RegexNode) -> Result<RegexNode> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number().ok_or_else(|| self.error("bad repetition"))?;
+                let max = if self.eat(',') { self.number() } else { Some(min) };
+                if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                    return Err(self.error("bad repetition"));
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, RegexNode::Start | RegexNode::End) {
+            return Err(self.error("nothing to repeat"));
+        }
+        Ok(RegexNode::Repeat(Box::new(atom), min, max))
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOn {
+    Key,
+    Value,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Eq(Json),
+    Ne(Json),
+    Lt(Json),
+    Le(Json),
+    Gt(Json),
+    Ge(Json),
+    Exists,
+}
+impl Predicate {
+    pub(crate) fn matches(&self, value: Option<&Json>) -> bool {
+        use std::cmp::Ordering::{Equal, Greater, Less};
+        let compare = |other: &Json| match (value?, other) {
+            (Json::Num(a), Json::Num(b)) => a.partial_cmp(b),
+            (Json::Str(a), Json::Str(b)) => Some(a.cmp(b)),
+            (Json::Bool(a), Json::Bool(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        match self {
+            Predicate::Eq(other) => value == Some(other),
+            Predicate::Ne(other) => value != Some(other),
+            Predicate::Lt(other) => compare(other) == Some(Less),
+            Predicate::Le(other) => matches!(compare(other), Some(Less | Equal)),
+            Predicate::Gt(other) => compare(other) == Some(Greater),
+            Predicate::Ge(other) => matches!(compare(other), Some(Greater | Equal)),
+            Predicate::Exists => value.is_some(),
+        }
+    }
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let (tag, operand) = match self {
+            Predicate::Eq(json) => (0, Some(json)),
+            Predicate::Ne(json) => (1, Some(json)),
+            Predicate::Lt(json) => (2, Some(json)),
+            Predicate::Le(json) => (3, Some(json)),
+            Predicate::Gt(json) => (4, Some(json)),
+            Predicate::Ge(json) => (5, Some(json)),
+            Predicate::Exists => (6, None),
+        };
+        buf.push(tag);
+        if let Some(json) = operand {
+            put_str(buf, &json.to_string());
+        }
+    }
+    pub(crate) fn decode(d: &mut Decoder) -> Result<Self> {
+        let tag = d.u8()?;
+        if tag == 6 {
+            return Ok(Predicate::Exists);
+        }
+        let json = Json::parse(&d.string()?)?;
+        Ok(match tag {
+            0 => Predicate::Eq(json),
+            1 => Predicate::Ne(json),
+            2 => Predicate::Lt(json),
+            3 => Predicate::Le(json),
+            4 => Predicate::Gt(json),
+            5 => Predicate::Ge(json),
+            tag => return Err(KVError::Corruption(format!("unknown predicate tag {}", tag))),
+        })
+    }
+}
+#[derive(Debug, Clone)]
+pub enum ValueFilter {
+    Regex(Regex),
+    Json(String, Predicate),
+    All(Vec<ValueFilter>),
+    Any(Vec<ValueFilter>),
+    Not(Box<ValueFilter>),
+}
+impl ValueFilter {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueFilter::Regex(regex) => regex.is_match(value),
+            ValueFilter::Json(path, predicate) => {
+                Json::parse(value).is_ok_and(|json| predicate.matches(json.path(path)))
+            }
+            ValueFilter::All(filters) => filters.iter().all(|filter| filter.matches(value)),
+            ValueFilter::Any(filters) => filters.iter().any(|filter| filter.matches(value)),
+            ValueFilter::Not(filter) => !filter.matches(value),
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+    pub(crate) fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            ValueFilter::Regex(regex) => {
+                buf.push(0);
+                put_str(buf, regex.as_str());
+            }
+            ValueFilter::Json(path, predicate) => {
+                buf.push(1);
+                put_str(buf, path);
+                predicate.encode(buf);
+            }
+            ValueFilter::All(filters) | ValueFilter::Any(filters) => {
+                buf.push(if matches!(self, ValueFilter::All(_)) { 2 } else { 3 });
+                put_u32(buf, filters.len() as u32);
+                for filter in filters {
+                    filter.encode_into(buf);
+                }
+            }
+            ValueFilter::Not(filter) => {
+                buf.push(4);
+                filter.encode_into(buf);
+            }
+        }
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::decode_from(&mut Decoder::new(bytes))
+    }
+    pub(crate) fn decode_from(d: &mut Decoder) -> Result<Self> {
+        Ok(match d.u8()? {
+            0 => ValueFilter::Regex(Regex::new(&d.string()?)?),
+            1 => ValueFilter::Json(d.string()?, Predicate::decode(d)?),
+            tag @ (2 | 3) => {
+                let filters = (0..d.u32()?).map(|_| Self::decode_from(d)).collect::<Result<Vec<_>>>()?;
+                if tag == 2 {
+                    ValueFilter::All(filters)
+                } else {
+                    ValueFilter::Any(filters)
+                }
+            }
+            4 => ValueFilter::Not(Box::new(Self::decode_from(d)?)),
+            tag => return Err(KVError::Corruption(format!("unknown filter tag {}", tag))),
+        })
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanCursor {
+    pub(crate) version: Version,
+    pub(crate) after: String,
+}
+impl ScanCursor {
+    pub fn version(&self) -> Version {
+        self.version
+    }
+}
+impl fmt::Display for ScanCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = self.version.to_le_bytes().to_vec();
+        bytes.extend_from_slice(self.after.as_bytes());
+        f.write_str(&hex(&bytes))
+    }
+}
+impl std::str::FromStr for ScanCursor {
+    type Err = KVError;
+    fn from_str(s: &str) -> Result<Self> {
+        let bad = || KVError::InvalidInput(format!("bad scan cursor {:?}", s));
+        if s.len() < 16 || !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(bad());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| bad()))
+            .collect::<Result<Vec<u8>>>()?;
+        Ok(ScanCursor {
+            version: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            after: String::from_utf8(bytes[8..].to_vec()).map_err(|_| bad())?,
+        })
+    }
+}
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(String, String)>,
+    pub next: Option<ScanCursor>,
+    pub(crate) policies: Policies,
+}
+impl fmt::Debug for ScanPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<_> = self.entries.iter().map(|(key, value)| (key, RedactedValue(self.policies.is_sensitive(key), value))).collect();
+        f.debug_struct("ScanPage").field("entries", &entries).field("next", &self.next).finish()
+    }
+}
+pub struct Query {
+    pub(crate) snapshot: Snapshot,
+    pub(crate) prefix: String,
+    pub(crate) filters: Vec<(String, Predicate)>,
+    pub(crate) limit: usize,
+    pub(crate) projection: Vec<String>,
+}
+impl Query {
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+    pub fn filter_json(mut self, path: &str, predicate: Predicate) -> Self {
+        self.filters.push((path.to_owned(), predicate));
+        self
+    }
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+    pub fn projection(mut self, paths: &[&str]) -> Self {
+        self.projection = paths.iter().map(|path| (*path).to_owned()).collect();
+        self
+    }
+    pub(crate) fn select(&self, value: &str) -> Option<String> {
+        if self.projection.is_empty() {
+            return self.matches(value).then(|| value.to_owned());
+        }
+        let json = Json::parse(value).ok();
+        let path = |path: &str| json.as_ref().and_then(|json| json.path(path));
+        if !self.filters.iter().all(|(at, predicate)| json.is_some() && predicate.matches(path(at))) {
+            return None;
+        }
+        let fields = self.projection.iter().filter_map(|at| Some((at.clone(), path(at)?.clone())));
+        Some(Json::Object(fields.collect()).to_string())
+    }
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        if self.filters.is_empty() {
+            return true;
+        }
+        let Ok(json) = Json::parse(value) else {
+            return false;
+        };
+        self.filters.iter().all(|(path, predicate)| predicate.matches(json.path(path)))
+    }
+    pub(crate) fn index_candidates(&self) -> Option<Vec<String>> {
+        let indexes = self.snapshot.store.indexes();
+        self.filters.iter().find_map(|(path, predicate)| {
+            let Predicate::Eq(value) = predicate else { return None };
+            let (name, _) = indexes.iter().find(|(name, def)| {
+                def.kind == IndexKind::Value
+                    && &def.path == path
+                    && self.prefix.starts_with(&def.prefix)
+                    && self.snapshot.get(&index_def_key(name)).as_deref() == Some(def.encode().as_str())
+                    && self.snapshot.get(&index_build_key(name)).is_none()
+            })?;
+            let entries = index_value_prefix(name, &encode_index_value(value)?);
+            Some(
+                self.snapshot
+                    .scan(prefix_range(&entries))
+                    .into_iter()
+                    .map(|(entry, _)| entry[entries.len()..].to_owned())
+                    .filter(|key| key.starts_with(&self.prefix))
+                    .collect(),
+            )
+        })
+    }
+    pub fn materialize(self, target: &str, aggregate: ViewAggregate) -> Result<()> {
+        let def = ViewDef { prefix: self.prefix, filters: self.filters, aggregate };
+        self.snapshot.store.define_view(target, def)
+    }
+    pub fn run(&self) -> Vec<(String, String)> {
+        if let Some(keys) = self.index_candidates() {
+            return keys
+                .into_iter()
+                .filter_map(|key| {
+                    let value = self.select(&self.snapshot.get(&key)?)?;
+                    Some((key, value))
+                })
+                .take(self.limit)
+                .collect();
+        }
+        self.snapshot.store.fold_at(prefix_range(&self.prefix), self.snapshot.version, Vec::new(), |mut found, key, value| {
+            if found.len() < self.limit {
+                if let Some(value) = self.select(value) {
+                    found.push((key.to_owned(), value));
+                }
+            }
+            found
+        })
+    }
+}
+pub(crate) const VIEW_KEYSPACE: &str = "__view";
+pub(crate) fn view_def_key(target: &str) -> String {
+    format!("{}{}{}", VIEW_KEYSPACE, KEYSPACE_SEPARATOR, target)
+}
+pub(crate) fn load_views(data: &VersionMap) -> BTreeMap<String, ViewDef> {
+    let prefix = view_def_key("");
+    data.range(prefix_range(&prefix))
+        .filter_map(|(key, versions)| Some((key[prefix.len()..].to_owned(), ViewDef::decode(versions.latest()?.1.as_deref()?)?)))
+        .collect()
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViewAggregate {
+    Count,
+    SumInt(String),
+    LastN(usize),
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewDef {
+    pub prefix: String,
+    pub filters: Vec<(String, Predicate)>,
+    pub aggregate: ViewAggregate,
+}
+impl ViewDef {
+    pub(crate) fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        put_str(&mut buf, &self.prefix);
+        match &self.aggregate {
+            ViewAggregate::Count => buf.push(0),
+            ViewAggregate::SumInt(path) => {
+                buf.push(1);
+                put_str(&mut buf, path);
+            }
+            ViewAggregate::LastN(n) => {
+                buf.push(2);
+                put_u64(&mut buf, *n as u64);
+            }
+        }
+        put_u32(&mut buf, self.filters.len() as u32);
+        for (path, predicate) in &self.filters {
+            put_str(&mut buf, path);
+            predicate.encode(&mut buf);
+        }
+        hex(&buf)
+    }
+    pub(crate) fn decode(value: &str) -> Option<Self> {
+        let bytes = unhex(value)?;
+        let mut d = Decoder::new(&bytes);
+        let prefix = d.string().ok()?;
+        let aggregate = match d.u8().ok()? {
+            0 => ViewAggregate::Count,
+            1 => ViewAggregate::SumInt(d.string().ok()?),
+            2 => ViewAggregate::LastN(d.u64().ok()? as usize),
+            _ => return None,
+        };
+        let filters = (0..d.u32().ok()?).map(|_| Some((d.string().ok()?, Predicate::decode(&mut d).ok()?))).collect::<Option<_>>()?;
+        Some(ViewDef { prefix, filters, aggregate })
+    }
+    pub(crate) fn covers(&self, key: &str) -> bool {
+        key.starts_with(self.prefix.as_str()) && !keyspace_of(key).starts_with("__")
+    }
+    pub(crate) fn selects(&self, value: Option<&str>) -> Option<Option<Json>> {
+        let value = value?;
+        if self.filters.is_empty() && !matches!(self.aggregate, ViewAggregate::SumInt(_)) {
+            return Some(None);
+        }
+        let json = Json::parse(value).ok()?;
+        self.filters.iter().all(|(path, predicate)| predicate.matches(json.path(path))).then_some(Some(json))
+    }
+    pub(crate) fn amount(&self, value: Option<&str>) -> i64 {
+        match (&self.aggregate, self.selects(value)) {
+            (ViewAggregate::SumInt(path), Some(Some(json))) => match json.path(path) {
+                Some(Json::Num(n)) => *n as i64,
+                _ => 0,
+            },
+            (_, selected) => selected.is_some() as i64,
+        }
+    }
+    pub(crate) fn update(&self, current: Option<&str>, touched: &[(&str, Option<String>, Option<&str>)]) -> String {
+        match self.aggregate {
+            ViewAggregate::Count | ViewAggregate::SumInt(_) => {
+                let total = current.and_then(|current| current.parse::<i64>().ok()).unwrap_or(0);
+                let delta: i64 = touched.iter().map(|(_, old, new)| self.amount(*new) - self.amount(old.as_deref())).sum();
+                (total + delta).to_string()
+            }
+            ViewAggregate::LastN(n) => {
+                let mut keys: Vec<String> = match current.map(Json::parse) {
+                    Some(Ok(Json::Array(items))) => {
+                        items.into_iter().filter_map(|item| if let Json::Str(key) = item { Some(key) } else { None }).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                // Walk back so keys written together end up in key order, as compute ranks them.
+                for (key, _, new) in touched.iter().rev() {
+                    keys.retain(|kept| kept != key);
+                    if self.selects(*new).is_some() {
+                        keys.insert(0, (*key).to_owned());
+                    }
+                }
+                keys.truncate(n);
+                Json::Array(keys.into_iter().map(Json::Str).collect()).to_string()
+            }
+        }
+    }
+    pub(crate) fn compute(&self, state: &mvcc::State, writes: &Writes) -> String {
+        let mut rows: BTreeMap<&str, (Version, Option<&str>)> = state
+            .map
+            .range(prefix_range(&self.prefix))
+            .filter(|(key, _)| self.covers(key))
+            .filter_map(|(key, versions)| versions.latest().map(|(version, value)| (key.as_str(), (*version, value.as_deref()))))
+            .collect();
+        rows.extend(writes.range(prefix_range(&self.prefix)).filter(|(key, _)| self.covers(key)).map(|(key, value)| (key.as_str(), (Version::MAX, value.as_deref()))));
+        match self.aggregate {
+            ViewAggregate::Count | ViewAggregate::SumInt(_) => rows.values().map(|(_, value)| self.amount(*value)).sum::<i64>().to_string(),
+            ViewAggregate::LastN(n) => {
+                let mut selected: Vec<_> = rows.into_iter().filter(|(_, (_, value))| self.selects(*value).is_some()).collect();
+                selected.sort_by_key(|(key, (version, _))| (std::cmp::Reverse(*version), *key));
+                Json::Array(selected.into_iter().take(n).map(|(key, _)| Json::Str(key.to_owned())).collect()).to_string()
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn users(store: &Arc<KVStore>) {
+        let mut tx = store.begin();
+        for (id, name, age, city) in [(1, "ada", 36, "london"), (2, "alan", 41, "wilmslow"), (3, "grace", 29, "new york"), (4, "edsger", 41, "austin")] {
+            let doc = format!(r#"{{"name":"{}","age":{},"address":{{"city":"{}"}}}}"#, name, age, city);
+            tx.put(format!("users:{}", id), doc).unwrap();
+        }
+        tx.put("users:5".to_owned(), "not json".to_owned()).unwrap();
+        tx.put("orders:1".to_owned(), r#"{"age":41}"#.to_owned()).unwrap();
+        tx.commit().unwrap();
+    }
+    fn wait_ready(store: &Arc<KVStore>, index: &str) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !store.index_ready(index) {
+            assert!(Instant::now() < deadline, "index {} never finished building", index);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    fn keys(rows: Vec<(String, String)>) -> Vec<String> {
+        rows.into_iter().map(|(key, _)| key).collect()
+    }
+    #[test]
+    fn pages_continue_at_the_cursor_snapshot() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        for i in 0..25 {
+            tx.put(format!("ks:{:02}", i), i.to_string()).unwrap();
+        }
+        let version = tx.commit().unwrap();
+        let range = || "ks:".to_owned().."ks;".to_owned();
+        let first = store.snapshot().scan_page(range(), 10);
+        assert_eq!(keys(first.entries), (0..10).map(|i| format!("ks:{:02}", i)).collect::<Vec<_>>());
+        let mut cursor = first.next.unwrap();
+        assert_eq!(cursor.version(), version);
+        let mut tx = store.begin();
+        tx.delete("ks:15".to_owned()).unwrap();
+        tx.put("ks:10a".to_owned(), "late".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut seen = 10;
+        loop {
+            let token = cursor.to_string();
+            let page = store.scan_continue(range(), &token.parse().unwrap(), 10).unwrap();
+            assert!(page.entries.iter().all(|(key, _)| key != "ks:10a"), "pages must not see later commits");
+            seen += page.entries.len();
+            match page.next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(seen, 25);
+        let exact = store.snapshot().scan_page("ks:00".to_owned().."ks:10".to_owned(), 10);
+        assert_eq!((exact.entries.len(), exact.next.is_none()), (10, true));
+        assert!(store.snapshot().scan_page(range(), 0).entries.is_empty());
+        assert!("00".parse::<ScanCursor>().is_err() && "zz".repeat(8).parse::<ScanCursor>().is_err());
+        let ahead = ScanCursor { version: version + 10, after: "ks:00".to_owned() };
+        assert!(matches!(store.scan_continue(range(), &ahead, 10), Err(KVError::InvalidInput(_))));
+        store.set_default_retention(Retention::LatestOnly);
+        store.gc();
+        assert!(matches!(store.scan_continue(range(), &cursor, 10), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn globs_match_wildcards_within_a_prefix_bounded_scan() {
+        for (pattern, key, expected) in [
+            ("user:*:settings", "user:42:settings", true),
+            ("user:*:settings", "user::settings", true),
+            ("user:*:settings", "user:42:profile", false),
+            ("user:?", "user:7", true),
+            ("user:?", "user:77", false),
+            ("*", "", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbY", false),
+            ("exact", "exact", true),
+            ("exact", "exactly", false),
+            ("ключ:?", "ключ:я", true),
+        ] {
+            assert_eq!(glob_match(pattern, key), expected, "{:?} against {:?}", pattern, key);
+        }
+        assert_eq!((glob_prefix("user:*:s"), glob_prefix("a?b"), glob_prefix("plain")), ("user:", "a", "plain"));
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        for key in ["user:1:settings", "user:1:profile", "user:22:settings", "users:3:settings", "admin:1:settings"] {
+            tx.put(key.to_owned(), "v".to_owned()).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(keys(store.snapshot().scan_match("user:*:settings")), ["user:1:settings", "user:22:settings"]);
+        assert_eq!(keys(store.snapshot().scan_match("user:?:*")), ["user:1:profile", "user:1:settings"]);
+        let mut tx = store.begin();
+        tx.put("user:3:settings".to_owned(), "new".to_owned()).unwrap();
+        tx.delete("user:1:settings".to_owned()).unwrap();
+        assert_eq!(keys(tx.scan_match("user:*:settings").unwrap()), ["user:22:settings", "user:3:settings"]);
+    }
+    #[test]
+    fn regexes_match_like_their_usual_syntax_and_filter_scans() {
+        for (pattern, text, expected) in [
+            ("abc", "xxabcxx", true),
+            ("^abc$", "xxabcxx", false),
+            ("^a.c$", "abc", true),
+            ("^\\d{3}-\\d{4}$", "555-1234", true),
+            ("^\\d{3}-\\d{4}$", "555-12345", false),
+            ("^(cat|dog)s?$", "dogs", true),
+            ("^(?:cat|dog)s?$", "cow", false),
+            ("^[a-c]+[^a-c]$", "abcabz", true),
+            ("^[a-c]+[^a-c]$", "abcabc", false),
+            ("^a{2,}$", "a", false),
+            ("^a{2,}$", "aaaa", true),
+            ("^\\w+@\\w+\\.com$", "ada@example.com", true),
+            ("^(a*)*$", "aaab", false),
+            ("^(a*)*$", "", true),
+            ("", "anything", true),
+        ] {
+            assert_eq!(Regex::new(pattern).unwrap().is_match(text), expected, "{:?} against {:?}", pattern, text);
+        }
+        for bad in ["(a", "a)", "[a", "*a", "a{3,1}", "[z-a]", "(?i)a", "a\\"] {
+            assert!(matches!(Regex::new(bad), Err(KVError::InvalidInput(_))), "{:?} should not compile", bad);
+        }
+        let deep = "(".repeat(100_000);
+        for (huge, why) in [("(a{1000}){1000}", "expand past"), (&deep[..], "nest too deeply")] {
+            assert!(Regex::new(huge).unwrap_err().to_string().contains(why), "{:?}", why);
+        }
+        assert!(Regex::new("^((){60000}){60000}a$").unwrap().is_match("a"));
+        let store = KVStore::new();
+        users(&store);
+        let snapshot = store.snapshot();
+        let range = || "users:".to_owned().."users;".to_owned();
+        let by_value = Regex::new(r#""city":"(london|austin)""#).unwrap();
+        assert_eq!(keys(snapshot.scan_regex(range(), &by_value, MatchOn::Value)), ["users:1", "users:4"]);
+        let by_key = Regex::new("^users:[2-3]$").unwrap();
+        assert_eq!(keys(snapshot.scan_regex(.., &by_key, MatchOn::Key)), ["users:2", "users:3"]);
+        assert_eq!(keys(snapshot.scan_filtered(range(), |value| !value.starts_with('{'))), ["users:5"]);
+    }
+    #[test]
+    fn pathological_patterns_and_multi_megabyte_values_match_in_linear_time() {
+        let started = Instant::now();
+        let almost = format!("{}b", "a".repeat(40));
+        for pattern in ["^(a+)+$", "^(a|a)*$", "^(a*)*$", "^(a|aa)+$", "^(.*a){20}$"] {
+            assert!(!Regex::new(pattern).unwrap().is_match(&almost), "{:?}", pattern);
+        }
+        let long = "a".repeat(2 << 20);
+        assert!(!Regex::new("a*b").unwrap().is_match(&long));
+        assert!(Regex::new("^(a|b)*$").unwrap().is_match(&long));
+        assert!(Regex::new("a{3}$").unwrap().is_match(&long));
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        tx.put("blob:1".to_owned(), format!("{}needle", "x".repeat(2 << 20))).unwrap();
+        tx.put("blob:2".to_owned(), "x".repeat(2 << 20)).unwrap();
+        tx.commit().unwrap();
+        let needle = Regex::new("x+needle$").unwrap();
+        assert_eq!(keys(store.snapshot().scan_regex(.., &needle, MatchOn::Value)), ["blob:1"]);
+        assert!(started.elapsed() < Duration::from_secs(30), "took {:?}", started.elapsed());
+    }
+    #[test]
+    fn queries_filter_project_and_limit_json_values() {
+        let store = KVStore::new();
+        users(&store);
+        let query = || store.snapshot().query().prefix("users:");
+        assert_eq!(keys(query().filter_json("$.age", Predicate::Gt(Json::Num(35.0))).run()), ["users:1", "users:2", "users:4"]);
+        let older = query().filter_json("$.age", Predicate::Ge(Json::Num(36.0))).filter_json("$.address.city", Predicate::Ne(Json::Str("austin".into())));
+        assert_eq!(keys(older.run()), ["users:1", "users:2"]);
+        assert_eq!(keys(query().filter_json("$.age", Predicate::Exists).limit(2).run()), ["users:1", "users:2"]);
+        assert_eq!(query().run().len(), 5);
+        let projected = query().filter_json("$.name", Predicate::Eq(Json::Str("grace".into()))).projection(&["$.address.city"]).run();
+        assert_eq!(projected, [("users:3".to_owned(), r#"{"$.address.city":"new york"}"#.to_owned())]);
+    }
+    #[test]
+    fn projections_return_only_the_requested_fields() {
+        let store = KVStore::new();
+        users(&store);
+        let query = || store.snapshot().query().prefix("users:").projection(&["$.name", "$.address", "$.missing"]);
+        let rows = query().limit(2).run();
+        assert_eq!(
+            rows,
+            [
+                ("users:1".to_owned(), r#"{"$.name":"ada","$.address":{"city":"london"}}"#.to_owned()),
+                ("users:2".to_owned(), r#"{"$.name":"alan","$.address":{"city":"wilmslow"}}"#.to_owned()),
+            ]
+        );
+        let all = query().run();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[4], ("users:5".to_owned(), "{}".to_owned()), "values that are not JSON project to no fields");
+        let filtered = query().filter_json("$.age", Predicate::Lt(Json::Num(40.0))).run();
+        assert_eq!(keys(filtered.clone()), ["users:1", "users:3"]);
+        assert!(filtered.iter().all(|(_, value)| !value.contains("age")));
+    }
+    #[test]
+    fn equality_queries_use_a_ready_index_and_agree_with_a_scan() {
+        let store = KVStore::new();
+        users(&store);
+        let by_age = || store.snapshot().query().prefix("users:").filter_json("$.age", Predicate::Eq(Json::Num(41.0)));
+        let scanned = by_age().run();
+        store.create_index("age", "users:", "$.age").unwrap();
+        wait_ready(&store, "age");
+        assert!(by_age().index_candidates().is_some());
+        assert_eq!(by_age().run(), scanned);
+        assert_eq!(keys(scanned), ["users:2", "users:4"]);
+    }
+    #[test]
+    fn deeply_nested_values_commit_unindexed_instead_of_overflowing_the_stack() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(JSON_MAX_DEPTH)).is_ok());
+        match Json::parse(&nested(JSON_MAX_DEPTH + 1)) {
+            Err(KVError::InvalidInput(message)) => assert!(message.contains("nested deeper than 128 levels"), "{}", message),
+            other => panic!("expected a depth error, got {:?}", other),
+        }
+        assert!(matches!(Json::parse(&format!(r#"{{"a":{}}}"#, "{\"a\":".repeat(200))), Err(KVError::InvalidInput(_))));
+        let store = KVStore::new();
+        store.create_index("status", "order:", "$.status").unwrap();
+        wait_ready(&store, "status");
+        let mut tx = store.begin();
+        tx.put("order:1".to_owned(), "[".repeat(1 << 20)).unwrap();
+        tx.put("order:2".to_owned(), r#"{"status":"open"}"#.to_owned()).unwrap();
+        tx.commit().unwrap();
+        let open = store.snapshot().get_range_by_index("status", Json::Str("open".into())..=Json::Str("open".into())).unwrap();
+        assert_eq!(open, ["order:2"]);
+        assert_eq!(store.begin().get("order:1").unwrap().map(|value| value.len()), Some(1 << 20));
+        let filtered = store.snapshot().query().prefix("order:").filter_json("$.status", Predicate::Eq(Json::Str("open".into()))).run();
+        assert_eq!(keys(filtered), ["order:2"]);
+    }
+    #[test]
+    fn index_ranges_follow_numeric_and_string_order() {
+        let store = KVStore::new();
+        users(&store);
+        let mut tx = store.begin();
+        tx.put("users:6".to_owned(), r#"{"name":"neg","age":-3.5}"#.to_owned()).unwrap();
+        tx.put("users:7".to_owned(), r#"{"name":"tiny","age":0.25}"#.to_owned()).unwrap();
+        tx.commit().unwrap();
+        store.create_index("age", "users:", "$.age").unwrap();
+        store.create_index("name", "users:", "$.name").unwrap();
+        wait_ready(&store, "age");
+        wait_ready(&store, "name");
+        let snapshot = store.snapshot();
+        let ages = |range: (Bound<Json>, Bound<Json>)| snapshot.get_range_by_index("age", range).unwrap();
+        let num = |n: f64| Json::Num(n);
+        assert_eq!(ages((Bound::Included(num(30.0)), Bound::Included(num(41.0)))), ["users:1", "users:2", "users:4"]);
+        assert_eq!(ages((Bound::Included(num(30.0)), Bound::Excluded(num(41.0)))), ["users:1"]);
+        assert_eq!(ages((Bound::Excluded(num(36.0)), Bound::Unbounded)), ["users:2", "users:4"]);
+        assert_eq!(ages((Bound::Unbounded, Bound::Excluded(num(30.0)))), ["users:6", "users:7", "users:3"]);
+        let names = snapshot.scan_by_index("name", Json::Str("alan".into())..Json::Str("grace".into())).unwrap();
+        assert_eq!(keys(names), ["users:2", "users:4"]);
+        assert!(matches!(snapshot.get_range_by_index("missing", ..), Err(KVError::InvalidInput(_))));
+        assert!(matches!(snapshot.get_range_by_index("age", Json::Array(Vec::new())..), Err(KVError::InvalidInput(_))));
+        let mut tx = store.begin();
+        tx.put("users:8".to_owned(), r#"{"age":37}"#.to_owned()).unwrap();
+        tx.delete("users:1".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(keys(store.snapshot().scan_by_index("age", num(30.0)..num(40.0)).unwrap()), ["users:8"]);
+    }
+    #[test]
+    fn backfills_index_existing_and_concurrent_writes_and_resume_after_a_reopen() {
+        let dir = TempDir::new("index-backfill");
+        let doc = |i: usize| format!(r#"{{"age":{}}}"#, i % 50);
+        let by_age = |store: &Arc<KVStore>, age: f64| store.snapshot().get_range_by_index("age", Json::Num(age)..=Json::Num(age)).unwrap();
+        let expected = |age: usize, count: usize| (0..count).filter(|i| i % 50 == age).map(|i| format!("users:{:05}", i)).collect::<Vec<_>>();
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let mut tx = store.begin();
+            for i in 0..3000 {
+                tx.put(format!("users:{:05}", i), doc(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            store.create_index("age", "users:", "$.age").unwrap();
+            for i in 3000..3100 {
+                let mut tx = store.begin();
+                tx.put(format!("users:{:05}", i), doc(i)).unwrap();
+                tx.commit().unwrap();
+            }
+            wait_ready(&store, "age");
+            assert_eq!(by_age(&store, 7.0), expected(7, 3100));
+            assert!(matches!(store.rebuild_index("missing"), Err(KVError::InvalidInput(_))));
+            store.rebuild_index("age").unwrap();
+            wait_ready(&store, "age");
+            assert_eq!(by_age(&store, 7.0), expected(7, 3100));
+            // Put the build back partway through, as if the store stopped mid-backfill.
+            let mut tx = store.begin();
+            tx.put_internal(index_build_key("age"), "users:01500".to_owned()).unwrap();
+            tx.commit().unwrap();
+            assert!(!store.index_ready("age"));
+            assert!(matches!(store.snapshot().get_range_by_index("age", ..), Err(KVError::InvalidInput(_))));
+            store.close().unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        wait_ready(&store, "age");
+        assert_eq!(by_age(&store, 7.0), expected(7, 3100));
+        store.close().unwrap();
+        drop(store);
+        let report = check::check_store(&FsStorage::new(dir.path())).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let index = report.indexes.iter().find(|index| index.name == "age").unwrap();
+        assert_eq!((index.entries, index.missing, index.stale), (3100, 0, 0));
+    }
+    #[test]
+    fn materialized_views_follow_commits_and_survive_a_reopen() {
+        let dir = TempDir::new("views");
+        let read = |store: &Arc<KVStore>| {
+            let tx = store.begin();
+            ["stats:users", "stats:older", "stats:ages", "stats:recent"].map(|key| tx.get(key).unwrap().unwrap_or_default())
+        };
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            users(&store);
+            let query = || store.snapshot().query().prefix("users:");
+            query().materialize("stats:users", ViewAggregate::Count).unwrap();
+            query().filter_json("$.age", Predicate::Ge(Json::Num(36.0))).materialize("stats:older", ViewAggregate::Count).unwrap();
+            query().materialize("stats:ages", ViewAggregate::SumInt("$.age".to_owned())).unwrap();
+            query().materialize("stats:recent", ViewAggregate::LastN(2)).unwrap();
+            assert!(matches!(query().materialize("stats:users", ViewAggregate::Count), Err(KVError::InvalidInput(_))));
+            assert!(matches!(query().materialize("users:count", ViewAggregate::Count), Err(KVError::InvalidInput(_))));
+            assert!(matches!(query().materialize("stats:none", ViewAggregate::LastN(0)), Err(KVError::InvalidInput(_))));
+            assert_eq!(read(&store), ["5", "3", "147", r#"["users:1","users:2"]"#]);
+            let mut tx = store.begin();
+            tx.put("users:6".to_owned(), r#"{"name":"barbara","age":50}"#.to_owned()).unwrap();
+            tx.delete("users:2".to_owned()).unwrap();
+            tx.put("users:3".to_owned(), r#"{"name":"grace","age":30}"#.to_owned()).unwrap();
+            tx.put("orders:2".to_owned(), r#"{"age":99}"#.to_owned()).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(read(&store), ["5", "3", "157", r#"["users:3","users:6"]"#]);
+            query().materialize("stats:rebuilt", ViewAggregate::LastN(2)).unwrap();
+            assert_eq!(store.begin().get("stats:rebuilt").unwrap(), store.begin().get("stats:recent").unwrap());
+            store.drop_view("stats:rebuilt").unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.views().len(), 4);
+        let mut tx = store.begin();
+        tx.put("users:7".to_owned(), r#"{"name":"tony","age":20}"#.to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(read(&store), ["6", "3", "177", r#"["users:7","users:3"]"#]);
+        store.drop_view("stats:ages").unwrap();
+        assert!(matches!(store.drop_view("stats:ages"), Err(KVError::InvalidInput(_))));
+        let mut tx = store.begin();
+        tx.delete("users:7".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.views().len(), 3);
+        assert_eq!(store.begin().get("stats:users").unwrap().as_deref(), Some("5"));
+    }
+    #[test]
+    fn covering_indexes_answer_with_stored_fragments() {
+        let store = KVStore::new();
+        users(&store);
+        assert!(matches!(store.create_covering_index("bad", "users:", "$.age", &["name"]), Err(KVError::InvalidInput(_))));
+        store.create_covering_index("age", "users:", "$.age", &["$.name", "$.address.city", "$.missing"]).unwrap();
+        store.create_index("plain", "users:", "$.age").unwrap();
+        wait_ready(&store, "age");
+        wait_ready(&store, "plain");
+        let covered = store.snapshot().scan_covering("age", Json::Num(40.0)..).unwrap();
+        assert_eq!(
+            covered,
+            [
+                ("users:2".to_owned(), r#"{"$.name":"alan","$.address.city":"wilmslow"}"#.to_owned()),
+                ("users:4".to_owned(), r#"{"$.name":"edsger","$.address.city":"austin"}"#.to_owned()),
+            ]
+        );
+        assert_eq!(store.snapshot().scan_covering("plain", Json::Num(40.0)..).unwrap()[0], ("users:2".to_owned(), String::new()));
+        let mut tx = store.begin();
+        tx.put("users:2".to_owned(), r#"{"name":"turing","age":41}"#.to_owned()).unwrap();
+        tx.commit().unwrap();
+        let covered = store.snapshot().scan_covering("age", Json::Num(41.0)..=Json::Num(41.0)).unwrap();
+        assert_eq!(covered[0], ("users:2".to_owned(), r#"{"$.name":"turing"}"#.to_owned()));
+    }
+    #[test]
+    fn text_search_matches_every_token_and_follows_updates() {
+        let store = KVStore::new();
+        users(&store);
+        store.create_text_index("names", "users:", "$.address.city").unwrap();
+        wait_ready(&store, "names");
+        assert_eq!(store.search("NEW"), ["users:3"]);
+        assert_eq!(store.search("new york"), ["users:3"]);
+        assert!(store.search("new london").is_empty());
+        assert!(store.search("  ").is_empty());
+        let mut tx = store.begin();
+        tx.put("users:3".to_owned(), r#"{"address":{"city":"York"}}"#.to_owned()).unwrap();
+        tx.put("users:6".to_owned(), r#"{"address":{"city":"York, again"}}"#.to_owned()).unwrap();
+        tx.delete("users:1".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert!(store.search("new").is_empty());
+        assert!(store.search("london").is_empty());
+        assert_eq!(store.search("york"), ["users:3", "users:6"]);
+        store.drop_index("names").unwrap();
+        assert!(store.search("york").is_empty());
+    }
+}
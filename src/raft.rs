@@ -0,0 +1,872 @@
+use super::{
+    crc32, decode_snapshot, encode_snapshot, frame, put_str, put_u32, put_u64, put_writes, read_writes, segment_frames,
+    tls, CommitMeta, CommitTrace, Decoder, KVError, KVStore, MemoryStorage, PeerTls, Proposer, Result, Storage, TlsClient, Version,
+    Writes, WAL_MAGIC,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+const META: &str = "raft.meta";
+const LOG: &str = "raft.log";
+const SNAP: &str = "raft.snap";
+const MAX_BATCH: usize = 512;
+pub type NodeId = u64;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+pub struct RaftConfig {
+    pub id: NodeId,
+    pub listen: SocketAddr,
+    pub peers: BTreeMap<NodeId, SocketAddr>,
+    pub election_timeout: Duration,
+    pub heartbeat: Duration,
+    pub commit_timeout: Duration,
+    pub snapshot_every: u64,
+    /// Mutual TLS between the nodes; every peer's certificate must name its IP address.
+    pub tls: Option<PeerTls>,
+}
+impl RaftConfig {
+    pub fn new(id: NodeId, listen: SocketAddr, peers: BTreeMap<NodeId, SocketAddr>) -> Self {
+        Self {
+            id,
+            listen,
+            peers,
+            election_timeout: Duration::from_millis(300),
+            heartbeat: Duration::from_millis(50),
+            commit_timeout: Duration::from_secs(5),
+            snapshot_every: 10_000,
+            tls: None,
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub struct RaftStatus {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<NodeId>,
+    pub commit_index: u64,
+    pub applied: u64,
+}
+#[derive(Clone)]
+struct Entry {
+    term: u64,
+    writes: Writes,
+    meta: CommitMeta,
+}
+impl Entry {
+    fn encode(&self, index: u64) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, index);
+        put_u64(&mut payload, self.term);
+        put_writes(&mut payload, &self.writes);
+        self.meta.encode(&mut payload);
+        frame(&payload)
+    }
+    fn decode(payload: &[u8]) -> Result<(u64, Entry)> {
+        let mut d = Decoder::new(payload);
+        let (index, term) = (d.u64()?, d.u64()?);
+        let writes = read_writes(&mut d)?;
+        Ok((index, Entry { term, writes, meta: CommitMeta::decode(&mut d)? }))
+    }
+}
+#[derive(Clone)]
+enum Message {
+    Vote { term: u64, candidate: NodeId, last_index: u64, last_term: u64 },
+    VoteReply { term: u64, granted: bool },
+    Append { term: u64, leader: NodeId, prev_index: u64, prev_term: u64, commit: u64, entries: Vec<Entry> },
+    AppendReply { term: u64, success: bool, last_index: u64 },
+    Snapshot { term: u64, leader: NodeId, index: u64, last_term: u64, data: Vec<u8> },
+    SnapshotReply { term: u64 },
+    Fetch { key: String, version: Version },
+    Fetched { entry: Option<Option<String>> },
+}
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 4];
+        match self {
+            Message::Vote { term, candidate, last_index, last_term } => {
+                buf.push(0);
+                for v in [term, candidate, last_index, last_term] {
+                    put_u64(&mut buf, *v);
+                }
+            }
+            Message::VoteReply { term, granted } => {
+                buf.push(1);
+                put_u64(&mut buf, *term);
+                buf.push(*granted as u8);
+            }
+            Message::Append { term, leader, prev_index, prev_term, commit, entries } => {
+                buf.push(2);
+                for v in [term, leader, prev_index, prev_term, commit] {
+                    put_u64(&mut buf, *v);
+                }
+                put_u32(&mut buf, entries.len() as u32);
+                for (i, entry) in entries.iter().enumerate() {
+                    buf.extend_from_slice(&entry.encode(prev_index + 1 + i as u64));
+                }
+            }
+            Message::AppendReply { term, success, last_index } => {
+                buf.push(3);
+                put_u64(&mut buf, *term);
+                buf.push(*success as u8);
+                put_u64(&mut buf, *last_index);
+            }
+            Message::Snapshot { term, leader, index, last_term, data } => {
+                buf.push(4);
+                for v in [term, leader, index, last_term] {
+                    put_u64(&mut buf, *v);
+                }
+                put_u32(&mut buf, data.len() as u32);
+                buf.extend_from_slice(data);
+            }
+            Message::SnapshotReply { term } => {
+                buf.push(5);
+                put_u64(&mut buf, *term);
+            }
+            Message::Fetch { key, version } => {
+                buf.push(6);
+                put_u64(&mut buf, *version);
+                put_str(&mut buf, key);
+            }
+            Message::Fetched { entry } => {
+                buf.push(7);
+                match entry {
+                    None => buf.push(0),
+                    Some(None) => buf.push(1),
+                    Some(Some(value)) => {
+                        buf.push(2);
+                        put_str(&mut buf, value);
+                    }
+                }
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+        buf
+    }
+    fn decode(payload: &[u8]) -> Result<Message> {
+        let mut d = Decoder::new(payload);
+        Ok(match d.u8()? {
+            0 => Message::Vote { term: d.u64()?, candidate: d.u64()?, last_index: d.u64()?, last_term: d.u64()? },
+            1 => Message::VoteReply { term: d.u64()?, granted: d.u8()? != 0 },
+            2 => {
+                let (term, leader, prev_index, prev_term, commit) = (d.u64()?, d.u64()?, d.u64()?, d.u64()?, d.u64()?);
+                let mut entries = Vec::new();
+                for _ in 0..d.u32()? {
+                    let len = d.u32()? as usize;
+                    let crc = d.u32()?;
+                    let payload = d.take(len)?;
+                    if crc32(payload) != crc {
+                        return Err(KVError::Corruption("raft entry checksum mismatch".to_owned()));
+                    }
+                    entries.push(Entry::decode(payload)?.1);
+                }
+                Message::Append { term, leader, prev_index, prev_term, commit, entries }
+            }
+            3 => Message::AppendReply { term: d.u64()?, success: d.u8()? != 0, last_index: d.u64()? },
+            4 => {
+                let (term, leader, index, last_term) = (d.u64()?, d.u64()?, d.u64()?, d.u64()?);
+                let len = d.u32()? as usize;
+                Message::Snapshot { term, leader, index, last_term, data: d.take(len)?.to_vec() }
+            }
+            5 => Message::SnapshotReply { term: d.u64()? },
+            6 => Message::Fetch { version: d.u64()?, key: d.string()? },
+            7 => Message::Fetched {
+                entry: match d.u8()? {
+                    0 => None,
+                    1 => Some(None),
+                    _ => Some(Some(d.string()?)),
+                },
+            },
+            tag => return Err(KVError::Corruption(format!("unknown raft message {}", tag))),
+        })
+    }
+}
+fn receive(stream: &mut impl Read) -> Result<Message> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Message::decode(&payload)
+}
+fn call(addr: SocketAddr, message: &Message, timeout: Duration, tls: Option<&TlsClient>) -> Result<Message> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    let mut stream = tls::over(tls, stream)?;
+    stream.write_all(&message.encode())?;
+    stream.flush()?;
+    receive(&mut stream)
+}
+struct State {
+    term: u64,
+    voted_for: Option<NodeId>,
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    commit_index: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    deadline: Instant,
+    votes: BTreeSet<NodeId>,
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+    in_flight: BTreeSet<NodeId>,
+}
+impl State {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |entry| entry.term)
+    }
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.snapshot_index)? {
+            0 => Some(self.snapshot_term),
+            offset => self.log.get(offset as usize - 1).map(|entry| entry.term),
+        }
+    }
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        self.log.get(index.checked_sub(self.snapshot_index + 1)? as usize)
+    }
+}
+pub struct RaftNode {
+    this: Weak<RaftNode>,
+    config: RaftConfig,
+    store: Weak<KVStore>,
+    storage: Arc<dyn Storage>,
+    acceptor: Option<tls::Acceptor>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+impl RaftNode {
+    pub fn start(store: &Arc<KVStore>, config: RaftConfig) -> Result<Arc<RaftNode>> {
+        let storage: Arc<dyn Storage> = match &store.storage {
+            Some(storage) => Arc::clone(storage),
+            None => Arc::new(MemoryStorage::new()),
+        };
+        let names = storage.list()?;
+        let (mut term, mut voted_for, mut snapshot_index, mut snapshot_term) = (0, None, 0, 0);
+        if names.iter().any(|name| name == META) {
+            let bytes = storage.read(META)?;
+            let mut d = Decoder::new(&bytes);
+            term = d.u64()?;
+            voted_for = match d.u8()? {
+                0 => None,
+                _ => Some(d.u64()?),
+            };
+            snapshot_index = d.u64()?;
+            snapshot_term = d.u64()?;
+        }
+        let mut log = Vec::new();
+        if names.iter().any(|name| name == LOG) {
+            let bytes = storage.read(LOG)?;
+            if !bytes.is_empty() && !bytes.starts_with(WAL_MAGIC) {
+                return Err(KVError::Corruption(format!("{} is not a raft log", LOG)));
+            }
+            let (frames, valid_len) = segment_frames(&bytes);
+            if bytes[valid_len..bytes.len().min(valid_len + 8)].iter().any(|b| *b != 0) {
+                storage.truncate(LOG, valid_len as u64)?;
+            }
+            for payload in frames {
+                let (index, entry) = Entry::decode(payload)?;
+                if index <= snapshot_index {
+                    continue;
+                }
+                if index != snapshot_index + log.len() as u64 + 1 {
+                    return Err(KVError::Corruption(format!("raft log skips to entry {}", index)));
+                }
+                log.push(entry);
+            }
+        } else {
+            storage.write(LOG, WAL_MAGIC)?;
+        }
+        if store.current_version() < snapshot_index {
+            let (version, map, timeline) = decode_snapshot(&storage.read(SNAP)?)?;
+            store.install_snapshot(version, map, timeline)?;
+        }
+        let applied = store.current_version();
+        if applied > snapshot_index + log.len() as u64 {
+            return Err(KVError::Corruption(format!(
+                "store is at version {} but the raft log ends at {}",
+                applied,
+                snapshot_index + log.len() as u64
+            )));
+        }
+        let acceptor = config.tls.as_ref().map(|tls| tls::Acceptor::new(&tls.server)).transpose()?;
+        let listener = TcpListener::bind(config.listen)?;
+        let node = Arc::new_cyclic(|this| RaftNode {
+            this: this.clone(),
+            acceptor,
+            state: Mutex::new(State {
+                term,
+                voted_for,
+                log,
+                snapshot_index,
+                snapshot_term,
+                commit_index: applied,
+                applied,
+                role: Role::Follower,
+                leader: None,
+                deadline: Instant::now() + config.election_timeout,
+                votes: BTreeSet::new(),
+                next_index: BTreeMap::new(),
+                match_index: BTreeMap::new(),
+                in_flight: BTreeSet::new(),
+            }),
+            config,
+            store: Arc::downgrade(store),
+            storage,
+            changed: Condvar::new(),
+        });
+        if store.proposer.set(Arc::clone(&node) as Arc<dyn Proposer>).is_err() {
+            return Err(KVError::InvalidInput("store is already replicated".to_owned()));
+        }
+        let (server, ticker, applier) = (Arc::clone(&node), Arc::clone(&node), Arc::clone(&node));
+        thread::spawn(move || server.serve(listener));
+        thread::spawn(move || ticker.tick());
+        thread::spawn(move || applier.apply());
+        Ok(node)
+    }
+    pub fn status(&self) -> RaftStatus {
+        let state = self.state.lock().unwrap();
+        RaftStatus {
+            id: self.config.id,
+            role: state.role,
+            term: state.term,
+            leader: state.leader,
+            commit_index: state.commit_index,
+            applied: state.applied,
+        }
+    }
+    fn not_leader(&self, state: &State) -> KVError {
+        KVError::NotLeader(state.leader.and_then(|id| self.config.peers.get(&id)).map(|addr| addr.to_string()))
+    }
+    fn election_deadline(&self) -> Instant {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos() as u64);
+        let spread = self.config.election_timeout.as_millis() as u64 + 1;
+        let jitter = (nanos ^ self.config.id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) % spread;
+        Instant::now() + self.config.election_timeout + Duration::from_millis(jitter)
+    }
+    fn save_meta(&self, state: &State) -> Result<()> {
+        let mut buf = Vec::new();
+        put_u64(&mut buf, state.term);
+        match state.voted_for {
+            Some(id) => {
+                buf.push(1);
+                put_u64(&mut buf, id);
+            }
+            None => buf.push(0),
+        }
+        put_u64(&mut buf, state.snapshot_index);
+        put_u64(&mut buf, state.snapshot_term);
+        self.storage.write(META, &buf)
+    }
+    fn log_frames(state: &State, from: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (i, entry) in state.log.iter().enumerate().skip(from) {
+            buf.extend_from_slice(&entry.encode(state.snapshot_index + 1 + i as u64));
+        }
+        buf
+    }
+    fn save_log(&self, state: &State) -> Result<()> {
+        let mut buf = WAL_MAGIC.to_vec();
+        buf.extend_from_slice(&Self::log_frames(state, 0));
+        self.storage.write(LOG, &buf)
+    }
+    fn step_down(&self, state: &mut State, term: u64) -> Result<()> {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+        }
+        if state.role == Role::Leader {
+            state.leader = None;
+        }
+        state.role = Role::Follower;
+        self.changed.notify_all();
+        self.save_meta(state)
+    }
+    fn follow(&self, state: &mut State, term: u64, leader: NodeId) -> Result<()> {
+        if term > state.term || state.role != Role::Follower {
+            self.step_down(state, term)?;
+        }
+        state.leader = Some(leader);
+        state.deadline = self.election_deadline();
+        Ok(())
+    }
+    fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming().flatten() {
+            if self.store.strong_count() == 0 {
+                return;
+            }
+            let Some(node) = self.this.upgrade() else { return };
+            thread::spawn(move || {
+                let _ = node.handle(stream);
+            });
+        }
+    }
+    fn dialer(&self) -> Option<&TlsClient> {
+        self.config.tls.as_ref().map(|tls| &tls.client)
+    }
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(self.config.election_timeout * 10))?;
+        let (mut stream, _) = tls::accept(self.acceptor.as_ref(), stream)?;
+        let request = receive(&mut stream)?;
+        let reply = if let Message::Fetch { key, version } = &request {
+            self.on_fetch(key, *version)?
+        } else {
+            let mut state = self.state.lock().unwrap();
+            match request {
+                Message::Vote { term, candidate, last_index, last_term } => {
+                    self.on_vote(&mut state, term, candidate, (last_term, last_index))?
+                }
+                Message::Append { term, leader, prev_index, prev_term, commit, entries } => {
+                    self.on_append(&mut state, term, leader, (prev_index, prev_term), commit, entries)?
+                }
+                Message::Snapshot { term, leader, index, last_term, data } => {
+                    self.on_snapshot(&mut state, term, leader, (index, last_term), &data)?
+                }
+                _ => return Err(KVError::InvalidInput("unexpected raft message".to_owned())),
+            }
+        };
+        stream.write_all(&reply.encode())?;
+        stream.flush()?;
+        Ok(())
+    }
+    fn on_fetch(&self, key: &str, version: Version) -> Result<Message> {
+        let store = self.store.upgrade().ok_or_else(|| KVError::InvalidInput("store is closed".to_owned()))?;
+        let entry = store.engine.with(|state| state.map.get(key).and_then(|versions| versions.get(&version).map(|value| value.as_deref().map(str::to_owned))));
+        Ok(Message::Fetched { entry })
+    }
+    fn on_vote(&self, state: &mut State, term: u64, candidate: NodeId, last: (u64, u64)) -> Result<Message> {
+        if term > state.term {
+            self.step_down(state, term)?;
+        }
+        let granted = term == state.term
+            && state.voted_for.is_none_or(|id| id == candidate)
+            && last >= (state.last_term(), state.last_index());
+        if granted {
+            state.voted_for = Some(candidate);
+            self.save_meta(state)?;
+            state.deadline = self.election_deadline();
+        }
+        Ok(Message::VoteReply { term: state.term, granted })
+    }
+    fn on_append(
+        &self,
+        state: &mut State,
+        term: u64,
+        leader: NodeId,
+        (prev_index, prev_term): (u64, u64),
+        commit: u64,
+        entries: Vec<Entry>,
+    ) -> Result<Message> {
+        let reject = |state: &State, hint: u64| Message::AppendReply { term: state.term, success: false, last_index: hint };
+        if term < state.term {
+            return Ok(reject(state, state.last_index()));
+        }
+        self.follow(state, term, leader)?;
+        if prev_index > state.last_index() {
+            return Ok(reject(state, state.last_index()));
+        }
+        if prev_index >= state.snapshot_index && state.term_at(prev_index) != Some(prev_term) {
+            return Ok(reject(state, state.commit_index));
+        }
+        let (mut index, mut truncated, mut first_new) = (prev_index, false, None);
+        for entry in entries {
+            index += 1;
+            if index <= state.snapshot_index {
+                continue;
+            }
+            match state.term_at(index) {
+                Some(existing) if existing == entry.term => continue,
+                Some(_) => {
+                    state.log.truncate((index - state.snapshot_index - 1) as usize);
+                    truncated = true;
+                }
+                None => {}
+            }
+            first_new.get_or_insert(state.log.len());
+            state.log.push(entry);
+        }
+        if truncated {
+            self.save_log(state)?;
+        } else if let Some(from) = first_new {
+            self.storage.append(LOG, &Self::log_frames(state, from))?;
+        }
+        if commit.min(index) > state.commit_index {
+            state.commit_index = commit.min(index);
+            self.changed.notify_all();
+        }
+        Ok(Message::AppendReply { term: state.term, success: true, last_index: index })
+    }
+    fn on_snapshot(
+        &self,
+        state: &mut State,
+        term: u64,
+        leader: NodeId,
+        (index, last_term): (u64, u64),
+        data: &[u8],
+    ) -> Result<Message> {
+        if term < state.term {
+            return Ok(Message::SnapshotReply { term: state.term });
+        }
+        self.follow(state, term, leader)?;
+        if index > state.commit_index {
+            let (version, map, timeline) = decode_snapshot(data)?;
+            if version != index {
+                return Err(KVError::Corruption(format!("snapshot for entry {} is at version {}", index, version)));
+            }
+            let store = self.store.upgrade().ok_or_else(|| KVError::InvalidInput("store is closed".to_owned()))?;
+            store.install_snapshot(version, map, timeline)?;
+            self.storage.write(SNAP, data)?;
+            if state.term_at(index) == Some(last_term) {
+                state.log.drain(..(index - state.snapshot_index) as usize);
+            } else {
+                state.log.clear();
+            }
+            state.snapshot_index = index;
+            state.snapshot_term = last_term;
+            state.commit_index = index;
+            state.applied = index;
+            self.save_meta(state)?;
+            self.save_log(state)?;
+            self.changed.notify_all();
+        }
+        Ok(Message::SnapshotReply { term: state.term })
+    }
+    fn tick(&self) {
+        while self.store.strong_count() > 0 {
+            thread::sleep(Duration::from_millis(10));
+            let mut state = self.state.lock().unwrap();
+            if Instant::now() < state.deadline {
+                continue;
+            }
+            if state.role == Role::Leader {
+                state.deadline = Instant::now() + self.config.heartbeat;
+                drop(state);
+                self.replicate_all();
+            } else {
+                self.start_election(&mut state);
+            }
+        }
+    }
+    fn start_election(&self, state: &mut State) {
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(self.config.id);
+        state.leader = None;
+        state.votes = BTreeSet::from([self.config.id]);
+        state.deadline = self.election_deadline();
+        if self.save_meta(state).is_err() {
+            return;
+        }
+        if self.config.peers.is_empty() {
+            self.become_leader(state);
+            return;
+        }
+        let request = Message::Vote {
+            term: state.term,
+            candidate: self.config.id,
+            last_index: state.last_index(),
+            last_term: state.last_term(),
+        };
+        let Some(this) = self.this.upgrade() else { return };
+        for (&peer, &addr) in &self.config.peers {
+            let (node, request) = (Arc::clone(&this), request.clone());
+            thread::spawn(move || {
+                let reply = call(addr, &request, node.config.election_timeout, node.dialer());
+                let mut state = node.state.lock().unwrap();
+                if let Ok(Message::VoteReply { term, granted }) = reply {
+                    if term > state.term {
+                        let _ = node.step_down(&mut state, term);
+                    } else if granted && term == state.term && state.role == Role::Candidate {
+                        state.votes.insert(peer);
+                        if state.votes.len() * 2 > node.config.peers.len() + 1 {
+                            node.become_leader(&mut state);
+                        }
+                    }
+                }
+            });
+        }
+    }
+    fn become_leader(&self, state: &mut State) {
+        state.role = Role::Leader;
+        state.leader = Some(self.config.id);
+        let next = state.last_index() + 1;
+        state.next_index = self.config.peers.keys().map(|&peer| (peer, next)).collect();
+        state.match_index = self.config.peers.keys().map(|&peer| (peer, 0)).collect();
+        state.log.push(Entry { term: state.term, writes: Writes::new(), meta: CommitMeta::default() });
+        if self.storage.append(LOG, &Self::log_frames(state, state.log.len() - 1)).is_err() {
+            state.log.pop();
+            let _ = self.step_down(state, state.term);
+            return;
+        }
+        self.advance_commit(state);
+        state.deadline = Instant::now();
+    }
+    fn advance_commit(&self, state: &mut State) {
+        let mut matched: Vec<u64> = state.match_index.values().copied().collect();
+        matched.push(state.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let quorum = matched[matched.len() / 2];
+        if quorum > state.commit_index && state.term_at(quorum) == Some(state.term) {
+            state.commit_index = quorum;
+            self.changed.notify_all();
+        }
+    }
+    fn replicate_all(&self) {
+        for &peer in self.config.peers.keys() {
+            self.replicate(peer);
+        }
+    }
+    fn replicate(&self, peer: NodeId) {
+        let Some(node) = self.this.upgrade() else { return };
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader || !state.in_flight.insert(peer) {
+            return;
+        }
+        let next = state.next_index[&peer];
+        let request = if next <= state.snapshot_index {
+            self.storage.read(SNAP).map(|data| Message::Snapshot {
+                term: state.term,
+                leader: self.config.id,
+                index: state.snapshot_index,
+                last_term: state.snapshot_term,
+                data,
+            })
+        } else {
+            let start = (next - 1 - state.snapshot_index) as usize;
+            Ok(Message::Append {
+                term: state.term,
+                leader: self.config.id,
+                prev_index: next - 1,
+                prev_term: state.term_at(next - 1).unwrap_or(0),
+                commit: state.commit_index,
+                entries: state.log[start..].iter().take(MAX_BATCH).cloned().collect(),
+            })
+        };
+        let (addr, term) = (self.config.peers[&peer], state.term);
+        drop(state);
+        thread::spawn(move || {
+            let timeout = match request {
+                Ok(Message::Snapshot { .. }) => node.config.election_timeout * 10,
+                _ => node.config.election_timeout,
+            };
+            let sent_snapshot = match &request {
+                Ok(Message::Snapshot { index, .. }) => Some(*index),
+                _ => None,
+            };
+            let reply = request.and_then(|request| call(addr, &request, timeout, node.dialer()));
+            let mut state = node.state.lock().unwrap();
+            state.in_flight.remove(&peer);
+            let (reply_term, matched) = match reply {
+                Ok(Message::AppendReply { term, success, last_index }) => (term, success.then_some(last_index).ok_or(last_index)),
+                Ok(Message::SnapshotReply { term }) => (term, sent_snapshot.ok_or(0)),
+                _ => return,
+            };
+            if reply_term > state.term {
+                let _ = node.step_down(&mut state, reply_term);
+                return;
+            }
+            if state.role != Role::Leader || state.term != term {
+                return;
+            }
+            let floor = state.match_index[&peer];
+            match matched {
+                Ok(index) => {
+                    state.match_index.insert(peer, floor.max(index));
+                    state.next_index.insert(peer, floor.max(index) + 1);
+                    node.advance_commit(&mut state);
+                }
+                Err(hint) => {
+                    state.next_index.insert(peer, floor.max(hint) + 1);
+                }
+            }
+            let behind = state.next_index[&peer] <= state.last_index();
+            drop(state);
+            if behind {
+                node.replicate(peer);
+            }
+        });
+    }
+    fn apply(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while state.applied >= state.commit_index {
+                if self.store.strong_count() == 0 {
+                    return;
+                }
+                state = self.changed.wait_timeout(state, Duration::from_millis(100)).unwrap().0;
+            }
+            let (from, to) = (state.applied + 1, state.commit_index);
+            let entries: Vec<(u64, Entry)> =
+                (from..=to).filter_map(|index| Some((index, state.entry(index)?.clone()))).collect();
+            drop(state);
+            let Some(store) = self.store.upgrade() else { return };
+            let mut applied = from - 1;
+            for (index, entry) in entries {
+                if index > store.current_version() && store.apply_traced(entry.writes, entry.meta, Some(index), None, None, &mut CommitTrace::default()).is_err() {
+                    break;
+                }
+                applied = index;
+            }
+            state = self.state.lock().unwrap();
+            if applied < from {
+                drop(state);
+                thread::sleep(Duration::from_millis(100));
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state.applied = state.applied.max(applied);
+            self.changed.notify_all();
+            if state.applied - state.snapshot_index >= self.config.snapshot_every {
+                let _ = self.compact(&mut state, &store);
+            }
+        }
+    }
+    fn compact(&self, state: &mut State, store: &KVStore) -> Result<()> {
+        let (version, bytes) = store
+            .engine
+            .with(|s| (s.version, encode_snapshot(s.version, &s.map, &store.timeline.lock().unwrap(), &store.compression())));
+        let term = state.term_at(version).ok_or_else(|| KVError::Corruption(format!("no raft entry {}", version)))?;
+        self.storage.write(SNAP, &bytes)?;
+        state.log.drain(..(version - state.snapshot_index) as usize);
+        state.snapshot_index = version;
+        state.snapshot_term = term;
+        self.save_meta(state)?;
+        self.save_log(state)
+    }
+}
+impl Proposer for RaftNode {
+    fn repair(&self, key: &str, version: Version) -> Option<Option<String>> {
+        let request = Message::Fetch { key: key.to_owned(), version };
+        self.config.peers.values().find_map(|addr| match call(*addr, &request, self.config.election_timeout, self.dialer()) {
+            Ok(Message::Fetched { entry }) => entry,
+            _ => None,
+        })
+    }
+    fn propose(&self, writes: Writes, meta: CommitMeta) -> Result<Version> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Err(self.not_leader(&state));
+        }
+        let term = state.term;
+        state.log.push(Entry { term, writes, meta });
+        let index = state.last_index();
+        if let Err(e) = self.storage.append(LOG, &Self::log_frames(&state, state.log.len() - 1)) {
+            state.log.pop();
+            return Err(e);
+        }
+        self.advance_commit(&mut state);
+        drop(state);
+        self.replicate_all();
+        let deadline = Instant::now() + self.config.commit_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.applied >= index {
+                return match state.term_at(index) {
+                    Some(t) if t != term => Err(self.not_leader(&state)),
+                    None if state.term != term => Err(self.not_leader(&state)),
+                    _ => Ok(index),
+                };
+            }
+            if state.term != term || state.role != Role::Leader {
+                return Err(self.not_leader(&state));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "raft commit timed out").into());
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    type Cluster = (BTreeMap<NodeId, SocketAddr>, Vec<(Arc<KVStore>, Arc<RaftNode>)>);
+    fn cluster(tls: impl Fn(NodeId) -> Option<PeerTls>) -> Cluster {
+        let addrs: BTreeMap<NodeId, SocketAddr> =
+            (1..=3).map(|id| (id, TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap())).collect();
+        let nodes = addrs
+            .iter()
+            .map(|(&id, &addr)| {
+                let store = KVStore::new();
+                let peers = addrs.iter().filter(|(&peer, _)| peer != id).map(|(&peer, &addr)| (peer, addr)).collect();
+                let config = RaftConfig { tls: tls(id), ..RaftConfig::new(id, addr, peers) };
+                let node = RaftNode::start(&store, config).unwrap();
+                (store, node)
+            })
+            .collect();
+        (addrs, nodes)
+    }
+    fn commit_on_leader(nodes: &[(Arc<KVStore>, Arc<RaftNode>)]) -> Version {
+        let mut version = None;
+        wait_until("a commit succeeds on the leader", || {
+            let Some((store, _)) = nodes.iter().find(|(_, node)| node.status().role == Role::Leader) else {
+                return false;
+            };
+            let mut tx = store.begin();
+            tx.put("k".to_owned(), "v".to_owned()).unwrap();
+            version = tx.commit().ok();
+            version.is_some()
+        });
+        let version = version.unwrap();
+        wait_until("every node applies the commit", || nodes.iter().all(|(store, _)| store.current_version() >= version));
+        version
+    }
+    #[test]
+    fn the_elected_leader_replicates_commits_to_every_node() {
+        let (addrs, nodes) = cluster(|_| None);
+        let leader = || nodes.iter().find(|(_, node)| node.status().role == Role::Leader);
+        let version = commit_on_leader(&nodes);
+        for (store, node) in &nodes {
+            assert_eq!(store.begin().get("k").unwrap().as_deref(), Some("v"));
+            assert!(node.status().applied >= version);
+        }
+        let (_, leader) = leader().unwrap();
+        let (follower, _) = nodes.iter().find(|(_, node)| node.status().role == Role::Follower).unwrap();
+        let mut tx = follower.begin();
+        tx.put("k".to_owned(), "lost".to_owned()).unwrap();
+        match tx.commit() {
+            Err(KVError::NotLeader(Some(addr))) => assert_eq!(addr, addrs[&leader.status().id].to_string()),
+            other => panic!("a follower accepted a write: {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn nodes_talk_over_mutual_tls_and_ignore_peers_without_a_trusted_certificate() {
+        let ca = tls::TestCa::new();
+        let (_, nodes) = cluster(|id| {
+            let name = format!("node{}", id);
+            Some(PeerTls { server: ca.server(&name, true), client: ca.client(Some(&name)) })
+        });
+        let version = commit_on_leader(&nodes);
+        for (store, _) in &nodes {
+            assert_eq!(store.begin().get("k").unwrap().as_deref(), Some("v"));
+        }
+        let addr = nodes[0].1.config.listen;
+        let vote = Message::Vote { term: u64::MAX / 2, candidate: 9, last_index: version + 1, last_term: u64::MAX / 2 };
+        assert!(call(addr, &vote, Duration::from_secs(1), None).is_err());
+        let outsider = tls::TestCa::new();
+        assert!(call(addr, &vote, Duration::from_secs(1), Some(&outsider.client(Some("node9")))).is_err());
+        assert!(nodes.iter().all(|(_, node)| node.status().term < u64::MAX / 2), "an untrusted vote request got through");
+    }
+}
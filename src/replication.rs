@@ -0,0 +1,861 @@
+use crate::*;
+pub(crate) const REPL_MAGIC: &[u8; 8] = b"KVREPL03";
+pub(crate) const REPL_HEARTBEAT: Duration = Duration::from_millis(100);
+pub(crate) const REPL_WAIT: Duration = Duration::from_secs(1);
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionToken(pub(crate) Version);
+impl SessionToken {
+    pub fn new(version: Version) -> Self {
+        SessionToken(version)
+    }
+    pub fn version(&self) -> Version {
+        self.0
+    }
+}
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+impl std::str::FromStr for SessionToken {
+    type Err = KVError;
+    fn from_str(s: &str) -> Result<Self> {
+        s.strip_prefix('v')
+            .and_then(|v| v.parse().ok())
+            .map(SessionToken)
+            .ok_or_else(|| KVError::InvalidInput(format!("bad session token {:?}", s)))
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub enum ReadConsistency {
+    Leader,
+    BoundedStaleness { max_versions: Option<u64>, max_age: Option<Duration> },
+    ReadYourWrites(SessionToken),
+}
+impl From<Version> for SessionToken {
+    fn from(version: Version) -> Self {
+        SessionToken(version)
+    }
+}
+impl Transaction {
+    pub fn commit_with_token(self) -> Result<SessionToken> {
+        self.commit().map(SessionToken)
+    }
+}
+impl Snapshot {
+    pub fn token(&self) -> SessionToken {
+        SessionToken(self.version)
+    }
+}
+impl KVStore {
+    pub fn session_token(&self) -> SessionToken {
+        SessionToken(self.current_version())
+    }
+    pub fn snapshot_after(self: &Arc<Self>, token: SessionToken) -> Result<Snapshot> {
+        let deadline = Instant::now() + REPL_WAIT;
+        while self.current_version() < token.0 {
+            if !self.is_read_only() || Instant::now() >= deadline {
+                return Err(KVError::InvalidInput(format!(
+                    "session token {} is ahead of the store at v{}",
+                    token,
+                    self.current_version()
+                )));
+            }
+            self.refresh()?;
+            if self.current_version() < token.0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        Ok(self.snapshot())
+    }
+    pub fn begin_after(self: &Arc<Self>, token: SessionToken) -> Result<Transaction> {
+        self.snapshot_after(token)?;
+        Ok(self.begin())
+    }
+    pub(crate) fn epoch_record(&self) -> (u64, Option<SocketAddr>) {
+        self.engine
+            .read_at(&epoch_key(), self.current_version())
+            .and_then(|record| {
+                let (epoch, owner) = record.split_once('@')?;
+                Some((epoch.parse().ok()?, owner.parse().ok()))
+            })
+            .unwrap_or((0, None))
+    }
+    pub fn epoch(&self) -> u64 {
+        self.epoch_record().0
+    }
+    pub(crate) fn promote(self: &Arc<Self>, node: SocketAddr) -> Result<u64> {
+        let epoch = self.epoch().max(self.fenced.load(Ordering::Acquire)) + 1;
+        let mut tx = self.begin();
+        tx.put_internal(epoch_key(), format!("{}@{}", epoch, node))?;
+        tx.commit()?;
+        Ok(epoch)
+    }
+    pub(crate) fn fence(&self, epoch: u64) {
+        self.fenced.fetch_max(epoch, Ordering::AcqRel);
+    }
+    pub fn is_fenced(&self) -> bool {
+        self.fenced.load(Ordering::Acquire) > self.epoch()
+    }
+}
+pub(crate) fn epoch_key() -> String {
+    format!("{}{}epoch", META_KEYSPACE, KEYSPACE_SEPARATOR)
+}
+pub(crate) fn read_frame(reader: &mut impl Read) -> Result<Option<WalRecord>> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    if crc32(&payload) != crc {
+        return Err(KVError::Corruption("replication frame checksum mismatch".to_owned()));
+    }
+    WalRecord::decode(&payload).map(Some)
+}
+pub(crate) fn read_message(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+pub(crate) fn write_message(writer: &mut impl Write, payload: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(payload.len() + 4);
+    put_u32(&mut buf, payload.len() as u32);
+    buf.extend_from_slice(payload);
+    writer.write_all(&buf)?;
+    Ok(())
+}
+pub(crate) fn put_bound(buf: &mut Vec<u8>, bound: Bound<&String>) {
+    match bound {
+        Bound::Unbounded => buf.push(0),
+        Bound::Included(key) => {
+            buf.push(1);
+            put_str(buf, key);
+        }
+        Bound::Excluded(key) => {
+            buf.push(2);
+            put_str(buf, key);
+        }
+    }
+}
+pub(crate) fn read_bound(d: &mut Decoder) -> Result<Bound<String>> {
+    Ok(match d.u8()? {
+        0 => Bound::Unbounded,
+        1 => Bound::Included(d.string()?),
+        2 => Bound::Excluded(d.string()?),
+        tag => return Err(KVError::Corruption(format!("unknown bound tag {}", tag))),
+    })
+}
+pub(crate) fn serve_replica(store: &KVStore, mut stream: impl Read + Write) -> Result<()> {
+    let mut hello = [0u8; 9];
+    stream.read_exact(&mut hello)?;
+    if &hello[..8] != REPL_MAGIC {
+        return Err(KVError::InvalidInput("not a replication handshake".to_owned()));
+    }
+    match hello[8] {
+        0 => ship_log(store, stream),
+        1 => {
+            let request = read_message(&mut stream)?;
+            let mut d = Decoder::new(&request);
+            let mut reply = Vec::new();
+            let version = store.current_version();
+            put_u64(&mut reply, version);
+            match d.u8()? {
+                0 => match store.engine.read_at(&d.string()?, version) {
+                    Some(value) => {
+                        reply.push(1);
+                        put_str(&mut reply, &value);
+                    }
+                    None => reply.push(0),
+                },
+                op => {
+                    let range = (read_bound(&mut d)?, read_bound(&mut d)?);
+                    let pairs = match op {
+                        2 => {
+                            let filter = ValueFilter::decode_from(&mut d)?;
+                            store.filtered_at(range, version, &filter)
+                        }
+                        _ => store.engine.scan_at(range, version),
+                    };
+                    put_u32(&mut reply, pairs.len() as u32);
+                    for (key, value) in &pairs {
+                        put_str(&mut reply, key);
+                        put_str(&mut reply, value);
+                    }
+                }
+            }
+            write_message(&mut stream, &reply)
+        }
+        2 => {
+            let (epoch, owner) = store.epoch_record();
+            let mut reply = Vec::new();
+            put_u64(&mut reply, store.current_version());
+            put_u64(&mut reply, epoch);
+            put_str(&mut reply, &owner.map(|owner| owner.to_string()).unwrap_or_default());
+            reply.push(store.is_fenced() as u8);
+            write_message(&mut stream, &reply)
+        }
+        op => Err(KVError::InvalidInput(format!("unknown replication request {}", op))),
+    }
+}
+pub(crate) enum CatchUp {
+    Backlog(Vec<ChangeBatch>),
+    Snapshot(Version, Vec<u8>),
+}
+pub(crate) fn ship_log(store: &KVStore, mut stream: impl Read + Write) -> Result<()> {
+    let mut from = [0u8; 16];
+    stream.read_exact(&mut from)?;
+    let mut last = u64::from_le_bytes(from[..8].try_into().unwrap());
+    let epoch = u64::from_le_bytes(from[8..].try_into().unwrap());
+    if epoch > store.epoch() {
+        store.fence(epoch);
+        return Ok(());
+    }
+    let (catch_up, feed) = store.subscribe_after(last);
+    let mut out = io::BufWriter::new(stream);
+    match catch_up {
+        CatchUp::Backlog(backlog) => {
+            for batch in &backlog {
+                out.write_all(&[0])?;
+                out.write_all(&WalRecord::encode(batch.version, batch.timestamp, &batch.writes))?;
+                last = batch.version;
+            }
+        }
+        CatchUp::Snapshot(version, snapshot) => {
+            out.write_all(&[2])?;
+            write_message(&mut out, &snapshot)?;
+            last = version;
+        }
+    }
+    let mut heartbeat_due = Instant::now();
+    loop {
+        match feed.recv_timeout(REPL_HEARTBEAT) {
+            Ok(batch) if batch.version > last => {
+                out.write_all(&[0])?;
+                out.write_all(&WalRecord::encode(batch.version, batch.timestamp, &batch.writes))?;
+                last = batch.version;
+            }
+            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if Instant::now() >= heartbeat_due {
+            let mut heartbeat = vec![1];
+            put_u64(&mut heartbeat, store.current_version());
+            out.write_all(&heartbeat)?;
+            heartbeat_due = Instant::now() + REPL_HEARTBEAT;
+        }
+        out.flush()?;
+    }
+}
+impl KVStore {
+    pub fn serve_replicas(self: &Arc<Self>, listener: TcpListener) -> thread::JoinHandle<()> {
+        self.serve_replicas_with(listener, None)
+    }
+    /// Like [`serve_replicas`](Self::serve_replicas), but only over TLS; with client roots set, replicas and
+    /// failover peers must present a certificate those roots vouch for.
+    pub fn serve_replicas_tls(self: &Arc<Self>, listener: TcpListener, tls: &TlsServer) -> Result<thread::JoinHandle<()>> {
+        let acceptor = tls::Acceptor::new(tls)?;
+        Ok(self.serve_replicas_with(listener, Some(Arc::new(acceptor))))
+    }
+    pub(crate) fn serve_replicas_with(self: &Arc<Self>, listener: TcpListener, acceptor: Option<Arc<tls::Acceptor>>) -> thread::JoinHandle<()> {
+        let store = Arc::clone(self);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (store, acceptor) = (Arc::clone(&store), acceptor.clone());
+                thread::spawn(move || {
+                    if let Ok((stream, _)) = tls::accept(acceptor.as_deref(), stream) {
+                        let _ = serve_replica(&store, stream);
+                    }
+                });
+            }
+        })
+    }
+}
+#[derive(Default)]
+pub(crate) struct ReplicaProgress {
+    pub(crate) connected: bool,
+    pub(crate) primary_version: Version,
+    pub(crate) pending: std::collections::VecDeque<(Version, Instant)>,
+    pub(crate) fresh_as_of: Option<Instant>,
+    pub(crate) heard: Option<Instant>,
+}
+impl ReplicaProgress {
+    pub(crate) fn caught_up(&mut self, local: Version) {
+        while let Some(&(version, seen)) = self.pending.front() {
+            if version > local {
+                break;
+            }
+            self.fresh_as_of = Some(seen);
+            self.pending.pop_front();
+        }
+    }
+}
+pub struct Replica {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) primary: Vec<SocketAddr>,
+    pub(crate) started: Instant,
+    pub(crate) progress: Arc<(Mutex<ReplicaProgress>, Condvar)>,
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) connection: Arc<Mutex<Option<TcpStream>>>,
+    pub(crate) tls: Option<TlsClient>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl Replica {
+    pub fn start<A: ToSocketAddrs>(store: Arc<KVStore>, primary: A) -> Result<Replica> {
+        Self::start_with(store, primary, None)
+    }
+    /// Follows a primary serving [`KVStore::serve_replicas_tls`]; its certificate must name the primary's IP
+    /// address unless `tls.server_name` says otherwise.
+    pub fn start_tls<A: ToSocketAddrs>(store: Arc<KVStore>, primary: A, tls: TlsClient) -> Result<Replica> {
+        Self::start_with(store, primary, Some(tls))
+    }
+    pub(crate) fn start_with<A: ToSocketAddrs>(store: Arc<KVStore>, primary: A, tls: Option<TlsClient>) -> Result<Replica> {
+        let primary: Vec<SocketAddr> = primary.to_socket_addrs()?.collect();
+        let progress = Arc::new((Mutex::new(ReplicaProgress::default()), Condvar::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connection = Arc::new(Mutex::new(None));
+        let worker = {
+            let (store, primary, progress) = (Arc::clone(&store), primary.clone(), Arc::clone(&progress));
+            let (stopped, connection, tls) = (Arc::clone(&stopped), Arc::clone(&connection), tls.clone());
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    if let Ok(stream) = TcpStream::connect(&primary[..]) {
+                        *connection.lock().unwrap() = stream.try_clone().ok();
+                        if !stopped.load(Ordering::Acquire) {
+                            let _ = tls::over(tls.as_ref(), stream).and_then(|stream| Self::follow(&store, &progress, stream));
+                        }
+                        *connection.lock().unwrap() = None;
+                        let mut progress = progress.0.lock().unwrap();
+                        progress.connected = false;
+                        progress.pending.clear();
+                    }
+                    if !stopped.load(Ordering::Acquire) {
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            })
+        };
+        Ok(Replica { store, primary, started: Instant::now(), progress, stopped, connection, tls, worker: Some(worker) })
+    }
+    pub(crate) fn follow(store: &KVStore, progress: &(Mutex<ReplicaProgress>, Condvar), mut stream: impl Read + Write) -> Result<()> {
+        let mut hello = REPL_MAGIC.to_vec();
+        hello.push(0);
+        put_u64(&mut hello, store.current_version());
+        put_u64(&mut hello, store.epoch());
+        stream.write_all(&hello)?;
+        let mut reader = io::BufReader::new(stream);
+        let mut kind = [0u8; 1];
+        while reader.read(&mut kind)? == 1 {
+            let mut progress_guard = match kind[0] {
+                0 => {
+                    let record = read_frame(&mut reader)?
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                    if record.version > store.current_version() {
+                        store.apply(record.writes, Some(record.version), Some(record.timestamp), None)?;
+                    }
+                    progress.0.lock().unwrap()
+                }
+                1 => {
+                    let mut version = [0u8; 8];
+                    reader.read_exact(&mut version)?;
+                    let mut guard = progress.0.lock().unwrap();
+                    guard.connected = true;
+                    let version = u64::from_le_bytes(version);
+                    guard.primary_version = version;
+                    guard.pending.push_back((version, Instant::now()));
+                    guard
+                }
+                2 => {
+                    let (version, map, timeline) = decode_snapshot(&read_message(&mut reader)?)?;
+                    store.install_snapshot(version, map, timeline)?;
+                    progress.0.lock().unwrap()
+                }
+                other => return Err(KVError::Corruption(format!("unknown replication message {}", other))),
+            };
+            progress_guard.heard = Some(Instant::now());
+            progress_guard.primary_version = progress_guard.primary_version.max(store.current_version());
+            progress_guard.caught_up(store.current_version());
+            progress.1.notify_all();
+        }
+        Ok(())
+    }
+    pub fn version(&self) -> Version {
+        self.store.current_version()
+    }
+    pub fn lag(&self) -> Option<(u64, Duration)> {
+        let progress = self.progress.0.lock().unwrap();
+        let age = progress.fresh_as_of?.elapsed();
+        progress.connected.then(|| (progress.primary_version.saturating_sub(self.version()), age))
+    }
+    pub fn silent_for(&self) -> Duration {
+        self.progress.0.lock().unwrap().heard.unwrap_or(self.started).elapsed()
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        self.store.snapshot()
+    }
+    pub(crate) fn local_version(&self, consistency: ReadConsistency) -> Option<Version> {
+        match consistency {
+            ReadConsistency::Leader => None,
+            ReadConsistency::BoundedStaleness { max_versions, max_age } => {
+                let (versions, age) = self.lag()?;
+                (max_versions.is_none_or(|max| versions <= max) && max_age.is_none_or(|max| age <= max))
+                    .then(|| self.version())
+            }
+            ReadConsistency::ReadYourWrites(token) => {
+                let (lock, changed) = &*self.progress;
+                let (_guard, _) = changed
+                    .wait_timeout_while(lock.lock().unwrap(), REPL_WAIT, |_| self.version() < token.0)
+                    .unwrap();
+                let version = self.version();
+                (version >= token.0).then_some(version)
+            }
+        }
+    }
+    pub(crate) fn leader_read(&self, request: &[u8]) -> Result<(Vec<u8>, Version)> {
+        let mut stream = tls::over(self.tls.as_ref(), TcpStream::connect(&self.primary[..])?)?;
+        let mut hello = REPL_MAGIC.to_vec();
+        hello.push(1);
+        stream.write_all(&hello)?;
+        write_message(&mut stream, request)?;
+        let reply = read_message(&mut stream)?;
+        let version = Decoder::new(&reply).u64()?;
+        Ok((reply, version))
+    }
+    pub fn get(&self, key: &str, consistency: ReadConsistency) -> Result<(Option<String>, SessionToken)> {
+        if let Some(version) = self.local_version(consistency) {
+            return Ok((self.store.engine.read_at(key, version), SessionToken(version)));
+        }
+        let mut request = vec![0];
+        put_str(&mut request, key);
+        let (reply, version) = self.leader_read(&request)?;
+        let mut d = Decoder::new(&reply[8..]);
+        let value = match d.u8()? {
+            0 => None,
+            _ => Some(d.string()?),
+        };
+        Ok((value, SessionToken(version)))
+    }
+    pub fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        consistency: ReadConsistency,
+    ) -> Result<(Vec<(String, String)>, SessionToken)> {
+        if let Some(version) = self.local_version(consistency) {
+            return Ok((self.store.engine.scan_at(range, version), SessionToken(version)));
+        }
+        let mut request = vec![1];
+        put_bound(&mut request, range.start_bound());
+        put_bound(&mut request, range.end_bound());
+        let (reply, version) = self.leader_read(&request)?;
+        let mut d = Decoder::new(&reply[8..]);
+        let mut pairs = Vec::new();
+        for _ in 0..d.u32()? {
+            pairs.push((d.string()?, d.string()?));
+        }
+        Ok((pairs, SessionToken(version)))
+    }
+    pub fn scan_filtered<R: RangeBounds<String> + Clone>(
+        &self,
+        range: R,
+        filter: &ValueFilter,
+        consistency: ReadConsistency,
+    ) -> Result<(Vec<(String, String)>, SessionToken)> {
+        if let Some(version) = self.local_version(consistency) {
+            let pairs = self.store.filtered_at(range, version, filter);
+            return Ok((pairs, SessionToken(version)));
+        }
+        let mut request = vec![2];
+        put_bound(&mut request, range.start_bound());
+        put_bound(&mut request, range.end_bound());
+        filter.encode_into(&mut request);
+        let (reply, version) = self.leader_read(&request)?;
+        let mut d = Decoder::new(&reply[8..]);
+        let mut pairs = Vec::new();
+        for _ in 0..d.u32()? {
+            pairs.push((d.string()?, d.string()?));
+        }
+        Ok((pairs, SessionToken(version)))
+    }
+}
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(stream) = self.connection.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    pub node: SocketAddr,
+    pub members: Vec<SocketAddr>,
+    pub heartbeat_timeout: Duration,
+    pub tls: Option<TlsClient>,
+}
+impl FailoverConfig {
+    pub fn new(node: SocketAddr, members: Vec<SocketAddr>) -> Self {
+        FailoverConfig { node, members, heartbeat_timeout: Duration::from_secs(1), tls: None }
+    }
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = timeout;
+        self
+    }
+    /// Dials members over TLS, for clusters whose members serve [`KVStore::serve_replicas_tls`].
+    pub fn tls(mut self, tls: TlsClient) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverRole {
+    Primary,
+    Follower(SocketAddr),
+    Fenced,
+}
+pub(crate) struct MemberStatus {
+    pub(crate) version: Version,
+    pub(crate) epoch: u64,
+    pub(crate) owner: Option<SocketAddr>,
+    pub(crate) fenced: bool,
+}
+pub(crate) fn member_status(addr: SocketAddr, timeout: Duration, tls: Option<&TlsClient>) -> Result<MemberStatus> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut stream = tls::over(tls, stream)?;
+    let mut hello = REPL_MAGIC.to_vec();
+    hello.push(2);
+    stream.write_all(&hello)?;
+    let reply = read_message(&mut stream)?;
+    let mut d = Decoder::new(&reply);
+    Ok(MemberStatus { version: d.u64()?, epoch: d.u64()?, owner: d.string()?.parse().ok(), fenced: d.u8()? != 0 })
+}
+pub struct Failover {
+    pub(crate) role: Arc<Mutex<FailoverRole>>,
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl Failover {
+    pub fn start(store: Arc<KVStore>, primary: SocketAddr, config: FailoverConfig) -> Result<Failover> {
+        if !config.members.contains(&config.node) {
+            return Err(KVError::InvalidConfig { option: "failover", reason: "members must include node".to_owned() });
+        }
+        if config.heartbeat_timeout <= REPL_HEARTBEAT {
+            return Err(KVError::InvalidConfig {
+                option: "failover",
+                reason: format!("heartbeat timeout must exceed {:?}", REPL_HEARTBEAT),
+            });
+        }
+        let mut replica = None;
+        let role = if primary == config.node {
+            if store.epoch_record().1.is_none() {
+                store.promote(config.node)?;
+            }
+            FailoverRole::Primary
+        } else {
+            replica = Some(Replica::start_with(Arc::clone(&store), primary, config.tls.clone())?);
+            FailoverRole::Follower(primary)
+        };
+        let role = Arc::new(Mutex::new(role));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (role, stopped) = (Arc::clone(&role), Arc::clone(&stopped));
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    thread::sleep(config.heartbeat_timeout / 4);
+                    let current = *role.lock().unwrap();
+                    *role.lock().unwrap() = Self::step(&store, &config, current, &mut replica);
+                }
+            })
+        };
+        Ok(Failover { role, stopped, worker: Some(worker) })
+    }
+    pub(crate) fn step(
+        store: &Arc<KVStore>,
+        config: &FailoverConfig,
+        role: FailoverRole,
+        replica: &mut Option<Replica>,
+    ) -> FailoverRole {
+        let timeout = config.heartbeat_timeout;
+        let peers = config.members.iter().copied().filter(|&member| member != config.node);
+        match role {
+            FailoverRole::Fenced => role,
+            FailoverRole::Primary if store.is_fenced() => FailoverRole::Fenced,
+            FailoverRole::Primary => {
+                let (epoch, _) = store.epoch_record();
+                for member in peers {
+                    let Ok(status) = member_status(member, timeout, config.tls.as_ref()) else {
+                        continue;
+                    };
+                    if status.epoch > epoch || (status.epoch == epoch && status.owner == Some(member) && member < config.node)
+                    {
+                        store.fence(epoch.max(status.epoch) + (status.epoch == epoch) as u64);
+                        return FailoverRole::Fenced;
+                    }
+                }
+                role
+            }
+            FailoverRole::Follower(primary) => {
+                if replica.as_ref().is_some_and(|replica| replica.silent_for() < timeout) {
+                    return role;
+                }
+                let epoch = store.epoch();
+                let mut winner = (store.current_version(), std::cmp::Reverse(config.node));
+                for member in peers.filter(|&member| member != primary) {
+                    let Ok(status) = member_status(member, timeout, config.tls.as_ref()) else {
+                        continue;
+                    };
+                    if status.fenced {
+                        continue;
+                    }
+                    if status.epoch > epoch && status.owner.is_some_and(|owner| owner != primary) {
+                        winner = (Version::MAX, std::cmp::Reverse(status.owner.unwrap()));
+                        break;
+                    }
+                    winner = winner.max((status.version, std::cmp::Reverse(member)));
+                }
+                let std::cmp::Reverse(winner) = winner.1;
+                *replica = None;
+                if winner == config.node {
+                    match store.promote(config.node) {
+                        Ok(_) => FailoverRole::Primary,
+                        Err(_) => role,
+                    }
+                } else {
+                    *replica = Replica::start_with(Arc::clone(store), winner, config.tls.clone()).ok();
+                    FailoverRole::Follower(winner)
+                }
+            }
+        }
+    }
+    pub fn role(&self) -> FailoverRole {
+        *self.role.lock().unwrap()
+    }
+}
+impl Drop for Failover {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn put(store: &Arc<KVStore>, key: &str, value: &str) -> Version {
+        let mut tx = store.begin();
+        tx.put(key.to_owned(), value.to_owned()).unwrap();
+        tx.commit().unwrap()
+    }
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    fn primary() -> (Arc<KVStore>, SocketAddr) {
+        let store = KVStore::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        store.serve_replicas(listener);
+        (store, addr)
+    }
+    // Forwards connections to `target` until the returned flag is set, then refuses them, as a crashed primary would.
+    fn proxy(target: SocketAddr) -> (SocketAddr, Arc<AtomicBool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (addr, killed) = (listener.local_addr().unwrap(), Arc::new(AtomicBool::new(false)));
+        listener.set_nonblocking(true).unwrap();
+        let flag = Arc::clone(&killed);
+        thread::spawn(move || {
+            let mut open = Vec::new();
+            while !flag.load(Ordering::Acquire) {
+                match listener.accept() {
+                    Ok((client, _)) => {
+                        client.set_nonblocking(false).unwrap();
+                        let server = TcpStream::connect(target).unwrap();
+                        for (mut from, mut to) in [(client.try_clone().unwrap(), server.try_clone().unwrap()), (server.try_clone().unwrap(), client.try_clone().unwrap())] {
+                            thread::spawn(move || io::copy(&mut from, &mut to));
+                        }
+                        open.extend([client, server]);
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+            for stream in open {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+        (addr, killed)
+    }
+    #[test]
+    fn followers_promote_the_most_caught_up_member_and_fence_the_old_primary() {
+        let (old, served) = primary();
+        let (old_addr, killed) = proxy(served);
+        let followers: Vec<(Arc<KVStore>, SocketAddr)> = (0..2).map(|_| primary()).collect();
+        let members = [old_addr, followers[0].1, followers[1].1];
+        let config = |node| FailoverConfig::new(node, members.to_vec()).heartbeat_timeout(Duration::from_millis(300));
+        let old_failover = Failover::start(Arc::clone(&old), old_addr, config(old_addr)).unwrap();
+        assert_eq!((old_failover.role(), old.epoch()), (FailoverRole::Primary, 1));
+        let failovers: Vec<Failover> =
+            followers.iter().map(|(store, node)| Failover::start(Arc::clone(store), old_addr, config(*node)).unwrap()).collect();
+        let written = put(&old, "k", "1");
+        wait_until("both followers replicate the write", || followers.iter().all(|(store, _)| store.current_version() == written));
+        killed.store(true, Ordering::Release);
+        // Equally caught-up members break the tie by address, so both followers pick the same winner.
+        let winner = if followers[0].1 < followers[1].1 { 0 } else { 1 };
+        let (new, new_addr) = &followers[winner];
+        wait_until("a follower is promoted", || failovers[winner].role() == FailoverRole::Primary);
+        assert_eq!(new.epoch(), 2);
+        wait_until("the other follower follows the new primary", || failovers[1 - winner].role() == FailoverRole::Follower(*new_addr));
+        wait_until("the old primary is fenced", || old_failover.role() == FailoverRole::Fenced);
+        assert!(old.is_fenced());
+        let mut tx = old.begin();
+        tx.put("k".to_owned(), "stale".to_owned()).unwrap();
+        assert!(matches!(tx.commit(), Err(KVError::NotLeader(None))));
+        let written = put(new, "k", "2");
+        let other = &followers[1 - winner].0;
+        wait_until("the new primary's writes replicate", || other.current_version() == written);
+        assert_eq!(other.begin().get("k").unwrap().as_deref(), Some("2"));
+    }
+    #[test]
+    fn session_tokens_round_trip_and_hold_reads_back_until_caught_up() {
+        let dir = TempDir::new("session-token");
+        let writer = KVStore::open(dir.path()).unwrap();
+        let mut tx = writer.begin();
+        tx.put("k".to_owned(), "1".to_owned()).unwrap();
+        let token = tx.commit_with_token().unwrap();
+        assert_eq!((token, writer.session_token(), writer.snapshot().token()), (SessionToken(1), token, token));
+        assert_eq!(token.to_string().parse::<SessionToken>().unwrap(), token);
+        assert!("1".parse::<SessionToken>().is_err() && "vx".parse::<SessionToken>().is_err());
+        let ahead = SessionToken::new(token.version() + 1);
+        assert!(matches!(writer.snapshot_after(ahead), Err(KVError::InvalidInput(_))));
+        let reader = KVStore::open_read_only(dir.path()).unwrap();
+        assert_eq!(reader.begin_after(token).unwrap().get("k").unwrap().as_deref(), Some("1"));
+        let late = {
+            let writer = Arc::clone(&writer);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                put(&writer, "k", "2")
+            })
+        };
+        let snapshot = reader.snapshot_after(ahead).unwrap();
+        assert_eq!(late.join().unwrap(), ahead.version());
+        assert_eq!((snapshot.get("k").as_deref(), snapshot.token()), (Some("2"), ahead));
+        assert!(matches!(reader.snapshot_after(SessionToken::new(100)), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn value_filters_run_where_the_read_is_served() {
+        let (primary, addr) = primary();
+        let mut tx = primary.begin();
+        for (id, status, total) in [(1, "open", 5), (2, "shipped", 50), (3, "open", 500), (4, "void", 0)] {
+            tx.put(format!("orders:{}", id), format!(r#"{{"status":"{}","total":{}}}"#, status, total)).unwrap();
+        }
+        tx.put("orders:5".to_owned(), "open but not json".to_owned()).unwrap();
+        let written = tx.commit().unwrap();
+        let filter = ValueFilter::All(vec![
+            ValueFilter::Any(vec![
+                ValueFilter::Json("$.status".to_owned(), Predicate::Eq(Json::Str("open".into()))),
+                ValueFilter::Regex(Regex::new("shipped").unwrap()),
+            ]),
+            ValueFilter::Not(Box::new(ValueFilter::Json("$.total".to_owned(), Predicate::Gt(Json::Num(100.0))))),
+        ]);
+        let decoded = ValueFilter::decode(&filter.encode()).unwrap();
+        assert_eq!(decoded.encode(), filter.encode());
+        assert!(matches!(ValueFilter::decode(&[9]), Err(KVError::Corruption(_))));
+        let replica = Replica::start(KVStore::new(), addr).unwrap();
+        wait_until("the replica catches up", || replica.version() == written);
+        let keys = |consistency| {
+            let (rows, token) = replica.scan_filtered("orders:".to_owned().."orders;".to_owned(), &filter, consistency).unwrap();
+            assert_eq!(token, SessionToken(written));
+            rows.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(ReadConsistency::Leader), ["orders:1", "orders:2"]);
+        assert_eq!(keys(ReadConsistency::ReadYourWrites(SessionToken(written))), ["orders:1", "orders:2"]);
+    }
+    #[test]
+    fn replicas_resume_from_a_snapshot_once_their_version_is_collected() {
+        let (primary, addr) = primary();
+        primary.set_default_retention(Retention::LatestOnly);
+        put(&primary, "ks:a", "1");
+        let resumed = put(&primary, "ks:b", "1");
+        let local = KVStore::new();
+        {
+            let replica = Replica::start(Arc::clone(&local), addr).unwrap();
+            wait_until("the first sync", || replica.version() == resumed);
+        }
+        let mut tx = primary.begin();
+        tx.delete("ks:a".to_owned()).unwrap();
+        tx.put("ks:c".to_owned(), "2".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let latest = put(&primary, "ks:b", "2");
+        assert!(primary.gc().watermark > resumed);
+        let replica = Replica::start(Arc::clone(&local), addr).unwrap();
+        wait_until("the snapshot is installed", || replica.version() == latest);
+        assert_eq!(local.snapshot().scan(..), primary.snapshot().scan(..));
+        assert_eq!(local.commit_time(latest), primary.commit_time(latest));
+        let next = put(&primary, "ks:d", "3");
+        wait_until("the replica follows the log again", || replica.version() == next);
+        assert_eq!(local.begin().get("ks:d").unwrap().as_deref(), Some("3"));
+    }
+    #[test]
+    fn replicas_catch_up_and_then_follow_the_log() {
+        let (primary, addr) = primary();
+        put(&primary, "before", "1");
+        let replica = Replica::start(KVStore::new(), addr).unwrap();
+        wait_until("the backlog is shipped", || replica.version() == primary.current_version());
+        let mut tx = primary.begin();
+        tx.delete("before".to_owned()).unwrap();
+        tx.put("after".to_owned(), "2".to_owned()).unwrap();
+        let latest = tx.commit().unwrap();
+        wait_until("the new commit is shipped", || replica.version() == latest);
+        assert_eq!(replica.snapshot().scan(..), [("after".to_owned(), "2".to_owned())]);
+        assert_eq!(replica.store.commit_time(latest), primary.commit_time(latest));
+    }
+    #[test]
+    fn reads_honour_the_requested_consistency() {
+        let (primary, addr) = primary();
+        let first = put(&primary, "k", "1");
+        let replica = Replica::start(KVStore::new(), addr).unwrap();
+        wait_until("the replica hears a heartbeat", || replica.lag().is_some_and(|(versions, _)| versions == 0));
+        let fresh = ReadConsistency::BoundedStaleness { max_versions: Some(0), max_age: Some(Duration::from_secs(5)) };
+        assert_eq!(replica.local_version(fresh), Some(first));
+        assert_eq!(replica.get("k", fresh).unwrap(), (Some("1".to_owned()), SessionToken(first)));
+        let written = put(&primary, "k", "2");
+        let (value, token) = replica.get("k", ReadConsistency::ReadYourWrites(SessionToken(written))).unwrap();
+        assert_eq!((value.as_deref(), token), (Some("2"), SessionToken(written)));
+        let (value, token) = replica.get("k", ReadConsistency::Leader).unwrap();
+        assert_eq!((value.as_deref(), token), (Some("2"), primary.session_token()));
+        let (rows, _) = replica.scan("a".to_owned().."z".to_owned(), ReadConsistency::Leader).unwrap();
+        assert_eq!(rows, [("k".to_owned(), "2".to_owned())]);
+        let ahead = ReadConsistency::ReadYourWrites(SessionToken(written + 100));
+        assert_eq!(replica.local_version(ahead), None);
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_replicas_need_a_certificate_the_primary_trusts() {
+        let ca = tls::TestCa::new();
+        let primary = KVStore::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        primary.serve_replicas_tls(listener, &ca.server("primary", true)).unwrap();
+        let written = put(&primary, "k", "v");
+        let plain = Replica::start(KVStore::new(), addr).unwrap();
+        let anonymous = Replica::start_tls(KVStore::new(), addr, ca.client(None)).unwrap();
+        let replica = Replica::start_tls(KVStore::new(), addr, ca.client(Some("replica"))).unwrap();
+        wait_until("the tls replica catches up", || replica.version() == written);
+        let (value, _) = replica.get("k", ReadConsistency::Leader).unwrap();
+        assert_eq!(value.as_deref(), Some("v"));
+        assert_eq!((plain.version(), anonymous.version()), (0, 0));
+    }
+}
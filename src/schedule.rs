@@ -0,0 +1,538 @@
+use crate::*;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotOutcome {
+    pub version: Version,
+    pub removed: Vec<Version>,
+}
+pub(crate) type SnapshotCallback = Box<dyn Fn(&Result<SnapshotOutcome>) + Send>;
+pub struct SnapshotSchedule {
+    pub(crate) interval: Duration,
+    pub(crate) keep_last: Option<usize>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) callback: Option<SnapshotCallback>,
+}
+impl SnapshotSchedule {
+    pub fn every(interval: Duration) -> Self {
+        SnapshotSchedule { interval, keep_last: None, max_age: None, callback: None }
+    }
+    pub fn keep_last(mut self, count: usize) -> Self {
+        self.keep_last = Some(count);
+        self
+    }
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+    pub fn on_result(mut self, callback: impl Fn(&Result<SnapshotOutcome>) + Send + 'static) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+    pub fn start(self, store: &Arc<KVStore>) -> SnapshotScheduler {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (store, stopped) = (Arc::clone(store), Arc::clone(&stopped));
+            thread::spawn(move || {
+                let mut due = Instant::now() + self.interval;
+                while !stopped.load(Ordering::Acquire) && !store.is_closed() {
+                    let now = Instant::now();
+                    if now < due {
+                        thread::sleep((due - now).min(Duration::from_millis(100)));
+                        continue;
+                    }
+                    due += self.interval;
+                    let outcome = store.checkpoint().and_then(|version| {
+                        let removed = store.rotate_snapshots(self.keep_last, self.max_age)?;
+                        Ok(SnapshotOutcome { version, removed })
+                    });
+                    if let Some(callback) = &self.callback {
+                        callback(&outcome);
+                    }
+                }
+            })
+        };
+        SnapshotScheduler { stopped, worker: Some(worker) }
+    }
+}
+pub struct SnapshotScheduler {
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+pub(crate) type LongTxnCallback = Box<dyn Fn(&LongTxn) + Send>;
+pub struct TxnWatchdog {
+    pub(crate) threshold: Duration,
+    pub(crate) interval: Duration,
+    pub(crate) callback: Option<LongTxnCallback>,
+}
+impl TxnWatchdog {
+    pub fn after(threshold: Duration) -> Self {
+        let interval = (threshold / 2).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        TxnWatchdog { threshold, interval, callback: None }
+    }
+    pub fn check_every(mut self, interval: Duration) -> Self {
+        self.interval = interval.max(Duration::from_millis(1));
+        self
+    }
+    pub fn on_long_running(mut self, callback: impl Fn(&LongTxn) + Send + 'static) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+    pub fn start(self, store: &Arc<KVStore>) -> Watchdog {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flagged = Arc::new(AtomicU64::new(0));
+        let worker = {
+            let (store, stopped, flagged) = (Arc::downgrade(store), Arc::clone(&stopped), Arc::clone(&flagged));
+            thread::spawn(move || {
+                let mut reported = BTreeSet::new();
+                while !stopped.load(Ordering::Acquire) {
+                    thread::sleep(self.interval.min(Duration::from_millis(100)));
+                    let Some(store) = store.upgrade().filter(|store| !store.is_closed()) else {
+                        break;
+                    };
+                    let long = store.long_running(self.threshold);
+                    reported.retain(|id| long.iter().any(|txn| txn.id == *id));
+                    for txn in long.iter().filter(|txn| reported.insert(txn.id)) {
+                        flagged.fetch_add(1, Ordering::Relaxed);
+                        if let Some(callback) = &self.callback {
+                            callback(txn);
+                        }
+                    }
+                }
+            })
+        };
+        Watchdog { stopped, flagged, worker: Some(worker) }
+    }
+}
+pub struct Watchdog {
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) flagged: Arc<AtomicU64>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl Watchdog {
+    pub fn flagged(&self) -> u64 {
+        self.flagged.load(Ordering::Relaxed)
+    }
+}
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+pub(crate) const CAPACITY_SAMPLES: usize = 1440;
+pub(crate) fn capacity_key(at: u64) -> String {
+    format!("{}{}capacity{}{:020}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, at)
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacitySample {
+    pub at: u64,
+    pub bytes: usize,
+    pub keys: usize,
+}
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapacityReport {
+    pub samples: Vec<CapacitySample>,
+    pub bytes_per_day: Option<f64>,
+    pub keys_per_day: Option<f64>,
+    pub memory_limit: Option<usize>,
+    pub time_to_limit: Option<Duration>,
+}
+pub struct CapacitySchedule {
+    pub(crate) interval: Duration,
+    pub(crate) keep: usize,
+}
+impl CapacitySchedule {
+    pub fn every(interval: Duration) -> Self {
+        CapacitySchedule { interval: interval.max(Duration::from_millis(1)), keep: CAPACITY_SAMPLES }
+    }
+    pub fn keep(mut self, samples: usize) -> Self {
+        self.keep = samples.max(2);
+        self
+    }
+    pub fn start(self, store: &Arc<KVStore>) -> CapacitySampler {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (store, stopped) = (Arc::downgrade(store), Arc::clone(&stopped));
+            thread::spawn(move || {
+                let mut due = Instant::now();
+                while !stopped.load(Ordering::Acquire) {
+                    let now = Instant::now();
+                    if now < due {
+                        thread::sleep((due - now).min(Duration::from_millis(100)));
+                        continue;
+                    }
+                    due += self.interval;
+                    let Some(store) = store.upgrade().filter(|store| !store.is_closed()) else {
+                        break;
+                    };
+                    let _ = store.record_capacity(self.keep);
+                }
+            })
+        };
+        CapacitySampler { stopped, worker: Some(worker) }
+    }
+}
+pub struct CapacitySampler {
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl Drop for CapacitySampler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+pub(crate) fn growth_per_day(samples: &[CapacitySample], value: impl Fn(&CapacitySample) -> usize) -> Option<f64> {
+    if samples.len() < 2 || samples[0].at == samples[samples.len() - 1].at {
+        return None;
+    }
+    let n = samples.len() as f64;
+    let days = |sample: &CapacitySample| (sample.at - samples[0].at) as f64 / 86_400_000.0;
+    let mean_t = samples.iter().map(days).sum::<f64>() / n;
+    let mean_v = samples.iter().map(|sample| value(sample) as f64).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for sample in samples {
+        let dt = days(sample) - mean_t;
+        covariance += dt * (value(sample) as f64 - mean_v);
+        variance += dt * dt;
+    }
+    Some(covariance / variance)
+}
+impl KVStore {
+    pub fn sample_capacity(&self) -> Result<CapacitySample> {
+        self.record_capacity(CAPACITY_SAMPLES)
+    }
+    pub(crate) fn record_capacity(&self, keep: usize) -> Result<CapacitySample> {
+        self.writable()?;
+        let stats = self.stats();
+        let keys = stats.keyspaces.iter().filter(|(keyspace, _)| !keyspace.starts_with("__")).map(|(_, count)| count).sum();
+        let sample = CapacitySample { at: self.now_millis(), bytes: stats.approx_bytes, keys };
+        let mut writes = Writes::from([(capacity_key(sample.at), Some(format!("{} {}", sample.bytes, sample.keys)))]);
+        let mut samples = self.capacity_samples();
+        samples.retain(|old| old.at != sample.at);
+        for old in samples.iter().take((samples.len() + 1).saturating_sub(keep)) {
+            writes.insert(capacity_key(old.at), None);
+        }
+        self.apply(writes, None, None, None)?;
+        Ok(sample)
+    }
+    pub(crate) fn capacity_samples(&self) -> Vec<CapacitySample> {
+        let prefix = capacity_key(0);
+        let prefix = &prefix[..prefix.len() - 20];
+        self.scan_at(prefix_range(prefix), self.current_version())
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let (bytes, keys) = value.split_once(' ')?;
+                Some(CapacitySample { at: key[prefix.len()..].parse().ok()?, bytes: bytes.parse().ok()?, keys: keys.parse().ok()? })
+            })
+            .collect()
+    }
+    pub fn capacity_report(&self) -> CapacityReport {
+        let samples = self.capacity_samples();
+        let bytes_per_day = growth_per_day(&samples, |sample| sample.bytes);
+        let memory_limit = self.memory_limit();
+        let time_to_limit = match (bytes_per_day, memory_limit) {
+            (Some(rate), Some(limit)) if rate > 0.0 => {
+                let headroom = limit.saturating_sub(self.memory_usage()) as f64;
+                Duration::try_from_secs_f64(headroom / rate * 86_400.0).ok()
+            }
+            _ => None,
+        };
+        CapacityReport { keys_per_day: growth_per_day(&samples, |sample| sample.keys), samples, bytes_per_day, memory_limit, time_to_limit }
+    }
+}
+impl Drop for SnapshotScheduler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+pub(crate) struct RateLimiter {
+    pub(crate) bytes_per_sec: u64,
+    pub(crate) next_free: Mutex<Instant>,
+}
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter { bytes_per_sec: bytes_per_sec.max(1), next_free: Mutex::new(Instant::now()) }
+    }
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next_free).max(now);
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start - now
+        };
+        thread::sleep(wait);
+    }
+}
+#[derive(Default)]
+pub(crate) struct CompactionLimits {
+    pub(crate) io: Option<RateLimiter>,
+    pub(crate) cpu: Option<f64>,
+    pub(crate) jobs: usize,
+}
+impl CompactionLimits {
+    /// Sleeps off the share of a step that started at `started` which exceeds the CPU budget.
+    pub(crate) fn pace(&self, started: Instant) {
+        if let Some(cpu) = self.cpu {
+            thread::sleep(started.elapsed().mul_f64((1.0 - cpu) / cpu));
+        }
+    }
+}
+pub(crate) type CompactionCallback = Box<dyn Fn(&Result<CompactionReport>) + Send>;
+pub struct CompactionSchedule {
+    pub(crate) interval: Duration,
+    pub(crate) limits: CompactionLimits,
+    pub(crate) callback: Option<CompactionCallback>,
+}
+impl CompactionSchedule {
+    pub fn every(interval: Duration) -> Self {
+        CompactionSchedule { interval, limits: CompactionLimits { jobs: 1, ..CompactionLimits::default() }, callback: None }
+    }
+    pub fn max_bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.limits.io = Some(RateLimiter::new(bytes));
+        self
+    }
+    pub fn max_cpu(mut self, fraction: f64) -> Self {
+        self.limits.cpu = Some(fraction.clamp(0.01, 1.0));
+        self
+    }
+    pub fn max_concurrent_jobs(mut self, jobs: usize) -> Self {
+        self.limits.jobs = jobs.max(1);
+        self
+    }
+    pub fn on_result(mut self, callback: impl Fn(&Result<CompactionReport>) + Send + 'static) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+    pub fn start(self, store: &Arc<KVStore>) -> CompactionScheduler {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (store, stopped) = (Arc::clone(store), Arc::clone(&stopped));
+            thread::spawn(move || {
+                let mut due = Instant::now() + self.interval;
+                while !stopped.load(Ordering::Acquire) && !store.is_closed() {
+                    let now = Instant::now();
+                    if now < due {
+                        thread::sleep((due - now).min(Duration::from_millis(100)));
+                        continue;
+                    }
+                    let outcome = store.run_compaction(&self.limits, &stopped);
+                    due = Instant::now() + self.interval;
+                    if let Some(callback) = &self.callback {
+                        callback(&outcome);
+                    }
+                }
+            })
+        };
+        CompactionScheduler { stopped, worker: Some(worker) }
+    }
+}
+pub struct CompactionScheduler {
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl Drop for CompactionScheduler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+impl KVStore {
+    pub fn rotate_snapshots(&self, keep_last: Option<usize>, max_age: Option<Duration>) -> Result<Vec<Version>> {
+        let storage = self.storage()?;
+        let now = UNIX_EPOCH + Duration::from_millis(self.now_millis());
+        let mut removed = Vec::new();
+        for (i, (version, name)) in storage_files(storage.as_ref(), "snap")?.into_iter().rev().enumerate().skip(1) {
+            let expired = max_age.is_some_and(|age| {
+                self.commit_time(version).and_then(|time| now.duration_since(time).ok()).is_some_and(|elapsed| elapsed > age)
+            });
+            if expired || keep_last.is_some_and(|count| i >= count) {
+                self.update_manifest(|_| Ok(()), |manifest| {
+                    manifest.snapshots.remove(&version);
+                })?;
+                storage.remove(&name)?;
+                removed.push(version);
+            }
+        }
+        removed.reverse();
+        Ok(removed)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn scheduled_snapshots_rotate_and_survive_a_reopen() {
+        let dir = TempDir::new("scheduled-snapshots");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let (tx, rx) = mpsc::channel();
+            let scheduler = SnapshotSchedule::every(Duration::from_millis(10))
+                .keep_last(2)
+                .on_result(move |outcome| {
+                    let _ = tx.send(outcome.as_ref().cloned().map_err(ToString::to_string));
+                })
+                .start(&store);
+            for i in 0..4 {
+                let mut txn = store.begin();
+                txn.put(format!("ks:{}", i), i.to_string()).unwrap();
+                let version = txn.commit().unwrap();
+                loop {
+                    let outcome = rx.recv_timeout(Duration::from_secs(10)).expect("no snapshot was taken").unwrap();
+                    if outcome.version >= version {
+                        break;
+                    }
+                }
+            }
+            drop(scheduler);
+            let storage = FsStorage::new(dir.path());
+            let snapshots = storage_files(&storage, "snap").unwrap();
+            assert_eq!(snapshots.len(), 2);
+            assert_eq!(snapshots.last().unwrap().0, store.current_version());
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.begin().get("ks:3").unwrap().as_deref(), Some("3"));
+    }
+    #[test]
+    fn the_rate_limiter_spaces_grants_by_their_size() {
+        let limiter = RateLimiter::new(10_000);
+        let started = Instant::now();
+        limiter.acquire(500);
+        assert!(started.elapsed() < Duration::from_millis(40), "the first grant must not wait");
+        limiter.acquire(500);
+        limiter.acquire(0);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+    #[test]
+    fn scheduled_compaction_rewrites_segments_within_its_limits() {
+        let dir = TempDir::new("scheduled-compaction");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_retention("ks", Retention::LatestOnly);
+            for round in 0..3 {
+                for i in 0..20 {
+                    let mut txn = store.begin();
+                    txn.put(format!("ks:{}", i), format!("{}-{}", round, "x".repeat(200))).unwrap();
+                    txn.commit().unwrap();
+                }
+                store.checkpoint().unwrap();
+            }
+            let (tx, rx) = mpsc::channel();
+            let scheduler = CompactionSchedule::every(Duration::from_millis(10))
+                .max_bytes_per_sec(1 << 20)
+                .max_cpu(0.5)
+                .max_concurrent_jobs(2)
+                .on_result(move |outcome| {
+                    let _ = tx.send(outcome.as_ref().cloned().map_err(ToString::to_string));
+                })
+                .start(&store);
+            let report = rx.recv_timeout(Duration::from_secs(10)).expect("no compaction ran").unwrap();
+            assert_eq!(report.version, store.current_version());
+            // The last round is still live, so only the two older segments shrink.
+            assert_eq!(report.segments_rewritten, 2);
+            assert!(report.bytes_reclaimed > 0);
+            let later = rx.recv_timeout(Duration::from_secs(10)).expect("compaction stopped repeating").unwrap();
+            assert_eq!((later.segments_rewritten, later.bytes_reclaimed), (0, 0));
+            drop(scheduler);
+            while rx.try_recv().is_ok() {}
+            thread::sleep(Duration::from_millis(50));
+            assert!(rx.try_recv().is_err(), "a dropped scheduler must stop compacting");
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let txn = store.begin();
+        assert!((0..20).all(|i| txn.get(&format!("ks:{}", i)).unwrap().is_some_and(|value| value.starts_with("2-"))));
+    }
+    #[test]
+    fn the_watchdog_flags_each_long_transaction_once() {
+        let store = KVStore::new();
+        let (tx, flagged) = mpsc::channel();
+        let watchdog = TxnWatchdog::after(Duration::from_millis(40))
+            .check_every(Duration::from_millis(5))
+            .on_long_running(move |txn| tx.send((txn.id, txn.snapshot, txn.open_for)).unwrap());
+        let watchdog = watchdog.start(&store);
+        let quick = store.begin();
+        drop(quick);
+        let long = store.begin();
+        let (id, snapshot) = (long.id(), store.current_version());
+        let (seen, at, open_for) = flagged.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((seen, at), (id, snapshot));
+        assert!(open_for >= Duration::from_millis(40));
+        assert_eq!(store.long_running(Duration::from_millis(40)).iter().map(|txn| txn.id).collect::<Vec<_>>(), [id]);
+        assert!(store.long_running(Duration::from_secs(60)).is_empty());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(watchdog.flagged(), 1);
+        drop(long);
+        assert!(store.long_running(Duration::ZERO).is_empty());
+        let again = store.begin();
+        assert_eq!(flagged.recv_timeout(Duration::from_secs(10)).unwrap().0, again.id());
+        assert_eq!(watchdog.flagged(), 2);
+        drop(watchdog);
+        assert!(flagged.try_recv().is_err());
+    }
+    #[test]
+    fn growth_is_the_least_squares_slope_per_day() {
+        let day = 86_400_000;
+        let samples: Vec<CapacitySample> = [(0, 1000, 10), (day, 2100, 20), (2 * day, 2900, 30), (3 * day, 4000, 40)]
+            .into_iter()
+            .map(|(at, bytes, keys)| CapacitySample { at, bytes, keys })
+            .collect();
+        assert!((growth_per_day(&samples, |sample| sample.bytes).unwrap() - 980.0).abs() < 1e-9);
+        assert!((growth_per_day(&samples, |sample| sample.keys).unwrap() - 10.0).abs() < 1e-9);
+        assert_eq!(growth_per_day(&samples[..1], |sample| sample.bytes), None);
+        assert_eq!(growth_per_day(&[samples[0], samples[0]], |sample| sample.bytes), None);
+    }
+    #[test]
+    fn capacity_reports_project_the_time_to_the_memory_limit() {
+        let dir = TempDir::new("capacity");
+        let clock = MockClock::new(0);
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_clock(clock.clone());
+            for day in 0..5 {
+                let mut tx = store.begin();
+                for i in 0..10 {
+                    tx.put(format!("ks:{}-{}", day, i), "v".repeat(100)).unwrap();
+                }
+                tx.commit().unwrap();
+                store.sample_capacity().unwrap();
+                clock.advance(Duration::from_secs(86_400));
+            }
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        store.set_clock(clock.clone());
+        let report = store.capacity_report();
+        assert_eq!(report.samples.len(), 5);
+        assert_eq!(report.samples.iter().map(|sample| sample.keys).collect::<Vec<_>>(), [10, 20, 30, 40, 50]);
+        assert!((report.keys_per_day.unwrap() - 10.0).abs() < 1e-9);
+        let rate = report.bytes_per_day.unwrap();
+        assert!(rate > 1000.0, "{} bytes per day", rate);
+        assert_eq!((report.memory_limit, report.time_to_limit), (None, None));
+        store.set_memory_limit(Some(store.memory_usage() + 9 * rate as usize));
+        let days = store.capacity_report().time_to_limit.unwrap().as_secs_f64() / 86_400.0;
+        assert!((8.9..9.1).contains(&days), "{} days to the limit", days);
+        store.set_memory_limit(None);
+        let sampler = CapacitySchedule::every(Duration::from_millis(1)).keep(3).start(&store);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while store.capacity_report().samples.iter().all(|sample| sample.at != clock.now_millis()) {
+            assert!(Instant::now() < deadline, "the sampler never ran");
+            thread::sleep(Duration::from_millis(5));
+        }
+        clock.advance(Duration::from_secs(1));
+        while store.capacity_report().samples.iter().all(|sample| sample.at != clock.now_millis()) {
+            assert!(Instant::now() < deadline, "the sampler never ran again");
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(sampler);
+        assert_eq!(store.capacity_report().samples.len(), 3);
+        assert!(store.capacity_report().samples.iter().all(|sample| sample.keys == 50));
+    }
+}
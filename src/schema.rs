@@ -0,0 +1,169 @@
+use crate::*;
+pub(crate) const SCHEMA_TAG: char = '\u{1}';
+pub(crate) type Upgrade = Box<dyn Fn(&str) -> Result<String> + Send + Sync>;
+#[derive(Default)]
+pub struct Schema {
+    pub(crate) version: u32,
+    pub(crate) upgrades: BTreeMap<u32, Upgrade>,
+}
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn upgrade(mut self, from: u32, upgrade: impl Fn(&str) -> Result<String> + Send + Sync + 'static) -> Self {
+        self.upgrades.insert(from, Box::new(upgrade));
+        self.version = self.version.max(from + 1);
+        self
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub(crate) fn encode(&self, value: &str) -> String {
+        format!("{}{}{}{}", SCHEMA_TAG, self.version, SCHEMA_TAG, value)
+    }
+    pub(crate) fn decode(&self, key: &str, stored: &str) -> Result<String> {
+        let (mut version, payload) = match stored.strip_prefix(SCHEMA_TAG).and_then(|rest| rest.split_once(SCHEMA_TAG)) {
+            Some((version, payload)) => {
+                (version.parse::<u32>().map_err(|_| KVError::Corruption(format!("bad schema version on {}", key)))?, payload)
+            }
+            None => (0, stored),
+        };
+        if version > self.version {
+            return Err(KVError::Corruption(format!("{} was written with schema v{}, newer than v{}", key, version, self.version)));
+        }
+        let mut value = payload.to_owned();
+        while version < self.version {
+            let upgrade = self
+                .upgrades
+                .get(&version)
+                .ok_or_else(|| KVError::Corruption(format!("no upgrade registered from schema v{} for {}", version, key)))?;
+            value = upgrade(&value)?;
+            version += 1;
+        }
+        Ok(value)
+    }
+}
+pub(crate) type MigrationStep = Box<dyn Fn(&mut Transaction) -> Result<()> + Send + Sync>;
+#[derive(Default)]
+pub struct Migrations {
+    pub(crate) steps: BTreeMap<u64, (String, MigrationStep)>,
+}
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn step(
+        mut self,
+        number: u64,
+        name: &str,
+        run: impl Fn(&mut Transaction) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        assert!(number > 0, "migration numbers start at 1");
+        assert!(self.steps.insert(number, (name.to_owned(), Box::new(run))).is_none(), "duplicate migration {}", number);
+        self
+    }
+    pub fn applied(store: &Arc<KVStore>) -> Result<u64> {
+        match store.snapshot().get(&Self::version_key()) {
+            Some(value) => value.parse().map_err(|_| KVError::Corruption(format!("bad migration version {:?}", value))),
+            None => Ok(0),
+        }
+    }
+    pub fn apply(&self, store: &Arc<KVStore>) -> Result<Vec<u64>> {
+        let mut applied = Vec::new();
+        for (&number, (name, run)) in self.steps.range(Self::applied(store)? + 1..) {
+            let mut tx = store.begin();
+            let current = tx.get(&Self::version_key())?.map_or(Ok(0), |value| {
+                value.parse::<u64>().map_err(|_| KVError::Corruption(format!("bad migration version {:?}", value)))
+            })?;
+            if current >= number {
+                continue;
+            }
+            run(&mut tx)?;
+            tx.put_internal(Self::version_key(), number.to_string())?;
+            tx.put_internal(format!("{}{}migration{}{:020}", META_KEYSPACE, KEYSPACE_SEPARATOR, KEYSPACE_SEPARATOR, number), name.clone())?;
+            tx.commit()?;
+            applied.push(number);
+        }
+        Ok(applied)
+    }
+    pub(crate) fn version_key() -> String {
+        format!("{}{}schema_version", META_KEYSPACE, KEYSPACE_SEPARATOR)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn validators_reject_puts_in_their_keyspace_only() {
+        let store = KVStore::new();
+        store.set_validator("users", |_, value| Json::parse(value).map(|_| ()).map_err(|e| e.to_string()));
+        let mut tx = store.begin();
+        match tx.put("users:1".to_owned(), "not json".to_owned()) {
+            Err(KVError::InvalidInput(message)) => assert!(message.contains("users:1") && message.contains("validator"), "{}", message),
+            other => panic!("expected the validator to reject the put, got {:?}", other),
+        }
+        tx.put("users:2".to_owned(), r#"{"name":"ada"}"#.to_owned()).unwrap();
+        tx.put("notes:1".to_owned(), "not json".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(store.begin().get("users:1").unwrap(), None);
+        assert!(store.clear_validator("users"));
+        assert!(!store.clear_validator("users"));
+        let mut tx = store.begin();
+        tx.put("users:1".to_owned(), "not json".to_owned()).unwrap();
+        tx.commit().unwrap();
+    }
+    fn steps() -> Migrations {
+        Migrations::new()
+            .step(1, "seed", |tx| tx.put("app:greeting".to_owned(), "hello".to_owned()))
+            .step(2, "shout", |tx| {
+                let greeting = tx.get("app:greeting")?.unwrap_or_default();
+                tx.put("app:greeting".to_owned(), greeting.to_uppercase())
+            })
+    }
+    #[test]
+    fn migrations_run_once_in_order_across_reopens() {
+        let dir = TempDir::new("migrations");
+        {
+            let store = KVStore::open_migrated(dir.path(), &steps()).unwrap();
+            assert_eq!(Migrations::applied(&store).unwrap(), 2);
+            assert_eq!(steps().apply(&store).unwrap(), Vec::<u64>::new());
+        }
+        let failing = steps()
+            .step(3, "half done", |tx| {
+                tx.put("app:partial".to_owned(), "x".to_owned())?;
+                Err(KVError::InvalidInput("step 3 failed".to_owned()))
+            })
+            .step(4, "never", |tx| tx.put("app:never".to_owned(), "x".to_owned()));
+        assert!(matches!(KVStore::open_migrated(dir.path(), &failing), Err(KVError::InvalidInput(_))));
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(Migrations::applied(&store).unwrap(), 2);
+        let tx = store.begin();
+        assert_eq!(tx.get("app:greeting").unwrap().as_deref(), Some("HELLO"));
+        assert_eq!((tx.get("app:partial").unwrap(), tx.get("app:never").unwrap()), (None, None));
+        let fixed = steps().step(3, "fixed", |tx| tx.put("app:fixed".to_owned(), "y".to_owned()));
+        assert_eq!(fixed.apply(&store).unwrap(), vec![3]);
+        assert_eq!(Migrations::applied(&store).unwrap(), 3);
+    }
+    #[test]
+    fn old_encodings_are_upgraded_on_read() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        tx.put("users:legacy".to_owned(), "ada".to_owned()).unwrap();
+        tx.commit().unwrap();
+        store.register_schema("users", Schema::new().upgrade(0, |name| Ok(format!(r#"{{"name":"{}"}}"#, name))));
+        let mut tx = store.begin();
+        tx.put_versioned("users:v1".to_owned(), r#"{"name":"alan"}"#).unwrap();
+        tx.commit().unwrap();
+        let schema = Schema::new()
+            .upgrade(0, |name| Ok(format!(r#"{{"name":"{}"}}"#, name)))
+            .upgrade(1, |v1| Ok(v1.replacen('}', r#","admin":false}"#, 1)));
+        assert_eq!(schema.version(), 2);
+        store.register_schema("users", schema);
+        let tx = store.begin();
+        assert_eq!(tx.get_versioned("users:legacy").unwrap().as_deref(), Some(r#"{"name":"ada","admin":false}"#));
+        assert_eq!(tx.get_versioned("users:v1").unwrap().as_deref(), Some(r#"{"name":"alan","admin":false}"#));
+        assert!(tx.get("users:v1").unwrap().unwrap().starts_with(SCHEMA_TAG));
+        store.register_schema("users", Schema::new());
+        assert!(matches!(store.begin().get_versioned("users:v1"), Err(KVError::Corruption(_))));
+    }
+}
@@ -0,0 +1,759 @@
+use crate::*;
+pub(crate) const HTTP_MAX_HEAD: u64 = 64 << 10;
+pub(crate) const HTTP_MAX_BODY: usize = MAX_VALUE_BYTES + (1 << 10);
+pub(crate) const HTTP_SCAN_LIMIT: usize = 1000;
+pub(crate) const RATE_LIMITED_CLIENTS: usize = 4096;
+/// A per-client budget for [`KVStore::serve_http`]. Each axis is a token bucket holding one second's worth, so a
+/// client may burst to its rate and is then held to it; `None` leaves that axis unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub ops_per_sec: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+}
+impl RateLimit {
+    pub fn ops(ops_per_sec: u64) -> Self {
+        RateLimit { ops_per_sec: Some(ops_per_sec), bytes_per_sec: None }
+    }
+    pub fn bytes(bytes_per_sec: u64) -> Self {
+        RateLimit { ops_per_sec: None, bytes_per_sec: Some(bytes_per_sec) }
+    }
+}
+/// How [`KVStore::serve_http`] treats its clients. A client authenticates as a principal with a bearer token, or
+/// with its certificate's common name under mutual TLS; one that does neither is anonymous and is rate limited by
+/// its IP address. Every keyspace is closed to every client until it is granted.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub(crate) rate_limit: RateLimit,
+    pub(crate) client_limits: BTreeMap<String, RateLimit>,
+    pub(crate) tls: Option<TlsServer>,
+    pub(crate) tokens: HashMap<[u8; 32], String>,
+    pub(crate) jwt_secret: Option<JwtSecret>,
+    pub(crate) grants: BTreeMap<String, BTreeSet<String>>,
+    pub(crate) anonymous: BTreeSet<String>,
+}
+#[derive(Clone)]
+pub(crate) struct JwtSecret(Vec<u8>);
+impl fmt::Debug for JwtSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JwtSecret(..)")
+    }
+}
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The budget of every client without one of its own.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = limit;
+        self
+    }
+    pub fn rate_limit_for(mut self, principal: &str, limit: RateLimit) -> Self {
+        self.client_limits.insert(principal.to_owned(), limit);
+        self
+    }
+    pub fn tls(mut self, tls: TlsServer) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+    /// Accepts `Authorization: Bearer <token>` as `principal`.
+    pub fn token(mut self, token: &str, principal: &str) -> Self {
+        self.tokens.insert(sha256(token.as_bytes()), principal.to_owned());
+        self
+    }
+    /// Accepts bearer JWTs signed with HS256 under `secret`. The `sub` claim names the principal and `exp` must be
+    /// in the future; a `keyspaces` array claim grants those keyspaces on top of [`grant`](Self::grant).
+    pub fn jwt_hs256(mut self, secret: &[u8]) -> Self {
+        self.jwt_secret = Some(JwtSecret(secret.to_vec()));
+        self
+    }
+    /// Lets `principal` read and write `keyspaces`. `"*"` stands for every keyspace but the store's internal `__`
+    /// ones, which have to be granted by name.
+    pub fn grant(mut self, principal: &str, keyspaces: &[&str]) -> Self {
+        self.grants.entry(principal.to_owned()).or_default().extend(keyspaces.iter().map(|keyspace| (*keyspace).to_owned()));
+        self
+    }
+    /// Lets clients without credentials read and write `keyspaces`.
+    pub fn grant_anonymous(mut self, keyspaces: &[&str]) -> Self {
+        self.anonymous.extend(keyspaces.iter().map(|keyspace| (*keyspace).to_owned()));
+        self
+    }
+}
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        Bucket { rate, tokens: rate, refilled: now }
+    }
+    fn refill(&mut self, now: Instant) {
+        let earned = now.saturating_duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.rate);
+        self.refilled = now;
+    }
+    /// How long until `cost` may be taken. A cost above the burst only waits for a full bucket and leaves it in debt.
+    fn wait(&self, cost: f64) -> Duration {
+        let short = cost.min(self.rate) - self.tokens;
+        Duration::from_secs_f64(short.max(0.0) / self.rate)
+    }
+}
+#[derive(Debug)]
+struct ClientBuckets {
+    ops: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+impl ClientBuckets {
+    fn refill(&mut self, now: Instant) -> bool {
+        let mut full = true;
+        for bucket in [&mut self.ops, &mut self.bytes].into_iter().flatten() {
+            bucket.refill(now);
+            full &= bucket.tokens >= bucket.rate;
+        }
+        full
+    }
+}
+/// Why a request was turned away with 429.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Throttled {
+    pub(crate) principal: String,
+    pub(crate) what: &'static str,
+    pub(crate) rate: u64,
+    pub(crate) retry_after: Duration,
+}
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {} exceeded its {} {} per second", self.principal, self.rate, self.what)
+    }
+}
+#[derive(Debug)]
+pub(crate) struct ClientLimiter {
+    default: RateLimit,
+    limits: BTreeMap<String, RateLimit>,
+    clients: Mutex<HashMap<String, ClientBuckets>>,
+}
+impl ClientLimiter {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        ClientLimiter { default: config.rate_limit, limits: config.client_limits.clone(), clients: Mutex::new(HashMap::new()) }
+    }
+    fn limit_for(&self, principal: &str) -> RateLimit {
+        self.limits.get(principal).copied().unwrap_or(self.default)
+    }
+    /// Takes one operation and `bytes` from `principal`'s buckets, or says how long it has to back off.
+    pub(crate) fn admit(&self, principal: &str, bytes: usize, now: Instant) -> std::result::Result<(), Throttled> {
+        let limit = self.limit_for(principal);
+        if limit == RateLimit::default() {
+            return Ok(());
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= RATE_LIMITED_CLIENTS && !clients.contains_key(principal) {
+            // A full bucket is what a new client starts with, so dropping those forgets nothing.
+            clients.retain(|_, buckets| !buckets.refill(now));
+        }
+        let buckets = clients.entry(principal.to_owned()).or_insert_with(|| ClientBuckets {
+            ops: limit.ops_per_sec.map(|rate| Bucket::new(rate, now)),
+            bytes: limit.bytes_per_sec.map(|rate| Bucket::new(rate, now)),
+        });
+        buckets.refill(now);
+        let costs = [(&mut buckets.ops, 1.0, "operations"), (&mut buckets.bytes, bytes as f64, "bytes")];
+        for (bucket, cost, what) in &costs {
+            let Some(bucket) = bucket else { continue };
+            let retry_after = bucket.wait(*cost);
+            if !retry_after.is_zero() {
+                return Err(Throttled { principal: principal.to_owned(), what, rate: bucket.rate as u64, retry_after });
+            }
+        }
+        for (bucket, cost, _) in costs {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+    /// Bills response bytes after the fact; the debt delays the client's next request.
+    pub(crate) fn charge(&self, principal: &str, bytes: usize, now: Instant) {
+        if let Some(bucket) = self.clients.lock().unwrap().get_mut(principal).and_then(|buckets| buckets.bytes.as_mut()) {
+            bucket.refill(now);
+            bucket.tokens -= bytes as f64;
+        }
+    }
+}
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: Vec<(String, String)>,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    pub(crate) close: bool,
+}
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(at, _)| at.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(at, _)| at == name).map(|(_, value)| value.as_str())
+    }
+}
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+}
+impl Response {
+    pub(crate) fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Response { status, headers: Vec::new(), body: body.into() }
+    }
+    pub(crate) fn error(e: &KVError) -> Self {
+        let status = match e {
+            KVError::InvalidInput(_) | KVError::Rejected { .. } => 400,
+            KVError::TransactionAborted => 409,
+            KVError::PreconditionFailed(_) => 412,
+            KVError::KeyTooLarge { .. } | KVError::ValueTooLarge { .. } => 413,
+            KVError::ReadOnly => 405,
+            KVError::Overloaded(_) | KVError::OutOfMemoryBudget(_) | KVError::NotLeader(_) | KVError::Closed => 503,
+            _ => 500,
+        };
+        Response::new(status, format!("{}\n", e))
+    }
+    fn unauthorized(reason: &str) -> Self {
+        let mut response = Response::new(401, format!("{}\n", reason));
+        response.headers.push(("WWW-Authenticate", "Bearer".to_owned()));
+        response
+    }
+    fn throttled(throttled: &Throttled) -> Self {
+        let mut response = Response::new(429, format!("{}\n", throttled));
+        let seconds = throttled.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        response.headers.push(("Retry-After", seconds.to_string()));
+        response
+    }
+    fn write_to(&self, out: &mut impl Write, close: bool) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            412 => "Precondition Failed",
+            413 => "Payload Too Large",
+            429 => "Too Many Requests",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", self.status, reason, self.body.len());
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(if close { "Connection: close\r\n\r\n" } else { "\r\n" });
+        out.write_all(head.as_bytes())?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+/// Who sent a request and which keyspaces it may reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Caller {
+    pub(crate) principal: String,
+    pub(crate) authenticated: bool,
+    pub(crate) keyspaces: BTreeSet<String>,
+}
+impl Caller {
+    fn may_access(&self, keyspace: &str) -> bool {
+        self.keyspaces.contains(keyspace) || (!keyspace.starts_with("__") && self.keyspaces.contains("*"))
+    }
+    fn authorize(&self, keyspace: &str) -> std::result::Result<(), Response> {
+        match (self.may_access(keyspace), self.authenticated) {
+            (true, _) => Ok(()),
+            (false, true) => Err(Response::new(403, format!("{} may not access the {:?} keyspace\n", self.principal, keyspace))),
+            (false, false) => Err(Response::unauthorized(&format!("the {:?} keyspace needs credentials", keyspace))),
+        }
+    }
+}
+/// Works out who sent `request`: its bearer token wins, then the client certificate's name, and a client with
+/// neither is anonymous under its IP address. A token that does not check out is an error to answer with 401.
+pub(crate) fn authenticate(config: &ServerConfig, request: &Request, certificate: Option<&str>, peer: IpAddr) -> std::result::Result<Caller, String> {
+    let granted = |principal: &str, mut keyspaces: BTreeSet<String>| {
+        keyspaces.extend(config.grants.get(principal).into_iter().flatten().cloned());
+        Caller { principal: principal.to_owned(), authenticated: true, keyspaces }
+    };
+    if let Some(authorization) = request.header("Authorization") {
+        let token = authorization.strip_prefix("Bearer ").ok_or("only bearer tokens are accepted")?.trim();
+        if let Some(principal) = config.tokens.get(&sha256(token.as_bytes())) {
+            return Ok(granted(principal, BTreeSet::new()));
+        }
+        let secret = config.jwt_secret.as_ref().filter(|_| token.contains('.')).ok_or("unknown bearer token")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (principal, keyspaces) = verify_jwt(&secret.0, token, now)?;
+        return Ok(granted(&principal, keyspaces));
+    }
+    Ok(match certificate {
+        Some(principal) => granted(principal, BTreeSet::new()),
+        None => Caller { principal: peer.to_string(), authenticated: false, keyspaces: config.anonymous.clone() },
+    })
+}
+fn base64url(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut held) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | sextet as u32;
+        held += 6;
+        if held >= 8 {
+            held -= 8;
+            bytes.push((bits >> held) as u8);
+        }
+    }
+    Some(bytes)
+}
+/// Checks an HS256 JWT against `secret` and the clock, returning its subject and the keyspaces it grants.
+pub(crate) fn verify_jwt(secret: &[u8], token: &str, now: u64) -> std::result::Result<(String, BTreeSet<String>), String> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(claims), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err("malformed token".to_owned());
+    };
+    let json = |part: &str| {
+        let text = base64url(part).and_then(|bytes| String::from_utf8(bytes).ok());
+        text.and_then(|text| Json::parse(&text).ok()).ok_or_else(|| "malformed token".to_owned())
+    };
+    if json(header)?.get("alg").and_then(Json::as_str) != Some("HS256") {
+        return Err("only HS256 tokens are accepted".to_owned());
+    }
+    let expected = hmac_sha256(secret, format!("{}.{}", header, claims).as_bytes());
+    if !base64url(signature).is_some_and(|signature| ct_eq(&signature, &expected)) {
+        return Err("token signature does not verify".to_owned());
+    }
+    let claims = json(claims)?;
+    let time = |claim| claims.get(claim).and_then(Json::as_u64);
+    match (time("exp"), time("nbf")) {
+        (None, _) => return Err("token has no exp claim".to_owned()),
+        (Some(exp), _) if exp <= now => return Err("token has expired".to_owned()),
+        (_, Some(nbf)) if nbf > now => return Err("token is not valid yet".to_owned()),
+        _ => {}
+    }
+    let subject = claims.get("sub").and_then(Json::as_str).ok_or("token has no sub claim")?;
+    let keyspaces = match claims.get("keyspaces") {
+        Some(Json::Array(keyspaces)) => keyspaces.iter().filter_map(Json::as_str).map(str::to_owned).collect(),
+        _ => BTreeSet::new(),
+    };
+    Ok((subject.to_owned(), keyspaces))
+}
+fn percent_decode(s: &str) -> Result<String> {
+    let bad = || KVError::InvalidInput(format!("malformed percent-encoding in {:?}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).ok_or_else(bad)?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| bad())?);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad())
+}
+fn read_line(reader: &mut impl BufRead, budget: &mut u64) -> Result<Option<String>> {
+    let mut line = String::new();
+    let read = Read::take(&mut *reader, *budget).read_line(&mut line)?;
+    *budget -= read as u64;
+    match (read, line.ends_with('\n')) {
+        (0, _) => Ok(None),
+        (_, false) => Err(KVError::InvalidInput(format!("request head exceeds {} bytes", HTTP_MAX_HEAD))),
+        (_, true) => Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned())),
+    }
+}
+/// Reads one HTTP/1.1 request, or `None` once the client has closed the connection between requests.
+pub(crate) fn read_request(reader: &mut impl BufRead) -> Result<Option<Request>> {
+    let mut budget = HTTP_MAX_HEAD;
+    let Some(line) = read_line(reader, &mut budget)? else { return Ok(None) };
+    let malformed = || KVError::InvalidInput(format!("malformed request line {:?}", line));
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(malformed());
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(malformed());
+    }
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut budget)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| KVError::InvalidInput(format!("malformed header {:?}", line)))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect::<Result<_>>()?;
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        headers,
+        body: Vec::new(),
+        close: version == "HTTP/1.0",
+    };
+    if let Some(connection) = request.header("Connection") {
+        request.close = connection.eq_ignore_ascii_case("close");
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(KVError::InvalidInput("chunked request bodies are not supported".to_owned()));
+    }
+    let len = match request.header("Content-Length") {
+        Some(len) => len.parse::<usize>().map_err(|_| KVError::InvalidInput(format!("malformed Content-Length {:?}", len)))?,
+        None => 0,
+    };
+    if len > HTTP_MAX_BODY {
+        return Err(KVError::InvalidInput(format!("request body of {} bytes exceeds the {} byte limit", len, HTTP_MAX_BODY)));
+    }
+    request.body = vec![0; len];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+fn route(store: &Arc<KVStore>, caller: &Caller, request: &Request) -> Result<Response> {
+    if let Some(key) = request.path.strip_prefix("/v1/kv/") {
+        let key = percent_decode(key)?;
+        if let Err(denied) = caller.authorize(keyspace_of(&key)) {
+            return Ok(denied);
+        }
+        return match request.method.as_str() {
+            "GET" => Ok(match store.begin().get(&key)? {
+                Some(value) => Response::new(200, value),
+                None => Response::new(404, format!("no value for {:?}\n", key)),
+            }),
+            "PUT" | "DELETE" => {
+                let mut txn = store.begin();
+                if caller.authenticated {
+                    txn.set_actor(&caller.principal);
+                }
+                match request.method.as_str() {
+                    "PUT" => {
+                        let value = String::from_utf8(request.body.clone())
+                            .map_err(|_| KVError::InvalidInput("values must be utf-8".to_owned()))?;
+                        txn.put(key, value)?;
+                    }
+                    _ => txn.delete(key)?,
+                }
+                Ok(Response::new(200, format!("{}\n", txn.commit()?)))
+            }
+            _ => Ok(Response::new(405, format!("{} is not supported on keys\n", request.method))),
+        };
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/scan") => {
+            let prefix = request.param("prefix").unwrap_or("");
+            // A prefix short of the separator could run across keyspaces, so only a `*` grant covers it.
+            let keyspace = if prefix.contains(KEYSPACE_SEPARATOR) { keyspace_of(prefix) } else { "*" };
+            if let Err(denied) = caller.authorize(keyspace) {
+                return Ok(denied);
+            }
+            let range = prefix_range(prefix);
+            let limit = match request.param("limit") {
+                Some(limit) => limit.parse::<usize>().map_err(|_| KVError::InvalidInput(format!("malformed limit {:?}", limit)))?,
+                None => HTTP_SCAN_LIMIT,
+            };
+            let page = match request.param("cursor") {
+                Some(cursor) => store.scan_continue(range, &cursor.parse()?, limit.min(HTTP_SCAN_LIMIT))?,
+                None => store.snapshot().scan_page(range, limit.min(HTTP_SCAN_LIMIT)),
+            };
+            let entries = page
+                .entries
+                .into_iter()
+                .map(|(key, value)| Json::Object(vec![("key".to_owned(), Json::Str(key)), ("value".to_owned(), Json::Str(value))]))
+                .collect();
+            let next = page.next.map_or(Json::Null, |cursor| Json::Str(cursor.to_string()));
+            let body = Json::Object(vec![("entries".to_owned(), Json::Array(entries)), ("next".to_owned(), next)]);
+            Ok(Response::new(200, format!("{}\n", body)))
+        }
+        (_, "/v1/scan") => Ok(Response::new(405, format!("{} is not supported on scans\n", request.method))),
+        _ => Ok(Response::new(404, format!("no route for {}\n", request.path))),
+    }
+}
+pub(crate) struct Frontend {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) config: ServerConfig,
+    pub(crate) limiter: ClientLimiter,
+}
+/// Serves one connection; `certificate` is the name on the client's certificate under mutual TLS.
+pub(crate) fn serve_connection(frontend: &Frontend, certificate: Option<&str>, peer: IpAddr, stream: impl Read + Write) -> Result<()> {
+    let mut reader = io::BufReader::new(stream);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) | Err(KVError::Io(_)) => return Ok(()),
+            Err(e) => return Ok(Response::error(&e).write_to(reader.get_mut(), true)?),
+        };
+        let caller = authenticate(&frontend.config, &request, certificate, peer);
+        let principal = caller.as_ref().map_or_else(|_| peer.to_string(), |caller| caller.principal.clone());
+        let limiter = &frontend.limiter;
+        let response = match (limiter.admit(&principal, request.body.len(), Instant::now()), caller) {
+            (Err(throttled), _) => Response::throttled(&throttled),
+            (Ok(()), Err(reason)) => Response::unauthorized(&reason),
+            (Ok(()), Ok(caller)) => route(&frontend.store, &caller, &request).unwrap_or_else(|e| Response::error(&e)),
+        };
+        if response.status != 429 {
+            limiter.charge(&principal, response.body.len(), Instant::now());
+        }
+        response.write_to(reader.get_mut(), request.close)?;
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+/// A running [`KVStore::serve_http`] listener; dropping it stops accepting and closes open connections.
+pub struct HttpServer {
+    pub(crate) addr: SocketAddr,
+    pub(crate) stopped: Arc<AtomicBool>,
+    pub(crate) connections: Arc<Mutex<HashMap<u64, TcpStream>>>,
+    pub(crate) worker: Option<thread::JoinHandle<()>>,
+}
+impl HttpServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        let _ = TcpStream::connect(self.addr);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        for (_, stream) in self.connections.lock().unwrap().drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+impl KVStore {
+    /// Serves the store over HTTP/1.1 on `listener`, a thread per connection:
+    /// `GET`/`PUT`/`DELETE /v1/kv/<key>` and `GET /v1/scan?prefix=&limit=&cursor=`. Clients over their
+    /// [`RateLimit`] get `429 Too Many Requests` with a `Retry-After` header, bad credentials get `401` and
+    /// keyspaces the caller was not granted get `403`.
+    pub fn serve_http(self: &Arc<Self>, listener: TcpListener, config: ServerConfig) -> Result<HttpServer> {
+        let acceptor = config.tls.as_ref().map(tls::Acceptor::new).transpose()?.map(Arc::new);
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let limiter = ClientLimiter::new(&config);
+        let frontend = Arc::new(Frontend { store: Arc::clone(self), config, limiter });
+        let (stop, open) = (Arc::clone(&stopped), Arc::clone(&connections));
+        let worker = thread::spawn(move || {
+            for (id, stream) in listener.incoming().enumerate() {
+                if stop.load(Ordering::Acquire) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let (Ok(peer), Ok(tracked)) = (stream.peer_addr(), stream.try_clone()) else { continue };
+                open.lock().unwrap().insert(id as u64, tracked);
+                let (frontend, open, acceptor) = (Arc::clone(&frontend), Arc::clone(&open), acceptor.clone());
+                thread::spawn(move || {
+                    if let Ok((stream, certificate)) = tls::accept(acceptor.as_deref(), stream) {
+                        let _ = serve_connection(&frontend, certificate.as_deref(), peer.ip(), stream);
+                    }
+                    open.lock().unwrap().remove(&(id as u64));
+                });
+            }
+        });
+        Ok(HttpServer { addr, stopped, connections, worker: Some(worker) })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn call(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String, String) {
+        call_as(addr, None, method, path, body)
+    }
+    fn call_as(addr: SocketAddr, token: Option<&str>, method: &str, path: &str, body: &str) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            authorization,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), head.to_owned(), body.to_owned())
+    }
+    fn serve(tag: &str, config: ServerConfig) -> (TempDir, HttpServer) {
+        let dir = TempDir::new(tag);
+        let store = KVStore::open(dir.path()).unwrap();
+        let server = store.serve_http(TcpListener::bind("127.0.0.1:0").unwrap(), config).unwrap();
+        (dir, server)
+    }
+    #[test]
+    fn buckets_burst_to_their_rate_and_refill_over_time() {
+        let start = Instant::now();
+        let limiter = ClientLimiter::new(&ServerConfig::new().rate_limit(RateLimit::ops(2)));
+        assert!(limiter.admit("a", 0, start).is_ok());
+        assert!(limiter.admit("a", 0, start).is_ok());
+        let throttled = limiter.admit("a", 0, start).unwrap_err();
+        assert_eq!(throttled.what, "operations");
+        assert_eq!(throttled.retry_after, Duration::from_millis(500));
+        assert!(limiter.admit("b", 0, start).is_ok(), "clients have buckets of their own");
+        assert!(limiter.admit("a", 0, start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.admit("a", 0, start + Duration::from_millis(500)).is_err());
+
+        let limiter = ClientLimiter::new(&ServerConfig::new().rate_limit(RateLimit::bytes(100)));
+        assert!(limiter.admit("a", 250, start).is_ok(), "an oversized request waits for a full bucket, not forever");
+        let throttled = limiter.admit("a", 50, start + Duration::from_secs(1)).unwrap_err();
+        assert_eq!((throttled.what, throttled.retry_after), ("bytes", Duration::from_secs(1)));
+        limiter.charge("a", 100, start + Duration::from_secs(3));
+        assert!(limiter.admit("a", 1, start + Duration::from_secs(3)).is_err(), "response bytes are billed too");
+    }
+    #[test]
+    fn http_frontend_reads_writes_and_scans() {
+        let (_dir, server) = serve("http_frontend", ServerConfig::new().grant_anonymous(&["user"]));
+        let addr = server.local_addr();
+        assert_eq!(call(addr, "PUT", "/v1/kv/user%3A1", "ada").0, 200);
+        assert_eq!(call(addr, "PUT", "/v1/kv/user%3A2", "grace").0, 200);
+        let (status, _, body) = call(addr, "GET", "/v1/kv/user%3A1", "");
+        assert_eq!((status, body.as_str()), (200, "ada"));
+        let (status, _, body) = call(addr, "GET", "/v1/scan?prefix=user%3A&limit=1", "");
+        assert_eq!(status, 200);
+        let page = Json::parse(&body).unwrap();
+        assert_eq!(page.path("$.entries.0.value").and_then(Json::as_str), Some("ada"));
+        let cursor = page.path("$.next").and_then(Json::as_str).unwrap().to_owned();
+        let (_, _, body) = call(addr, "GET", &format!("/v1/scan?prefix=user%3A&cursor={}", cursor), "");
+        assert_eq!(Json::parse(&body).unwrap().path("$.entries.0.key").and_then(Json::as_str), Some("user:2"));
+        assert_eq!(call(addr, "DELETE", "/v1/kv/user%3A1", "").0, 200);
+        assert_eq!(call(addr, "GET", "/v1/kv/user%3A1", "").0, 404);
+        assert_eq!(call(addr, "POST", "/v1/kv/user%3A1", "").0, 405);
+        assert_eq!(call(addr, "GET", "/v1/kv/bad%zz", "").0, 400);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /v1/kv/user%3A2 HTTP/1.1\r\n\r\nGET /v1/kv/user%3A2 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut both = String::new();
+        stream.read_to_string(&mut both).unwrap();
+        assert_eq!(both.matches("200 OK").count(), 2, "connections are kept alive between requests");
+    }
+    #[test]
+    fn clients_over_their_rate_limit_get_429_with_retry_after() {
+        let config = ServerConfig::new()
+            .grant_anonymous(&[""])
+            .rate_limit(RateLimit::ops(1000))
+            .rate_limit_for("127.0.0.1", RateLimit::ops(2));
+        let (_dir, server) = serve("http_rate_limit", config);
+        let addr = server.local_addr();
+        assert_eq!(call(addr, "PUT", "/v1/kv/k", "v").0, 200);
+        assert_eq!(call(addr, "GET", "/v1/kv/k", "").0, 200);
+        let (status, head, body) = call(addr, "GET", "/v1/kv/k", "");
+        assert_eq!(status, 429);
+        assert!(head.contains("Retry-After: 1"), "{}", head);
+        assert_eq!(body, "client 127.0.0.1 exceeded its 2 operations per second\n");
+    }
+    #[cfg(feature = "tls")]
+    #[test]
+    fn mutual_tls_clients_are_limited_by_their_certificate_name() {
+        let ca = tls::TestCa::new();
+        let config = ServerConfig::new()
+            .tls(ca.server("server", true))
+            .grant("alice", &["*"])
+            .grant("bob", &["*"])
+            .rate_limit_for("alice", RateLimit::ops(1));
+        let (_dir, server) = serve("https_rate_limit", config);
+        let get = |client: &TlsClient| -> Result<String> {
+            let mut stream = tls::over(Some(client), TcpStream::connect(server.local_addr())?)?;
+            stream.write_all(b"GET /v1/kv/k HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let alice = ca.client(Some("alice"));
+        assert!(get(&alice).unwrap().starts_with("HTTP/1.1 404"));
+        assert!(get(&alice).unwrap().contains("client alice exceeded its 1 operations per second"));
+        assert!(get(&ca.client(Some("bob"))).unwrap().starts_with("HTTP/1.1 404"));
+        assert!(get(&ca.client(None)).is_err(), "clients without a certificate are turned away");
+        assert!(get(&TlsClient { roots: Some(tls::TestCa::new().roots()), ..alice }).is_err());
+    }
+    fn base64url_encode(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut text = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                text.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        text
+    }
+    fn jwt(secret: &[u8], alg: &str, claims: &str) -> String {
+        let signed = format!("{}.{}", base64url_encode(format!(r#"{{"alg":"{}","typ":"JWT"}}"#, alg).as_bytes()), base64url_encode(claims.as_bytes()));
+        format!("{}.{}", signed, base64url_encode(&hmac_sha256(secret, signed.as_bytes())))
+    }
+    #[test]
+    fn jwts_must_be_hs256_signed_current_and_name_a_subject() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"\xff\xfe\xfd\xfc"] {
+            assert_eq!(base64url(&base64url_encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64url("not/base64"), None);
+        let token = jwt(b"secret", "HS256", r#"{"sub":"carol","exp":200,"nbf":100,"keyspaces":["users",7]}"#);
+        let (subject, keyspaces) = verify_jwt(b"secret", &token, 150).unwrap();
+        assert_eq!((subject.as_str(), keyspaces.into_iter().collect::<Vec<_>>()), ("carol", vec!["users".to_owned()]));
+        let rejected = |token: &str, now| verify_jwt(b"secret", token, now).unwrap_err();
+        assert_eq!(rejected(&token, 200), "token has expired");
+        assert_eq!(rejected(&token, 99), "token is not valid yet");
+        assert_eq!(rejected(&jwt(b"other", "HS256", r#"{"sub":"carol","exp":200}"#), 150), "token signature does not verify");
+        assert_eq!(rejected(&jwt(b"secret", "none", r#"{"sub":"carol","exp":200}"#), 150), "only HS256 tokens are accepted");
+        assert_eq!(rejected(&jwt(b"secret", "HS256", r#"{"sub":"carol"}"#), 150), "token has no exp claim");
+        assert_eq!(rejected(&jwt(b"secret", "HS256", r#"{"exp":200}"#), 150), "token has no sub claim");
+        let (head, _) = token.rsplit_once('.').unwrap();
+        assert_eq!(rejected(&format!("{}.", head), 150), "token signature does not verify");
+        assert_eq!(rejected("a.b", 150), "malformed token");
+    }
+    #[test]
+    fn bearer_credentials_map_to_principals_and_deny_keyspaces_by_default() {
+        let config = ServerConfig::new()
+            .token("ops-token", "ops")
+            .grant("ops", &["orders"])
+            .jwt_hs256(b"secret")
+            .grant("carol", &["users"]);
+        let (_dir, server) = serve("http_auth", config);
+        let addr = server.local_addr();
+        let (status, head, _) = call(addr, "GET", "/v1/kv/orders%3A1", "");
+        assert_eq!(status, 401);
+        assert!(head.contains("WWW-Authenticate: Bearer"), "{}", head);
+        assert_eq!(call_as(addr, Some("guess"), "GET", "/v1/kv/orders%3A1", "").0, 401);
+        assert_eq!(call_as(addr, Some("ops-token"), "PUT", "/v1/kv/orders%3A1", "open").0, 200);
+        let (status, _, body) = call_as(addr, Some("ops-token"), "GET", "/v1/kv/users%3A1", "");
+        assert_eq!((status, body.as_str()), (403, "ops may not access the \"users\" keyspace\n"));
+        assert_eq!(call_as(addr, Some("ops-token"), "GET", "/v1/scan?prefix=orders%3A", "").0, 200);
+        assert_eq!(call_as(addr, Some("ops-token"), "GET", "/v1/scan?prefix=ord", "").0, 403, "a bare prefix spans keyspaces");
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        let carol = jwt(b"secret", "HS256", &format!(r#"{{"sub":"carol","exp":{},"keyspaces":["billing"]}}"#, exp));
+        assert_eq!(call_as(addr, Some(&carol), "PUT", "/v1/kv/users%3A1", "carol").0, 200);
+        assert_eq!(call_as(addr, Some(&carol), "PUT", "/v1/kv/billing%3A1", "10").0, 200);
+        assert_eq!(call_as(addr, Some(&carol), "GET", "/v1/kv/orders%3A1", "").0, 403);
+        let expired = jwt(b"secret", "HS256", r#"{"sub":"carol","exp":1}"#);
+        let (status, _, body) = call_as(addr, Some(&expired), "GET", "/v1/kv/users%3A1", "");
+        assert_eq!((status, body.as_str()), (401, "token has expired\n"));
+    }
+}
@@ -0,0 +1,816 @@
+use crate::*;
+pub(crate) const MANIFEST: &str = "MANIFEST";
+pub(crate) const MANIFEST_MAGIC: &[u8; 8] = b"KVMANI01";
+#[derive(Debug, Default)]
+pub(crate) struct StoreManifest {
+    pub(crate) snapshots: BTreeSet<Version>,
+    pub(crate) segments: BTreeSet<Version>,
+    pub(crate) policies: BTreeMap<String, KeyspacePolicy>,
+    pub(crate) audit: bool,
+}
+impl StoreManifest {
+    pub(crate) fn scan(storage: &dyn Storage) -> Result<Self> {
+        Ok(StoreManifest {
+            snapshots: scan_files(storage, "snap")?.into_iter().map(|(v, _)| v).collect(),
+            segments: scan_files(storage, "wal")?.into_iter().map(|(v, _)| v).collect(),
+            policies: BTreeMap::new(),
+            audit: false,
+        })
+    }
+    pub(crate) fn load(storage: &dyn Storage) -> Result<Option<Self>> {
+        if !storage.list()?.iter().any(|name| name == MANIFEST) {
+            return Ok(None);
+        }
+        let bytes = storage.read(MANIFEST)?;
+        let corrupt = || KVError::Corruption("manifest is damaged".to_owned());
+        let (body, crc) = bytes.split_at_checked(bytes.len().saturating_sub(4)).ok_or_else(corrupt)?;
+        if !body.starts_with(MANIFEST_MAGIC) || crc32(body).to_le_bytes() != crc {
+            return Err(corrupt());
+        }
+        let mut d = Decoder::new(&body[MANIFEST_MAGIC.len()..]);
+        let mut manifest = StoreManifest::default();
+        for set in [&mut manifest.snapshots, &mut manifest.segments] {
+            for _ in 0..d.u32()? {
+                set.insert(d.u64()?);
+            }
+        }
+        if d.pos < d.buf.len() {
+            for _ in 0..d.u32()? {
+                let keyspace = d.string()?;
+                manifest.policies.insert(keyspace, KeyspacePolicy::decode(&mut d)?);
+            }
+        }
+        if d.pos < d.buf.len() {
+            for _ in 0..d.u32()? {
+                manifest.policies.entry(d.string()?).or_default().sensitive = true;
+            }
+        }
+        if d.pos < d.buf.len() {
+            manifest.audit = d.u8()? != 0;
+        }
+        Ok(Some(manifest))
+    }
+    pub(crate) fn read(storage: &dyn Storage) -> Result<Self> {
+        match Self::load(storage)? {
+            Some(manifest) => Ok(manifest),
+            None => Self::scan(storage),
+        }
+    }
+    pub(crate) fn save(&self, storage: &dyn Storage) -> Result<()> {
+        let mut buf = MANIFEST_MAGIC.to_vec();
+        for set in [&self.snapshots, &self.segments] {
+            put_u32(&mut buf, set.len() as u32);
+            for v in set {
+                put_u64(&mut buf, *v);
+            }
+        }
+        put_u32(&mut buf, self.policies.len() as u32);
+        for (keyspace, policy) in &self.policies {
+            put_str(&mut buf, keyspace);
+            policy.encode(&mut buf);
+        }
+        let sensitive: Vec<&String> = self.policies.iter().filter(|(_, policy)| policy.sensitive).map(|(keyspace, _)| keyspace).collect();
+        put_u32(&mut buf, sensitive.len() as u32);
+        for keyspace in sensitive {
+            put_str(&mut buf, keyspace);
+        }
+        buf.push(self.audit as u8);
+        buf.extend_from_slice(&crc32(&buf).to_le_bytes());
+        storage.write(MANIFEST, &buf)
+    }
+    pub(crate) fn files(&self, ext: &str) -> Vec<(Version, String)> {
+        match ext {
+            "snap" => self.snapshots.iter().map(|v| (*v, snapshot_name(*v))).collect(),
+            "wal" => self.segments.iter().map(|v| (*v, segment_name(*v))).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+pub(crate) const QUARANTINE: &str = "QUARANTINE";
+pub(crate) const QUARANTINE_MAGIC: &[u8; 8] = b"KVQUAR01";
+#[derive(Debug, Clone)]
+pub(crate) struct Quarantined {
+    pub(crate) version: Version,
+    pub(crate) reason: String,
+}
+pub(crate) type Quarantine = BTreeMap<String, Quarantined>;
+pub(crate) fn load_quarantine(storage: &dyn Storage) -> Result<Quarantine> {
+    if !storage.list()?.iter().any(|name| name == QUARANTINE) {
+        return Ok(Quarantine::new());
+    }
+    let bytes = storage.read(QUARANTINE)?;
+    let corrupt = || KVError::Corruption("quarantine list is damaged".to_owned());
+    let (body, crc) = bytes.split_at_checked(bytes.len().saturating_sub(4)).ok_or_else(corrupt)?;
+    if !body.starts_with(QUARANTINE_MAGIC) || crc32(body).to_le_bytes() != crc {
+        return Err(corrupt());
+    }
+    let mut d = Decoder::new(&body[QUARANTINE_MAGIC.len()..]);
+    let mut quarantine = Quarantine::new();
+    for _ in 0..d.u32()? {
+        let (key, version, reason) = (d.string()?, d.u64()?, d.string()?);
+        quarantine.insert(key, Quarantined { version, reason });
+    }
+    Ok(quarantine)
+}
+pub(crate) fn save_quarantine(storage: &dyn Storage, quarantine: &Quarantine) -> Result<()> {
+    if quarantine.is_empty() {
+        return match storage.list()?.iter().any(|name| name == QUARANTINE) {
+            true => storage.remove(QUARANTINE),
+            false => Ok(()),
+        };
+    }
+    let mut buf = QUARANTINE_MAGIC.to_vec();
+    put_u32(&mut buf, quarantine.len() as u32);
+    for (key, entry) in quarantine {
+        put_str(&mut buf, key);
+        put_u64(&mut buf, entry.version);
+        put_str(&mut buf, &entry.reason);
+    }
+    buf.extend_from_slice(&crc32(&buf).to_le_bytes());
+    storage.write(QUARANTINE, &buf)
+}
+pub(crate) fn snapshot_name(version: Version) -> String {
+    format!("{:020}.snap", version)
+}
+pub(crate) fn file_version(name: &str) -> Option<Version> {
+    name.rsplit('/').next()?.split('.').next()?.parse().ok()
+}
+pub(crate) fn storage_files(storage: &dyn Storage, ext: &str) -> Result<Vec<(Version, String)>> {
+    match StoreManifest::load(storage)? {
+        Some(manifest) => Ok(manifest.files(ext)),
+        None => scan_files(storage, ext),
+    }
+}
+pub(crate) fn scan_files(storage: &dyn Storage, ext: &str) -> Result<Vec<(Version, String)>> {
+    let mut files: Vec<(Version, String)> = storage
+        .list()?
+        .into_iter()
+        .filter(|name| Path::new(name).extension().is_some_and(|e| e == ext))
+        .filter_map(|name| Some((file_version(&name)?, name)))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+pub trait Storage: Send + Sync {
+    fn list(&self) -> Result<Vec<String>>;
+    fn read(&self, name: &str) -> Result<Vec<u8>>;
+    fn read_stream(&self, name: &str) -> Result<(u64, Box<dyn Read + '_>)> {
+        let bytes = self.read(name)?;
+        Ok((bytes.len() as u64, Box::new(io::Cursor::new(bytes))))
+    }
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()>;
+    fn append(&self, name: &str, bytes: &[u8]) -> Result<()>;
+    fn truncate(&self, name: &str, len: u64) -> Result<()>;
+    fn discard_tail(&self, name: &str, len: u64) -> Result<()> {
+        self.truncate(name, len)
+    }
+    fn remove(&self, name: &str) -> Result<()>;
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    fn recycle(&self, _retired: &str, _name: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+pub(crate) const LOCK_FILE: &str = "LOCK";
+pub(crate) fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err(KVError::Locked(dir.to_path_buf())),
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    #[default]
+    Fsync,
+    Buffered,
+}
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub(crate) mod uring {
+    use std::ffi::c_void;
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    extern "C" {
+        fn syscall(number: i64, ...) -> i64;
+        fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+        fn close(fd: i32) -> i32;
+    }
+    const SYS_IO_URING_SETUP: i64 = 425;
+    const SYS_IO_URING_ENTER: i64 = 426;
+    const OFF_SQ_RING: i64 = 0;
+    const OFF_CQ_RING: i64 = 0x800_0000;
+    const OFF_SQES: i64 = 0x1000_0000;
+    const OP_FSYNC: u8 = 3;
+    const OP_WRITE: u8 = 23;
+    const FSYNC_DATASYNC: u32 = 1;
+    const SQE_IO_LINK: u8 = 1 << 2;
+    const ENTER_GETEVENTS: u32 = 1;
+    const ENTRIES: u32 = 8;
+    const HEAD: usize = 0;
+    const TAIL: usize = 1;
+    const MASK: usize = 2;
+    const CQES: usize = 5;
+    const ARRAY: usize = 6;
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: [u32; 10],
+        cq_off: [u32; 10],
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        op_flags: u32,
+        user_data: u64,
+        buf_index: u16,
+        personality: u16,
+        splice_fd_in: i32,
+        addr3: u64,
+        pad: u64,
+    }
+    #[repr(C)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+    struct Map(*mut u8, usize);
+    impl Map {
+        fn new(fd: i32, len: usize, offset: i64) -> io::Result<Self> {
+            let addr = unsafe { mmap(ptr::null_mut(), len, 3, 0x8001, fd, offset) };
+            if addr as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Map(addr.cast(), len))
+        }
+        fn at<T>(&self, offset: u32) -> *mut T {
+            unsafe { self.0.add(offset as usize).cast() }
+        }
+    }
+    impl Drop for Map {
+        fn drop(&mut self) {
+            unsafe { munmap(self.0.cast(), self.1) };
+        }
+    }
+    pub struct Ring {
+        fd: i32,
+        params: Params,
+        sq: Map,
+        cq: Map,
+        sqes: Map,
+    }
+    unsafe impl Send for Ring {}
+    impl Ring {
+        pub fn new() -> io::Result<Self> {
+            let mut params = Params::default();
+            let fd = unsafe { syscall(SYS_IO_URING_SETUP, ENTRIES, &mut params as *mut Params) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = fd as i32;
+            let maps = (|| {
+                let sq = Map::new(fd, (params.sq_off[ARRAY] + params.sq_entries * 4) as usize, OFF_SQ_RING)?;
+                let cq = Map::new(fd, (params.cq_off[CQES] + params.cq_entries * 16) as usize, OFF_CQ_RING)?;
+                let sqes = Map::new(fd, params.sq_entries as usize * std::mem::size_of::<Sqe>(), OFF_SQES)?;
+                Ok((sq, cq, sqes))
+            })();
+            match maps {
+                Ok((sq, cq, sqes)) => Ok(Ring { fd, params, sq, cq, sqes }),
+                Err(e) => {
+                    unsafe { close(fd) };
+                    Err(e)
+                }
+            }
+        }
+        fn counter(map: &Map, offset: u32) -> &AtomicU32 {
+            unsafe { &*map.at::<AtomicU32>(offset) }
+        }
+        fn push(&mut self, sqe: Sqe) {
+            let mask = unsafe { *self.sq.at::<u32>(self.params.sq_off[MASK]) };
+            let tail = Self::counter(&self.sq, self.params.sq_off[TAIL]).load(Ordering::Relaxed);
+            let index = tail & mask;
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(index * std::mem::size_of::<Sqe>() as u32), sqe);
+                *self.sq.at::<u32>(self.params.sq_off[ARRAY]).add(index as usize) = index;
+            }
+            Self::counter(&self.sq, self.params.sq_off[TAIL]).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        fn reap(&mut self) -> (u64, i32) {
+            let mask = unsafe { *self.cq.at::<u32>(self.params.cq_off[MASK]) };
+            let head = Self::counter(&self.cq, self.params.cq_off[HEAD]).load(Ordering::Acquire);
+            let cqe = unsafe { &*self.cq.at::<Cqe>(self.params.cq_off[CQES]).add((head & mask) as usize) };
+            let (user_data, res) = (cqe.user_data, cqe.res);
+            Self::counter(&self.cq, self.params.cq_off[HEAD]).store(head.wrapping_add(1), Ordering::Release);
+            (user_data, res)
+        }
+        pub fn write_sync(&mut self, file: &mut File, bytes: &[u8]) -> io::Result<()> {
+            let fd = file.as_raw_fd();
+            let len = bytes.len().min(u32::MAX as usize);
+            self.push(Sqe {
+                opcode: OP_WRITE,
+                flags: SQE_IO_LINK,
+                fd,
+                off: u64::MAX,
+                addr: bytes.as_ptr() as u64,
+                len: len as u32,
+                user_data: 0,
+                ..Sqe::default()
+            });
+            self.push(Sqe { opcode: OP_FSYNC, fd, op_flags: FSYNC_DATASYNC, user_data: 1, ..Sqe::default() });
+            while unsafe { syscall(SYS_IO_URING_ENTER, self.fd, 2u32, 2u32, ENTER_GETEVENTS, 0usize, 0usize) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            let mut written = Ok(0);
+            let mut synced = Ok(());
+            for _ in 0..2 {
+                match self.reap() {
+                    (0, res) if res < 0 => written = Err(io::Error::from_raw_os_error(-res)),
+                    (0, res) => written = Ok(res as usize),
+                    (_, res) if res < 0 => synced = Err(io::Error::from_raw_os_error(-res)),
+                    _ => {}
+                }
+            }
+            let written = written?;
+            if written < bytes.len() {
+                file.write_all(&bytes[written..])?;
+                return file.sync_data();
+            }
+            synced
+        }
+    }
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            unsafe { close(self.fd) };
+        }
+    }
+}
+pub struct FsStorage {
+    pub(crate) dir: PathBuf,
+    pub(crate) appender: Mutex<Option<(String, File, bool)>>,
+    pub(crate) durability: Durability,
+    pub(crate) direct_io: bool,
+    pub(crate) preallocate: u64,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) ring: Option<Mutex<uring::Ring>>,
+}
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+    const EOPNOTSUPP: i32 = 95;
+    extern "C" {
+        fn fallocate(fd: i32, mode: i32, offset: i64, len: i64) -> i32;
+    }
+    if unsafe { fallocate(file.as_raw_fd(), FALLOC_FL_KEEP_SIZE, 0, len as i64) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(EOPNOTSUPP) => Ok(()),
+        e => Err(e),
+    }
+}
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(_: &File, _: u64) -> io::Result<()> {
+    Ok(())
+}
+#[cfg(all(target_os = "linux", any(target_arch = "aarch64", target_arch = "arm")))]
+pub(crate) const O_DIRECT: i32 = 0o200000;
+#[cfg(all(target_os = "linux", not(any(target_arch = "aarch64", target_arch = "arm"))))]
+pub(crate) const O_DIRECT: i32 = 0o40000;
+#[cfg(target_os = "linux")]
+pub(crate) fn write_direct(path: &Path, bytes: &[u8]) -> io::Result<bool> {
+    use std::os::unix::fs::OpenOptionsExt;
+    const DIRECT_ALIGN: usize = 4096;
+    const DIRECT_CHUNK: usize = 1 << 20;
+    let mut file = match OpenOptions::new().write(true).create(true).truncate(true).custom_flags(O_DIRECT).open(path) {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(22) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut raw = vec![0u8; DIRECT_CHUNK + DIRECT_ALIGN];
+    let offset = raw.as_ptr().align_offset(DIRECT_ALIGN);
+    let buffer = &mut raw[offset..offset + DIRECT_CHUNK];
+    for chunk in bytes.chunks(DIRECT_CHUNK) {
+        let len = chunk.len().next_multiple_of(DIRECT_ALIGN);
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        buffer[chunk.len()..len].fill(0);
+        file.write_all(&buffer[..len])?;
+    }
+    file.set_len(bytes.len() as u64)?;
+    file.sync_all()?;
+    Ok(true)
+}
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn write_direct(_: &Path, _: &[u8]) -> io::Result<bool> {
+    Ok(false)
+}
+impl FsStorage {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self::with_durability(dir, Durability::Fsync)
+    }
+    pub fn with_durability<P: AsRef<Path>>(dir: P, durability: Durability) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            appender: Mutex::new(None),
+            durability,
+            direct_io: false,
+            preallocate: 0,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring: uring::Ring::new().ok().map(Mutex::new),
+        }
+    }
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+    pub fn preallocate(mut self, bytes: u64) -> Self {
+        self.preallocate = bytes;
+        self
+    }
+    pub(crate) fn close_appender(&self, name: &str) -> std::sync::MutexGuard<'_, Option<(String, File, bool)>> {
+        let mut appender = self.appender.lock().unwrap();
+        if appender.as_ref().is_some_and(|(open, _, _)| open == name) {
+            *appender = None;
+        }
+        appender
+    }
+}
+impl Storage for FsStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.dir.join(name))?)
+    }
+    fn read_stream(&self, name: &str) -> Result<(u64, Box<dyn Read + '_>)> {
+        let file = File::open(self.dir.join(name))?;
+        Ok((file.metadata()?.len(), Box::new(file)))
+    }
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let _appender = self.close_appender(name);
+        static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{}.{}.tmp", name, TMP_SEQ.fetch_add(1, Ordering::Relaxed)));
+        let written = (|| -> Result<()> {
+            if !(self.direct_io && write_direct(&tmp, bytes)?) {
+                let mut file = File::create(&tmp)?;
+                file.write_all(bytes)?;
+                file.sync_all()?;
+            }
+            Ok(fs::rename(&tmp, &path)?)
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written?;
+        sync_dir(&self.dir)
+    }
+    fn append(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let mut appender = self.appender.lock().unwrap();
+        if appender.as_ref().is_none_or(|(open, _, _)| open != name) {
+            let path = self.dir.join(name);
+            let created = !path.exists();
+            let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path)?;
+            let mut scrub = false;
+            if created {
+                if name.ends_with(".wal") && self.preallocate > 0 {
+                    preallocate(&file, self.preallocate)?;
+                }
+                sync_dir(&self.dir)?;
+            } else {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                // A segment whose tail was discarded or recycled ends at its first zero frame header, not at EOF.
+                let end = match name.ends_with(".wal") && bytes.starts_with(WAL_MAGIC) {
+                    true => segment_frames(&bytes).1,
+                    false => bytes.len(),
+                };
+                scrub = end < bytes.len();
+                file.seek(SeekFrom::Start(end as u64))?;
+            }
+            *appender = Some((name.to_owned(), file, scrub));
+        }
+        let (_, file, scrub) = appender.as_mut().unwrap();
+        let framed;
+        let bytes = if *scrub {
+            framed = [bytes, &[0; 8]].concat();
+            &framed[..]
+        } else {
+            bytes
+        };
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let synced = match (&self.ring, self.durability) {
+            (Some(ring), Durability::Fsync) => {
+                ring.lock().unwrap().write_sync(file, bytes)?;
+                true
+            }
+            _ => false,
+        };
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let synced = false;
+        if !synced {
+            file.write_all(bytes)?;
+            if self.durability == Durability::Fsync {
+                file.sync_data()?;
+            }
+        }
+        if *scrub {
+            file.seek(SeekFrom::Current(-8))?;
+        }
+        Ok(())
+    }
+    fn truncate(&self, name: &str, len: u64) -> Result<()> {
+        let _appender = self.close_appender(name);
+        OpenOptions::new().write(true).open(self.dir.join(name))?.set_len(len)?;
+        Ok(())
+    }
+    fn discard_tail(&self, name: &str, len: u64) -> Result<()> {
+        let _appender = self.close_appender(name);
+        let mut file = OpenOptions::new().write(true).open(self.dir.join(name))?;
+        file.seek(SeekFrom::Start(len))?;
+        file.write_all(&[0; 8])?;
+        Ok(file.sync_data()?)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        let _appender = self.close_appender(name);
+        fs::remove_file(self.dir.join(name))?;
+        sync_dir(&self.dir)
+    }
+    fn flush(&self) -> Result<()> {
+        if let Some((_, file, _)) = self.appender.lock().unwrap().take() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+    fn recycle(&self, retired: &str, name: &str) -> Result<bool> {
+        let mut appender = self.close_appender(retired);
+        let path = self.dir.join(name);
+        if path.exists() {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new().write(true).open(self.dir.join(retired))?;
+        file.write_all(&[&WAL_MAGIC[..], &[0; 8]].concat())?;
+        file.sync_data()?;
+        fs::rename(self.dir.join(retired), &path)?;
+        sync_dir(&self.dir)?;
+        file.seek(SeekFrom::Start(0))?;
+        *appender = Some((name.to_owned(), file, true));
+        Ok(true)
+    }
+}
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub(crate) files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Storage for MemoryStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_owned()).into())
+    }
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.files.lock().unwrap().insert(name.to_owned(), bytes.to_vec());
+        Ok(())
+    }
+    fn append(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.files.lock().unwrap().entry(name.to_owned()).or_default().extend_from_slice(bytes);
+        Ok(())
+    }
+    fn truncate(&self, name: &str, len: u64) -> Result<()> {
+        if let Some(file) = self.files.lock().unwrap().get_mut(name) {
+            file.truncate(len as usize);
+        }
+        Ok(())
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        self.files.lock().unwrap().remove(name);
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn a_directory_opens_for_writing_once_at_a_time() {
+        let dir = TempDir::new("lock");
+        let store = KVStore::open(dir.path()).unwrap();
+        match KVStore::open(dir.path()) {
+            Err(KVError::Locked(path)) => assert_eq!(path, dir.path()),
+            other => panic!("expected the directory to be locked, got {:?}", other.map(|_| ())),
+        }
+        assert!(KVStore::open_read_only(dir.path()).is_ok());
+        drop(store);
+        let store = KVStore::open(dir.path()).unwrap();
+        store.close().unwrap();
+        assert!(KVStore::open(dir.path()).is_ok());
+    }
+    #[test]
+    fn the_manifest_decides_which_files_a_reopen_trusts() {
+        let dir = TempDir::new("manifest");
+        let put = |store: &Arc<KVStore>, key: &str, value: &str| {
+            let mut tx = store.begin();
+            tx.put(key.to_owned(), value.to_owned()).unwrap();
+            tx.commit().unwrap()
+        };
+        let checkpoint = {
+            let store = KVStore::open(dir.path()).unwrap();
+            put(&store, "a", "1");
+            let checkpoint = store.checkpoint().unwrap();
+            put(&store, "b", "2");
+            checkpoint
+        };
+        let storage = FsStorage::new(dir.path());
+        let manifest = StoreManifest::load(&storage).unwrap().unwrap();
+        assert_eq!(manifest.snapshots, BTreeSet::from([checkpoint]));
+        assert!(manifest.segments.contains(&0) && manifest.segments.contains(&checkpoint));
+        // A snapshot the manifest never recorded, as left by a crash between writing it and saving the manifest.
+        let stray = snapshot_name(checkpoint + 100);
+        fs::copy(dir.path().join(snapshot_name(checkpoint)), dir.path().join(&stray)).unwrap();
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let tx = store.begin();
+            assert_eq!((tx.get("a").unwrap().as_deref(), tx.get("b").unwrap().as_deref()), (Some("1"), Some("2")));
+            assert_eq!(store.remove_obsolete_files().unwrap(), vec![stray.clone()]);
+        }
+        assert!(!dir.path().join(&stray).exists());
+        fs::remove_file(dir.path().join(snapshot_name(checkpoint))).unwrap();
+        match KVStore::open(dir.path()) {
+            Err(KVError::Corruption(reason)) => assert!(reason.contains(&snapshot_name(checkpoint)), "{}", reason),
+            other => panic!("expected a missing-file error, got {:?}", other.map(|_| ())),
+        }
+        let mut bytes = fs::read(dir.path().join(MANIFEST)).unwrap();
+        bytes[MANIFEST_MAGIC.len()] ^= 1;
+        fs::write(dir.path().join(MANIFEST), bytes).unwrap();
+        assert!(matches!(KVStore::open(dir.path()), Err(KVError::Corruption(reason)) if reason.contains("manifest")));
+    }
+    #[test]
+    fn stores_over_a_pluggable_backend_reopen_from_its_files() {
+        let storage = Arc::new(MemoryStorage::new());
+        let put = |store: &Arc<KVStore>, key: &str, value: &str| {
+            let mut tx = store.begin();
+            tx.put(key.to_owned(), value.to_owned()).unwrap();
+            tx.commit().unwrap()
+        };
+        {
+            let store = KVStore::open_storage(Arc::clone(&storage) as Arc<dyn Storage>).unwrap();
+            put(&store, "ks:a", "1");
+            store.checkpoint().unwrap();
+            put(&store, "ks:b", "2");
+        }
+        let names = storage.list().unwrap();
+        assert!(names.contains(&MANIFEST.to_owned()) && names.iter().any(|name| name.ends_with(".snap")));
+        let segment = storage_files(storage.as_ref(), "wal").unwrap().pop().unwrap().1;
+        storage.append(&segment, b"torn").unwrap();
+        let store = KVStore::open_storage(Arc::clone(&storage) as Arc<dyn Storage>).unwrap();
+        let tx = store.begin();
+        assert_eq!((tx.get("ks:a").unwrap().as_deref(), tx.get("ks:b").unwrap().as_deref()), (Some("1"), Some("2")));
+        let version = put(&store, "ks:c", "3");
+        drop(tx);
+        drop(store);
+        let store = KVStore::open_storage(storage).unwrap();
+        assert_eq!(store.current_version(), version);
+        assert_eq!(store.begin().get("ks:c").unwrap().as_deref(), Some("3"));
+    }
+    #[test]
+    fn concurrent_writes_of_sibling_files_do_not_share_a_temp_file() {
+        let dir = TempDir::new("tmp-names");
+        let storage = Arc::new(FsStorage::new(dir.path()));
+        let writers: Vec<_> = ["7.snap", "7.wal"]
+            .into_iter()
+            .map(|name| {
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        storage.write(name, format!("{}-{}", name, i).as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(storage.read("7.snap").unwrap(), b"7.snap-199");
+        assert_eq!(storage.read("7.wal").unwrap(), b"7.wal-199");
+        assert!(storage.list().unwrap().iter().all(|name| !name.ends_with(".tmp")));
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn ring_appends_land_in_order_and_survive_a_reopen() {
+        let dir = TempDir::new("uring");
+        let storage = FsStorage::new(dir.path());
+        for i in 0..100 {
+            storage.append("log", format!("{:03},", i).as_bytes()).unwrap();
+        }
+        let expected: String = (0..100).map(|i| format!("{:03},", i)).collect();
+        assert_eq!(storage.read("log").unwrap(), expected.as_bytes());
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            for i in 0..20 {
+                let mut tx = store.begin();
+                tx.put(format!("k{}", i), i.to_string()).unwrap();
+                tx.commit().unwrap();
+            }
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let tx = store.begin();
+        assert!((0..20).all(|i| tx.get(&format!("k{}", i)).unwrap() == Some(i.to_string())));
+    }
+    #[test]
+    fn direct_writes_round_trip_unaligned_lengths() {
+        let dir = TempDir::new("direct");
+        let storage = FsStorage::new(dir.path()).direct_io(true);
+        for len in [0, 1, 4095, 4096, 4097, (1 << 20) + 3] {
+            let bytes: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            storage.write("1.snap", &bytes).unwrap();
+            assert_eq!(storage.read("1.snap").unwrap(), bytes, "length {}", len);
+        }
+        assert!(storage.list().unwrap().iter().all(|name| !name.ends_with(".tmp")));
+        let dir = TempDir::new("direct-store");
+        {
+            let store = KVStore::builder().path(dir.path()).direct_io(true).open().unwrap();
+            let mut tx = store.begin();
+            tx.put("a".to_owned(), "1".repeat(5000)).unwrap();
+            tx.commit().unwrap();
+            store.checkpoint().unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.begin().get("a").unwrap(), Some("1".repeat(5000)));
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn preallocated_segments_survive_reopens_and_torn_tails() {
+        use std::os::unix::fs::MetadataExt;
+        const PREALLOCATE: u64 = 1 << 20;
+        let dir = TempDir::new("prealloc");
+        let open = || KVStore::builder().path(dir.path()).wal_preallocate(PREALLOCATE).open().unwrap();
+        let put = |store: &Arc<KVStore>, key: &str| {
+            let mut tx = store.begin();
+            tx.put(key.to_owned(), "v".to_owned()).unwrap();
+            tx.commit().unwrap();
+        };
+        let segment = || {
+            let (_, name) = storage_files(&FsStorage::new(dir.path()), "wal").unwrap().pop().unwrap();
+            dir.path().join(name)
+        };
+        put(&open(), "a");
+        let meta = fs::metadata(segment()).unwrap();
+        assert!(meta.len() < PREALLOCATE, "preallocation must not change the logical size");
+        let reserved = meta.blocks() * 512;
+        assert!(reserved >= PREALLOCATE);
+        OpenOptions::new().append(true).open(segment()).unwrap().write_all(b"torn record").unwrap();
+        {
+            let store = open();
+            assert_eq!(store.begin().get("a").unwrap().as_deref(), Some("v"));
+            put(&store, "b");
+        }
+        assert_eq!(fs::metadata(segment()).unwrap().blocks() * 512, reserved);
+        let store = open();
+        assert_eq!(store.begin().get("a").unwrap().as_deref(), Some("v"));
+        assert_eq!(store.begin().get("b").unwrap().as_deref(), Some("v"));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::*;
+pub(crate) trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+/// A PEM certificate chain and the PEM private key it certifies.
+#[derive(Clone, Default)]
+pub struct TlsIdentity {
+    pub cert_chain: Vec<u8>,
+    pub key: Vec<u8>,
+}
+impl fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsIdentity").field("cert_chain", &String::from_utf8_lossy(&self.cert_chain)).finish_non_exhaustive()
+    }
+}
+/// How an outgoing connection checks the server's certificate. Without `roots` the bundled Mozilla root set is used;
+/// with them only certificates chaining to those PEM roots are accepted. The certificate must name the dialed host,
+/// or `server_name` when set, and `identity` is presented to servers that ask for a client certificate. Needs the
+/// `tls` feature.
+#[derive(Debug, Clone, Default)]
+pub struct TlsClient {
+    pub roots: Option<Vec<u8>>,
+    pub identity: Option<TlsIdentity>,
+    pub server_name: Option<String>,
+}
+/// How one of the store's listeners speaks TLS. With `client_roots` it is mutual TLS: clients must present a
+/// certificate chaining to those PEM roots, and its subject common name becomes the client's principal. Needs the
+/// `tls` feature.
+#[derive(Debug, Clone, Default)]
+pub struct TlsServer {
+    pub identity: TlsIdentity,
+    pub client_roots: Option<Vec<u8>>,
+}
+/// TLS for the links between cluster peers: each node listens with `server` and dials the others with `client`.
+#[derive(Debug, Clone, Default)]
+pub struct PeerTls {
+    pub server: TlsServer,
+    pub client: TlsClient,
+}
+#[cfg(feature = "tls")]
+mod imp {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::server::WebPkiClientVerifier;
+    pub(crate) fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+    pub(crate) fn tls_error(e: impl fmt::Display) -> KVError {
+        KVError::InvalidInput(format!("tls: {}", e))
+    }
+    pub(crate) fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+        let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<io::Result<Vec<_>>>()?;
+        match certs.is_empty() {
+            true => Err(tls_error("no certificates in the PEM input")),
+            false => Ok(certs),
+        }
+    }
+    pub(crate) fn root_store(pem: Option<&[u8]>) -> Result<rustls::RootCertStore> {
+        let mut roots = rustls::RootCertStore::empty();
+        match pem {
+            Some(pem) => {
+                for cert in certificates(pem)? {
+                    roots.add(cert).map_err(tls_error)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        Ok(roots)
+    }
+    fn identity(identity: &TlsIdentity) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let key = rustls_pemfile::private_key(&mut &identity.key[..])?.ok_or_else(|| tls_error("no private key in the PEM input"))?;
+        Ok((certificates(&identity.cert_chain)?, key))
+    }
+    pub(crate) fn connect(config: &TlsClient, server: &str, tcp: TcpStream) -> Result<Box<dyn Stream>> {
+        let client = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(root_store(config.roots.as_deref())?);
+        let client = match &config.identity {
+            Some(id) => {
+                let (certs, key) = identity(id)?;
+                client.with_client_auth_cert(certs, key).map_err(tls_error)?
+            }
+            None => client.with_no_client_auth(),
+        };
+        let name = ServerName::try_from(config.server_name.as_deref().unwrap_or(server).to_owned()).map_err(tls_error)?;
+        let connection = rustls::ClientConnection::new(Arc::new(client), name).map_err(tls_error)?;
+        Ok(Box::new(rustls::StreamOwned::new(connection, tcp)))
+    }
+    /// A listener's half of [`TlsServer`], built once so every connection shares the parsed certificates.
+    pub(crate) struct Acceptor(Arc<rustls::ServerConfig>);
+    impl Acceptor {
+        pub(crate) fn new(config: &TlsServer) -> Result<Acceptor> {
+            let server = rustls::ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions().map_err(tls_error)?;
+            let server = match &config.client_roots {
+                Some(pem) => {
+                    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(Some(pem))?), provider())
+                        .build()
+                        .map_err(tls_error)?;
+                    server.with_client_cert_verifier(verifier)
+                }
+                None => server.with_no_client_auth(),
+            };
+            let (certs, key) = identity(&config.identity)?;
+            Ok(Acceptor(Arc::new(server.with_single_cert(certs, key).map_err(tls_error)?)))
+        }
+        /// Runs the handshake, returning the stream and the client certificate's common name if one was presented.
+        pub(crate) fn accept(&self, mut tcp: TcpStream) -> Result<(Box<dyn Stream>, Option<String>)> {
+            let mut connection = rustls::ServerConnection::new(Arc::clone(&self.0)).map_err(tls_error)?;
+            while connection.is_handshaking() {
+                connection.complete_io(&mut tcp)?;
+            }
+            let principal = connection.peer_certificates().and_then(|chain| common_name(chain.first()?));
+            Ok((Box::new(ServerStream(rustls::StreamOwned::new(connection, tcp))), principal))
+        }
+    }
+    /// Sends close_notify when dropped, so clients can tell a finished response from a cut connection.
+    struct ServerStream(rustls::StreamOwned<rustls::ServerConnection, TcpStream>);
+    impl Read for ServerStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for ServerStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+    impl Drop for ServerStream {
+        fn drop(&mut self) {
+            let rustls::StreamOwned { conn, sock } = &mut self.0;
+            conn.send_close_notify();
+            while conn.wants_write() && conn.write_tls(sock).is_ok() {}
+        }
+    }
+    /// Splits one DER element off `der` into its tag, its contents and whatever follows it.
+    fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, rest) = der.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let mut len = len as usize;
+        if len >= 0x80 {
+            let octets = len & 0x7f;
+            if octets == 0 || octets > 4 || rest.len() < octets {
+                return None;
+            }
+            len = rest[..octets].iter().fold(0, |len, &byte| len << 8 | byte as usize);
+            rest = &rest[octets..];
+        }
+        (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+    }
+    /// The subject common name of a DER certificate.
+    pub(crate) fn common_name(cert: &[u8]) -> Option<String> {
+        let (_, certificate, _) = der_element(cert)?;
+        let (_, mut tbs, _) = der_element(certificate)?;
+        let mut fields = Vec::new();
+        while let Some((tag, contents, rest)) = der_element(tbs).filter(|_| fields.len() < 6) {
+            fields.push((tag, contents));
+            tbs = rest;
+        }
+        // The explicit version is optional; after it come serial, signature, issuer, validity and then subject.
+        let explicit_version = fields.first()?.0 == 0xa0;
+        let (_, mut names) = *fields.get(4 + explicit_version as usize)?;
+        while let Some((_, mut set, rest)) = der_element(names) {
+            while let Some((_, attribute, more)) = der_element(set) {
+                let (tag, oid, value) = der_element(attribute)?;
+                if tag == 0x06 && oid == [0x55, 0x04, 0x03] {
+                    let (_, name, _) = der_element(value)?;
+                    return String::from_utf8(name.to_vec()).ok();
+                }
+                set = more;
+            }
+            names = rest;
+        }
+        None
+    }
+}
+#[cfg(feature = "tls")]
+pub(crate) use imp::*;
+#[cfg(not(feature = "tls"))]
+pub(crate) fn connect(_: &TlsClient, server: &str, _: TcpStream) -> Result<Box<dyn Stream>> {
+    Err(KVError::InvalidInput(format!("connecting to {} over tls needs kvstore built with the tls feature", server)))
+}
+#[cfg(not(feature = "tls"))]
+pub(crate) enum Acceptor {}
+#[cfg(not(feature = "tls"))]
+impl Acceptor {
+    pub(crate) fn new(_: &TlsServer) -> Result<Acceptor> {
+        Err(KVError::InvalidInput("serving tls needs kvstore built with the tls feature".to_owned()))
+    }
+    pub(crate) fn accept(&self, _: TcpStream) -> Result<(Box<dyn Stream>, Option<String>)> {
+        match *self {}
+    }
+}
+/// Wraps an accepted connection in TLS when the listener has an acceptor.
+pub(crate) fn accept(acceptor: Option<&Acceptor>, tcp: TcpStream) -> Result<(Box<dyn Stream>, Option<String>)> {
+    match acceptor {
+        Some(acceptor) => acceptor.accept(tcp),
+        None => Ok((Box::new(tcp), None)),
+    }
+}
+/// Wraps a dialed connection in TLS when `tls` is set, checking the certificate against the peer's IP address.
+pub(crate) fn over(tls: Option<&TlsClient>, tcp: TcpStream) -> Result<Box<dyn Stream>> {
+    match tls {
+        Some(tls) => {
+            let peer = tcp.peer_addr()?.ip().to_string();
+            connect(tls, &peer, tcp)
+        }
+        None => Ok(Box::new(tcp)),
+    }
+}
+/// Splits `host:port` (or `[v6]:port`) into the name to verify and the address to dial, filling in `default_port`.
+pub(crate) fn authority(authority: &str, default_port: u16) -> (&str, String) {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') && port.parse::<u16>().is_ok() => (host, None),
+        _ => (authority, Some(default_port)),
+    };
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    match port {
+        Some(port) => (name, format!("{}:{}", host, port)),
+        None => (name, authority.to_owned()),
+    }
+}
+/// A throwaway certificate authority for tests that need TLS peers.
+#[cfg(all(test, feature = "tls"))]
+pub(crate) struct TestCa {
+    cert: rcgen::Certificate,
+    key: rcgen::KeyPair,
+}
+#[cfg(all(test, feature = "tls"))]
+impl TestCa {
+    pub(crate) fn new() -> Self {
+        let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "kvstore test ca");
+        let key = rcgen::KeyPair::generate().unwrap();
+        TestCa { cert: params.self_signed(&key).unwrap(), key }
+    }
+    pub(crate) fn roots(&self) -> Vec<u8> {
+        self.cert.pem().into_bytes()
+    }
+    /// A certificate for `name` that is valid for 127.0.0.1 as both a server and a client.
+    pub(crate) fn issue(&self, name: &str) -> TlsIdentity {
+        let mut params = rcgen::CertificateParams::new(vec!["127.0.0.1".to_owned()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth, rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        TlsIdentity { cert_chain: cert.pem().into_bytes(), key: key.serialize_pem().into_bytes() }
+    }
+    pub(crate) fn server(&self, name: &str, mutual: bool) -> TlsServer {
+        TlsServer { identity: self.issue(name), client_roots: mutual.then(|| self.roots()) }
+    }
+    pub(crate) fn client(&self, name: Option<&str>) -> TlsClient {
+        TlsClient { roots: Some(self.roots()), identity: name.map(|name| self.issue(name)), server_name: None }
+    }
+}
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn client_certificates_name_their_principal() {
+        let ca = TestCa::new();
+        let alice = ca.issue("alice");
+        let der = certificates(&alice.cert_chain).unwrap();
+        assert_eq!(common_name(&der[0]).as_deref(), Some("alice"));
+        assert_eq!(common_name(&der[0][..40]), None);
+        let acceptor = Arc::new(Acceptor::new(&ca.server("server", true)).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = thread::spawn(move || {
+            (0..2).map(|_| acceptor.accept(listener.accept().unwrap().0).map(|(_, principal)| principal)).collect::<Vec<_>>()
+        });
+        let mut stream = over(Some(&ca.client(Some("alice"))), TcpStream::connect(addr).unwrap()).unwrap();
+        stream.flush().unwrap();
+        let mut anonymous = over(Some(&ca.client(None)), TcpStream::connect(addr).unwrap()).unwrap();
+        let _ = anonymous.write_all(b"x").and_then(|_| anonymous.flush());
+        let accepted = accepted.join().unwrap();
+        assert_eq!(accepted[0].as_ref().unwrap().as_deref(), Some("alice"));
+        assert!(accepted[1].is_err(), "mutual tls turns away clients without a certificate");
+    }
+}
@@ -0,0 +1,1029 @@
+use crate::*;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    Absent,
+    Equals(String),
+    VersionIs(Version),
+}
+impl Precondition {
+    pub(crate) fn encode(&self) -> String {
+        match self {
+            Precondition::Absent => "a".to_owned(),
+            Precondition::Equals(value) => format!("={}", value),
+            Precondition::VersionIs(version) => format!("@{}", version),
+        }
+    }
+    pub(crate) fn decode(encoded: &str) -> Option<Self> {
+        match encoded.split_at_checked(1)? {
+            ("a", "") => Some(Precondition::Absent),
+            ("=", value) => Some(Precondition::Equals(value.to_owned())),
+            ("@", version) => version.parse().ok().map(Precondition::VersionIs),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, PartialEq, Eq)]
+pub struct BrokenPrecondition {
+    pub key: String,
+    pub expected: Precondition,
+    pub value: Option<String>,
+    pub version: Version,
+    pub(crate) policies: Policies,
+}
+impl fmt::Debug for BrokenPrecondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sensitive = self.policies.is_sensitive(&self.key);
+        let mut s = f.debug_struct("BrokenPrecondition");
+        s.field("key", &self.key);
+        match &self.expected {
+            Precondition::Equals(value) => s.field("expected", &format_args!("Equals({:?})", RedactedValue(sensitive, value))),
+            expected => s.field("expected", expected),
+        };
+        s.field("value", &redacted(&self.policies, &self.key, self.value.as_deref())).field("version", &self.version).finish()
+    }
+}
+impl fmt::Display for BrokenPrecondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sensitive = self.policies.is_sensitive(&self.key);
+        match &self.expected {
+            Precondition::Absent => write!(f, "{:?} should be absent", self.key)?,
+            Precondition::Equals(value) => write!(f, "{:?} should equal {:?}", self.key, RedactedValue(sensitive, value))?,
+            Precondition::VersionIs(version) => write!(f, "{:?} should be at version {}", self.key, version)?,
+        }
+        match &self.value {
+            Some(value) => write!(f, " but holds {:?} from version {}", RedactedValue(sensitive, value), self.version),
+            None if self.version == 0 => write!(f, " but was never written"),
+            None => write!(f, " but was deleted at version {}", self.version),
+        }
+    }
+}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CommitMeta {
+    pub(crate) idempotency: Option<String>,
+    pub(crate) expected: Vec<(String, Precondition)>,
+    pub(crate) actor: Option<String>,
+    pub(crate) txn: Option<u64>,
+    pub(crate) source: Option<(String, u64)>,
+}
+impl CommitMeta {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        if let Some(token) = &self.idempotency {
+            buf.push(1);
+            put_str(buf, token);
+        }
+        for (key, precondition) in &self.expected {
+            buf.push(2);
+            put_str(buf, key);
+            put_str(buf, &precondition.encode());
+        }
+        if let Some(actor) = &self.actor {
+            buf.push(3);
+            put_str(buf, actor);
+        }
+        if let Some(txn) = self.txn {
+            buf.push(4);
+            put_u64(buf, txn);
+        }
+        if let Some((source, seq)) = &self.source {
+            buf.push(5);
+            put_str(buf, source);
+            put_u64(buf, *seq);
+        }
+    }
+    pub(crate) fn decode(d: &mut Decoder) -> Result<Self> {
+        let mut meta = CommitMeta::default();
+        while d.pos < d.buf.len() {
+            match d.u8()? {
+                1 => meta.idempotency = Some(d.string()?),
+                2 => {
+                    let key = d.string()?;
+                    let encoded = d.string()?;
+                    let precondition = Precondition::decode(&encoded)
+                        .ok_or_else(|| KVError::Corruption(format!("malformed precondition {:?}", encoded)))?;
+                    meta.expected.push((key, precondition));
+                }
+                3 => meta.actor = Some(d.string()?),
+                4 => meta.txn = Some(d.u64()?),
+                5 => meta.source = Some((d.string()?, d.u64()?)),
+                tag => return Err(KVError::Corruption(format!("unknown commit metadata tag {}", tag))),
+            }
+        }
+        Ok(meta)
+    }
+}
+pub struct Transaction {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) buffer: TxnBuffer,
+    pub(crate) attached: BTreeMap<String, Transaction>,
+    pub(crate) misses: Mutex<BTreeSet<String>>,
+    pub(crate) meta: CommitMeta,
+    pub(crate) pin: Option<Pin>,
+    pub(crate) started: Instant,
+    pub(crate) ticket: TxnTicket,
+}
+pub(crate) fn attached_bound(bound: Bound<&String>, prefix: &str, start: bool) -> Option<Bound<String>> {
+    let key = match bound {
+        Bound::Included(key) | Bound::Excluded(key) => key,
+        Bound::Unbounded => return Some(Bound::Unbounded),
+    };
+    match key.strip_prefix(prefix) {
+        Some(rest) if matches!(bound, Bound::Included(_)) => Some(Bound::Included(rest.to_owned())),
+        Some(rest) => Some(Bound::Excluded(rest.to_owned())),
+        None if (key.as_str() < prefix) == start => Some(Bound::Unbounded),
+        None => None,
+    }
+}
+/// Indices into a `multi_get` batch, with the attached keyspace (and its prefix length) they belong to.
+type KeyGroup = (Option<(String, usize)>, Vec<usize>);
+impl Transaction {
+    pub(crate) fn split_key(&self, key: &str) -> Option<(String, usize)> {
+        let (keyspace, _) = key.split_once(KEYSPACE_SEPARATOR)?;
+        self.attached.contains_key(keyspace).then(|| (keyspace.to_owned(), keyspace.len() + 1))
+    }
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let started = Instant::now();
+        let value = self.read(key);
+        self.store.record(self.ticket.id, started, || TraceOp::Get {
+            key: key.to_owned(),
+            found: value.as_ref().ok().and_then(|value| value.as_deref()).map(|value| trace_value(&self.store.policies, key, value)),
+        });
+        value
+    }
+    pub(crate) fn read(&self, key: &str) -> Result<Option<String>> {
+        match self.split_key(key) {
+            Some((keyspace, at)) => self.attached[&keyspace].get(&key[at..]),
+            None => {
+                let mut damaged = Ok(());
+                let value = self.buffer.get_with(&self.store.fold_key(key).unwrap_or_else(|| key.to_owned()), |key, version| {
+                    damaged = self.store.check_quarantine(key, version);
+                    if damaged.is_err() {
+                        return None;
+                    }
+                    let mut misses = self.misses.lock().unwrap();
+                    if misses.contains(key) {
+                        return None;
+                    }
+                    let value = self.store.read_at(key, version);
+                    if value.is_none() {
+                        misses.insert(key.to_owned());
+                    }
+                    value
+                })?;
+                damaged.map(|_| value)
+            }
+        }
+    }
+    /// Reads `keys` in one pass per attached store, with local keys read as a batch under a single engine lock.
+    /// Batches of at least `MULTI_GET_FANOUT_MIN` keys fan out over up to one thread per core: each attached store is a
+    /// job, and local keys are split into chunks of at least that size.
+    pub fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let mut groups: BTreeMap<Option<(String, usize)>, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            groups.entry(self.split_key(key)).or_default().push(i);
+        }
+        let workers = match keys.len() >= MULTI_GET_FANOUT_MIN {
+            true => thread::available_parallelism().map_or(1, usize::from),
+            false => 1,
+        };
+        let mut jobs: Vec<KeyGroup> = Vec::new();
+        for (part, indices) in groups {
+            match part {
+                None if workers > 1 => {
+                    let chunk = indices.len().div_ceil(workers).max(MULTI_GET_FANOUT_MIN);
+                    jobs.extend(indices.chunks(chunk).map(|chunk| (None, chunk.to_vec())));
+                }
+                part => jobs.push((part, indices)),
+            }
+        }
+        let fetch = |(part, indices): &KeyGroup| match part {
+            Some((keyspace, at)) => self.attached[keyspace].multi_get(&indices.iter().map(|&i| &keys[i][*at..]).collect::<Vec<_>>()),
+            None => self.get_local(&indices.iter().map(|&i| keys[i]).collect::<Vec<_>>()),
+        };
+        let fetched: Vec<Result<Vec<Option<String>>>> = match workers.min(jobs.len()) {
+            0 | 1 => jobs.iter().map(fetch).collect(),
+            threads => {
+                let next = AtomicUsize::new(0);
+                let slots: Vec<Mutex<Option<_>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+                thread::scope(|scope| {
+                    for _ in 0..threads {
+                        scope.spawn(|| loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(job) = jobs.get(i) else { break };
+                            *slots[i].lock().unwrap() = Some(fetch(job));
+                        });
+                    }
+                });
+                slots.into_iter().map(|slot| slot.into_inner().unwrap().unwrap()).collect()
+            }
+        };
+        let mut results = vec![None; keys.len()];
+        for ((_, indices), values) in jobs.iter().zip(fetched) {
+            for (&i, value) in indices.iter().zip(values?) {
+                results[i] = value;
+            }
+        }
+        Ok(results)
+    }
+    fn get_local(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let started = Instant::now();
+        let version = self.buffer.snapshot_version();
+        let mut values = vec![None; keys.len()];
+        let mut unread = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let folded = self.store.fold_key(key).unwrap_or_else(|| key.to_string());
+            let mut from_store = false;
+            values[i] = self.buffer.get_with(&folded, |_, _| {
+                from_store = true;
+                None
+            })?;
+            if from_store {
+                self.store.check_quarantine(&folded, version)?;
+                unread.push((i, folded));
+            }
+        }
+        {
+            let misses = self.misses.lock().unwrap();
+            unread.retain(|(_, key)| !misses.contains(key));
+        }
+        let found = self.store.read_many_at(&unread.iter().map(|(_, key)| key.as_str()).collect::<Vec<_>>(), version);
+        let mut misses = self.misses.lock().unwrap();
+        for ((i, key), value) in unread.into_iter().zip(found) {
+            if value.is_none() {
+                misses.insert(key);
+            }
+            values[i] = value;
+        }
+        drop(misses);
+        for (key, value) in keys.iter().zip(&values) {
+            self.store.record(self.ticket.id, started, || TraceOp::Get {
+                key: key.to_string(),
+                found: value.as_deref().map(|value| trace_value(&self.store.policies, key, value)),
+            });
+        }
+        Ok(values)
+    }
+    pub fn get_range_by_index<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<String>> {
+        let bounds = index_range(index, self.get(&index_def_key(index))?, self.get(&index_build_key(index))?.is_some(), &range)?;
+        Ok(self.scan(bounds)?.into_iter().filter_map(|(entry, _)| index_entry_key(&entry)).collect())
+    }
+    pub fn scan_covering<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<(String, String)>> {
+        let bounds = index_range(index, self.get(&index_def_key(index))?, self.get(&index_build_key(index))?.is_some(), &range)?;
+        Ok(self.scan(bounds)?.into_iter().filter_map(|(entry, covered)| Some((index_entry_key(&entry)?, covered))).collect())
+    }
+    pub fn scan_by_index<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<(String, String)>> {
+        let mut rows = Vec::new();
+        for key in self.get_range_by_index(index, range)? {
+            if let Some(value) = self.get(&key)? {
+                rows.push((key, value));
+            }
+        }
+        Ok(rows)
+    }
+    pub fn scan_match(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let mut rows = self.scan(prefix_range(glob_prefix(pattern)))?;
+        rows.retain(|(key, _)| glob_match(pattern, key));
+        Ok(rows)
+    }
+    pub fn scan<R: RangeBounds<String> + Clone>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let range = self.store.fold_range(&range);
+        let mut results = self.buffer.scan_with(range.clone(), |range, version| self.store.scan_at(range, version))?;
+        for (key, _) in results.iter_mut().filter(|(key, _)| self.store.is_case_folded(key)) {
+            if let Some(original) = self.buffer.get_with(&case_key(key), |key, version| self.store.read_at(key, version))? {
+                *key = original;
+            }
+        }
+        if self.attached.is_empty() {
+            self.store.order_keys(&mut results);
+            return Ok(results);
+        }
+        results.retain(|(key, _)| self.split_key(key).is_none());
+        for (keyspace, part) in &self.attached {
+            let prefix = format!("{}{}", keyspace, KEYSPACE_SEPARATOR);
+            let start = attached_bound(range.start_bound(), &prefix, true);
+            let end = attached_bound(range.end_bound(), &prefix, false);
+            if let (Some(start), Some(end)) = (start, end) {
+                results.extend(part.scan((start, end))?.into_iter().map(|(key, value)| (format!("{}{}", prefix, key), value)));
+            }
+        }
+        results.sort();
+        self.store.order_keys(&mut results);
+        Ok(results)
+    }
+    pub fn put(&mut self, key: String, value: String) -> Result<()> {
+        check_user_key(&key)?;
+        self.put_internal(key, value)
+    }
+    pub(crate) fn put_internal(&mut self, key: String, value: String) -> Result<()> {
+        let started = Instant::now();
+        let op = self.store.recorder.read().unwrap().is_some().then(|| TraceOp::Put { value: trace_value(&self.store.policies, &key, &value), key: key.clone() });
+        let result = self.write(key, value);
+        if let (Some(op), Ok(())) = (op, &result) {
+            self.store.record(self.ticket.id, started, || op);
+        }
+        result
+    }
+    pub(crate) fn write(&mut self, key: String, value: String) -> Result<()> {
+        match self.split_key(&key) {
+            Some((keyspace, at)) => self.attached.get_mut(&keyspace).unwrap().put(key[at..].to_owned(), value),
+            None => {
+                self.store.validate(&key, &value)?;
+                let ttl = self.store.default_ttl(keyspace_of(&key));
+                self.write_local(key.clone(), Some(value))?;
+                match ttl {
+                    Some(ttl) => self.expire(&key, ttl),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+    pub fn get_secret(&self, key: &str) -> Result<Option<Secret>> {
+        self.get(key).map(|value| value.map(Secret))
+    }
+    pub fn put_secret(&mut self, key: String, mut value: Secret) -> Result<()> {
+        self.put(key, std::mem::take(&mut value.0))
+    }
+    pub fn delete(&mut self, key: String) -> Result<()> {
+        check_user_key(&key)?;
+        self.delete_internal(key)
+    }
+    pub(crate) fn delete_internal(&mut self, key: String) -> Result<()> {
+        let started = Instant::now();
+        self.store.record(self.ticket.id, started, || TraceOp::Delete { key: key.clone() });
+        match self.split_key(&key) {
+            Some((keyspace, at)) => self.attached.get_mut(&keyspace).unwrap().delete(key[at..].to_owned()),
+            None => {
+                self.store.check_key_size(&key)?;
+                self.write_local(key, None)
+            }
+        }
+    }
+    pub(crate) fn write_local(&mut self, key: String, value: Option<String>) -> Result<()> {
+        let key = match self.store.fold_key(&key) {
+            Some(folded) => {
+                match value {
+                    Some(_) => self.buffer.put(case_key(&folded), key)?,
+                    None => self.buffer.delete(case_key(&folded))?,
+                }
+                folded
+            }
+            None => key,
+        };
+        match value {
+            Some(value) => self.buffer.put(key, value)?,
+            None => self.buffer.delete(key)?,
+        }
+        Ok(())
+    }
+    pub fn put_versioned(&mut self, key: String, value: &str) -> Result<()> {
+        check_user_key(&key)?;
+        if let Some((keyspace, at)) = self.split_key(&key) {
+            return self.attached.get_mut(&keyspace).unwrap().put_versioned(key[at..].to_owned(), value);
+        }
+        self.store.validate(&key, value)?;
+        let encoded = match self.store.schema(&key) {
+            Some(schema) => schema.encode(value),
+            None => value.to_owned(),
+        };
+        self.write_local(key, Some(encoded))
+    }
+    pub fn get_versioned(&self, key: &str) -> Result<Option<String>> {
+        if let Some((keyspace, at)) = self.split_key(key) {
+            return self.attached[&keyspace].get_versioned(&key[at..]);
+        }
+        let Some(stored) = self.get(key)? else { return Ok(None) };
+        match self.store.schema(key) {
+            Some(schema) => schema.decode(key, &stored).map(Some),
+            None => Ok(Some(stored)),
+        }
+    }
+    pub(crate) fn flatten_into(mut self, parts: &mut Vec<Transaction>) {
+        let attached = std::mem::take(&mut self.attached);
+        parts.push(self);
+        for part in attached.into_values() {
+            part.flatten_into(parts);
+        }
+    }
+    pub fn commit(self) -> Result<Version> {
+        let (store, id, started) = (Arc::clone(&self.store), self.id(), Instant::now());
+        let result = self.commit_parts();
+        store.record(id, started, || TraceOp::Commit { result: result.as_ref().copied().map_err(|e| e.to_string()) });
+        result
+    }
+    pub(crate) fn commit_parts(self) -> Result<Version> {
+        if self.attached.is_empty() {
+            return self.commit_local();
+        }
+        let store = Arc::clone(&self.store);
+        let snapshot = self.buffer.snapshot_version();
+        let mut parts = Vec::new();
+        self.flatten_into(&mut parts);
+        parts.retain(|part| part.buffer.has_writes() || Arc::ptr_eq(&part.store, &store));
+        match parts.iter().filter(|part| part.buffer.has_writes()).count() {
+            0 => Ok(snapshot),
+            1 => {
+                let i = parts.iter().position(|part| part.buffer.has_writes()).unwrap();
+                let version = parts.swap_remove(i).commit_local()?;
+                Ok(if i == 0 { version } else { snapshot })
+            }
+            _ => store.coordinator.get().unwrap().commit_parts(parts).map(|versions| versions[0]),
+        }
+    }
+    pub fn set_actor(&mut self, actor: &str) {
+        self.meta.actor = Some(actor.to_owned());
+        for part in self.attached.values_mut() {
+            part.set_actor(actor);
+        }
+    }
+    pub fn id(&self) -> u64 {
+        self.ticket.id
+    }
+    pub fn set_idempotency_key(&mut self, token: &str) -> Result<()> {
+        if token.is_empty() {
+            return Err(KVError::InvalidInput("idempotency key must not be empty".to_owned()));
+        }
+        self.meta.idempotency = Some(token.to_owned());
+        Ok(())
+    }
+    pub fn expect(&mut self, key: &str, precondition: Precondition) {
+        match self.split_key(key) {
+            Some((keyspace, at)) => self.attached.get_mut(&keyspace).unwrap().expect(&key[at..], precondition),
+            None => self.meta.expected.push((key.to_owned(), precondition)),
+        }
+    }
+    pub(crate) fn into_writes(mut self) -> Option<(Writes, CommitMeta)> {
+        let writes = self.buffer.into_writes()?;
+        if self.store.audit.load(Ordering::Relaxed) {
+            self.meta.txn = Some(self.ticket.id);
+        }
+        Some((writes, self.meta))
+    }
+    pub(crate) fn commit_local(self) -> Result<Version> {
+        let snapshot = self.buffer.snapshot_version();
+        let store = Arc::clone(&self.store);
+        let (id, started) = (self.id(), self.started);
+        let mut trace = CommitTrace::default();
+        let mut keys = 0;
+        let expected = match self.buffer.has_writes() {
+            true => Vec::new(),
+            false => self.meta.expected.clone(),
+        };
+        let result = match self.into_writes() {
+            Some(_) if store.is_read_only() => Err(KVError::ReadOnly),
+            Some(_) if store.is_fenced() => Err(KVError::NotLeader(None)),
+            Some(_) if id >= store.draining.load(Ordering::Acquire) => Err(KVError::Closed),
+            Some((writes, meta)) => {
+                keys = writes.len();
+                store.admit().and_then(|()| match store.proposer.get() {
+                    Some(proposer) => proposer.propose(writes, meta),
+                    None => store.apply_traced(writes, meta, None, None, None, &mut trace),
+                })
+            }
+            None if expected.is_empty() => Ok(snapshot),
+            None => store.engine.with(|state| check_preconditions(&store.policies, expected, state)).map(|()| snapshot),
+        };
+        store.record_slow(id, started, keys, trace, result.as_ref().ok().copied());
+        result
+    }
+    pub fn rollback(&mut self) -> Result<()> {
+        self.store.record(self.ticket.id, Instant::now(), || TraceOp::Rollback);
+        self.buffer.rollback();
+        self.meta.expected.clear();
+        self.pin = None;
+        self.attached.values_mut().try_for_each(|part| part.rollback())
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    SumInt,
+    Min,
+    Max,
+}
+pub struct Snapshot {
+    pub(crate) store: Arc<KVStore>,
+    pub(crate) version: Version,
+}
+impl Snapshot {
+    pub fn version(&self) -> Version {
+        self.version
+    }
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.store.fold_key(key) {
+            Some(folded) => self.store.read_at(&folded, self.version),
+            None => self.store.read_at(key, self.version),
+        }
+    }
+    pub fn scan<R: RangeBounds<String> + Clone>(&self, range: R) -> Vec<(String, String)> {
+        let mut results = self.store.scan_at(self.store.fold_range(&range), self.version);
+        for (key, _) in results.iter_mut().filter(|(key, _)| self.store.is_case_folded(key)) {
+            if let Some(original) = self.store.read_at(&case_key(key), self.version) {
+                *key = original;
+            }
+        }
+        self.store.order_keys(&mut results);
+        results
+    }
+    pub fn scan_match(&self, pattern: &str) -> Vec<(String, String)> {
+        let mut rows = self.scan(prefix_range(glob_prefix(pattern)));
+        rows.retain(|(key, _)| glob_match(pattern, key));
+        rows
+    }
+    pub fn scan_regex<R: RangeBounds<String> + Clone>(&self, range: R, regex: &Regex, on: MatchOn) -> Vec<(String, String)> {
+        self.scan_where(range, |key, value| regex.is_match(if on == MatchOn::Key { key } else { value }))
+    }
+    pub fn scan_filtered<R: RangeBounds<String> + Clone>(
+        &self,
+        range: R,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<(String, String)> {
+        self.scan_where(range, |_, value| filter(value))
+    }
+    pub(crate) fn scan_where<R: RangeBounds<String> + Clone>(
+        &self,
+        range: R,
+        keep: impl Fn(&str, &str) -> bool,
+    ) -> Vec<(String, String)> {
+        let mut rows = self.store.fold_at(self.store.fold_range(&range), self.version, Vec::new(), |mut rows, key, value| {
+            if keep(key, value) {
+                rows.push((key.to_owned(), value.to_owned()));
+            }
+            rows
+        });
+        for (key, _) in rows.iter_mut().filter(|(key, _)| self.store.is_case_folded(key)) {
+            if let Some(original) = self.store.read_at(&case_key(key), self.version) {
+                *key = original;
+            }
+        }
+        self.store.order_keys(&mut rows);
+        rows
+    }
+    pub fn scan_page<R: RangeBounds<String>>(&self, range: R, limit: usize) -> ScanPage {
+        let (start, end) = self.store.fold_range(&range);
+        self.page(start, end, limit)
+    }
+    pub(crate) fn page(&self, start: Bound<String>, end: Bound<String>, limit: usize) -> ScanPage {
+        let empty = match (&start, &end) {
+            (Bound::Included(a), Bound::Included(b)) => a > b,
+            (Bound::Included(a) | Bound::Excluded(a), Bound::Excluded(b)) | (Bound::Excluded(a), Bound::Included(b)) => {
+                a >= b
+            }
+            _ => false,
+        };
+        let mut entries = if empty || limit == 0 {
+            Vec::new()
+        } else if self.store.parent.is_some() {
+            self.store.scan_at((start, end), self.version).into_iter().take(limit + 1).collect()
+        } else {
+            self.store.engine.with(|state| {
+                state
+                    .map
+                    .range((start, end))
+                    .filter_map(|(key, versions)| match versions.at(self.version) {
+                        Some((_, Some(value))) => Some((key.to_string(), value.to_string())),
+                        _ => None,
+                    })
+                    .take(limit + 1)
+                    .collect::<Vec<_>>()
+            })
+        };
+        let next = (entries.len() > limit).then(|| {
+            entries.truncate(limit);
+            ScanCursor { version: self.version, after: entries[limit - 1].0.clone() }
+        });
+        for (key, _) in entries.iter_mut().filter(|(key, _)| self.store.is_case_folded(key)) {
+            if let Some(original) = self.store.read_at(&case_key(key), self.version) {
+                *key = original;
+            }
+        }
+        ScanPage { entries, next, policies: self.store.policies.clone() }
+    }
+    pub fn estimate<R: RangeBounds<String>>(&self, range: R) -> RangeEstimate {
+        let range = self.store.fold_range(&range);
+        let bounds = (range.start_bound().map(String::as_str), range.end_bound().map(String::as_str));
+        let sampled = self.store.sample.read().unwrap().keys.range::<str, _>(bounds).fold(
+            RangeEstimate::default(),
+            |acc, (_, bytes)| RangeEstimate { keys: acc.keys + 1, bytes: acc.bytes + *bytes as u64 },
+        );
+        if self.store.parent.is_none() && sampled.keys << SAMPLE_SHIFT >= EXACT_ESTIMATE as u64 {
+            return RangeEstimate { keys: sampled.keys << SAMPLE_SHIFT, bytes: sampled.bytes << SAMPLE_SHIFT };
+        }
+        let exact = |acc: RangeEstimate, key: &str, value: &str| RangeEstimate {
+            keys: acc.keys + 1,
+            bytes: acc.bytes + (key.len() + value.len()) as u64,
+        };
+        if self.store.parent.is_some() {
+            return self.store.fold_at(range, self.version, RangeEstimate::default(), exact);
+        }
+        self.store.engine.with(|state| {
+            let mut estimate = RangeEstimate::default();
+            for (key, versions) in state.map.range(range).take(EXACT_ESTIMATE) {
+                if let Some((_, Some(value))) = versions.at(self.version) {
+                    estimate = exact(estimate, key, value);
+                }
+            }
+            estimate
+        })
+    }
+    pub fn sample(&self, n: usize) -> Vec<String> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let mut rng = sim::Rng::new(nanos ^ self.version);
+        let user = |key: &str| !keyspace_of(key).starts_with("__");
+        let mut picked = Vec::new();
+        let mut anchors: Vec<String> = match &self.store.parent {
+            Some(_) => Vec::new(),
+            None => self.store.sample.read().unwrap().keys.keys().filter(|key| user(key)).map(Key::to_string).collect(),
+        };
+        if anchors.len() >= n.saturating_mul(2) {
+            for i in 0..anchors.len() {
+                if picked.len() == n {
+                    break;
+                }
+                let j = i + rng.below((anchors.len() - i) as u64) as usize;
+                anchors.swap(i, j);
+                if self.store.read_at(&anchors[i], self.version).is_some() {
+                    picked.push(anchors[i].clone());
+                }
+            }
+        }
+        if picked.len() < n {
+            picked = self
+                .store
+                .fold_at(.., self.version, (0u64, Vec::new()), |(seen, mut reservoir), key, _| {
+                    if !user(key) {
+                        return (seen, reservoir);
+                    }
+                    if reservoir.len() < n {
+                        reservoir.push(key.to_owned());
+                    } else if let Some(slot) = reservoir.get_mut(rng.below(seen + 1) as usize) {
+                        *slot = key.to_owned();
+                    }
+                    (seen + 1, reservoir)
+                })
+                .1;
+        }
+        for key in picked.iter_mut().filter(|key| self.store.is_case_folded(key)) {
+            if let Some(original) = self.store.read_at(&case_key(key), self.version) {
+                *key = original;
+            }
+        }
+        picked
+    }
+    pub fn aggregate<R: RangeBounds<String> + Clone>(&self, range: R, aggregate: Aggregate) -> Option<i64> {
+        let ints = |acc: Option<i64>, value: &str, pick: fn(i64, i64) -> i64| match value.trim().parse::<i64>() {
+            Ok(n) => Some(acc.map_or(n, |acc| pick(acc, n))),
+            Err(_) => acc,
+        };
+        match aggregate {
+            Aggregate::Count => Some(self.store.fold_at(range, self.version, 0, |n, _, _| n + 1)),
+            Aggregate::SumInt => Some(
+                self.store.fold_at(range, self.version, None, |acc, _, v| ints(acc, v, i64::wrapping_add)).unwrap_or(0),
+            ),
+            Aggregate::Min => self.store.fold_at(range, self.version, None, |acc, _, v| ints(acc, v, i64::min)),
+            Aggregate::Max => self.store.fold_at(range, self.version, None, |acc, _, v| ints(acc, v, i64::max)),
+        }
+    }
+    pub fn search(&self, query: &str) -> Vec<String> {
+        let tokens = tokenize(query);
+        if tokens.is_empty() {
+            return Vec::new();
+        }
+        let mut found = BTreeSet::new();
+        for (name, def) in self.store.indexes() {
+            if def.kind != IndexKind::FullText
+                || self.get(&index_def_key(&name)) != Some(def.encode())
+                || self.get(&index_build_key(&name)).is_some()
+            {
+                continue;
+            }
+            let matches = tokens.iter().map(|token| {
+                let entries = index_value_prefix(&name, &encode_index_value(&Json::Str(token.clone())).unwrap());
+                self.scan(prefix_range(&entries)).into_iter().map(|(entry, _)| entry[entries.len()..].to_owned()).collect::<BTreeSet<_>>()
+            });
+            let all = matches.reduce(|all, keys| all.intersection(&keys).cloned().collect()).unwrap_or_default();
+            found.extend(all);
+        }
+        found.into_iter().collect()
+    }
+    pub fn get_range_by_index<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<String>> {
+        let bounds = index_range(index, self.get(&index_def_key(index)), self.get(&index_build_key(index)).is_some(), &range)?;
+        Ok(self.scan(bounds).into_iter().filter_map(|(entry, _)| index_entry_key(&entry)).collect())
+    }
+    pub fn scan_covering<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<(String, String)>> {
+        let bounds = index_range(index, self.get(&index_def_key(index)), self.get(&index_build_key(index)).is_some(), &range)?;
+        Ok(self.scan(bounds).into_iter().filter_map(|(entry, covered)| Some((index_entry_key(&entry)?, covered))).collect())
+    }
+    pub fn scan_by_index<R: RangeBounds<Json>>(&self, index: &str, range: R) -> Result<Vec<(String, String)>> {
+        let keys = self.get_range_by_index(index, range)?;
+        Ok(keys.into_iter().filter_map(|key| self.get(&key).map(|value| (key, value))).collect())
+    }
+    pub fn query(&self) -> Query {
+        Query {
+            snapshot: Snapshot { store: Arc::clone(&self.store), version: self.version },
+            prefix: String::new(),
+            filters: Vec::new(),
+            limit: usize::MAX,
+            projection: Vec::new(),
+        }
+    }
+    pub fn fold<R: RangeBounds<String> + Clone, A>(&self, range: R, init: A, mut f: impl FnMut(A, (&str, &str)) -> A) -> A {
+        self.store.fold_at(range, self.version, init, |acc, key, value| f(acc, (key, value)))
+    }
+    pub(crate) fn partitions<R: RangeBounds<String>>(&self, range: R, partitions: usize) -> Vec<(Bound<String>, Bound<String>)> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut bounds = Vec::new();
+        let mut lower = start.clone();
+        for split in self.store.split_points((start, end.clone()), partitions) {
+            bounds.push((lower, Bound::Excluded(split.clone())));
+            lower = Bound::Included(split);
+        }
+        bounds.push((lower, end));
+        bounds
+    }
+    pub fn par_fold<R: RangeBounds<String>, A: Send>(
+        &self,
+        range: R,
+        partitions: usize,
+        init: impl Fn() -> A + Sync,
+        f: impl Fn(A, (&str, &str)) -> A + Sync,
+        combine: impl Fn(A, A) -> A,
+    ) -> A {
+        let (init, f) = (&init, &f);
+        let results: Vec<A> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .partitions(range, partitions)
+                .into_iter()
+                .map(|range| {
+                    scope.spawn(move || {
+                        self.store.fold_chunked(range, self.version, init(), |acc, key, value| f(acc, (key, value)))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        results.into_iter().reduce(combine).unwrap_or_else(init)
+    }
+    pub fn par_scan<R: RangeBounds<String>>(&self, range: R, partitions: usize, sink: impl Fn(usize, &str, &str) + Sync) {
+        let sink = &sink;
+        thread::scope(|scope| {
+            for (i, range) in self.partitions(range, partitions).into_iter().enumerate() {
+                scope.spawn(move || self.store.fold_chunked(range, self.version, (), |(), key, value| sink(i, key, value)));
+            }
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn multi_get_reads_attached_stores_in_key_order() {
+        let dir = TempDir::new("multi-get");
+        let store = KVStore::new();
+        let users = store.attach("users", dir.path().join("users")).unwrap();
+        let orders = store.attach("orders", dir.path().join("orders")).unwrap();
+        let mut tx = users.begin();
+        tx.put("1".to_owned(), "ada".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut tx = orders.begin();
+        tx.put("7".to_owned(), "book".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let mut tx = store.begin();
+        tx.put("local".to_owned(), "here".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let tx = store.begin();
+        let values = tx.multi_get(&["orders:7", "local", "users:1", "users:2", "missing"]).unwrap();
+        assert_eq!(values, vec![Some("book".to_owned()), Some("here".to_owned()), Some("ada".to_owned()), None, None]);
+    }
+    #[test]
+    fn large_multi_gets_fan_out_and_match_point_reads() {
+        let dir = TempDir::new("multi-get-fanout");
+        let store = KVStore::new();
+        let users = store.attach("users", dir.path().join("users")).unwrap();
+        let mut tx = users.begin();
+        for i in (0..400).step_by(2) {
+            tx.put(i.to_string(), format!("user {}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let mut tx = store.begin();
+        for i in (0..1000).step_by(3) {
+            tx.put(format!("ks:{}", i), format!("local {}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let mut tx = store.begin();
+        tx.put("ks:1".to_owned(), "own write".to_owned()).unwrap();
+        tx.delete("ks:3".to_owned()).unwrap();
+        let mut keys: Vec<String> = (0..1000).map(|i| format!("ks:{}", i)).chain((0..400).map(|i| format!("users:{}", i))).collect();
+        keys.reverse();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        assert!(keys.len() >= MULTI_GET_FANOUT_MIN * 4);
+        let batched = tx.multi_get(&keys).unwrap();
+        let pointwise: Vec<Option<String>> = keys.iter().map(|key| tx.get(key).unwrap()).collect();
+        assert_eq!(batched, pointwise);
+        assert_eq!(batched[keys.iter().position(|key| *key == "ks:1").unwrap()].as_deref(), Some("own write"));
+        assert_eq!(batched[keys.iter().position(|key| *key == "ks:3").unwrap()], None);
+        assert_eq!(batched[keys.iter().position(|key| *key == "ks:999").unwrap()].as_deref(), Some("local 999"));
+        assert_eq!(batched[keys.iter().position(|key| *key == "users:398").unwrap()].as_deref(), Some("user 398"));
+        assert_eq!(batched.iter().filter(|value| value.is_some()).count(), 333 + 1 + 200);
+        assert_eq!(tx.multi_get(&[]).unwrap(), Vec::<Option<String>>::new());
+    }
+    #[test]
+    fn misses_are_remembered_for_the_snapshot_but_not_over_own_writes() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        assert_eq!(tx.get("ks:k").unwrap(), None);
+        assert!(tx.misses.lock().unwrap().contains("ks:k"));
+        let mut other = store.begin();
+        other.put("ks:k".to_owned(), "theirs".to_owned()).unwrap();
+        other.commit().unwrap();
+        assert_eq!(tx.get("ks:k").unwrap(), None);
+        tx.put("ks:k".to_owned(), "mine".to_owned()).unwrap();
+        assert_eq!(tx.get("ks:k").unwrap().as_deref(), Some("mine"));
+        tx.rollback().unwrap();
+        let tx = store.begin();
+        assert_eq!(tx.get("ks:k").unwrap().as_deref(), Some("theirs"));
+        assert!(tx.misses.lock().unwrap().is_empty());
+    }
+    fn numbered(store: &Arc<KVStore>, n: usize) {
+        let mut tx = store.begin();
+        for i in 0..n {
+            tx.put(format!("ks:{:04}", i), i.to_string()).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+    #[test]
+    fn par_scan_visits_each_key_once_at_the_snapshot() {
+        let store = KVStore::new();
+        numbered(&store, 1000);
+        let snapshot = store.snapshot();
+        let mut tx = store.begin();
+        tx.put("ks:late".to_owned(), "x".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let seen = Mutex::new(BTreeMap::<usize, Vec<String>>::new());
+        snapshot.par_scan(prefix_range("ks:"), 4, |part, key, _| seen.lock().unwrap().entry(part).or_default().push(key.to_owned()));
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 4);
+        let keys: Vec<String> = seen.into_values().flatten().collect();
+        assert_eq!(keys, (0..1000).map(|i| format!("ks:{:04}", i)).collect::<Vec<_>>());
+    }
+    #[test]
+    fn aggregates_skip_values_that_are_not_integers() {
+        let store = KVStore::new();
+        numbered(&store, 10);
+        let mut tx = store.begin();
+        tx.put("ks:name".to_owned(), "ada".to_owned()).unwrap();
+        tx.put("ks:neg".to_owned(), " -5 ".to_owned()).unwrap();
+        tx.put("other:1".to_owned(), "100".to_owned()).unwrap();
+        tx.commit().unwrap();
+        let snapshot = store.snapshot();
+        let ks = || prefix_range("ks:");
+        assert_eq!(snapshot.aggregate(ks(), Aggregate::Count), Some(12));
+        assert_eq!(snapshot.aggregate(ks(), Aggregate::SumInt), Some(40));
+        assert_eq!(snapshot.aggregate(ks(), Aggregate::Min), Some(-5));
+        assert_eq!(snapshot.aggregate(ks(), Aggregate::Max), Some(9));
+        assert_eq!(snapshot.aggregate(prefix_range("none:"), Aggregate::Count), Some(0));
+        assert_eq!(snapshot.aggregate(prefix_range("none:"), Aggregate::SumInt), Some(0));
+        assert_eq!(snapshot.aggregate(prefix_range("none:"), Aggregate::Max), None);
+    }
+    #[test]
+    fn folds_agree_with_their_parallel_variant() {
+        let store = KVStore::new();
+        numbered(&store, 2000);
+        let fork = store.fork();
+        let mut tx = fork.begin();
+        tx.delete("ks:0000".to_owned()).unwrap();
+        tx.put("ks:9999".to_owned(), "9999".to_owned()).unwrap();
+        tx.commit().unwrap();
+        for store in [&store, &fork] {
+            let snapshot = store.snapshot();
+            let sum = |acc: u64, (_, value): (&str, &str)| acc + value.parse::<u64>().unwrap();
+            let serial = snapshot.fold(prefix_range("ks:"), 0, sum);
+            assert_eq!(snapshot.par_fold(prefix_range("ks:"), 8, || 0, sum, |a, b| a + b), serial);
+            let in_order = snapshot.fold(.., (true, String::new()), |(sorted, last), (key, _)| (sorted && *key > *last, key.to_owned()));
+            assert!(in_order.0);
+        }
+        assert_eq!(store.snapshot().fold(.., 0, |n, _| n + 1), 2000);
+        assert_eq!(fork.snapshot().fold(prefix_range("ks:"), 0u64, |acc, (_, v)| acc + v.parse::<u64>().unwrap()), 1999 * 2000 / 2 + 9999);
+    }
+    #[test]
+    fn idempotency_key_replays_without_writing_a_token_key() {
+        let store = KVStore::new();
+        let commit = |value: &str| {
+            let mut tx = store.begin();
+            tx.set_idempotency_key("order-1").unwrap();
+            tx.put("order".to_owned(), value.to_owned()).unwrap();
+            tx.commit().unwrap()
+        };
+        let first = commit("first");
+        assert_eq!(commit("second"), first);
+        assert_eq!(store.begin().get("order").unwrap().as_deref(), Some("first"));
+        assert!(store.snapshot().scan(prefix_range("__idem")).iter().all(|(key, _)| !key.contains("token")));
+    }
+    #[test]
+    fn user_writes_to_reserved_keys_are_rejected() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        for key in ["__idem\0token", "__meta:hold:x", "__expect\0k"] {
+            assert!(matches!(tx.put(key.to_owned(), "v".to_owned()), Err(KVError::InvalidInput(_))), "{:?}", key);
+            assert!(matches!(tx.delete(key.to_owned()), Err(KVError::InvalidInput(_))), "{:?}", key);
+        }
+        assert!(matches!(tx.put_versioned("__ttl:x".to_owned(), "v"), Err(KVError::InvalidInput(_))));
+    }
+    #[test]
+    fn preconditions_are_checked_without_marker_writes() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        tx.expect("k", Precondition::Absent);
+        tx.put("k".to_owned(), "1".to_owned()).unwrap();
+        let version = tx.commit().unwrap();
+        let mut tx = store.begin();
+        tx.expect("k", Precondition::Absent);
+        tx.put("k".to_owned(), "2".to_owned()).unwrap();
+        let Err(KVError::PreconditionFailed(broken)) = tx.commit() else { panic!("precondition should fail") };
+        assert_eq!((broken[0].value.as_deref(), broken[0].version), (Some("1"), version));
+        let mut tx = store.begin();
+        tx.expect("k", Precondition::VersionIs(version));
+        tx.put("k".to_owned(), "3".to_owned()).unwrap();
+        tx.commit().unwrap();
+        assert!(store.snapshot().scan(prefix_range("__expect")).is_empty());
+    }
+    #[test]
+    fn commit_meta_round_trips() {
+        let meta = CommitMeta {
+            idempotency: Some("token".to_owned()),
+            expected: vec![("a".to_owned(), Precondition::Equals("x".to_owned())), ("b".to_owned(), Precondition::VersionIs(3))],
+            actor: Some("alice".to_owned()),
+            txn: Some(42),
+            source: Some(("feed".to_owned(), 9)),
+        };
+        let mut buf = Vec::new();
+        meta.encode(&mut buf);
+        assert_eq!(CommitMeta::decode(&mut Decoder::new(&buf)).unwrap(), meta);
+        assert_eq!(CommitMeta::decode(&mut Decoder::new(&[])).unwrap(), CommitMeta::default());
+        assert!(CommitMeta::decode(&mut Decoder::new(&[99])).is_err());
+    }
+    #[test]
+    fn prepared_idempotency_key_survives_a_reopen() {
+        let dir = TempDir::new("prepared-meta");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            let mut tx = store.begin();
+            tx.set_idempotency_key("once").unwrap();
+            tx.put("k".to_owned(), "v".to_owned()).unwrap();
+            let (writes, meta) = tx.into_writes().unwrap();
+            store.prepare_with(7, writes, meta).unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        assert_eq!(store.in_doubt(), vec![7]);
+        let version = store.commit_prepared(7).unwrap().unwrap();
+        let mut tx = store.begin();
+        tx.set_idempotency_key("once").unwrap();
+        tx.put("k".to_owned(), "again".to_owned()).unwrap();
+        assert_eq!(tx.commit().unwrap(), version);
+        assert_eq!(store.begin().get("k").unwrap().as_deref(), Some("v"));
+    }
+    #[test]
+    fn estimates_are_exact_for_small_ranges_and_close_for_large_ones() {
+        let store = KVStore::new();
+        let mut tx = store.begin();
+        for i in 0..40_000 {
+            tx.put(format!("big:{:06}", i), "x".repeat(20)).unwrap();
+        }
+        for i in 0..100 {
+            tx.put(format!("small:{:03}", i), "y".repeat(10)).unwrap();
+        }
+        tx.commit().unwrap();
+        let snapshot = store.snapshot();
+        let small = snapshot.estimate("small:".to_owned().."small;".to_owned());
+        assert_eq!(small, RangeEstimate { keys: 100, bytes: 100 * (9 + 10) });
+        let big = snapshot.estimate("big:".to_owned().."big;".to_owned());
+        assert!((32_000..48_000).contains(&big.keys), "estimated {} of 40000 keys", big.keys);
+        assert!((big.bytes as f64 / big.keys as f64 - 30.0).abs() < 0.5, "{:?}", big);
+        let half = snapshot.estimate("big:".to_owned().."big:020000".to_owned());
+        assert!((14_000..26_000).contains(&half.keys), "estimated {} of 20000 keys", half.keys);
+        let mut tx = store.begin();
+        for i in 0..30_000 {
+            tx.delete(format!("big:{:06}", i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let shrunk = store.snapshot().estimate("big:".to_owned().."big;".to_owned());
+        assert!((7_000..13_000).contains(&shrunk.keys), "estimated {} of 10000 keys", shrunk.keys);
+    }
+    #[test]
+    fn samples_pick_distinct_live_user_keys() {
+        let store = KVStore::new();
+        numbered(&store, 10);
+        let mut tx = store.begin();
+        tx.delete("ks:0003".to_owned()).unwrap();
+        tx.commit().unwrap();
+        store.sample_capacity().unwrap();
+        let snapshot = store.snapshot();
+        let all = snapshot.sample(100);
+        assert_eq!(all.len(), 9);
+        assert!(!all.contains(&"ks:0003".to_owned()) && all.iter().all(|key| key.starts_with("ks:")));
+        let some = snapshot.sample(4);
+        assert_eq!(some.len(), 4);
+        assert_eq!(some.iter().collect::<BTreeSet<_>>().len(), 4);
+        let seen: BTreeSet<String> = (0..200).flat_map(|_| snapshot.sample(1)).collect();
+        assert!(seen.len() > 1, "repeated samples keep picking {:?}", seen);
+        assert!(snapshot.sample(0).is_empty());
+    }
+}
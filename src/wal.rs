@@ -0,0 +1,511 @@
+use crate::*;
+pub(crate) fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+pub(crate) fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+pub(crate) fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+pub(crate) struct Decoder<'a> {
+    pub(crate) buf: &'a [u8],
+    pub(crate) pos: usize,
+}
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(KVError::Corruption("unexpected end of record".to_owned()));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub(crate) fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| KVError::Corruption("invalid utf-8 in record".to_owned()))
+    }
+}
+pub(crate) fn put_writes(buf: &mut Vec<u8>, writes: &Writes) {
+    put_u32(buf, writes.len() as u32);
+    for (key, value) in writes {
+        put_str(buf, key);
+        match value {
+            Some(v) => {
+                buf.push(1);
+                put_str(buf, v);
+            }
+            None => buf.push(0),
+        }
+    }
+}
+pub(crate) fn read_writes(d: &mut Decoder) -> Result<Writes> {
+    let count = d.u32()?;
+    let mut writes = BTreeMap::new();
+    for _ in 0..count {
+        let key = d.string()?;
+        let value = match d.u8()? {
+            0 => None,
+            1 => Some(d.string()?),
+            tag => return Err(KVError::Corruption(format!("unknown value tag {}", tag))),
+        };
+        writes.insert(key, value);
+    }
+    Ok(writes)
+}
+pub(crate) fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    put_u32(&mut frame, payload.len() as u32);
+    put_u32(&mut frame, crc32(payload));
+    frame.extend_from_slice(payload);
+    frame
+}
+pub(crate) struct WalRecord {
+    pub(crate) version: Version,
+    pub(crate) timestamp: u64,
+    pub(crate) writes: BTreeMap<String, Option<String>>,
+    pub(crate) txn: Option<u64>,
+}
+impl WalRecord {
+    pub(crate) fn encode(version: Version, timestamp: u64, writes: &BTreeMap<String, Option<String>>) -> Vec<u8> {
+        Self::encode_txn(version, timestamp, writes, None)
+    }
+    pub(crate) fn encode_txn(version: Version, timestamp: u64, writes: &Writes, txn: Option<u64>) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, version);
+        put_u64(&mut payload, timestamp);
+        put_writes(&mut payload, writes);
+        if let Some(id) = txn {
+            put_u64(&mut payload, id);
+        }
+        frame(&payload)
+    }
+    pub(crate) fn decode(payload: &[u8]) -> Result<Self> {
+        let mut d = Decoder::new(payload);
+        let version = d.u64()?;
+        let timestamp = d.u64()?;
+        let writes = read_writes(&mut d)?;
+        let txn = if d.pos < payload.len() { Some(d.u64()?) } else { None };
+        Ok(Self { version, timestamp, writes, txn })
+    }
+}
+pub(crate) enum TxnMarker {
+    Prepared(u64, Writes, CommitMeta),
+    Aborted(u64),
+}
+impl TxnMarker {
+    pub(crate) fn encode_prepared(id: u64, writes: &Writes, meta: &CommitMeta) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, 0);
+        payload.push(1);
+        put_u64(&mut payload, id);
+        put_writes(&mut payload, writes);
+        meta.encode(&mut payload);
+        frame(&payload)
+    }
+    pub(crate) fn encode_aborted(id: u64) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u64(&mut payload, 0);
+        payload.push(2);
+        put_u64(&mut payload, id);
+        frame(&payload)
+    }
+    pub(crate) fn decode(payload: &[u8]) -> Result<Self> {
+        let mut d = Decoder::new(payload);
+        d.u64()?;
+        let kind = d.u8()?;
+        let id = d.u64()?;
+        match kind {
+            1 => Ok(TxnMarker::Prepared(id, read_writes(&mut d)?, CommitMeta::decode(&mut d)?)),
+            2 => Ok(TxnMarker::Aborted(id)),
+            kind => Err(KVError::Corruption(format!("unknown wal marker {}", kind))),
+        }
+    }
+}
+pub(crate) struct Segment {
+    pub(crate) records: Vec<WalRecord>,
+    pub(crate) markers: Vec<TxnMarker>,
+    pub(crate) valid_len: u64,
+    pub(crate) torn: bool,
+}
+pub(crate) fn segment_frames(bytes: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut pos = WAL_MAGIC.len();
+    let mut frames = Vec::new();
+    while bytes.len().saturating_sub(pos) >= 8 {
+        let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+        if len == 0 || bytes.len() - pos - 8 < len {
+            break;
+        }
+        let payload = &bytes[pos + 8..pos + 8 + len];
+        if crc32(payload) != crc {
+            break;
+        }
+        frames.push(payload);
+        pos += 8 + len;
+    }
+    (frames, pos.min(bytes.len()))
+}
+pub(crate) fn salvage_frames(bytes: &[u8]) -> (Vec<&[u8]>, Vec<Range<usize>>, usize) {
+    let end = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let mut pos = WAL_MAGIC.len();
+    let (mut frames, mut damaged, mut skipped, mut valid) = (Vec::new(), None, Vec::new(), pos.min(bytes.len()));
+    while pos < end && bytes.len() - pos >= 8 {
+        let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+        if len > 0 && bytes.len() - pos - 8 >= len && crc32(&bytes[pos + 8..pos + 8 + len]) == crc {
+            skipped.extend(damaged.take().map(|start| start..pos));
+            frames.push(&bytes[pos + 8..pos + 8 + len]);
+            pos += 8 + len;
+            valid = pos;
+            continue;
+        }
+        if bytes[pos] != 0 {
+            damaged.get_or_insert(pos);
+        }
+        pos += 1;
+    }
+    skipped.extend(damaged.map(|start| start..end.max(start)));
+    (frames, skipped, valid)
+}
+pub(crate) fn damaged_keys(block: &[u8]) -> Option<(Version, Vec<String>)> {
+    let payload = block.get(8..)?;
+    if payload.starts_with(&[0; 8]) {
+        return Some((0, Vec::new()));
+    }
+    let mut d = Decoder::new(payload);
+    let (version, _, count) = (d.u64().ok()?, d.u64().ok()?, d.u32().ok()?);
+    let mut keys = Vec::new();
+    for _ in 0..count {
+        let Ok(key) = d.string() else { break };
+        keys.push(key);
+        match d.u8() {
+            Ok(1) if d.string().is_ok() => {}
+            Ok(0) => {}
+            _ => break,
+        }
+    }
+    (!keys.is_empty()).then_some((version, keys))
+}
+pub(crate) fn decode_frames(frames: Vec<&[u8]>) -> Result<(Vec<WalRecord>, Vec<TxnMarker>)> {
+    let mut records = Vec::new();
+    let mut markers = Vec::new();
+    for payload in frames {
+        if payload.starts_with(&[0; 8]) {
+            markers.push(TxnMarker::decode(payload)?);
+        } else {
+            records.push(WalRecord::decode(payload)?);
+        }
+    }
+    Ok((records, markers))
+}
+pub(crate) fn parse_segment(bytes: &[u8], name: &str) -> Result<Segment> {
+    if bytes.is_empty() {
+        return Ok(Segment { records: Vec::new(), markers: Vec::new(), valid_len: 0, torn: false });
+    }
+    if bytes.len() < WAL_MAGIC.len() || &bytes[..WAL_MAGIC.len()] != WAL_MAGIC {
+        return Err(KVError::Corruption(format!("{} is not a wal segment", name)));
+    }
+    let (frames, pos) = segment_frames(bytes);
+    let (records, markers) = decode_frames(frames)?;
+    let torn = bytes[pos..bytes.len().min(pos + 8)].iter().any(|b| *b != 0);
+    Ok(Segment { records, markers, valid_len: pos as u64, torn })
+}
+#[derive(Default)]
+pub(crate) struct ValueCodecs {
+    pub(crate) dictionaries: BTreeMap<String, Vec<u8>>,
+    pub(crate) lzf: BTreeSet<String>,
+    #[cfg(feature = "zstd")]
+    pub(crate) compressors: BTreeMap<String, zstd::bulk::Compressor<'static>>,
+    #[cfg(feature = "zstd")]
+    pub(crate) decompressors: BTreeMap<String, zstd::bulk::Decompressor<'static>>,
+}
+impl ValueCodecs {
+    pub(crate) fn for_map(data: &VersionMap, compression: &BTreeMap<String, Compression>) -> Self {
+        let mut codecs = ValueCodecs::default();
+        for (keyspace, compression) in compression {
+            if *compression == Compression::Lzf {
+                codecs.lzf.insert(keyspace.clone());
+            }
+        }
+        #[cfg(feature = "zstd")]
+        {
+            let prefix = dictionary_key("");
+            let mut dictionaries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+            for (key, versions) in data.range(prefix_range(&prefix)) {
+                if let Some(dictionary) = versions.latest().and_then(|(_, value)| unhex(value.as_ref()?)) {
+                    dictionaries.insert(key[prefix.len()..].to_owned(), dictionary);
+                }
+            }
+            for (keyspace, compression) in compression {
+                if matches!(compression, Compression::Zstd(_)) {
+                    dictionaries.entry(keyspace.clone()).or_default();
+                }
+            }
+            for (keyspace, dictionary) in dictionaries {
+                let level = match compression.get(&keyspace) {
+                    None => zstd::DEFAULT_COMPRESSION_LEVEL,
+                    Some(Compression::Zstd(level)) => *level,
+                    Some(_) => continue,
+                };
+                if let Ok(compressor) = zstd::bulk::Compressor::with_dictionary(level, &dictionary) {
+                    codecs.compressors.insert(keyspace.clone(), compressor);
+                    codecs.dictionaries.insert(keyspace, dictionary);
+                }
+            }
+        }
+        #[cfg(not(feature = "zstd"))]
+        let _ = data;
+        codecs
+    }
+    pub(crate) fn put_dictionaries(&self, buf: &mut Vec<u8>) {
+        put_u32(buf, self.dictionaries.len() as u32);
+        for (keyspace, dictionary) in &self.dictionaries {
+            put_str(buf, keyspace);
+            put_u32(buf, dictionary.len() as u32);
+            buf.extend_from_slice(dictionary);
+        }
+    }
+    pub(crate) fn read_dictionaries(d: &mut Decoder) -> Result<Self> {
+        let mut codecs = ValueCodecs::default();
+        for _ in 0..d.u32()? {
+            let keyspace = d.string()?;
+            let len = d.u32()? as usize;
+            let dictionary = d.take(len)?.to_vec();
+            #[cfg(feature = "zstd")]
+            codecs.decompressors.insert(keyspace.clone(), zstd::bulk::Decompressor::with_dictionary(&dictionary)?);
+            codecs.dictionaries.insert(keyspace, dictionary);
+        }
+        Ok(codecs)
+    }
+    pub(crate) fn put_value(&mut self, buf: &mut Vec<u8>, key: &str, value: &str) {
+        if self.lzf.contains(keyspace_of(key)) {
+            let packed = lzf_compress(value.as_bytes());
+            if packed.len() < value.len() {
+                buf.push(3);
+                put_u32(buf, value.len() as u32);
+                put_u32(buf, packed.len() as u32);
+                buf.extend_from_slice(&packed);
+                return;
+            }
+        }
+        #[cfg(feature = "zstd")]
+        if let Some(compressor) = self.compressors.get_mut(keyspace_of(key)) {
+            if let Ok(packed) = compressor.compress(value.as_bytes()) {
+                if packed.len() < value.len() {
+                    buf.push(2);
+                    put_u32(buf, value.len() as u32);
+                    put_u32(buf, packed.len() as u32);
+                    buf.extend_from_slice(&packed);
+                    return;
+                }
+            }
+        }
+        buf.push(1);
+        put_str(buf, value);
+    }
+    pub(crate) fn read_value(&mut self, d: &mut Decoder, key: &str) -> Result<Option<String>> {
+        match d.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(d.string()?)),
+            2 => {
+                let len = d.u32()? as usize;
+                let packed_len = d.u32()? as usize;
+                let packed = d.take(packed_len)?;
+                self.unpack(key, packed, len).map(Some)
+            }
+            3 => {
+                let len = d.u32()? as usize;
+                let packed_len = d.u32()? as usize;
+                let corrupt = |_| KVError::Corruption(format!("compressed value of {:?} is damaged", key));
+                let bytes = lzf_decompress(d.take(packed_len)?, len).map_err(corrupt)?;
+                String::from_utf8(bytes).map(Some).map_err(|_| KVError::Corruption("invalid utf-8 in record".to_owned()))
+            }
+            tag => Err(KVError::Corruption(format!("unknown value tag {}", tag))),
+        }
+    }
+    #[cfg(feature = "zstd")]
+    pub(crate) fn unpack(&mut self, key: &str, packed: &[u8], len: usize) -> Result<String> {
+        let Some(decompressor) = self.decompressors.get_mut(keyspace_of(key)) else {
+            return Err(KVError::Corruption(format!("no compression dictionary for {:?}", key)));
+        };
+        let bytes = decompressor
+            .decompress(packed, len)
+            .map_err(|e| KVError::Corruption(format!("compressed value of {:?} is damaged: {}", key, e)))?;
+        String::from_utf8(bytes).map_err(|_| KVError::Corruption("invalid utf-8 in record".to_owned()))
+    }
+    #[cfg(not(feature = "zstd"))]
+    pub(crate) fn unpack(&mut self, key: &str, _: &[u8], _: usize) -> Result<String> {
+        Err(KVError::Corruption(format!("{:?} is zstd-compressed; build with the zstd feature to read it", key)))
+    }
+}
+pub(crate) fn encode_snapshot(
+    version: Version,
+    data: &VersionMap,
+    timeline: &[(Version, u64)],
+    compression: &BTreeMap<String, Compression>,
+) -> Vec<u8> {
+    let mut buf = SNAP_MAGIC.to_vec();
+    put_u64(&mut buf, version);
+    let mut codecs = ValueCodecs::for_map(data, compression);
+    codecs.put_dictionaries(&mut buf);
+    put_u64(&mut buf, data.len() as u64);
+    let mut previous = "";
+    for (key, versions) in data {
+        let mut shared = previous.bytes().zip(key.bytes()).take_while(|(a, b)| a == b).count();
+        while !key.is_char_boundary(shared) {
+            shared -= 1;
+        }
+        put_u32(&mut buf, shared as u32);
+        put_str(&mut buf, &key[shared..]);
+        previous = key;
+        put_u32(&mut buf, versions.len() as u32);
+        for (v, value) in versions {
+            put_u64(&mut buf, *v);
+            match value {
+                Some(value) => codecs.put_value(&mut buf, key, value),
+                None => buf.push(0),
+            }
+        }
+    }
+    put_u64(&mut buf, timeline.len() as u64);
+    for (v, timestamp) in timeline {
+        put_u64(&mut buf, *v);
+        put_u64(&mut buf, *timestamp);
+    }
+    let crc = crc32(&buf);
+    put_u32(&mut buf, crc);
+    buf
+}
+pub(crate) fn decode_snapshot(bytes: &[u8]) -> Result<(Version, VersionMap, Timeline)> {
+    let magic = bytes.get(..SNAP_MAGIC.len());
+    let known = [SNAP_MAGIC, SNAP_MAGIC_V3, SNAP_MAGIC_V2, SNAP_MAGIC_V1];
+    if bytes.len() < SNAP_MAGIC.len() + 4 || !known.iter().any(|m| magic == Some(&m[..])) {
+        return Err(KVError::Corruption("not a snapshot file".to_owned()));
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(KVError::Corruption("snapshot checksum mismatch".to_owned()));
+    }
+    let mut d = Decoder::new(&body[SNAP_MAGIC.len()..]);
+    let version = d.u64()?;
+    let mut codecs = match magic == Some(SNAP_MAGIC) {
+        true => ValueCodecs::read_dictionaries(&mut d)?,
+        false => ValueCodecs::default(),
+    };
+    let mut data = VersionMap::new();
+    let mut previous = String::new();
+    for _ in 0..d.u64()? {
+        let key = match magic == Some(SNAP_MAGIC) || magic == Some(SNAP_MAGIC_V3) {
+            true => {
+                let shared = d.u32()? as usize;
+                let prefix = previous.get(..shared).ok_or_else(|| KVError::Corruption("bad shared key prefix".to_owned()))?;
+                format!("{}{}", prefix, d.string()?)
+            }
+            false => d.string()?,
+        };
+        previous.clone_from(&key);
+        let count = d.u32()?;
+        let mut versions = data.entry(key.clone());
+        for _ in 0..count {
+            let v = d.u64()?;
+            versions.insert(v, codecs.read_value(&mut d, &key)?);
+        }
+    }
+    let mut timeline = Vec::new();
+    if magic != Some(SNAP_MAGIC_V1) {
+        for _ in 0..d.u64()? {
+            timeline.push((d.u64()?, d.u64()?));
+        }
+    }
+    Ok((version, data, timeline))
+}
+pub(crate) fn write_staged(storage: &dyn Storage, staged: &[Staged]) -> Result<()> {
+    for segment in staged.chunk_by(|a, b| a.segment == b.segment) {
+        let bytes: Vec<u8> = segment.iter().flat_map(|staged| staged.record.iter().copied()).collect();
+        storage.append(&segment_name(segment[0].segment), &bytes)?;
+    }
+    Ok(())
+}
+pub(crate) fn segment_name(base: Version) -> String {
+    format!("{:020}.wal", base)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn snapshots_front_code_shared_key_prefixes() {
+        let mut data = VersionMap::new();
+        for tenant in ["acme", "ünïcode"] {
+            for order in 0..100 {
+                data.insert(format!("tenants:{}/orders/{:05}/line", tenant, order), 1, Some("x"));
+            }
+        }
+        data.insert("tenants:ü".to_owned(), 2, None::<&str>);
+        let bytes = encode_snapshot(2, &data, &[(1, 10), (2, 20)], &BTreeMap::new());
+        let prefix = "tenants:acme/orders/".as_bytes();
+        assert_eq!(bytes.windows(prefix.len()).filter(|window| *window == prefix).count(), 1);
+        let (version, decoded, timeline) = decode_snapshot(&bytes).unwrap();
+        assert_eq!((version, timeline), (2, vec![(1, 10), (2, 20)]));
+        assert_eq!(decoded, data);
+        let mut damaged = bytes.clone();
+        damaged[SNAP_MAGIC.len() + 20] ^= 1;
+        assert!(matches!(decode_snapshot(&damaged), Err(KVError::Corruption(_))));
+    }
+    #[cfg(feature = "zstd")]
+    fn documents() -> VersionMap {
+        let mut data = VersionMap::new();
+        for i in 0..500 {
+            let value = format!("{{\"id\":{},\"kind\":\"invoice\",\"status\":\"paid\",\"currency\":\"EUR\",\"lines\":[{}],\"note\":\"{}\"}}", i, i % 7, "net 30 days ".repeat(8));
+            data.insert(format!("docs:{:04}", i), 1, Some(value.as_str()));
+        }
+        data
+    }
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_snapshot_values_round_trip() {
+        let data = documents();
+        let plain = encode_snapshot(1, &data, &[], &BTreeMap::new());
+        let compression = BTreeMap::from([("docs".to_owned(), Compression::Zstd(3))]);
+        let packed = encode_snapshot(1, &data, &[(1, 7)], &compression);
+        assert!(packed.len() < plain.len(), "{} >= {}", packed.len(), plain.len());
+        let (version, decoded, timeline) = decode_snapshot(&packed).unwrap();
+        assert_eq!((version, timeline), (1, vec![(1, 7)]));
+        assert_eq!(decoded, data);
+    }
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn trained_dictionary_survives_a_reopen() {
+        let dir = TempDir::new("zstd");
+        {
+            let store = KVStore::open(dir.path()).unwrap();
+            store.set_keyspace_policy("docs", KeyspacePolicy::new().compression(Compression::Zstd(3))).unwrap();
+            let mut tx = store.begin();
+            for (key, chain) in &documents() {
+                tx.put(key.to_string(), chain.latest().unwrap().1.as_ref().unwrap().as_str().to_owned()).unwrap();
+            }
+            tx.commit().unwrap();
+            assert!(store.train_dictionary("docs", 1024).unwrap() > 0);
+            store.checkpoint().unwrap();
+        }
+        let store = KVStore::open(dir.path()).unwrap();
+        let tx = store.begin();
+        for (key, chain) in &documents() {
+            assert_eq!(tx.get(key).unwrap().as_deref(), Some(chain.latest().unwrap().1.as_ref().unwrap().as_str()));
+        }
+    }
+}